@@ -0,0 +1,70 @@
+//! Compares `match` dispatch with `dispatch = table` for a backend with many allocators
+//!
+//! Run with `cargo bench --bench dispatch`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    time::Instant,
+};
+
+use okaoka::{MultiAllocatorBackend, TagHandle};
+
+macro_rules! backend {
+    ($name:ident, $tag:ident, $($options:tt)*) => {
+        okaoka::create_multi_allocator_backend! {
+            $name,
+            $tag,
+            A0 => System,
+            A1 => System,
+            A2 => System,
+            A3 => System,
+            A4 => System,
+            A5 => System,
+            A6 => System,
+            A7 => System,
+            A8 => System,
+            A9 => System,
+            A10 => System,
+            A11 => System,
+            A12 => System,
+            A13 => System,
+            A14 => System,
+            A15 => System,
+            $($options)*
+        }
+    };
+}
+
+backend!(MatchBackend, MatchTag,);
+backend!(TableBackend, TableTag, dispatch = table);
+
+const ITERATIONS: usize = 1_000_000;
+
+fn bench<Backend: MultiAllocatorBackend>(name: &str) {
+    let handles: Vec<_> = Backend::TAGS
+        .iter()
+        .map(|&tag| TagHandle::<Backend>::new(tag))
+        .collect();
+    let layout = Layout::new::<[u64; 4]>();
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        let handle = black_box(&handles[i % handles.len()]);
+        unsafe {
+            let ptr = handle.alloc(layout);
+            handle.dealloc(black_box(ptr), layout);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{name}: {:.1} ns per alloc/dealloc",
+        elapsed.as_nanos() as f64 / ITERATIONS as f64
+    );
+}
+
+fn main() {
+    bench::<MatchBackend>("match");
+    bench::<TableBackend>("table");
+}
@@ -0,0 +1,70 @@
+//! Measures the overhead of randomized placement over the allocator it wraps
+//!
+//! Run with `cargo bench --bench randomized --features randomized`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    time::Instant,
+};
+
+use okaoka::TagHandle;
+
+okaoka::create_multi_allocator_backend! {
+    Backend,
+    Tag,
+    Plain => System,
+    Randomized(randomized) => System,
+    Spread(randomized, max_gap: 4096) => System,
+}
+
+const ITERATIONS: usize = 1_000_000;
+
+const LIVE: usize = 64;
+
+fn bench(name: &str, tag: Tag, layout: Layout) {
+    let handle = TagHandle::<Backend>::new(tag);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        unsafe {
+            let ptr = handle.alloc(layout);
+            handle.dealloc(black_box(ptr), layout);
+        }
+    }
+    let pairs = start.elapsed();
+
+    // Keeps blocks alive, so that the backing allocator can't serve every block from the same
+    // address
+    let mut live = [std::ptr::null_mut::<u8>(); LIVE];
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        unsafe {
+            let slot = &mut live[i % LIVE];
+            if !slot.is_null() {
+                handle.dealloc(*slot, layout);
+            }
+            *slot = black_box(handle.alloc(layout));
+        }
+    }
+    let churn = start.elapsed();
+    for ptr in live {
+        unsafe { handle.dealloc(ptr, layout) };
+    }
+
+    println!(
+        "{name} ({} bytes): {:.1} ns per alloc/dealloc, {:.1} ns with {LIVE} live blocks",
+        layout.size(),
+        pairs.as_nanos() as f64 / ITERATIONS as f64,
+        churn.as_nanos() as f64 / ITERATIONS as f64,
+    );
+}
+
+fn main() {
+    for size in [32, 1024] {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        bench("plain", Tag::Plain, layout);
+        bench("randomized", Tag::Randomized, layout);
+        bench("randomized, max_gap: 4096", Tag::Spread, layout);
+    }
+}
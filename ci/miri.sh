@@ -0,0 +1,32 @@
+#!/bin/sh
+# Check okaoka with Miri, from the root of the repository
+#
+# Needs a nightly toolchain with the `miri` and `rust-src` components. The tests using
+# `MultiAllocator` values run in the default mode of Miri. The ones installing it as the global
+# allocator, and the examples, run with Tree Borrows: under Stacked Borrows, the pointer given
+# back to `dealloc` by a `Box` only grants access to the contents of the box, not to the hidden
+# tag before them. The `live` feature writes the links of a block through the pointers its
+# neighbours were allocated with, which Tree Borrows rejects for the boxes of the block, so it's
+# only checked in the default mode. The examples using jemalloc are skipped, as Miri can't call
+# foreign functions, and so is the one of the watchdog, which expects samples 10 ms apart from a
+# process much slower under Miri. Leaks are ignored for the examples leaking on purpose.
+
+set -eu
+
+FEATURES=stats,owner,checksum,accounting,lifetimes,thread-arenas,slab,tlsf,buddy,cap,trace,testing,assertions
+
+cargo +nightly miri test --lib --features "$FEATURES,live"
+
+export MIRIFLAGS="-Zmiri-tree-borrows -Zmiri-ignore-leaks"
+cargo +nightly miri test --test "*" --features "$FEATURES"
+cargo +nightly miri test --doc --features "$FEATURES" -- \
+    --skip "src/handle.rs - handle::TagHandle " \
+    --skip "src/lib.rs - AllocatorGuard " \
+    --skip "src/lib.rs - MultiAllocator " \
+    --skip "src/lib.rs - MultiAllocatorBackend::aligned_route " \
+    --skip "src/lib.rs - create_multi_allocator_backend " \
+    --skip "src/lib.rs - push_allocator " \
+    --skip "src/lib.rs - set_multi_global_allocator " \
+    --skip "src/pool.rs - pool " \
+    --skip "src/registry.rs - registry " \
+    --skip "src/watchdog.rs - watchdog "
@@ -0,0 +1,190 @@
+"""Debugger commands for okaoka
+
+With the `debugger` feature, this script is embedded in the binaries using okaoka and GDB loads it
+automatically, if allowed by its `auto-load` settings. LLDB loads it with:
+
+    command script import /path/to/okaoka/debugger/okaoka.py
+
+`okaoka ptr <address> [align]` then prints the hidden tag of a live block allocated by the global
+`MultiAllocator`, from a running process or a core dump:
+
+    (gdb) okaoka ptr 0x5555555a2b70
+    0x5555555a2b70: Arena (raw tag 1), 4096 bytes, align <= 64, header at 0x5555555a2b30
+
+The alignment of the block isn't stored, so blocks aligned to more than the size of the hidden tag
+need it as second argument. The size is only known with the `live` feature.
+"""
+
+import re
+import struct
+
+MAGIC = b"OKAOKADB"
+VERSION = 1
+SYMBOL = "OKAOKA_DEBUG_INFO"
+ABSENT = 0xFFFFFFFF
+
+# Magic, then version, header size, repr size, the offsets of the owner, the accounting token,
+# the callsite, the time of the allocation, the node and the thread, the tag count and the size
+# of a tag name
+FIELDS = "8s11I"
+TAG = "I{}s"
+
+
+class Error(Exception):
+    pass
+
+
+class Layout:
+    """Layout of the hidden tags, read from the metadata of the binary"""
+
+    def __init__(self, read, address):
+        data = read(address, struct.calcsize("<" + FIELDS))
+        for order in "<>":
+            fields = struct.unpack(order + FIELDS, data)
+            if fields[1] == VERSION:
+                break
+        else:
+            raise Error("unsupported okaoka debug info at {:#x}".format(address))
+        if fields[0] != MAGIC:
+            raise Error("no okaoka debug info at {:#x}".format(address))
+        self.order = order
+        (
+            _,
+            _,
+            self.header_size,
+            self.repr_size,
+            self.owner_offset,
+            self.token_offset,
+            self.callsite_offset,
+            self.timestamp_offset,
+            self.node_offset,
+            self.thread_offset,
+            tag_count,
+            name_size,
+        ) = fields
+        tag = order + TAG.format(name_size)
+        tags = read(address + struct.calcsize(order + FIELDS), tag_count * struct.calcsize(tag))
+        self.names = {}
+        for raw_tag, name in struct.iter_unpack(tag, tags):
+            self.names[raw_tag] = name.split(b"\0", 1)[0].decode("utf-8", "replace")
+
+    def unpack(self, format, read, address):
+        return struct.unpack(self.order + format, read(address, struct.calcsize(format)))[0]
+
+    def describe(self, read, pointer_size, ptr, align=None):
+        if self.header_size == 0:
+            raise Error("okaoka was built with the `single-allocator` feature, blocks have no tag")
+        tag_size = max(align or 1, self.header_size)
+        header = ptr - tag_size
+        raw_tag = self.unpack({1: "B", 2: "H"}[self.repr_size], read, header)
+        name = self.names.get(raw_tag, "an allocator added at runtime")
+        parts = ["{:#x}: {} (raw tag {})".format(ptr, name, raw_tag)]
+        if self.node_offset != ABSENT:
+            word = {4: "I", 8: "Q"}[pointer_size]
+            size = self.unpack(word, read, header + self.node_offset + 2 * pointer_size)
+            parts.append("{} bytes".format(size))
+        if align:
+            parts.append("align {}".format(align))
+        else:
+            parts.append("align <= {}".format(self.header_size))
+        # The block is aligned to at most the lowest bit set in its address
+        max_align = ptr & -ptr
+        parts.append("header at {:#x}".format(header))
+        if self.owner_offset != ABSENT:
+            parts.append("owner {}".format(self.unpack("H", read, header + self.owner_offset)))
+        if self.callsite_offset != ABSENT:
+            index = self.unpack("H", read, header + self.callsite_offset)
+            parts.append("callsite #{}".format(index))
+        if self.thread_offset != ABSENT:
+            parts.append("thread #{}".format(self.unpack("H", read, header + self.thread_offset)))
+        description = ", ".join(parts)
+        if not align and max_align > self.header_size:
+            note = "\nthe address allows an alignment of up to {}, pass it if it's more than {}"
+            description += note.format(max_align, self.header_size)
+        return description
+
+
+USAGE = "usage: okaoka ptr <address> [align]"
+
+
+def parse_args(arguments, evaluate):
+    words = arguments.split()
+    if len(words) not in (2, 3) or words[0] != "ptr":
+        raise Error(USAGE)
+    ptr = evaluate(words[1])
+    align = evaluate(words[2]) if len(words) == 3 else None
+    if align is not None and (align <= 0 or align & (align - 1)):
+        raise Error("the alignment must be a power of two")
+    return ptr, align
+
+
+try:
+    import gdb
+except ImportError:
+    gdb = None
+
+if gdb is not None:
+
+    class OkaokaCommand(gdb.Command):
+        """Inspect the hidden tag of an okaoka allocation
+
+        okaoka ptr <address> [align]"""
+
+        def __init__(self):
+            super().__init__("okaoka", gdb.COMMAND_DATA)
+
+        def invoke(self, arguments, from_tty):
+            inferior = gdb.selected_inferior()
+
+            def read(address, size):
+                return bytes(inferior.read_memory(address, size))
+
+            try:
+                ptr, align = parse_args(arguments, lambda word: int(gdb.parse_and_eval(word)))
+                info = gdb.execute("info address " + SYMBOL, to_string=True)
+                address = re.search(r"0x[0-9a-fA-F]+", info)
+                if address is None:
+                    raise Error("{} not found, is okaoka built with `debugger`?".format(SYMBOL))
+                layout = Layout(read, int(address.group(0), 16))
+                pointer_size = gdb.lookup_type("char").pointer().sizeof
+                print(layout.describe(read, pointer_size, ptr, align))
+            except (Error, gdb.error, gdb.MemoryError) as error:
+                raise gdb.GdbError(str(error))
+
+    OkaokaCommand()
+
+
+def okaoka(debugger, arguments, result, internal_dict):
+    """Inspect the hidden tag of an okaoka allocation: okaoka ptr <address> [align]"""
+    import lldb
+
+    target = debugger.GetSelectedTarget()
+    process = target.GetProcess()
+
+    def read(address, size):
+        error = lldb.SBError()
+        data = process.ReadMemory(address, size, error)
+        if error.Fail():
+            raise Error(error.GetCString())
+        return data
+
+    def evaluate(word):
+        value = target.EvaluateExpression(word)
+        if value.GetError().Fail():
+            raise Error(value.GetError().GetCString())
+        return value.GetValueAsUnsigned()
+
+    try:
+        ptr, align = parse_args(arguments, evaluate)
+        symbols = target.FindSymbols(SYMBOL)
+        if symbols.GetSize() == 0:
+            raise Error("{} not found, is okaoka built with `debugger`?".format(SYMBOL))
+        address = symbols[0].GetSymbol().GetStartAddress().GetLoadAddress(target)
+        layout = Layout(read, address)
+        result.AppendMessage(layout.describe(read, target.GetAddressByteSize(), ptr, align))
+    except Error as error:
+        result.SetError(str(error))
+
+
+def __lldb_init_module(debugger, internal_dict):
+    debugger.HandleCommand("command script add -f {}.okaoka okaoka".format(__name__))
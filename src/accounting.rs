@@ -0,0 +1,195 @@
+//! Accounting of allocations by token
+//!
+//! With the `accounting` feature, an [`AccountingToken`] can be attached to the current thread
+//! with [`with_token`], and every allocation made while it's attached is charged to it. The
+//! hidden tag of the allocation remembers the token, so the deallocation is credited back to it
+//! even if it happens on another thread or after the token has been detached. A token can be
+//! attached to several threads at once, e.g. to the threads that serve the same request, which
+//! makes tokens the building block of per-request memory limits.
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::accounting::{with_token, AccountingToken};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//! }
+//!
+//! fn main() {
+//!     let request = AccountingToken::new();
+//!     let mut response = Vec::new();
+//!     with_token(&request, || response = vec![0u8; 1024]);
+//!     assert_eq!(request.stats().live_bytes, 1024);
+//!
+//!     std::thread::spawn(move || drop(response)).join().unwrap();
+//!     assert_eq!(request.stats().live_bytes, 0);
+//! }
+//! ```
+//!
+//! With the `single-allocator` feature, there's no hidden tag, so nothing is charged.
+
+use std::{
+    cell::Cell,
+    ptr::{self, NonNull},
+    sync::atomic::{self, AtomicUsize, Ordering},
+};
+
+use crate::stats::{Counters, TagStats};
+
+/// Size of the token in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = std::mem::size_of::<*const Inner>();
+
+thread_local! {
+    /// Token attached to the current thread, null if there's none
+    static CURRENT_TOKEN: Cell<*const Inner> = const { Cell::new(ptr::null()) };
+}
+
+struct Inner {
+    // Number of tokens plus number of live allocations charged to the token
+    refs: AtomicUsize,
+    counters: Counters,
+}
+
+impl Inner {
+    fn acquire(&self) {
+        self.refs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// # Safety
+    ///
+    /// `inner` must come from [`AccountingToken::new`] and hold a reference.
+    unsafe fn release(inner: *const Inner) {
+        if unsafe { &*inner }.refs.fetch_sub(1, Ordering::Release) == 1 {
+            atomic::fence(Ordering::Acquire);
+            drop(unsafe { Box::from_raw(inner.cast_mut()) });
+        }
+    }
+}
+
+/// Token that allocations are charged to while it's attached with [`with_token`]
+///
+/// Tokens are cheap to clone, every clone refers to the same statistics. The statistics are kept
+/// alive until the last clone is dropped and every allocation charged to the token is
+/// deallocated.
+pub struct AccountingToken {
+    inner: NonNull<Inner>,
+}
+
+// SAFETY: `Inner` only contains atomics
+unsafe impl Send for AccountingToken {}
+unsafe impl Sync for AccountingToken {}
+
+impl AccountingToken {
+    pub fn new() -> Self {
+        let inner = Box::new(Inner {
+            refs: AtomicUsize::new(1),
+            counters: Counters::new(),
+        });
+        Self {
+            inner: NonNull::from(Box::leak(inner)),
+        }
+    }
+
+    fn inner(&self) -> &Inner {
+        unsafe { self.inner.as_ref() }
+    }
+
+    /// Statistics of the allocations charged to the token
+    pub fn stats(&self) -> TagStats {
+        self.inner().counters.snapshot()
+    }
+}
+
+impl Default for AccountingToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for AccountingToken {
+    fn clone(&self) -> Self {
+        self.inner().acquire();
+        Self { inner: self.inner }
+    }
+}
+
+impl Drop for AccountingToken {
+    fn drop(&mut self) {
+        unsafe { Inner::release(self.inner.as_ptr()) }
+    }
+}
+
+impl std::fmt::Debug for AccountingToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AccountingToken")
+            .field(&self.stats())
+            .finish()
+    }
+}
+
+/// Charge the allocations of the current thread to `token` inside the closure, restoring the
+/// previous token after returning
+pub fn with_token(token: &AccountingToken, mut closure: impl FnMut()) {
+    struct Restore(*const Inner);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT_TOKEN.with(|token| token.set(self.0));
+        }
+    }
+
+    let token = token.inner.as_ptr().cast_const();
+    let _restore = Restore(CURRENT_TOKEN.with(|old_token| old_token.replace(token)));
+    closure();
+}
+
+/// Write the current token to `ptr` and charge it `size` bytes
+///
+/// # Safety
+///
+/// `ptr` must be valid for writing `SIZE` bytes. It doesn't need to be aligned.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn charge(ptr: *mut u8, size: usize) {
+    let token = CURRENT_TOKEN.with(Cell::get);
+    unsafe { ptr::write_unaligned(ptr.cast(), token) };
+    if let Some(inner) = unsafe { token.as_ref() } {
+        inner.acquire();
+        inner.counters.record_alloc(size);
+    }
+}
+
+/// Credit `size` bytes back to the token written to `ptr` by [`charge`]
+///
+/// # Safety
+///
+/// `ptr` must have been written by [`charge`], and this must be called once per call to
+/// [`charge`].
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn credit(ptr: *const u8, size: usize) {
+    let token: *const Inner = unsafe { ptr::read_unaligned(ptr.cast()) };
+    if let Some(inner) = unsafe { token.as_ref() } {
+        inner.counters.record_dealloc(size);
+        unsafe { Inner::release(token) };
+    }
+}
+
+/// Move the charge of the token written to `ptr` from `old_size` to `new_size` bytes
+///
+/// # Safety
+///
+/// `ptr` must have been written by [`charge`].
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn recharge(ptr: *const u8, old_size: usize, new_size: usize) {
+    let token: *const Inner = unsafe { ptr::read_unaligned(ptr.cast()) };
+    if let Some(inner) = unsafe { token.as_ref() } {
+        inner.counters.record_dealloc(old_size);
+        inner.counters.record_alloc(new_size);
+    }
+}
@@ -0,0 +1,190 @@
+use core::iter::FusedIterator;
+
+use crate::{get_allocator_tag, AllocatorGuard};
+
+/// Adapter running the iterator it wraps with an allocator, see [`AllocateIn::allocate_in`]
+#[derive(Debug, Clone)]
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct AllocatingIn<I> {
+    iter: I,
+    allocator_tag: u16,
+}
+
+/// Extension of iterators switching the allocator around the work of the iterator
+pub trait AllocateIn: Iterator + Sized {
+    /// Run each `next()` of this iterator with the allocator identified by the raw
+    /// `allocator_tag`
+    ///
+    /// The elements of a lazy pipeline are computed where it's consumed, which can be far from
+    /// where it was built and the allocator that was current there. With this adapter, the
+    /// allocations made while computing the elements go to the allocator of the pipeline, while
+    /// those of the code consuming them, including the adapters chained after this one, go to the
+    /// current allocator:
+    ///
+    /// ```rust
+    /// # use std::alloc::System;
+    /// # okaoka::set_multi_global_allocator! {
+    /// #     GlobalAllocator,
+    /// #     AllocatorTag,
+    /// #     System => System,
+    /// #     Arena => System,
+    /// # }
+    /// # fn main() {
+    /// use okaoka::AllocateIn;
+    ///
+    /// let lines = ["a", "b", "c"]
+    ///     .into_iter()
+    ///     .map(|line| line.repeat(2))
+    ///     .allocate_in(AllocatorTag::Arena as u8);
+    /// // The strings are allocated with the arena, the vector with the current allocator
+    /// let lines: Vec<String> = lines.collect();
+    /// # }
+    /// ```
+    ///
+    /// `fold`, and the methods built on it like `for_each`, switch the allocator once around the
+    /// whole iteration, and back around each call of their closure. The other methods, like
+    /// `try_fold`, switch it around each `next()`.
+    fn allocate_in(self, allocator_tag: impl Into<u16>) -> AllocatingIn<Self> {
+        AllocatingIn {
+            iter: self,
+            allocator_tag: allocator_tag.into(),
+        }
+    }
+}
+
+impl<I: Iterator> AllocateIn for I {}
+
+impl<I> AllocatingIn<I> {
+    /// Raw tag of the allocator the iterator is run with
+    pub fn tag(&self) -> u16 {
+        self.allocator_tag
+    }
+
+    /// Unwrap the iterator, which is then run with the current allocator
+    pub fn into_inner(self) -> I {
+        self.iter
+    }
+}
+
+impl<I: Iterator> Iterator for AllocatingIn<I> {
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        let _guard = AllocatorGuard::new(self.allocator_tag);
+        self.iter.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+
+    #[inline]
+    fn nth(&mut self, n: usize) -> Option<I::Item> {
+        let _guard = AllocatorGuard::new(self.allocator_tag);
+        self.iter.nth(n)
+    }
+
+    #[inline]
+    fn fold<B, F: FnMut(B, I::Item) -> B>(self, init: B, mut f: F) -> B {
+        let outer_tag = get_allocator_tag();
+        let _guard = AllocatorGuard::new(self.allocator_tag);
+        self.iter.fold(init, |accumulator, item| {
+            let _guard = AllocatorGuard::with_tag(outer_tag);
+            f(accumulator, item)
+        })
+    }
+}
+
+impl<I: DoubleEndedIterator> DoubleEndedIterator for AllocatingIn<I> {
+    #[inline]
+    fn next_back(&mut self) -> Option<I::Item> {
+        let _guard = AllocatorGuard::new(self.allocator_tag);
+        self.iter.next_back()
+    }
+
+    #[inline]
+    fn rfold<B, F: FnMut(B, I::Item) -> B>(self, init: B, mut f: F) -> B {
+        let outer_tag = get_allocator_tag();
+        let _guard = AllocatorGuard::new(self.allocator_tag);
+        self.iter.rfold(init, |accumulator, item| {
+            let _guard = AllocatorGuard::with_tag(outer_tag);
+            f(accumulator, item)
+        })
+    }
+}
+
+impl<I: ExactSizeIterator> ExactSizeIterator for AllocatingIn<I> {}
+
+impl<I: FusedIterator> FusedIterator for AllocatingIn<I> {}
+
+#[cfg(all(test, feature = "std", not(feature = "single-allocator")))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    use super::*;
+    use crate::{
+        test_util::{self, Counted},
+        MultiAllocator,
+    };
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        Heap => Counted::<2>,
+        Arena => Counted::<3>,
+    }
+
+    static ALLOCATOR: MultiAllocator<Backend> = MultiAllocator::new();
+    const LAYOUT: Layout = Layout::new::<u64>();
+
+    /// Allocate a block with the current allocator, returning the allocations of each allocator
+    /// so far
+    fn allocate() -> [usize; 2] {
+        unsafe { ALLOCATOR.dealloc(ALLOCATOR.alloc(LAYOUT), LAYOUT) };
+        [test_util::allocated(2), test_util::allocated(3)]
+    }
+
+    #[test]
+    fn items_are_computed_with_the_named_allocator() {
+        let arena = BackendTag::Arena as u8;
+        let mut items = (0..4).map(|_| allocate()).allocate_in(arena);
+        assert_eq!(items.next(), Some([0, 1]));
+        assert_eq!(items.next_back(), Some([0, 2]));
+        assert_eq!(items.nth(1), Some([0, 4]));
+        assert_eq!(items.next(), None);
+        assert_eq!(allocate(), [1, 4]);
+
+        // `fold` switches back to the outer allocator around its closure
+        let counts =
+            (0..2)
+                .map(|_| allocate())
+                .allocate_in(arena)
+                .fold(Vec::new(), |mut counts, item| {
+                    counts.push((item, allocate()));
+                    counts
+                });
+        assert_eq!(counts, [([1, 5], [2, 5]), ([2, 6], [3, 6])]);
+        let counts =
+            (0..2)
+                .map(|_| allocate())
+                .allocate_in(arena)
+                .rfold(Vec::new(), |mut counts, item| {
+                    counts.push((item, allocate()));
+                    counts
+                });
+        assert_eq!(counts, [([3, 7], [4, 7]), ([4, 8], [5, 8])]);
+
+        // Other methods switch it around each `next()`
+        let sum: usize = (0..2)
+            .map(|_| allocate()[1])
+            .allocate_in(arena)
+            .map(|count| count + allocate()[0])
+            .sum();
+        assert_eq!(sum, 9 + 6 + 10 + 7);
+        assert_eq!(allocate(), [8, 10]);
+    }
+}
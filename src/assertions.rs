@@ -0,0 +1,409 @@
+//! Allocation assertions for tests
+//!
+//! With the `assertions` feature, these macros check the allocations of the current thread while
+//! they evaluate a block:
+//!
+//! - [`assert_no_alloc!`](crate::assert_no_alloc) forbids them, to guard real-time code and hot
+//!   paths against regressions,
+//! - [`assert_allocates_in!`](crate::assert_allocates_in) only allows the ones made with a given
+//!   allocator, to check that a scope selecting it covers the intended code paths,
+//! - [`assert_alloc_count!`](crate::assert_alloc_count) counts them and checks conditions on the
+//!   counts, to lock in how much a function allocates.
+//!
+//! An allocation or reallocation they don't allow is a violation, reported with the layout of the
+//! allocation, its allocator, the location of the macro and a backtrace of the allocation:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut samples = Vec::with_capacity(64);
+//!     let sum = okaoka::assert_no_alloc! {
+//!         samples.extend([1, 2, 3]);
+//!         samples.iter().sum::<i32>()
+//!     };
+//!     assert_eq!(sum, 6);
+//!
+//!     let result = std::panic::catch_unwind(|| okaoka::assert_no_alloc!(vec![1, 2, 3]));
+//!     assert!(result.is_err());
+//!
+//!     let mut names = Vec::new();
+//!     okaoka::assert_allocates_in!(AllocatorTag::Arena, {
+//!         GlobalAllocator::with(AllocatorTag::Arena, || names.push("first".to_string()));
+//!     });
+//!     let result = std::panic::catch_unwind(|| {
+//!         okaoka::assert_allocates_in!(AllocatorTag::Arena, Box::new(10))
+//!     });
+//!     assert!(result.is_err());
+//!
+//!     let numbers = okaoka::assert_alloc_count!(|| vec![1u64, 2, 3], allocs == 1, bytes <= 64);
+//!     let sum = okaoka::assert_alloc_count!(move || numbers.into_iter().sum::<u64>(), allocs == 0);
+//!     assert_eq!(sum, 6);
+//! }
+//! ```
+//!
+//! Only the allocations made through [`MultiAllocator`](crate::MultiAllocator) are checked,
+//! deallocating is allowed. Like in the [`stats`](crate::stats), a reallocation counts as a
+//! deallocation and an allocation. Growing a block allocated outside of the macro is checked
+//! against its own allocator. With the `single-allocator` feature, every allocation is made with
+//! the default allocator.
+
+use std::{
+    alloc::Layout,
+    backtrace::Backtrace,
+    cell::{Cell, RefCell},
+    fmt,
+    panic::Location,
+    process,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{stats::Counters, MultiAllocatorBackend, TagRepr};
+
+/// What happens when a thread allocates inside [`assert_no_alloc!`](crate::assert_no_alloc) or
+/// with another allocator inside [`assert_allocates_in!`](crate::assert_allocates_in)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NoAllocMode {
+    /// Panic once the macro has evaluated its block, reporting the first violation and the
+    /// number of violations
+    #[default]
+    Panic,
+    /// Print the violation to stderr and abort on the spot, e.g. to get a core dump
+    Abort,
+}
+
+static NO_ALLOC_MODE: AtomicU8 = AtomicU8::new(NoAllocMode::Panic as u8);
+
+/// Set what happens on violations, for every thread
+pub fn set_no_alloc_mode(mode: NoAllocMode) {
+    NO_ALLOC_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// What happens on violations
+pub fn no_alloc_mode() -> NoAllocMode {
+    match NO_ALLOC_MODE.load(Ordering::Relaxed) {
+        0 => NoAllocMode::Panic,
+        _ => NoAllocMode::Abort,
+    }
+}
+
+type Callsite = &'static Location<'static>;
+
+/// Allocations made by a thread inside [`assert_alloc_count!`](crate::assert_alloc_count)
+///
+/// Sizes are the ones requested by the user, without the hidden tag. They're the differences of
+/// the [`Counters`] of the thread, which count like the ones of the tags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AllocCount {
+    /// Number of allocations made
+    pub allocs: usize,
+    /// Number of deallocations made, including the ones of blocks allocated before
+    pub deallocs: usize,
+    /// Total bytes allocated
+    pub bytes: usize,
+    /// Total bytes deallocated
+    pub deallocated_bytes: usize,
+    /// Highest value the bytes allocated minus the bytes deallocated have reached
+    pub peak_bytes: usize,
+}
+
+impl AllocCount {
+    /// Bytes allocated minus bytes deallocated, negative if more bytes were deallocated
+    pub fn net_bytes(&self) -> isize {
+        self.bytes as isize - self.deallocated_bytes as isize
+    }
+}
+
+/// Allocations allowed by the innermost macro of a thread
+#[derive(Clone, Copy)]
+struct Scope {
+    callsite: Callsite,
+    // Raw tag of the allowed allocator, none if allocating is forbidden
+    raw_tag: Option<u16>,
+}
+
+/// First allocation not allowed by a scope
+struct Violation {
+    scope: Scope,
+    layout: Layout,
+    raw_tag: u16,
+    tag_name: fn(u16) -> Option<&'static str>,
+    backtrace: Backtrace,
+    count: usize,
+}
+
+impl Violation {
+    fn write_tag(&self, f: &mut fmt::Formatter<'_>, raw_tag: u16) -> fmt::Result {
+        match (self.tag_name)(raw_tag) {
+            Some(name) => write!(f, "{name} ({raw_tag})"),
+            None => write!(f, "{raw_tag}"),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allocation of {} bytes aligned to {} with ",
+            self.layout.size(),
+            self.layout.align(),
+        )?;
+        self.write_tag(f, self.raw_tag)?;
+        match self.scope.raw_tag {
+            None => write!(f, " inside assert_no_alloc! at {}", self.scope.callsite)?,
+            Some(raw_tag) => {
+                write!(f, " instead of ")?;
+                self.write_tag(f, raw_tag)?;
+                write!(f, " inside assert_allocates_in! at {}", self.scope.callsite)?;
+            }
+        }
+        if self.count > 1 {
+            write!(f, " ({} violations in total)", self.count)?;
+        }
+        write!(f, "\n{}", self.backtrace)
+    }
+}
+
+thread_local! {
+    /// Innermost scope of the current thread checking allocations
+    static SCOPE: Cell<Option<Scope>> = const { Cell::new(None) };
+    /// Violation of the innermost scope of the current thread
+    static VIOLATION: RefCell<Option<Violation>> = const { RefCell::new(None) };
+    /// Allocations of the current thread, whose differences are the counts of the scopes
+    ///
+    /// Its live bytes wrap around when the thread deallocates more than it allocated.
+    static COUNTERS: Counters = const { Counters::new() };
+    /// Highest live bytes of [`COUNTERS`] since the innermost scope of the current thread
+    /// counting allocations started, `None` outside of those scopes
+    static PEAK: Cell<Option<isize>> = const { Cell::new(None) };
+}
+
+/// Run the closure, returning its result and the allocations the current thread made inside
+/// it, see [`assert_alloc_count!`](crate::assert_alloc_count)
+pub fn count_allocs<R>(closure: impl FnOnce() -> R) -> (R, AllocCount) {
+    // Restores the peak of the outer scope, raised to the inner one, even when unwinding
+    struct Restore(Option<isize>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let inner = PEAK.with(Cell::get);
+            PEAK.with(|peak| peak.set(self.0.map(|outer| outer.max(inner.unwrap_or(outer)))));
+        }
+    }
+
+    let start = COUNTERS.with(Counters::snapshot);
+    let restore = Restore(PEAK.with(|peak| peak.replace(Some(start.live_bytes as isize))));
+    let result = closure();
+    let end = COUNTERS.with(Counters::snapshot);
+    let peak = PEAK.with(Cell::get).unwrap_or_default();
+    drop(restore);
+    let count = AllocCount {
+        allocs: end.allocations.wrapping_sub(start.allocations),
+        deallocs: end.deallocations.wrapping_sub(start.deallocations),
+        bytes: end.allocated_bytes.wrapping_sub(start.allocated_bytes),
+        deallocated_bytes: end.deallocated_bytes.wrapping_sub(start.deallocated_bytes),
+        peak_bytes: peak.wrapping_sub(start.live_bytes as isize).max(0) as usize,
+    };
+    (result, count)
+}
+
+/// Run the closure, failing as configured with [`set_no_alloc_mode`] if the current thread
+/// allocates inside it, see [`assert_no_alloc!`](crate::assert_no_alloc)
+#[track_caller]
+pub fn no_alloc<R>(closure: impl FnOnce() -> R) -> R {
+    check_scope(None, closure)
+}
+
+/// Run the closure, failing as configured with [`set_no_alloc_mode`] if the current thread
+/// allocates with another allocator than the one identified by the raw `allocator_tag` inside
+/// it, see [`assert_allocates_in!`](crate::assert_allocates_in)
+#[track_caller]
+pub fn allocates_in<R>(allocator_tag: impl Into<u16>, closure: impl FnOnce() -> R) -> R {
+    check_scope(Some(allocator_tag.into()), closure)
+}
+
+#[track_caller]
+fn check_scope<R>(raw_tag: Option<u16>, closure: impl FnOnce() -> R) -> R {
+    // Restores the outer scope, even when unwinding
+    struct Restore(Option<Scope>, Option<Violation>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SCOPE.with(|scope| scope.set(self.0));
+            let outer = self.1.take();
+            VIOLATION.with(|violation| violation.replace(outer));
+        }
+    }
+
+    let scope = Scope {
+        callsite: Location::caller(),
+        raw_tag,
+    };
+    // Taken before checking allocations, initializing the thread-local may allocate
+    let outer_violation = VIOLATION.with(|violation| violation.take());
+    let restore = Restore(
+        SCOPE.with(|current| current.replace(Some(scope))),
+        outer_violation,
+    );
+    let result = closure();
+    SCOPE.with(|current| current.set(None));
+    let violation = VIOLATION.with(|violation| violation.take());
+    drop(restore);
+    if let Some(violation) = violation {
+        panic!("{violation}");
+    }
+    result
+}
+
+/// Report an allocation of `layout` with the allocator identified by `raw_tag` if the current
+/// thread is inside a scope that doesn't allow it
+#[inline(always)]
+pub(crate) fn check_alloc<Backend: MultiAllocatorBackend>(layout: Layout, raw_tag: u16) {
+    if let Ok(Some(scope)) = SCOPE.try_with(Cell::get) {
+        if scope.raw_tag != Some(raw_tag) {
+            violate(scope, layout, raw_tag, tag_name::<Backend>);
+        }
+    }
+}
+
+/// Count an allocation of `size` bytes by the current thread
+#[inline(always)]
+pub(crate) fn record_alloc(size: usize) {
+    let _ = COUNTERS.try_with(|counters| {
+        counters.record_alloc(size);
+        let live_bytes = counters.live_bytes() as isize;
+        let _ = PEAK.try_with(|peak| {
+            if let Some(current) = peak.get() {
+                peak.set(Some(current.max(live_bytes)));
+            }
+        });
+    });
+}
+
+/// Count a deallocation of `size` bytes by the current thread
+#[inline(always)]
+pub(crate) fn record_dealloc(size: usize) {
+    let _ = COUNTERS.try_with(|counters| counters.record_dealloc(size));
+}
+
+fn tag_name<Backend: MultiAllocatorBackend>(raw_tag: u16) -> Option<&'static str> {
+    let tag = Backend::Repr::from_raw(raw_tag)?;
+    Some(Backend::tag_name(Backend::Tag::try_from(tag).ok()?))
+}
+
+#[cold]
+#[inline(never)]
+fn violate(scope: Scope, layout: Layout, raw_tag: u16, tag_name: fn(u16) -> Option<&'static str>) {
+    // Reporting allocates
+    SCOPE.with(|current| current.set(None));
+    let _ = VIOLATION.try_with(|violation| match &mut *violation.borrow_mut() {
+        Some(violation) => violation.count += 1,
+        violation => {
+            *violation = Some(Violation {
+                scope,
+                layout,
+                raw_tag,
+                tag_name,
+                // Miri can't print backtraces without disabling its isolation
+                backtrace: if cfg!(miri) {
+                    Backtrace::disabled()
+                } else {
+                    Backtrace::force_capture()
+                },
+                count: 1,
+            })
+        }
+    });
+    if no_alloc_mode() == NoAllocMode::Abort {
+        if let Ok(Some(violation)) = VIOLATION.try_with(|violation| violation.take()) {
+            eprintln!("{violation}");
+        }
+        process::abort();
+    }
+    SCOPE.with(|current| current.set(Some(scope)));
+}
+
+/// Evaluate the expression or block, failing as configured with
+/// [`set_no_alloc_mode`](crate::assertions::set_no_alloc_mode) if the current thread allocates
+/// while doing so, see the [`assertions`](crate::assertions) module
+///
+/// A closure without arguments is called instead. Returns the value of the expression. By
+/// default, the violation is reported with a panic once
+/// the expression has been evaluated, since panicking inside the allocator would abort.
+#[macro_export]
+macro_rules! assert_no_alloc {
+    (move || $body:expr) => {
+        $crate::assertions::no_alloc(move || $body)
+    };
+    (|| $body:expr) => {
+        $crate::assertions::no_alloc(|| $body)
+    };
+    ($($body:tt)+) => {
+        $crate::assertions::no_alloc(|| { $($body)+ })
+    };
+}
+
+/// Evaluate the expression or block after the tag, failing as configured with
+/// [`set_no_alloc_mode`](crate::assertions::set_no_alloc_mode) if the current thread allocates
+/// with another allocator while doing so, see the [`assertions`](crate::assertions) module
+///
+/// The tag is a variant of the tag enum of the backend or a raw tag. A closure without arguments
+/// is called instead. Returns the value of the expression. Selecting the allocator is left to the
+/// expression.
+#[macro_export]
+macro_rules! assert_allocates_in {
+    ($tag:expr, move || $body:expr) => {
+        $crate::assertions::allocates_in($tag as u16, move || $body)
+    };
+    ($tag:expr, || $body:expr) => {
+        $crate::assertions::allocates_in($tag as u16, || $body)
+    };
+    ($tag:expr, $($body:tt)+) => {
+        $crate::assertions::allocates_in($tag as u16, || { $($body)+ })
+    };
+}
+
+/// Evaluate the expression, or call the closure without arguments, and assert conditions on the
+/// allocations the current thread made while doing so, see the
+/// [`assertions`](crate::assertions) module
+///
+/// Each condition compares a field of [`AllocCount`](crate::assertions::AllocCount) to a
+/// value, like `allocs == 3` or `bytes <= 4096`. Returns the value of the expression. A closure
+/// keeps its `move`, if any.
+#[macro_export]
+macro_rules! assert_alloc_count {
+    (@count $closure:expr, $($field:ident $op:tt $value:expr),+ $(,)?) => {{
+        let (result, count) = $crate::assertions::count_allocs($closure);
+        $(
+            assert!(
+                count.$field $op $value,
+                "allocation count assertion failed: {} {} {}, was {}\n{:?}",
+                stringify!($field),
+                stringify!($op),
+                stringify!($value),
+                count.$field,
+                count,
+            );
+        )+
+        result
+    }};
+    (move || $body:expr, $($conditions:tt)+) => {
+        $crate::assert_alloc_count!(@count move || $body, $($conditions)+)
+    };
+    (|| $body:expr, $($conditions:tt)+) => {
+        $crate::assert_alloc_count!(@count || $body, $($conditions)+)
+    };
+    ($body:expr, $($conditions:tt)+) => {
+        $crate::assert_alloc_count!(@count || $body, $($conditions)+)
+    };
+}
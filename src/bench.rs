@@ -0,0 +1,162 @@
+//! Allocation counts in criterion benchmarks
+//!
+//! With the `criterion` feature, [`Allocations`] wraps the routine of a criterion benchmark, and
+//! counts the allocations and the bytes allocated by each tag per iteration while criterion times
+//! it. [`bench_function`] also prints them after the timing of the benchmark, so a routine that
+//! starts allocating more shows up in the same benches as one that gets slower:
+//!
+//! ```rust
+//! use std::{alloc::System, time::Duration};
+//!
+//! use criterion::Criterion;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut criterion = Criterion::default()
+//!         .warm_up_time(Duration::from_millis(10))
+//!         .measurement_time(Duration::from_millis(100))
+//!         .sample_size(10);
+//!
+//!     // parse          time:   [40.216 ns 40.318 ns 40.488 ns]
+//!     // parse: allocations per iteration:
+//!     //   Arena: 1 allocations, 64 bytes
+//!     let allocations = okaoka::bench::bench_function::<GlobalAllocator, _>(
+//!         &mut criterion,
+//!         "parse",
+//!         || {
+//!             let mut values = Vec::new();
+//!             GlobalAllocator::with(AllocatorTag::Arena, || values = vec![0u64; 8]);
+//!             values
+//!         },
+//!     );
+//!     assert_eq!(allocations.allocations(AllocatorTag::Arena), 1.0);
+//!     assert_eq!(allocations.bytes(AllocatorTag::Arena), 64.0);
+//! }
+//! ```
+//!
+//! [`Allocations::iter`] takes the place of `Bencher::iter` for the benchmarks that
+//! [`bench_function`] doesn't cover, like those of a group. The counts are those of the `stats`
+//! feature, so they include the allocations of the other threads while the routine runs, and the
+//! deallocation of the values returned by the routine, which are dropped after each iteration.
+
+use std::{fmt, hint::black_box, time::Instant};
+
+use criterion::{measurement::WallTime, Bencher, Criterion};
+
+use crate::{
+    stats::{tag_stats, TagStats},
+    MultiAllocatorBackend,
+};
+
+/// Allocations of each tag over the iterations of a benchmark, see the module documentation
+pub struct Allocations<Backend: MultiAllocatorBackend> {
+    // Allocations and allocated bytes of each tag, by index
+    totals: Vec<(usize, usize)>,
+    iterations: u64,
+    // Statistics of each tag before and after iterating, allocated once
+    before: Vec<TagStats>,
+    after: Vec<TagStats>,
+    _backend: std::marker::PhantomData<Backend>,
+}
+
+impl<Backend: MultiAllocatorBackend> Allocations<Backend> {
+    pub fn new() -> Self {
+        Self {
+            totals: vec![(0, 0); Backend::TAGS.len()],
+            iterations: 0,
+            before: Vec::with_capacity(Backend::TAGS.len()),
+            after: Vec::with_capacity(Backend::TAGS.len()),
+            _backend: std::marker::PhantomData,
+        }
+    }
+
+    /// Time `routine` with `bencher`, like `Bencher::iter`, counting its allocations
+    pub fn iter<O>(&mut self, bencher: &mut Bencher<'_, WallTime>, mut routine: impl FnMut() -> O) {
+        bencher.iter_custom(|iterations| {
+            // Read into the buffers allocated beforehand, so that reading doesn't allocate
+            snapshot::<Backend>(&mut self.before);
+            let start = Instant::now();
+            for _ in 0..iterations {
+                black_box(routine());
+            }
+            let elapsed = start.elapsed();
+            snapshot::<Backend>(&mut self.after);
+
+            for ((total, before), after) in
+                self.totals.iter_mut().zip(&self.before).zip(&self.after)
+            {
+                total.0 += after.allocations - before.allocations;
+                total.1 += after.allocated_bytes - before.allocated_bytes;
+            }
+            self.iterations += iterations;
+            elapsed
+        });
+    }
+
+    /// Allocations per iteration made with the allocator identified by `tag`
+    pub fn allocations(&self, tag: Backend::Tag) -> f64 {
+        self.per_iteration(self.totals[Backend::tag_index(tag)].0)
+    }
+
+    /// Bytes allocated per iteration with the allocator identified by `tag`
+    pub fn bytes(&self, tag: Backend::Tag) -> f64 {
+        self.per_iteration(self.totals[Backend::tag_index(tag)].1)
+    }
+
+    fn per_iteration(&self, total: usize) -> f64 {
+        total as f64 / self.iterations.max(1) as f64
+    }
+}
+
+impl<Backend: MultiAllocatorBackend> Default for Allocations<Backend> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lines of the tags that allocated, or a line saying that none did
+impl<Backend: MultiAllocatorBackend> fmt::Display for Allocations<Backend> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut allocated = false;
+        for &tag in Backend::TAGS {
+            let allocations = self.allocations(tag);
+            if allocations > 0.0 {
+                allocated = true;
+                writeln!(
+                    f,
+                    "  {}: {allocations} allocations, {} bytes",
+                    Backend::tag_name(tag),
+                    self.bytes(tag)
+                )?;
+            }
+        }
+        if !allocated {
+            writeln!(f, "  no allocations")?;
+        }
+        Ok(())
+    }
+}
+
+fn snapshot<Backend: MultiAllocatorBackend>(stats: &mut Vec<TagStats>) {
+    stats.clear();
+    stats.extend(Backend::TAGS.iter().map(|&tag| tag_stats::<Backend>(tag)));
+}
+
+/// Benchmark `routine` as `id` with `criterion`, printing its allocations per iteration after
+/// its timing, and returning them
+pub fn bench_function<Backend: MultiAllocatorBackend, O>(
+    criterion: &mut Criterion<WallTime>,
+    id: &str,
+    mut routine: impl FnMut() -> O,
+) -> Allocations<Backend> {
+    let mut allocations = Allocations::new();
+    criterion.bench_function(id, |bencher| allocations.iter(bencher, &mut routine));
+    print!("{id}: allocations per iteration:\n{allocations}");
+    allocations
+}
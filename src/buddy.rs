@@ -0,0 +1,388 @@
+//! Buddy allocator over a fixed buffer
+//!
+//! With the `buddy` feature, [`Buddy`] splits a buffer of a power of two bytes into blocks of
+//! powers of two. A request is rounded up to the smallest block that holds it, obtained by
+//! halving larger blocks, and a freed block is merged with its buddy, the other half of the block
+//! it was split from, as soon as both are free. Every block is aligned to its size, up to
+//! [`MAX_ALIGN`], and the fragmentation only depends on the sizes requested, which suits DMA
+//! buffers or the memory of emulated machines:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::buddy::Buddy;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Dma => static Buddy<{ 1 << 20 }> = Buddy::new(),
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::with(AllocatorTag::Dma, || {
+//!         let buffers: Vec<Vec<u8>> = (0..8).map(|_| Vec::with_capacity(4000)).collect();
+//!         drop(buffers);
+//!     });
+//! }
+//! ```
+//!
+//! The size of a block includes its hidden tag, so a request of exactly a power of two bytes
+//! takes a block twice as large. The first `N / 256` bytes of the buffer, or `N / 128` on 32-bit
+//! targets, hold the bitmap of free blocks.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    mem::{size_of, MaybeUninit},
+    ptr,
+    sync::Mutex,
+};
+
+const WORD: usize = size_of::<usize>();
+
+/// Size of the smallest block, whose payload holds the links of the free lists once it's freed
+pub const MIN_BLOCK: usize = 4 * WORD;
+
+/// Alignment of the buffer, which bounds the alignment of the blocks
+pub const MAX_ALIGN: usize = 4096;
+
+/// Absent block
+const NONE: usize = usize::MAX;
+
+#[repr(C, align(4096))]
+struct Buffer<const N: usize>(MaybeUninit<[u8; N]>);
+
+/// Heads of the free lists by order, with blocks identified by their offset in the buffer
+struct FreeLists {
+    initialized: bool,
+    heads: [usize; usize::BITS as usize],
+}
+
+/// Buddy allocator over a fixed buffer of `N` bytes, see the module documentation
+pub struct Buddy<const N: usize> {
+    buffer: UnsafeCell<Buffer<N>>,
+    free_lists: Mutex<FreeLists>,
+}
+
+// SAFETY: the buffer is only accessed with the lock of the free lists held, or through blocks
+// handed out once
+unsafe impl<const N: usize> Sync for Buddy<N> {}
+
+impl<const N: usize> Buddy<N> {
+    /// Order of the block spanning the buffer
+    const MAX_ORDER: usize = (N / MIN_BLOCK).trailing_zeros() as usize;
+
+    pub const fn new() -> Self {
+        assert!(
+            N.is_power_of_two() && N >= 8 * MIN_BLOCK,
+            "the buffer of a buddy allocator must be a power of two of at least 8 blocks"
+        );
+        Self {
+            buffer: UnsafeCell::new(Buffer(MaybeUninit::uninit())),
+            free_lists: Mutex::new(FreeLists {
+                initialized: false,
+                heads: [NONE; usize::BITS as usize],
+            }),
+        }
+    }
+
+    /// Size of the buffer
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Bytes of the free blocks
+    pub fn free_bytes(&self) -> usize {
+        let (free_lists, blocks) = self.lock();
+        (0..=Self::MAX_ORDER)
+            .map(|order| {
+                let mut block = free_lists.heads[order];
+                let mut bytes = 0;
+                while block != NONE {
+                    bytes += MIN_BLOCK << order;
+                    block = unsafe { *blocks.word(block, 0) };
+                }
+                bytes
+            })
+            .sum()
+    }
+
+    /// Order of the block serving `layout`, `None` if it's larger than the buffer
+    fn order(layout: Layout) -> Option<usize> {
+        if layout.align() > MAX_ALIGN {
+            return None;
+        }
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(MIN_BLOCK)
+            .checked_next_power_of_two()?;
+        (size <= N).then(|| (size / MIN_BLOCK).trailing_zeros() as usize)
+    }
+
+    /// Free lists, initializing the buffer if needed
+    fn lock(&self) -> (std::sync::MutexGuard<'_, FreeLists>, Blocks) {
+        let mut free_lists = self.free_lists.lock().unwrap_or_else(|e| e.into_inner());
+        let blocks = Blocks {
+            base: self.buffer.get().cast(),
+        };
+        if !free_lists.initialized {
+            free_lists.initialized = true;
+            // Reserve the first block large enough for the bitmap, freeing the rest of the buffer
+            let bitmap_bytes = N / MIN_BLOCK / 8;
+            let bitmap_order = Self::order(Layout::from_size_align(bitmap_bytes, 1).unwrap())
+                .unwrap_or(Self::MAX_ORDER);
+            unsafe { ptr::write_bytes(blocks.base, 0, bitmap_bytes) };
+            for order in bitmap_order..Self::MAX_ORDER {
+                unsafe { blocks.push(&mut free_lists, MIN_BLOCK << order, order) };
+            }
+        }
+        (free_lists, blocks)
+    }
+}
+
+impl<const N: usize> Default for Buddy<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Blocks {
+    base: *mut u8,
+}
+
+// Free blocks hold the next and previous blocks of their list and their order, and have their
+// bit set in the bitmap at the start of the buffer
+impl Blocks {
+    unsafe fn word(&self, block: usize, index: usize) -> *mut usize {
+        unsafe { self.base.add(block).cast::<usize>().add(index) }
+    }
+
+    unsafe fn is_free(&self, block: usize, order: usize) -> bool {
+        let bit = block / MIN_BLOCK;
+        unsafe { *self.base.add(bit / 8) & (1 << (bit % 8)) != 0 && *self.word(block, 2) == order }
+    }
+
+    unsafe fn set_free(&self, block: usize, free: bool) {
+        let bit = block / MIN_BLOCK;
+        unsafe {
+            let byte = self.base.add(bit / 8);
+            if free {
+                *byte |= 1 << (bit % 8);
+            } else {
+                *byte &= !(1 << (bit % 8));
+            }
+        }
+    }
+
+    unsafe fn push(&self, free_lists: &mut FreeLists, block: usize, order: usize) {
+        let next = free_lists.heads[order];
+        unsafe {
+            *self.word(block, 0) = next;
+            *self.word(block, 1) = NONE;
+            *self.word(block, 2) = order;
+            if next != NONE {
+                *self.word(next, 1) = block;
+            }
+            self.set_free(block, true);
+        }
+        free_lists.heads[order] = block;
+    }
+
+    unsafe fn remove(&self, free_lists: &mut FreeLists, block: usize, order: usize) {
+        unsafe {
+            let (next, prev) = (*self.word(block, 0), *self.word(block, 1));
+            if prev == NONE {
+                free_lists.heads[order] = next;
+            } else {
+                *self.word(prev, 0) = next;
+            }
+            if next != NONE {
+                *self.word(next, 1) = prev;
+            }
+            self.set_free(block, false);
+        }
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for Buddy<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(order) = Self::order(layout) else {
+            return ptr::null_mut();
+        };
+        let (mut free_lists, blocks) = self.lock();
+        let Some(mut found) = (order..=Self::MAX_ORDER).find(|&o| free_lists.heads[o] != NONE)
+        else {
+            return ptr::null_mut();
+        };
+        let block = free_lists.heads[found];
+        unsafe {
+            blocks.remove(&mut free_lists, block, found);
+            // Free the upper halves until the block has the right size
+            while found > order {
+                found -= 1;
+                blocks.push(&mut free_lists, block + (MIN_BLOCK << found), found);
+            }
+            blocks.base.add(block)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(mut order) = Self::order(layout) else {
+            return;
+        };
+        let (mut free_lists, blocks) = self.lock();
+        let mut block = unsafe { ptr.offset_from(blocks.base) as usize };
+        while order < Self::MAX_ORDER {
+            let buddy = block ^ (MIN_BLOCK << order);
+            if !unsafe { blocks.is_free(buddy, order) } {
+                break;
+            }
+            unsafe { blocks.remove(&mut free_lists, buddy, order) };
+            block = block.min(buddy);
+            order += 1;
+        }
+        unsafe { blocks.push(&mut free_lists, block, order) };
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        if Self::order(layout) == Self::order(new_layout) {
+            return ptr;
+        }
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 1 << 16;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    /// Allocate blocks of the smallest order until the allocator is full
+    fn fill<const N: usize>(buddy: &Buddy<N>) -> Vec<*mut u8> {
+        let mut blocks = Vec::new();
+        loop {
+            let ptr = unsafe { buddy.alloc(layout(1, 1)) };
+            if ptr.is_null() {
+                break blocks;
+            }
+            blocks.push(ptr);
+        }
+    }
+
+    #[test]
+    fn splits_down_to_the_smallest_order() {
+        let buddy = Buddy::<SIZE>::new();
+        let free = buddy.free_bytes();
+        let ptr = unsafe { buddy.alloc(layout(1, 1)) };
+        assert!(!ptr.is_null());
+        assert_eq!(buddy.free_bytes(), free - MIN_BLOCK);
+        unsafe { buddy.dealloc(ptr, layout(1, 1)) };
+        assert_eq!(buddy.free_bytes(), free);
+
+        let mut blocks = fill(&buddy);
+        assert_eq!(blocks.len(), free / MIN_BLOCK);
+        assert_eq!(buddy.free_bytes(), 0);
+        blocks.sort_unstable();
+        for pair in blocks.windows(2) {
+            assert_eq!(pair[1] as usize - pair[0] as usize, MIN_BLOCK);
+        }
+        for ptr in blocks {
+            unsafe { buddy.dealloc(ptr, layout(1, 1)) };
+        }
+    }
+
+    #[test]
+    fn merges_back_to_full_capacity() {
+        let buddy = Buddy::<SIZE>::new();
+        let free = buddy.free_bytes();
+        // Everything but the block of the bitmap, in the lower half
+        assert!(free >= SIZE / 2);
+
+        let orders: [fn(&mut Vec<*mut u8>); 3] = [
+            |_| {},
+            |blocks| blocks.reverse(),
+            |blocks| {
+                let even = blocks.iter().step_by(2);
+                let odd = blocks.iter().skip(1).step_by(2);
+                *blocks = even.chain(odd).copied().collect();
+            },
+        ];
+        for order in orders {
+            let mut blocks = fill(&buddy);
+            order(&mut blocks);
+            for ptr in blocks {
+                unsafe { buddy.dealloc(ptr, layout(1, 1)) };
+            }
+            assert_eq!(buddy.free_bytes(), free);
+
+            let half = layout(SIZE / 2, 1);
+            let ptr = unsafe { buddy.alloc(half) };
+            assert!(!ptr.is_null());
+            unsafe { buddy.dealloc(ptr, half) };
+        }
+    }
+
+    #[test]
+    fn alignment_larger_than_the_size() {
+        let buddy = Buddy::<SIZE>::new();
+        let free = buddy.free_bytes();
+        let mut blocks = Vec::new();
+        for align in (MIN_BLOCK.trailing_zeros()..=MAX_ALIGN.trailing_zeros()).map(|log2| 1 << log2)
+        {
+            let layout = layout(8, align);
+            let ptr = unsafe { buddy.alloc(layout) };
+            assert!(!ptr.is_null(), "{align}");
+            assert_eq!(ptr as usize % align, 0, "{align}");
+            unsafe { ptr.write_bytes(0xAA, 8) };
+            blocks.push((ptr, layout));
+        }
+        assert_eq!(
+            buddy.free_bytes(),
+            free - blocks
+                .iter()
+                .map(|(_, layout)| layout.align())
+                .sum::<usize>()
+        );
+        assert!(unsafe { buddy.alloc(layout(8, 2 * MAX_ALIGN)) }.is_null());
+        for (ptr, layout) in blocks {
+            unsafe { buddy.dealloc(ptr, layout) };
+        }
+        assert_eq!(buddy.free_bytes(), free);
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let buddy = Buddy::<SIZE>::new();
+        for size in [SIZE, SIZE + 1, isize::MAX as usize] {
+            assert!(unsafe { buddy.alloc(layout(size, 1)) }.is_null(), "{size}");
+        }
+
+        let mut blocks = fill(&buddy);
+        assert!(unsafe { buddy.alloc(layout(1, 1)) }.is_null());
+        let ptr = blocks.pop().unwrap();
+        unsafe { buddy.dealloc(ptr, layout(1, 1)) };
+        assert!(unsafe { buddy.alloc(layout(2 * MIN_BLOCK, 1)) }.is_null());
+        let ptr = unsafe { buddy.alloc(layout(1, 1)) };
+        assert!(!ptr.is_null());
+        blocks.push(ptr);
+        for ptr in blocks {
+            unsafe { buddy.dealloc(ptr, layout(1, 1)) };
+        }
+    }
+}
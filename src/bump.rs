@@ -0,0 +1,50 @@
+//! Integration with `bumpalo`
+//!
+//! With the `bumpalo` feature, [`with_arena_scope`], also available as
+//! `GlobalAllocator::with_arena_scope(tag, closure)`, calls the closure with the allocator
+//! identified by `tag` and a [`Bump`] whose chunks are allocated by that same allocator. Code
+//! written against `bumpalo` then puts its temporaries in the tagged arena, next to the
+//! allocations of the scope, and both go away together when the scope ends:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let count = GlobalAllocator::with_arena_scope(AllocatorTag::Arena, |bump| {
+//!         // The chunks of the bump and the strings are allocated by `Arena`
+//!         let squares = bump.alloc_slice_fill_with(100, |i| i * i);
+//!         let labels: Vec<String> = squares.iter().map(|square| square.to_string()).collect();
+//!         labels.len()
+//!     });
+//!     assert_eq!(count, 100);
+//! }
+//! ```
+//!
+//! The bump is dropped when the closure returns, so its allocations can't escape the scope. The
+//! allocations made with the tag inside the closure can, like with `with`. With an allocator
+//! implementing `frame::FrameAlloc`, like a `FrameArena`, dropping the bump frees nothing, and
+//! its chunks are reclaimed with the rest of the frame by `end_frame`.
+
+pub use bumpalo::Bump;
+
+use crate::{AllocatorGuard, MultiAllocatorBackend};
+
+/// Call `closure` with the allocator identified by `tag` and a [`Bump`] allocating its chunks
+/// with it, restoring the previous allocator after returning
+#[track_caller]
+pub fn with_arena_scope<Backend: MultiAllocatorBackend, R>(
+    tag: Backend::Tag,
+    closure: impl FnOnce(&Bump) -> R,
+) -> R {
+    let _guard = AllocatorGuard::new(Backend::raw_tag(tag));
+    // Chunks are only allocated once needed, so all of them are allocated with the tag
+    let bump = Bump::new();
+    closure(&bump)
+}
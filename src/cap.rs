@@ -0,0 +1,128 @@
+//! Process-wide memory cap
+//!
+//! With the `cap` feature, [`MultiAllocator`](crate::MultiAllocator) counts the bytes of every
+//! block it allocates, whatever its tag, and fails the allocations that would go over the limit
+//! set with [`set_limit`]. A containerized service can then fail allocations, e.g. the
+//! `try_reserve` of a request buffer, before the cgroup OOM killer kills it. The hook installed
+//! with [`set_near_limit_hook`] is called when the allocated bytes go over a percentage of the
+//! limit, e.g. to shed load or drop caches:
+//!
+//! ```rust
+//! use std::{
+//!     alloc::System,
+//!     sync::atomic::{AtomicBool, Ordering},
+//! };
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! static NEAR_LIMIT: AtomicBool = AtomicBool::new(false);
+//!
+//! fn main() {
+//!     okaoka::cap::set_near_limit_hook(90, |_allocated, _limit| {
+//!         NEAR_LIMIT.store(true, Ordering::Relaxed);
+//!     })
+//!     .unwrap();
+//!     okaoka::cap::set_limit(Some(okaoka::cap::allocated() + 64 * 1024)).unwrap();
+//!
+//!     let mut buffer: Vec<u8> = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || {
+//!         assert!(buffer.try_reserve(1024 * 1024).is_err());
+//!         assert!(buffer.try_reserve(60 * 1024).is_ok());
+//!     });
+//!     assert!(NEAR_LIMIT.load(Ordering::Relaxed));
+//!
+//!     okaoka::cap::set_limit(None).unwrap();
+//! }
+//! ```
+//!
+//! The bytes of a block include its hidden tag, and are counted from the first allocation of the
+//! process, so the limit can be set at any time. Reallocations are charged the bytes they add.
+//! The blocks allocated through a [`TagHandle`](crate::TagHandle) bypass the cap, like they
+//! bypass the hidden tag. A failed allocation returns null before reaching any allocator or
+//! [`OomStrategy`](crate::oom::OomStrategy).
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::once::SetOnce;
+
+// Bytes of the live blocks
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+// Limit, `usize::MAX` when there's none
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Hook called with the allocated bytes and the limit
+pub type NearLimitHook = fn(usize, usize);
+
+// Percentage of the limit and hook called when going over it
+static NEAR_LIMIT_HOOK: SetOnce<(u8, NearLimitHook)> = SetOnce::new();
+
+/// Set the maximum bytes allocated by the process, `None` removing it
+///
+/// Fails with the bytes currently allocated if they're already over `limit`, leaving the limit
+/// unchanged.
+pub fn set_limit(limit: Option<usize>) -> Result<(), usize> {
+    let limit = limit.unwrap_or(usize::MAX);
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    if allocated > limit {
+        return Err(allocated);
+    }
+    LIMIT.store(limit, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Maximum bytes allocated by the process, if any
+pub fn limit() -> Option<usize> {
+    match LIMIT.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        limit => Some(limit),
+    }
+}
+
+/// Bytes currently allocated by the process
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// Install `hook` to be called with the allocated bytes and the limit when an allocation makes
+/// the allocated bytes go over `percent` percent of the limit, for the rest of the program
+///
+/// The hook is called again each time they go back under it and over it again. It's called
+/// inside the allocator, so it must not allocate. Returns `hook` back if a hook is already
+/// installed.
+pub fn set_near_limit_hook(percent: u8, hook: fn(usize, usize)) -> Result<(), fn(usize, usize)> {
+    NEAR_LIMIT_HOOK
+        .set((percent, hook))
+        .map_err(|(_, hook)| hook)
+}
+
+/// Charge `bytes` to the process, returning false without charging them if they would go over
+/// the limit
+#[inline(always)]
+pub(crate) fn charge(bytes: usize) -> bool {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    let before = ALLOCATED.fetch_add(bytes, Ordering::Relaxed);
+    let after = before.saturating_add(bytes);
+    if after > limit {
+        ALLOCATED.fetch_sub(bytes, Ordering::Relaxed);
+        return false;
+    }
+    if let Some((percent, hook)) = NEAR_LIMIT_HOOK.get() {
+        let threshold = limit / 100 * usize::from(percent);
+        if before < threshold && after >= threshold {
+            hook(after, limit);
+        }
+    }
+    true
+}
+
+/// Give `bytes` charged with [`charge`] back
+#[inline(always)]
+pub(crate) fn credit(bytes: usize) {
+    ALLOCATED.fetch_sub(bytes, Ordering::Relaxed);
+}
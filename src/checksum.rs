@@ -0,0 +1,186 @@
+//! Integrity checks of the hidden tags
+//!
+//! With the `checksum` feature, the hidden tag put before every allocation also holds a checksum
+//! of its tag and of the size of the block, checked when the block is reallocated or
+//! deallocated. A buffer underflow that overwrites the hidden tag of the block it writes before
+//! would otherwise send the block to the wrong allocator, or to no allocator at all, and corrupt
+//! it much later, far from the bug. With the checksum, the process aborts on the spot with the
+//! tag read from the hidden tag, raw in parentheses, and the address of the block instead:
+//!
+//! ```text
+//! okaoka: heap corruption detected in tag Arena (1) at 0x5555555a2b70
+//! ```
+//!
+//! The checksum isn't a secret, so it only catches accidental corruption, not an attacker
+//! rewriting the hidden tag on purpose. Deallocating a block with a size other than its own
+//! also fails the check:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut values = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || values.extend(0..1000));
+//!     values.truncate(10);
+//!     values.shrink_to_fit(); // Checked, then checksummed with the new size
+//!     drop(values); // Checked
+//! }
+//! ```
+//!
+//! The checksum takes 4 bytes of the hidden tag, after the thread. With the `single-allocator`
+//! feature, there's no hidden tag to check.
+
+#[cfg(not(feature = "single-allocator"))]
+use crate::{MultiAllocatorBackend, TagRepr};
+
+/// Size of the checksum in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = std::mem::size_of::<u32>();
+
+/// Checksum of a block of `size` bytes allocated with the allocator identified by `raw_tag`
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+fn checksum(raw_tag: u16, size: usize) -> u32 {
+    // The finalizer of SplitMix64, so that a zeroed hidden tag doesn't match
+    let mut z = (size as u64 ^ (u64::from(raw_tag) << 48)).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) as u32
+}
+
+/// Write the checksum of `raw_tag` and `size` to `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for writing `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn write(ptr: *mut u8, raw_tag: u16, size: usize) {
+    unsafe { std::ptr::write_unaligned(ptr.cast(), checksum(raw_tag, size)) }
+}
+
+/// Check the checksum at `ptr` against `raw_tag` and `size`, aborting if it doesn't match
+///
+/// `block` is the address of the block given to the user, printed when aborting.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reading `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn check<Backend: MultiAllocatorBackend>(
+    ptr: *const u8,
+    raw_tag: u16,
+    size: usize,
+    block: *const u8,
+) {
+    if unsafe { std::ptr::read_unaligned(ptr.cast::<u32>()) } != checksum(raw_tag, size) {
+        corrupted::<Backend>(raw_tag, block);
+    }
+}
+
+#[cfg(not(feature = "single-allocator"))]
+#[cold]
+#[inline(never)]
+fn corrupted<Backend: MultiAllocatorBackend>(raw_tag: u16, block: *const u8) -> ! {
+    match Backend::Repr::from_raw(raw_tag).and_then(|tag| Backend::Tag::try_from(tag).ok()) {
+        Some(tag) => eprintln!(
+            "okaoka: heap corruption detected in tag {} ({raw_tag}) at {block:p}",
+            Backend::tag_name(tag)
+        ),
+        None => eprintln!("okaoka: heap corruption detected in tag {raw_tag} at {block:p}"),
+    }
+    std::process::abort();
+}
+
+#[cfg(all(test, not(feature = "single-allocator")))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        env,
+        process::Command,
+    };
+
+    use crate::{checksum_offset, tag_size, MultiAllocator};
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        System => System,
+        Arena => System,
+    }
+
+    /// Variable telling the child process how to corrupt the hidden tag
+    const CORRUPTION: &str = "OKAOKA_TEST_CORRUPTION";
+
+    /// Corrupt the hidden tag of a block of the arena as selected by `corruption`, then free it
+    fn corrupt_and_free(corruption: &str) {
+        let allocator = MultiAllocator::<Backend>::new();
+        let layout = Layout::new::<[u64; 4]>();
+        let mut ptr = std::ptr::null_mut();
+        crate::with_allocator(BackendTag::Arena as u8, || {
+            ptr = unsafe { allocator.alloc(layout) }
+        });
+        println!("block {ptr:p}");
+        let hidden_tag = unsafe { ptr.sub(tag_size::<Backend>(layout)) };
+        unsafe {
+            match corruption {
+                // An underflow zeroing the whole hidden tag
+                "zeroed" => hidden_tag.write_bytes(0, tag_size::<Backend>(layout)),
+                "checksum" => *hidden_tag.add(checksum_offset::<Backend>()) ^= 1,
+                _ => unreachable!(),
+            }
+            allocator.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "spawns a process")]
+    fn corrupted_hidden_tags_abort() {
+        if let Ok(corruption) = env::var(CORRUPTION) {
+            corrupt_and_free(&corruption);
+            return;
+        }
+        for corruption in ["zeroed", "checksum"] {
+            let output = Command::new(env::current_exe().unwrap())
+                .args(["--exact", "checksum::tests::corrupted_hidden_tags_abort"])
+                .args(["--nocapture", "--test-threads=1"])
+                .env(CORRUPTION, corruption)
+                .output()
+                .unwrap();
+            assert!(!output.status.success(), "{corruption}");
+            #[cfg(unix)]
+            assert_eq!(
+                std::os::unix::process::ExitStatusExt::signal(&output.status),
+                Some(6),
+                "{corruption}"
+            );
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            // After the name of the test on the same line
+            let block = stdout
+                .split("block ")
+                .nth(1)
+                .unwrap()
+                .lines()
+                .next()
+                .unwrap();
+            // The tag read from the corrupted hidden tag, not the one of the block
+            let raw_tag = match corruption {
+                "zeroed" => 0,
+                _ => BackendTag::Arena as u16,
+            };
+            let message = format!("({raw_tag}) at {block}\n");
+            assert!(stderr.contains(&message), "{corruption}: {stderr}");
+        }
+    }
+}
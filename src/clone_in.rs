@@ -0,0 +1,78 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use crate::AllocatorGuard;
+
+/// Clone `value` with the allocator identified by the raw `allocator_tag`
+///
+/// Data built in a short-lived allocator, like an arena reset after each request, has to be
+/// copied to a long-lived one to outlive it. The copy is allocated with the allocator of
+/// `allocator_tag`, along with the allocations of the clones of its elements:
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     Heap => System,
+/// #     Arena => System,
+/// # }
+/// # fn main() {
+/// let mut names = Vec::new();
+/// GlobalAllocator::with(AllocatorTag::Arena, || {
+///     names = vec!["request".to_string(), "response".to_string()];
+/// });
+/// // The vector and its strings are allocated with the heap
+/// let kept = okaoka::clone_into(AllocatorTag::Heap as u8, &names);
+/// okaoka::drop_in(AllocatorTag::Arena as u8, names);
+/// assert_eq!(kept, ["request", "response"]);
+/// # }
+/// ```
+#[inline(always)]
+#[track_caller]
+pub fn clone_into<T: CloneIn>(allocator_tag: impl Into<u16>, value: &T) -> T {
+    value.clone_in(allocator_tag)
+}
+
+/// Containers that can be cloned with a given allocator, see [`clone_into`]
+pub trait CloneIn: Sized {
+    /// Clone `self` with the allocator identified by the raw `allocator_tag`
+    #[track_caller]
+    fn clone_in(&self, allocator_tag: impl Into<u16>) -> Self;
+}
+
+impl<T: Clone> CloneIn for Vec<T> {
+    #[inline(always)]
+    #[track_caller]
+    fn clone_in(&self, allocator_tag: impl Into<u16>) -> Self {
+        let _guard = AllocatorGuard::new(allocator_tag);
+        self.clone()
+    }
+}
+
+impl CloneIn for String {
+    #[inline(always)]
+    #[track_caller]
+    fn clone_in(&self, allocator_tag: impl Into<u16>) -> Self {
+        let _guard = AllocatorGuard::new(allocator_tag);
+        self.clone()
+    }
+}
+
+impl<T: Clone> CloneIn for Box<[T]> {
+    #[inline(always)]
+    #[track_caller]
+    fn clone_in(&self, allocator_tag: impl Into<u16>) -> Self {
+        let _guard = AllocatorGuard::new(allocator_tag);
+        self.clone()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Clone, V: Clone, S: Clone> CloneIn for std::collections::HashMap<K, V, S> {
+    #[inline(always)]
+    #[track_caller]
+    fn clone_in(&self, allocator_tag: impl Into<u16>) -> Self {
+        let _guard = AllocatorGuard::new(allocator_tag);
+        self.clone()
+    }
+}
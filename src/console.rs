@@ -0,0 +1,320 @@
+//! Live memory console
+//!
+//! With the `console` feature, [`serve`] answers a small line-based protocol on a localhost
+//! socket, from a new thread, so that a terminal can attach to a running process, e.g. with
+//! `nc localhost 7070`, and look at its memory while it runs. Every command is a line, answered
+//! with lines followed by an empty one:
+//!
+//! - `tags`: the live allocations, live bytes and peak bytes of each tag, like the `summary`
+//!   report.
+//! - `callsites [n]`: the `n` callsites with the most live bytes, 10 by default, with the
+//!   `profiling` feature.
+//! - `events [n]`: the last `n` events recorded since `trace::start`, 10 by default, with the
+//!   `trace` feature.
+//! - `watch [ms]`: `tags` every `ms` milliseconds, 1000 by default and at least
+//!   [`MIN_INTERVAL_MS`], until the connection is closed.
+//! - `help`: the commands compiled in.
+//!
+//! ```text
+//! $ nc localhost 7070
+//! tags
+//! okaoka report:
+//!   System: 12 live allocations, 1180 live bytes, 9344 peak bytes
+//!   Arena: 0 live allocations, 0 live bytes, 4096 peak bytes
+//!
+//! ```
+//!
+//! ```rust
+//! use std::{
+//!     alloc::System,
+//!     io::{BufRead, BufReader, Write},
+//!     net::TcpStream,
+//! };
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let address = okaoka::console::serve::<GlobalAllocator>("127.0.0.1:0").unwrap();
+//!
+//!     let mut stream = TcpStream::connect(address).unwrap();
+//!     stream.write_all(b"tags\n").unwrap();
+//!     let lines: Vec<String> = BufReader::new(stream)
+//!         .lines()
+//!         .map(Result::unwrap)
+//!         .take_while(|line| !line.is_empty())
+//!         .collect();
+//!     assert!(lines[2].starts_with("  Arena: "));
+//! }
+//! ```
+//!
+//! Only loopback addresses are accepted, as the console has no authentication. Every connection
+//! is served by its own thread, whose allocations are made with the default tag and show up in
+//! the statistics. At most [`MAX_CLIENTS`] connections are served at once, the next ones being
+//! answered with an error and closed. Lines longer than [`MAX_LINE`] bytes are answered with an
+//! error and skipped, and connections without a command for [`IDLE_TIMEOUT`] are closed.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{report, MultiAllocatorBackend};
+
+/// Number of lines of `callsites` and `events` without a count
+#[cfg(any(feature = "profiling", feature = "trace"))]
+const DEFAULT_COUNT: usize = 10;
+
+/// Largest count accepted by `callsites` and `events`
+#[cfg(any(feature = "profiling", feature = "trace"))]
+const MAX_COUNT: usize = 1 << 20;
+
+/// Interval of `watch` without an interval, in milliseconds
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+/// Smallest interval of `watch`, in milliseconds, shorter ones being raised to it
+pub const MIN_INTERVAL_MS: u64 = 100;
+
+/// Largest length of a command line, in bytes, newline included
+pub const MAX_LINE: usize = 1024;
+
+/// Time after which a connection without a command is closed
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Largest number of connections served at once by a console
+pub const MAX_CLIENTS: usize = 8;
+
+/// Serve the console on `address`, which must be a loopback address, from a new thread
+///
+/// Returns the address listened on, e.g. to find the port chosen for port 0.
+pub fn serve<Backend: MultiAllocatorBackend + 'static>(
+    address: impl ToSocketAddrs,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    if !address.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the console only listens on loopback addresses",
+        ));
+    }
+    thread::Builder::new()
+        .name("okaoka-console".into())
+        .spawn(move || {
+            let clients = Arc::new(AtomicUsize::new(0));
+            for mut stream in listener.incoming().flatten() {
+                if clients.fetch_add(1, Ordering::Relaxed) >= MAX_CLIENTS {
+                    clients.fetch_sub(1, Ordering::Relaxed);
+                    let _ = writeln!(stream, "error: too many connections\n");
+                    continue;
+                }
+                let client = Client(clients.clone());
+                // A failure to spawn only drops the connection, and the client with it
+                let _ = thread::Builder::new()
+                    .name("okaoka-console-client".into())
+                    .spawn(move || {
+                        let _client = client;
+                        let _ = serve_client::<Backend>(stream);
+                    });
+            }
+        })?;
+    Ok(address)
+}
+
+/// Connection counted by a console until it's dropped
+struct Client(Arc<AtomicUsize>);
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn serve_client<Backend: MultiAllocatorBackend>(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut out = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)?
+            == 0
+        {
+            return Ok(());
+        }
+        if line.len() == MAX_LINE && !line.ends_with(b"\n") {
+            reader.skip_until(b'\n')?;
+            writeln!(out, "error: lines are limited to {MAX_LINE} bytes")?;
+            writeln!(out)?;
+            continue;
+        }
+        let line = String::from_utf8_lossy(&line);
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        match command {
+            "" => continue,
+            "tags" => out.write_all(report::summary::<Backend>().as_bytes())?,
+            #[cfg(feature = "profiling")]
+            "callsites" => match count(argument) {
+                Some(top) => crate::profiling::dump::<Backend>(&mut out, top)?,
+                None => writeln!(out, "error: expected a count up to {MAX_COUNT}")?,
+            },
+            #[cfg(feature = "trace")]
+            "events" => match count(argument) {
+                Some(count) => {
+                    for event in crate::trace::recent(count) {
+                        writeln!(out, "{event}")?;
+                    }
+                }
+                None => writeln!(out, "error: expected a count up to {MAX_COUNT}")?,
+            },
+            "watch" => {
+                let interval = match argument.map(str::parse::<u64>) {
+                    None => DEFAULT_INTERVAL_MS,
+                    Some(Ok(interval)) => interval.max(MIN_INTERVAL_MS),
+                    Some(Err(_)) => {
+                        writeln!(out, "error: expected an interval in milliseconds")?;
+                        writeln!(out)?;
+                        continue;
+                    }
+                };
+                // Ends when writing fails, once the connection is closed
+                loop {
+                    out.write_all(report::summary::<Backend>().as_bytes())?;
+                    writeln!(out)?;
+                    thread::sleep(Duration::from_millis(interval));
+                }
+            }
+            "help" => help(&mut out)?,
+            _ => writeln!(out, "error: unknown command `{command}`, try `help`")?,
+        }
+        writeln!(out)?;
+    }
+}
+
+/// Write the commands compiled in
+fn help(out: &mut impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "tags: live allocations, live bytes and peak bytes of each tag"
+    )?;
+    #[cfg(feature = "profiling")]
+    writeln!(out, "callsites [n]: callsites with the most live bytes")?;
+    #[cfg(feature = "trace")]
+    writeln!(out, "events [n]: last recorded events")?;
+    writeln!(out, "watch [ms]: tags every ms milliseconds")?;
+    writeln!(out, "help: this help")
+}
+
+/// Count given as the argument of a command, [`DEFAULT_COUNT`] without one, `None` if it isn't a
+/// number up to [`MAX_COUNT`]
+#[cfg(any(feature = "profiling", feature = "trace"))]
+fn count(argument: Option<&str>) -> Option<usize> {
+    argument
+        .map_or(Some(DEFAULT_COUNT), |count| count.parse().ok())
+        .filter(|&count| count <= MAX_COUNT)
+}
+
+#[cfg(test)]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::{alloc::System, time::Instant};
+
+    use super::*;
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        System => System,
+    }
+
+    /// Lines of the next answer on `reader`
+    fn answer(reader: &mut impl BufRead) -> Vec<String> {
+        reader
+            .lines()
+            .map(Result::unwrap)
+            .take_while(|line| !line.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn long_lines_are_skipped() {
+        let address = serve::<Backend>("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        writeln!(stream, "tags{}\ntags", " ".repeat(4 * MAX_LINE)).unwrap();
+        assert_eq!(
+            answer(&mut reader),
+            [format!("error: lines are limited to {MAX_LINE} bytes")]
+        );
+        assert!(answer(&mut reader)[1].starts_with("  System: "));
+    }
+
+    #[test]
+    fn watch_waits_at_least_the_smallest_interval() {
+        let address = serve::<Backend>("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        writeln!(stream, "watch 0").unwrap();
+        answer(&mut reader);
+        let start = Instant::now();
+        answer(&mut reader);
+        answer(&mut reader);
+        assert!(start.elapsed() >= Duration::from_millis(2 * MIN_INTERVAL_MS));
+    }
+
+    #[test]
+    fn connections_are_limited() {
+        let address = serve::<Backend>("127.0.0.1:0").unwrap();
+        let mut clients: Vec<_> = (0..MAX_CLIENTS)
+            .map(|_| {
+                let mut stream = TcpStream::connect(address).unwrap();
+                let reader = BufReader::new(stream.try_clone().unwrap());
+                // Answered once the connection is served
+                writeln!(stream, "help").unwrap();
+                (stream, reader)
+            })
+            .collect();
+        for (_, reader) in &mut clients {
+            assert!(!answer(reader).is_empty());
+        }
+
+        let mut reader = BufReader::new(TcpStream::connect(address).unwrap());
+        assert_eq!(answer(&mut reader), ["error: too many connections"]);
+
+        // A closed connection makes room for another one
+        drop(clients.pop());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            writeln!(stream, "help").unwrap();
+            // A refused connection can also be reset, as the command isn't read
+            let lines: Vec<_> = reader
+                .lines()
+                .map_while(Result::ok)
+                .take_while(|line| !line.is_empty())
+                .collect();
+            if !lines.is_empty() && lines != ["error: too many connections"] {
+                assert!(lines[0].starts_with("tags: "));
+                break;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
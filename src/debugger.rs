@@ -0,0 +1,122 @@
+//! Debugger commands for the hidden tags
+//!
+//! With the `debugger` feature, [`set_multi_global_allocator`](crate::set_multi_global_allocator)
+//! exports the layout of the hidden tag and the names of the tags in the `OKAOKA_DEBUG_INFO`
+//! symbol, and `debugger/okaoka.py` reads it to add an `okaoka ptr <address> [align]` command to
+//! GDB and LLDB, which prints the allocator of a live block from a running process or a core
+//! dump:
+//!
+//! ```text
+//! (gdb) okaoka ptr 0x5555555a2b70
+//! 0x5555555a2b70: Arena (raw tag 1), 4096 bytes, align <= 64, header at 0x5555555a2b30
+//! ```
+//!
+//! The script is embedded in the binaries, and GDB loads it if its `auto-load safe-path` allows
+//! it. LLDB loads it with `command script import debugger/okaoka.py` from the sources of this
+//! crate. The alignment of a block isn't stored, so a block aligned to more than the size of the
+//! hidden tag needs it as the second argument. Its size is printed with the `live` feature, and
+//! its owner, callsite and thread, as indices, with the `owner`, `profiling` and `thread-stats`
+//! features. With the `single-allocator` feature, there's no hidden tag to print.
+
+use crate::{MultiAllocatorBackend, TagRepr};
+
+/// Bytes of a tag name, including a nul terminator, longer names are truncated
+const NAME_SIZE: usize = 60;
+
+/// Offset of the fields missing from the hidden tag, unused when none is missing
+#[allow(dead_code)]
+const ABSENT: u32 = u32::MAX;
+
+/// Name of a tag, read by the debugger
+#[doc(hidden)]
+#[repr(C)]
+pub struct TagInfo {
+    raw_tag: u32,
+    name: [u8; NAME_SIZE],
+}
+
+impl TagInfo {
+    pub const fn new(raw_tag: u16, name: &str) -> Self {
+        let mut bytes = [0; NAME_SIZE];
+        let mut i = 0;
+        while i < name.len() && i < NAME_SIZE - 1 {
+            bytes[i] = name.as_bytes()[i];
+            i += 1;
+        }
+        Self {
+            raw_tag: raw_tag as u32,
+            name: bytes,
+        }
+    }
+}
+
+/// Layout of the hidden tag and tags of a backend, read by the debugger
+///
+/// Every field is a `u32` so that the layout is the same on every target, keep it in sync with
+/// `debugger/okaoka.py`.
+#[doc(hidden)]
+#[repr(C)]
+pub struct DebugInfo<const N: usize> {
+    magic: [u8; 8],
+    version: u32,
+    /// Size of the hidden tag of the blocks aligned to at most this size, 0 without hidden tags
+    header_size: u32,
+    repr_size: u32,
+    owner_offset: u32,
+    token_offset: u32,
+    callsite_offset: u32,
+    timestamp_offset: u32,
+    node_offset: u32,
+    thread_offset: u32,
+    tag_count: u32,
+    name_size: u32,
+    tags: [TagInfo; N],
+}
+
+impl<const N: usize> DebugInfo<N> {
+    pub const fn new<Backend: MultiAllocatorBackend>(tags: [TagInfo; N]) -> Self {
+        #[cfg(not(feature = "single-allocator"))]
+        let header_size = crate::min_tag_size::<Backend>() as u32;
+        #[cfg(feature = "single-allocator")]
+        let header_size = 0;
+        #[cfg(all(feature = "owner", not(feature = "single-allocator")))]
+        let owner_offset = Backend::Repr::SIZE as u32;
+        #[cfg(not(all(feature = "owner", not(feature = "single-allocator"))))]
+        let owner_offset = ABSENT;
+        #[cfg(all(feature = "accounting", not(feature = "single-allocator")))]
+        let token_offset = crate::token_offset::<Backend>() as u32;
+        #[cfg(not(all(feature = "accounting", not(feature = "single-allocator"))))]
+        let token_offset = ABSENT;
+        #[cfg(all(feature = "profiling", not(feature = "single-allocator")))]
+        let callsite_offset = crate::callsite_offset::<Backend>() as u32;
+        #[cfg(not(all(feature = "profiling", not(feature = "single-allocator"))))]
+        let callsite_offset = ABSENT;
+        #[cfg(all(feature = "lifetimes", not(feature = "single-allocator")))]
+        let timestamp_offset = crate::timestamp_offset::<Backend>() as u32;
+        #[cfg(not(all(feature = "lifetimes", not(feature = "single-allocator"))))]
+        let timestamp_offset = ABSENT;
+        #[cfg(all(feature = "live", not(feature = "single-allocator")))]
+        let node_offset = crate::node_offset::<Backend>() as u32;
+        #[cfg(not(all(feature = "live", not(feature = "single-allocator"))))]
+        let node_offset = ABSENT;
+        #[cfg(all(feature = "thread-stats", not(feature = "single-allocator")))]
+        let thread_offset = crate::thread_offset::<Backend>() as u32;
+        #[cfg(not(all(feature = "thread-stats", not(feature = "single-allocator"))))]
+        let thread_offset = ABSENT;
+        Self {
+            magic: *b"OKAOKADB",
+            version: 1,
+            header_size,
+            repr_size: Backend::Repr::SIZE as u32,
+            owner_offset,
+            token_offset,
+            callsite_offset,
+            timestamp_offset,
+            node_offset,
+            thread_offset,
+            tag_count: N as u32,
+            name_size: NAME_SIZE as u32,
+            tags,
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Allocation log filtered by an environment variable
+//!
+//! With the `env-log` feature, the allocations, reallocations and deallocations of
+//! [`MultiAllocator`](crate::MultiAllocator) matching the filter in the `OKAOKA_LOG`
+//! environment variable are printed on stderr, to debug a program in the field without
+//! rebuilding it:
+//!
+//! ```text
+//! $ OKAOKA_LOG='arena>=4096' cargo run
+//! okaoka: alloc 65536 bytes at 0x7f3a5c000b70 with Arena from src/main.rs:12:5
+//! okaoka: dealloc 65536 bytes at 0x7f3a5c000b70 with Arena from src/main.rs:12:5
+//! ```
+//!
+//! The filter is a comma-separated list of directives, and an event is printed if it matches
+//! any of them. A directive is the name of a tag, matched ignoring case, followed by a
+//! comparison of the size of the block with `>=`, `>`, `<=`, `<` or `=`, either of which can be
+//! left out: `arena` matches every event of `Arena` and `>=1048576` every event of a megabyte or
+//! more. The callsite of a block, see the `profiling` module, is printed with the `profiling`
+//! feature.
+//!
+//! The variable is read on the first allocation, and the allocations made while it's read or
+//! while an event is printed aren't printed themselves. With the `single-allocator` feature,
+//! every event is attributed to the default tag and has no callsite.
+
+use std::{
+    cell::Cell,
+    io::Write,
+    panic::Location,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+};
+
+use crate::{MultiAllocatorBackend, TagRepr};
+
+/// Environment variable holding the filter
+pub const ENV_VAR: &str = "OKAOKA_LOG";
+
+const UNRESOLVED: u8 = 0;
+const RESOLVING: u8 = 1;
+const DISABLED: u8 = 2;
+const ENABLED: u8 = 3;
+
+static STATE: AtomicU8 = AtomicU8::new(UNRESOLVED);
+
+static DIRECTIVES: OnceLock<Vec<Directive>> = OnceLock::new();
+
+thread_local! {
+    /// Whether the thread is printing an event
+    static PRINTING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Any,
+    AtLeast(usize),
+    Above(usize),
+    AtMost(usize),
+    Below(usize),
+    Equal(usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Directive {
+    /// Raw tag of the events, `None` for every tag
+    raw_tag: Option<u16>,
+    size: Comparison,
+}
+
+impl Directive {
+    fn matches(&self, raw_tag: u16, size: usize) -> bool {
+        self.raw_tag.is_none_or(|other| other == raw_tag)
+            && match self.size {
+                Comparison::Any => true,
+                Comparison::AtLeast(bound) => size >= bound,
+                Comparison::Above(bound) => size > bound,
+                Comparison::AtMost(bound) => size <= bound,
+                Comparison::Below(bound) => size < bound,
+                Comparison::Equal(bound) => size == bound,
+            }
+    }
+}
+
+/// Parse a directive, `None` if its tag isn't a tag of `Backend` or its size isn't an integer
+fn parse<Backend: MultiAllocatorBackend>(directive: &str) -> Option<Directive> {
+    let start = directive.find(['>', '<', '=']).unwrap_or(directive.len());
+    let (name, comparison) = directive.split_at(start);
+    let name = name.trim();
+    let raw_tag = match name {
+        "" => None,
+        name => Some(
+            Backend::TAGS
+                .iter()
+                .find(|&&tag| Backend::tag_name(tag).eq_ignore_ascii_case(name))
+                .map(|&tag| Backend::raw_tag(tag))?,
+        ),
+    };
+    let bound = |prefix: &str| comparison.strip_prefix(prefix)?.trim().parse().ok();
+    let size = if comparison.is_empty() {
+        Comparison::Any
+    } else if let Some(bound) = bound(">=") {
+        Comparison::AtLeast(bound)
+    } else if let Some(bound) = bound("<=") {
+        Comparison::AtMost(bound)
+    } else if let Some(bound) = bound(">") {
+        Comparison::Above(bound)
+    } else if let Some(bound) = bound("<") {
+        Comparison::Below(bound)
+    } else {
+        Comparison::Equal(bound("=")?)
+    };
+    Some(Directive { raw_tag, size })
+}
+
+/// Read the filter, returning whether any directive was given
+#[cold]
+#[inline(never)]
+fn resolve<Backend: MultiAllocatorBackend>() -> bool {
+    if STATE
+        .compare_exchange(UNRESOLVED, RESOLVING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        // Another thread is reading the variable
+        return false;
+    }
+    // Reading the variable allocates, those allocations see `RESOLVING` and aren't printed
+    let filter = std::env::var(ENV_VAR).unwrap_or_default();
+    let mut directives = Vec::new();
+    for directive in filter
+        .split(',')
+        .filter(|directive| !directive.trim().is_empty())
+    {
+        match parse::<Backend>(directive) {
+            Some(directive) => directives.push(directive),
+            None => {
+                let _ = writeln!(
+                    std::io::stderr(),
+                    "okaoka: ignoring the invalid directive {:?} of {ENV_VAR}",
+                    directive.trim()
+                );
+            }
+        }
+    }
+    let enabled = !directives.is_empty();
+    let _ = DIRECTIVES.set(directives);
+    STATE.store(if enabled { ENABLED } else { DISABLED }, Ordering::Release);
+    enabled
+}
+
+/// Disable the log in the child of a `fork` made while the filter was being read, since the
+/// thread reading it doesn't exist in the child
+#[cfg(all(unix, feature = "fork-safety"))]
+pub(crate) fn forget_resolving() {
+    let _ = STATE.compare_exchange(RESOLVING, DISABLED, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// Print the event if it matches the filter
+///
+/// `block` is the start of the hidden tag of the block, null with the `single-allocator`
+/// feature.
+///
+/// # Safety
+///
+/// `block` must be null or the hidden tag of a live block.
+#[inline(always)]
+pub(crate) unsafe fn log<Backend: MultiAllocatorBackend>(
+    event: &str,
+    raw_tag: u16,
+    size: usize,
+    ptr: *mut u8,
+    block: *const u8,
+) {
+    let enabled = match STATE.load(Ordering::Acquire) {
+        ENABLED => true,
+        UNRESOLVED => resolve::<Backend>(),
+        _ => false,
+    };
+    if enabled {
+        unsafe { log_slow::<Backend>(event, raw_tag, size, ptr, block) };
+    }
+}
+
+#[cold]
+#[inline(never)]
+unsafe fn log_slow<Backend: MultiAllocatorBackend>(
+    event: &str,
+    raw_tag: u16,
+    size: usize,
+    ptr: *mut u8,
+    block: *const u8,
+) {
+    let directives = DIRECTIVES.get().map_or(&[][..], Vec::as_slice);
+    if !directives
+        .iter()
+        .any(|directive| directive.matches(raw_tag, size))
+    {
+        return;
+    }
+    // Printing may allocate, which must not print again
+    if PRINTING
+        .try_with(|printing| printing.replace(true))
+        .unwrap_or(true)
+    {
+        return;
+    }
+    let name = Backend::Repr::from_raw(raw_tag)
+        .and_then(|tag| Backend::Tag::try_from(tag).ok())
+        .map_or("an unknown allocator", Backend::tag_name);
+    #[cfg(all(feature = "profiling", not(feature = "single-allocator")))]
+    let callsite = unsafe { callsite::<Backend>(block) };
+    #[cfg(not(all(feature = "profiling", not(feature = "single-allocator"))))]
+    let callsite: Option<&Location> = {
+        let _ = block;
+        None
+    };
+    let mut stderr = std::io::stderr().lock();
+    let _ = match callsite {
+        Some(callsite) => writeln!(
+            stderr,
+            "okaoka: {event} {size} bytes at {ptr:p} with {name} from {callsite}"
+        ),
+        None => writeln!(
+            stderr,
+            "okaoka: {event} {size} bytes at {ptr:p} with {name}"
+        ),
+    };
+    drop(stderr);
+    let _ = PRINTING.try_with(|printing| printing.set(false));
+}
+
+/// Callsite of the block whose hidden tag is at `block`
+#[cfg(all(feature = "profiling", not(feature = "single-allocator")))]
+unsafe fn callsite<Backend: MultiAllocatorBackend>(
+    block: *const u8,
+) -> Option<&'static Location<'static>> {
+    if block.is_null() {
+        return None;
+    }
+    let index =
+        unsafe { std::ptr::read_unaligned(block.add(crate::callsite_offset::<Backend>()).cast()) };
+    crate::profiling::location(index)
+}
@@ -0,0 +1,67 @@
+//! Storage of the current tag in the fiber-local storage of Windows
+
+use core::{
+    ffi::c_void,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+const FLS_OUT_OF_INDEXES: u32 = u32::MAX;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn FlsAlloc(callback: Option<unsafe extern "system" fn(*const c_void)>) -> u32;
+    fn FlsFree(index: u32) -> i32;
+    fn FlsGetValue(index: u32) -> *mut c_void;
+    fn FlsSetValue(index: u32, data: *const c_void) -> i32;
+}
+
+// Index of the slot holding the tag, allocated on first use
+static INDEX: AtomicU32 = AtomicU32::new(FLS_OUT_OF_INDEXES);
+
+/// Index of the slot holding the tag, `None` if the process is out of indices
+#[inline(always)]
+fn index() -> Option<u32> {
+    match INDEX.load(Ordering::Acquire) {
+        FLS_OUT_OF_INDEXES => allocate_index(),
+        index => Some(index),
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn allocate_index() -> Option<u32> {
+    let index = unsafe { FlsAlloc(None) };
+    if index == FLS_OUT_OF_INDEXES {
+        return None;
+    }
+    match INDEX.compare_exchange(
+        FLS_OUT_OF_INDEXES,
+        index,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => Some(index),
+        // Another thread allocated the slot first
+        Err(other) => {
+            unsafe { FlsFree(index) };
+            Some(other)
+        }
+    }
+}
+
+// The tag is stored plus one, so that the null value of a fiber that never set it is `None`
+
+#[inline(always)]
+pub(crate) fn load() -> Option<u16> {
+    let value = unsafe { FlsGetValue(index()?) } as usize;
+    value.checked_sub(1).map(|raw_tag| raw_tag as u16)
+}
+
+#[inline(always)]
+pub(crate) fn store(new_tag: Option<u16>) {
+    let Some(index) = index() else {
+        panic!("no fiber-local storage index left for the current allocator tag");
+    };
+    let value = new_tag.map_or(0, |raw_tag| usize::from(raw_tag) + 1);
+    unsafe { FlsSetValue(index, value as *const c_void) };
+}
@@ -0,0 +1,231 @@
+//! Fork safety
+//!
+//! With the `fork-safety` feature, [`install_fork_handlers`] registers handlers with
+//! `pthread_atfork` that take the locks of this crate before `fork` and release them after it, in
+//! the parent and in the child. Without them, a child forked while another thread held one of
+//! them, e.g. while linking a block into the list of the `live` module, deadlocks on its next
+//! allocation, since the thread holding the lock doesn't exist in the child:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//! }
+//!
+//! fn main() {
+//!     okaoka::fork::install_fork_handlers::<GlobalAllocator>().unwrap();
+//!
+//!     match unsafe { libc::fork() } {
+//!         0 => {
+//!             let _request = vec![0u8; 1024];
+//!             unsafe { libc::_exit(0) };
+//!         }
+//!         pid => {
+//!             let mut status = 0;
+//!             assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+//!             assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The handlers cover the global state of this crate, and the live list and the registry slots
+//! of the backend they're installed for. In the child, the arenas of a `ThreadArenas` owned by
+//! threads lost in the fork are released to the remaining threads. They don't cover the
+//! allocators of the backend, which handle `fork` themselves like the
+//! system allocator does. The threads of this crate don't exist in the child either: the helper
+//! thread of `signal_dump` is spawned again by the next `install_signal_dump`, while the threads
+//! of a `Watchdog` or of a `Pool` have to be started again by the child.
+
+use std::{cell::UnsafeCell, io, sync::Once};
+
+use crate::{registry, MultiAllocatorBackend};
+
+/// Guards taken by the `prepare` handler and dropped by the `parent` or `child` one, which all run
+/// on the thread calling `fork`
+struct Held(UnsafeCell<Option<Guards>>);
+
+// SAFETY: only the thread calling `fork` accesses the guards, between the handlers
+unsafe impl Sync for Held {}
+
+// Locks that may be held while allocating come first, so that a thread holding one of them can
+// still take the locks of the allocation path while `prepare` waits for it
+struct Guards {
+    #[cfg(feature = "thread-routing")]
+    _routes: std::sync::RwLockWriteGuard<'static, Vec<(&'static str, u16)>>,
+    _strategies: std::sync::RwLockWriteGuard<'static, Vec<(u16, crate::oom::OomStrategy)>>,
+    #[cfg(feature = "jemalloc-stats")]
+    _arenas: std::sync::RwLockWriteGuard<'static, Vec<(u16, u32)>>,
+    #[cfg(feature = "signal-dump")]
+    _path: std::sync::MutexGuard<'static, Option<std::path::PathBuf>>,
+    #[cfg(feature = "thread-stats")]
+    _names: std::sync::MutexGuard<'static, Vec<&'static str>>,
+    #[cfg(feature = "trace")]
+    _trace: std::sync::MutexGuard<'static, crate::trace::Buffer>,
+    #[cfg(all(feature = "live", not(feature = "single-allocator")))]
+    _live: std::sync::MutexGuard<'static, crate::live::Head>,
+}
+
+static HELD: Held = Held(UnsafeCell::new(None));
+
+static INSTALL: Once = Once::new();
+
+/// Slots of the registry of `Backend`, each once, through the raw tag of its first generation
+fn registry_slots<Backend: MultiAllocatorBackend>() -> impl Iterator<Item = &'static registry::Slot>
+{
+    (0..=u16::MAX).step_by(2).filter_map(Backend::dynamic_slot)
+}
+
+/// Register the fork handlers of `Backend`, the backend of the global allocator
+///
+/// Only the first call registers them, later calls do nothing.
+pub fn install_fork_handlers<Backend: MultiAllocatorBackend>() -> io::Result<()> {
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        let code = unsafe {
+            libc::pthread_atfork(
+                Some(prepare::<Backend>),
+                Some(release::<Backend>),
+                Some(release_in_child::<Backend>),
+            )
+        };
+        if code != 0 {
+            result = Err(io::Error::from_raw_os_error(code));
+        }
+    });
+    result
+}
+
+extern "C" fn prepare<Backend: MultiAllocatorBackend>() {
+    // Swapping an allocator allocates, so the slots are taken before the locks of allocations
+    for slot in registry_slots::<Backend>() {
+        slot.lock_for_fork();
+    }
+    let guards = Guards {
+        #[cfg(feature = "thread-routing")]
+        _routes: crate::thread_routing::lock_routes(),
+        _strategies: crate::oom::lock_strategies(),
+        #[cfg(feature = "jemalloc-stats")]
+        _arenas: crate::jemalloc::lock_arenas(),
+        #[cfg(feature = "signal-dump")]
+        _path: crate::signal_dump::lock_path(),
+        #[cfg(feature = "thread-stats")]
+        _names: crate::thread_stats::names(),
+        #[cfg(feature = "trace")]
+        _trace: crate::trace::lock(),
+        #[cfg(all(feature = "live", not(feature = "single-allocator")))]
+        _live: Backend::live_list().lock(),
+    };
+    unsafe { *HELD.0.get() = Some(guards) };
+}
+
+extern "C" fn release<Backend: MultiAllocatorBackend>() {
+    unsafe { *HELD.0.get() = None };
+    for slot in registry_slots::<Backend>() {
+        slot.unlock_after_fork();
+    }
+}
+
+extern "C" fn release_in_child<Backend: MultiAllocatorBackend>() {
+    release::<Backend>();
+    #[cfg(feature = "thread-arenas")]
+    crate::thread_arenas::forget_other_threads();
+    #[cfg(feature = "env-log")]
+    crate::env_log::forget_resolving();
+    #[cfg(feature = "signal-dump")]
+    crate::signal_dump::forget_helper();
+}
+
+#[cfg(all(
+    test,
+    any(feature = "thread-arenas", not(feature = "single-allocator"))
+))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::alloc::System;
+
+    use super::*;
+
+    // The handlers are only installed once per process, so every test installs them for this
+    // backend
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        dynamic = 1,
+        System => System,
+    }
+
+    /// Run `check` in a forked child, returning whether it succeeded there
+    fn fork(check: impl FnOnce() -> bool) -> bool {
+        install_fork_handlers::<Backend>().unwrap();
+        match unsafe { libc::fork() } {
+            0 => unsafe { libc::_exit(if check() { 0 } else { 1 }) },
+            pid => {
+                let mut status = 0;
+                let waited = unsafe { libc::waitpid(pid, &mut status, 0) };
+                waited == pid && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    #[cfg(not(feature = "single-allocator"))]
+    #[test]
+    fn registry_slots_can_be_swapped_in_the_child() {
+        let tag = Backend::register(Box::new(System)).ok().unwrap();
+        assert!(fork(|| Backend::swap(tag, Box::new(System)).is_ok()));
+        assert!(Backend::swap(tag, Box::new(System)).is_ok());
+    }
+
+    #[cfg(feature = "thread-arenas")]
+    #[test]
+    fn arenas_of_lost_threads_are_released_in_the_child() {
+        use std::{
+            alloc::{GlobalAlloc, Layout},
+            sync::{mpsc, Barrier},
+            thread,
+        };
+
+        use crate::{
+            test_util::{self, Counted},
+            thread_arenas::ThreadArenas,
+        };
+
+        static ARENAS: ThreadArenas<Counted<15>, 1> = ThreadArenas::new(|| Counted);
+        struct Block(*mut u8);
+        unsafe impl Send for Block {}
+
+        let layout = Layout::new::<u64>();
+        let (sender, receiver) = mpsc::channel();
+        let forked = Barrier::new(2);
+        thread::scope(|scope| {
+            // Holds the only arena during the fork
+            scope.spawn(|| {
+                sender.send(Block(unsafe { ARENAS.alloc(layout) })).unwrap();
+                forked.wait();
+            });
+            let block = receiver.recv().unwrap();
+            // Freed remotely to the arena of the other thread
+            unsafe { ARENAS.dealloc(block.0, layout) };
+            assert_eq!(test_util::freed(15), 0);
+
+            let exited = fork(|| {
+                let released = ARENAS.arenas_in_use() == 0;
+                let block = unsafe { ARENAS.alloc(layout) };
+                // Claimed, and its remote frees drained
+                let claimed = ARENAS.arenas_in_use() == 1
+                    && test_util::allocated(15) == 2
+                    && test_util::freed(15) == 1;
+                unsafe { ARENAS.dealloc(block, layout) };
+                released && claimed
+            });
+            forked.wait();
+            assert!(exited);
+        });
+        // Released in the parent once the other thread exited
+        assert_eq!(ARENAS.arenas_in_use(), 0);
+    }
+}
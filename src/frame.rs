@@ -0,0 +1,265 @@
+//! Per-frame allocators for game loops
+//!
+//! With the `frame` feature, allocators that free everything at once, like [`FrameArena`],
+//! implement [`FrameAlloc`], and backends created with
+//! [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get a frame
+//! lifecycle for each tag:
+//!
+//! - `begin_frame(tag)` starts a frame.
+//! - `end_frame(tag)` ends it: the bytes used by the allocator during the frame are recorded in a
+//!   rolling history of the last [`Frame::HISTORY`] frames, the allocator is reset and, if the
+//!   tag has a budget, the frame is checked against it.
+//! - `set_frame_budget(tag, budget)` sets the maximum bytes of a frame, `None` removing it.
+//! - `frame_history(tag)` returns the recorded frames, oldest first.
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::frame::FrameArena;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Frame => static FrameArena<4096> = FrameArena::new(),
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::set_frame_budget(AllocatorTag::Frame, Some(1024));
+//!     for _ in 0..3 {
+//!         GlobalAllocator::begin_frame(AllocatorTag::Frame);
+//!         GlobalAllocator::with(AllocatorTag::Frame, || {
+//!             let scratch = vec![0u8; 256];
+//!             drop(scratch);
+//!         });
+//!         unsafe { GlobalAllocator::end_frame(AllocatorTag::Frame) };
+//!     }
+//!     // Each frame used the 256 bytes of the vector, plus the hidden tag
+//!     assert_eq!(GlobalAllocator::frame_history(AllocatorTag::Frame).len(), 3);
+//! }
+//! ```
+//!
+//! Tags whose allocator isn't a [`FrameAlloc`] aren't reset and record frames of 0 bytes.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::MultiAllocatorBackend;
+
+/// Allocator that frees all its blocks at once
+///
+/// Deallocating a block usually does nothing, the memory is reclaimed by [`Self::reset`].
+pub trait FrameAlloc: GlobalAlloc {
+    /// Bytes used since the last reset, including padding
+    fn used(&self) -> usize;
+
+    /// Free every block
+    ///
+    /// # Safety
+    ///
+    /// No block allocated before the reset may be used afterwards.
+    unsafe fn reset(&self);
+}
+
+/// Bump allocator over a fixed buffer of `N` bytes
+///
+/// Allocations are carved from the buffer one after the other and fail once it's full.
+/// Deallocations do nothing, so the bytes used by a frame are also its peak.
+pub struct FrameArena<const N: usize> {
+    buffer: UnsafeCell<MaybeUninit<[u8; N]>>,
+    used: AtomicUsize,
+}
+
+// SAFETY: the buffer is only handed out in disjoint blocks reserved with `used`
+unsafe impl<const N: usize> Sync for FrameArena<N> {}
+
+impl<const N: usize> FrameArena<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Size of the buffer
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<const N: usize> Default for FrameArena<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for FrameArena<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.buffer.get().cast::<u8>();
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let start = used + base.wrapping_add(used).align_offset(layout.align());
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= N => end,
+                _ => return ptr::null_mut(),
+            };
+            match self
+                .used
+                .compare_exchange_weak(used, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return unsafe { base.add(start) },
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+impl<const N: usize> FrameAlloc for FrameArena<N> {
+    fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    unsafe fn reset(&self) {
+        self.used.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Frame state of a tag
+pub struct Frame {
+    // Bytes of the last frames, indexed by frame number modulo `Self::HISTORY`
+    history: [AtomicUsize; Self::HISTORY],
+    // Number of frames ended
+    frames: AtomicUsize,
+    // Maximum bytes of a frame, `usize::MAX` when there's no budget
+    budget: AtomicUsize,
+    in_frame: AtomicBool,
+}
+
+impl Frame {
+    /// Number of frames kept in the history
+    pub const HISTORY: usize = 64;
+
+    pub const fn new() -> Self {
+        Self {
+            history: [const { AtomicUsize::new(0) }; Self::HISTORY],
+            frames: AtomicUsize::new(0),
+            budget: AtomicUsize::new(usize::MAX),
+            in_frame: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Start a frame of the allocator identified by `tag`
+///
+/// # Panics
+///
+/// Panics if a frame of `tag` is already in progress.
+pub fn begin_frame<Backend: MultiAllocatorBackend>(tag: Backend::Tag) {
+    let in_frame = Backend::frame(tag).in_frame.swap(true, Ordering::Relaxed);
+    assert!(
+        !in_frame,
+        "frame of `{}` already in progress",
+        Backend::tag_name(tag)
+    );
+}
+
+/// End the frame of the allocator identified by `tag`, returning the bytes it used
+///
+/// The bytes are recorded in the history and the allocator is reset.
+///
+/// # Panics
+///
+/// Panics if no frame of `tag` is in progress, or if the frame went over the budget of `tag`. The
+/// frame is still recorded and the allocator reset in the latter case.
+///
+/// # Safety
+///
+/// No block allocated by the allocator identified by `tag` may be used after the frame ends.
+pub unsafe fn end_frame<Backend: MultiAllocatorBackend>(tag: Backend::Tag) -> usize {
+    let frame = Backend::frame(tag);
+    let in_frame = frame.in_frame.swap(false, Ordering::Relaxed);
+    assert!(
+        in_frame,
+        "no frame of `{}` in progress",
+        Backend::tag_name(tag)
+    );
+
+    let bytes = unsafe { Backend::reset_frame(tag) }.unwrap_or(0);
+    let index = frame.frames.fetch_add(1, Ordering::Relaxed) % Frame::HISTORY;
+    frame.history[index].store(bytes, Ordering::Relaxed);
+
+    let budget = frame.budget.load(Ordering::Relaxed);
+    assert!(
+        bytes <= budget,
+        "frame of `{}` used {bytes} bytes, over its budget of {budget} bytes",
+        Backend::tag_name(tag)
+    );
+    bytes
+}
+
+/// Set the maximum bytes of a frame of the allocator identified by `tag`, `None` removing it
+pub fn set_frame_budget<Backend: MultiAllocatorBackend>(tag: Backend::Tag, budget: Option<usize>) {
+    let budget = budget.unwrap_or(usize::MAX);
+    Backend::frame(tag).budget.store(budget, Ordering::Relaxed);
+}
+
+/// Bytes used by the last frames of the allocator identified by `tag`, oldest first
+pub fn frame_history<Backend: MultiAllocatorBackend>(tag: Backend::Tag) -> Vec<usize> {
+    let frame = Backend::frame(tag);
+    let frames = frame.frames.load(Ordering::Relaxed);
+    let first = frames.saturating_sub(Frame::HISTORY);
+    (first..frames)
+        .map(|frame_number| frame.history[frame_number % Frame::HISTORY].load(Ordering::Relaxed))
+        .collect()
+}
+
+/// Dispatch of [`MultiAllocatorBackend::reset_frame`] to the allocators of the entries, by
+/// autoref: `(&allocator).reset_frame()` resolves to `ViaFrameAlloc` for allocators that
+/// implement [`FrameAlloc`] and to `ViaGlobalAlloc` otherwise
+#[doc(hidden)]
+pub mod dispatch {
+    use super::FrameAlloc;
+
+    pub trait ViaFrameAlloc {
+        /// # Safety
+        ///
+        /// Same contract as [`FrameAlloc::reset`].
+        unsafe fn reset_frame(&self) -> Option<usize>;
+    }
+
+    impl<A: FrameAlloc> ViaFrameAlloc for A {
+        #[inline(always)]
+        unsafe fn reset_frame(&self) -> Option<usize> {
+            let used = self.used();
+            unsafe { self.reset() };
+            Some(used)
+        }
+    }
+
+    pub trait ViaGlobalAlloc {
+        /// # Safety
+        ///
+        /// Always safe, nothing is reset.
+        unsafe fn reset_frame(&self) -> Option<usize>;
+    }
+
+    impl<A> ViaGlobalAlloc for &A {
+        #[inline(always)]
+        unsafe fn reset_frame(&self) -> Option<usize> {
+            None
+        }
+    }
+}
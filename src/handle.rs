@@ -0,0 +1,397 @@
+use core::{alloc::GlobalAlloc, alloc::Layout, marker::PhantomData};
+
+use crate::MultiAllocatorBackend;
+
+/// Handle to one of the allocators of a backend
+///
+/// Allocates directly with the allocator identified by its tag, without the hidden tag that
+/// [`MultiAllocator`](crate::MultiAllocator) adds and regardless of the current allocator of the
+/// thread. This makes a backend usable without installing it as the global allocator, e.g. to
+/// test it or to allocate collections with a specific allocator.
+///
+/// Memory allocated by a handle must be deallocated by a handle with the same tag.
+///
+/// With the `allocator-api` feature (nightly only), handles implement
+/// [`Allocator`](std::alloc::Allocator).
+///
+/// # Example
+///
+/// ```rust
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// okaoka::create_multi_allocator_backend! {
+///     Backend,
+///     AllocatorTag,
+///     System => System,
+///     Jemalloc => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     let handle = Backend::jemalloc_handle();
+///     let layout = Layout::new::<u64>();
+///     unsafe {
+///         let ptr = handle.alloc(layout);
+///         assert!(!ptr.is_null());
+///         handle.dealloc(ptr, layout);
+///     }
+/// }
+/// ```
+pub struct TagHandle<Backend: MultiAllocatorBackend> {
+    tag: Backend::Tag,
+    _backend: PhantomData<fn() -> Backend>,
+}
+
+impl<Backend: MultiAllocatorBackend> TagHandle<Backend> {
+    pub const fn new(tag: Backend::Tag) -> Self {
+        Self {
+            tag,
+            _backend: PhantomData,
+        }
+    }
+
+    /// Tag of the allocator behind this handle
+    pub fn tag(&self) -> Backend::Tag {
+        self.tag
+    }
+}
+
+impl<Backend: MultiAllocatorBackend> Clone for TagHandle<Backend> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Backend: MultiAllocatorBackend> Copy for TagHandle<Backend> {}
+
+impl<Backend> core::fmt::Debug for TagHandle<Backend>
+where
+    Backend: MultiAllocatorBackend,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("TagHandle")
+            .field(&Backend::tag_name(self.tag))
+            .finish()
+    }
+}
+
+unsafe impl<Backend: MultiAllocatorBackend> GlobalAlloc for TagHandle<Backend> {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { Backend::alloc(self.tag, layout) };
+        #[cfg(feature = "stats")]
+        if !ptr.is_null() {
+            Backend::counters(self.tag).record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "stats")]
+        Backend::counters(self.tag).record_dealloc(layout.size());
+        unsafe { Backend::dealloc(self.tag, ptr, layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = if new_size >= layout.size() {
+            unsafe { Backend::grow(self.tag, ptr, layout, new_size) }
+        } else {
+            unsafe { Backend::shrink(self.tag, ptr, layout, new_size) }
+        };
+        #[cfg(feature = "stats")]
+        if !new_ptr.is_null() {
+            Backend::counters(self.tag).record_dealloc(layout.size());
+            Backend::counters(self.tag).record_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+unsafe impl<Backend: MultiAllocatorBackend> core::alloc::Allocator for TagHandle<Backend> {
+    fn allocate(
+        &self,
+        layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        allocate(self, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: Layout) {
+        unsafe { deallocate(self, ptr, layout) }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        unsafe { resize(self, ptr, old_layout, new_layout) }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        unsafe { resize(self, ptr, old_layout, new_layout) }
+    }
+}
+
+/// Implementation of `Allocator::allocate` for a [`GlobalAlloc`], which doesn't support
+/// zero-sized blocks
+#[cfg(feature = "allocator-api")]
+pub(crate) fn allocate(
+    allocator: &impl GlobalAlloc,
+    layout: Layout,
+) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+    use core::ptr::NonNull;
+
+    if layout.size() == 0 {
+        let dangling = NonNull::new(core::ptr::without_provenance_mut(layout.align())).unwrap();
+        return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+    }
+    let ptr = unsafe { allocator.alloc(layout) };
+    match NonNull::new(ptr) {
+        Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, layout.size())),
+        None => Err(core::alloc::AllocError),
+    }
+}
+
+/// Implementation of `Allocator::deallocate` for a [`GlobalAlloc`]
+///
+/// # Safety
+///
+/// Same contract as `Allocator::deallocate`, with blocks allocated by [`allocate`].
+#[cfg(feature = "allocator-api")]
+pub(crate) unsafe fn deallocate(
+    allocator: &impl GlobalAlloc,
+    ptr: core::ptr::NonNull<u8>,
+    layout: Layout,
+) {
+    if layout.size() != 0 {
+        unsafe { allocator.dealloc(ptr.as_ptr(), layout) }
+    }
+}
+
+/// Implementation of `Allocator::grow` and `Allocator::shrink` for a [`GlobalAlloc`]
+///
+/// Resizes the block with [`GlobalAlloc::realloc`] when possible, moving it to a new block
+/// otherwise.
+///
+/// # Safety
+///
+/// Same contract as `Allocator::grow` or `Allocator::shrink`, with blocks allocated by
+/// [`allocate`].
+#[cfg(feature = "allocator-api")]
+pub(crate) unsafe fn resize(
+    allocator: &impl GlobalAlloc,
+    ptr: core::ptr::NonNull<u8>,
+    old_layout: Layout,
+    new_layout: Layout,
+) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+    use core::ptr::NonNull;
+
+    if old_layout.align() != new_layout.align() || old_layout.size() == 0 || new_layout.size() == 0
+    {
+        // `realloc` only resizes non-empty blocks with the same alignment
+        let new_ptr = allocate(allocator, new_layout)?;
+        unsafe {
+            let size = old_layout.size().min(new_layout.size());
+            core::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.cast().as_ptr(), size);
+            deallocate(allocator, ptr, old_layout);
+        }
+        return Ok(new_ptr);
+    }
+    let new_ptr = unsafe { allocator.realloc(ptr.as_ptr(), old_layout, new_layout.size()) };
+    match NonNull::new(new_ptr) {
+        Some(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size())),
+        None => Err(core::alloc::AllocError),
+    }
+}
+
+/// Handle to the allocator of a backend identified by a raw tag known at compile time
+///
+/// Unlike [`TagHandle`], it's zero-sized and implements [`Default`], so it can be the allocator
+/// type of collections created without an allocator value, see [`NewIn`].
+///
+/// # Panics
+///
+/// Allocating panics if `TAG` isn't a tag of the backend.
+#[cfg(feature = "allocator-api")]
+pub struct ConstTagHandle<Backend: MultiAllocatorBackend, const TAG: u16> {
+    _backend: PhantomData<fn() -> Backend>,
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Backend: MultiAllocatorBackend, const TAG: u16> ConstTagHandle<Backend, TAG> {
+    pub const fn new() -> Self {
+        Self {
+            _backend: PhantomData,
+        }
+    }
+
+    /// Handle to the same allocator
+    #[inline(always)]
+    pub fn handle(&self) -> TagHandle<Backend> {
+        use crate::TagRepr;
+
+        let tag = Backend::Repr::from_raw(TAG).and_then(|raw_tag| raw_tag.try_into().ok());
+        match tag {
+            Some(tag) => TagHandle::new(tag),
+            None => panic!("invalid allocator tag {TAG}"),
+        }
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Backend: MultiAllocatorBackend, const TAG: u16> Default for ConstTagHandle<Backend, TAG> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Backend: MultiAllocatorBackend, const TAG: u16> Clone for ConstTagHandle<Backend, TAG> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<Backend: MultiAllocatorBackend, const TAG: u16> Copy for ConstTagHandle<Backend, TAG> {}
+
+#[cfg(feature = "allocator-api")]
+impl<Backend, const TAG: u16> core::fmt::Debug for ConstTagHandle<Backend, TAG>
+where
+    Backend: MultiAllocatorBackend,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("ConstTagHandle").field(&TAG).finish()
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+unsafe impl<Backend, const TAG: u16> core::alloc::Allocator for ConstTagHandle<Backend, TAG>
+where
+    Backend: MultiAllocatorBackend,
+{
+    #[inline(always)]
+    fn allocate(
+        &self,
+        layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        self.handle().allocate(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: Layout) {
+        unsafe { self.handle().deallocate(ptr, layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn grow(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        unsafe { self.handle().grow(ptr, old_layout, new_layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        unsafe { self.handle().shrink(ptr, old_layout, new_layout) }
+    }
+}
+
+/// Collections with an allocator, named through this crate so that the aliases generated by
+/// [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) don't require
+/// `#![feature(allocator_api)]` in the crate using the macro
+#[cfg(feature = "allocator-api")]
+#[doc(hidden)]
+pub mod aliases {
+    pub type Vec<T, A> = alloc::vec::Vec<T, A>;
+    pub type VecDeque<T, A> = alloc::collections::VecDeque<T, A>;
+    pub type Box<T, A> = alloc::boxed::Box<T, A>;
+}
+
+/// Constructors of collections whose allocator is a [`Default`] handle
+///
+/// With the `collections` option,
+/// [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) generates a
+/// [`ConstTagHandle`] alias and collection aliases for each entry, e.g. `ArenaHandle`,
+/// `ArenaVec<T>`, `ArenaVecDeque<T>` and `ArenaBox<T>` for `Arena`. This trait, and [`NewBoxIn`]
+/// for boxes, give the aliases the constructors of the collections of the global allocator:
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// use okaoka::{NewBoxIn, NewIn};
+///
+/// okaoka::create_multi_allocator_backend! {
+///     Backend,
+///     AllocatorTag,
+///     collections,
+///     System => System,
+///     Arena => System,
+/// }
+///
+/// fn main() {
+///     let mut names = ArenaVec::new();
+///     names.push("arena");
+///     let boxed = ArenaBox::new(10);
+///     assert_eq!(*boxed, 10);
+/// }
+/// ```
+#[cfg(feature = "allocator-api")]
+pub trait NewIn: Sized {
+    /// Create an empty collection
+    fn new() -> Self;
+
+    /// Create an empty collection with room for `capacity` elements
+    fn with_capacity(capacity: usize) -> Self;
+}
+
+#[cfg(feature = "allocator-api")]
+impl<T, A: core::alloc::Allocator + Default> NewIn for Vec<T, A> {
+    fn new() -> Self {
+        Vec::new_in(A::default())
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity_in(capacity, A::default())
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl<T, A: core::alloc::Allocator + Default> NewIn for alloc::collections::VecDeque<T, A> {
+    fn new() -> Self {
+        alloc::collections::VecDeque::new_in(A::default())
+    }
+
+    fn with_capacity(capacity: usize) -> Self {
+        alloc::collections::VecDeque::with_capacity_in(capacity, A::default())
+    }
+}
+
+/// Constructor of boxes whose allocator is a [`Default`] handle, see [`NewIn`]
+#[cfg(feature = "allocator-api")]
+pub trait NewBoxIn<T> {
+    /// Move `value` to a new box
+    fn new(value: T) -> Self;
+}
+
+#[cfg(feature = "allocator-api")]
+impl<T, A: core::alloc::Allocator + Default> NewBoxIn<T> for Box<T, A> {
+    fn new(value: T) -> Self {
+        Box::new_in(value, A::default())
+    }
+}
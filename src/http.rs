@@ -0,0 +1,316 @@
+//! HTTP endpoint of the statistics
+//!
+//! With the `http` feature, [`serve_stats`] answers HTTP requests on a dedicated thread, so a
+//! service gets a memory endpoint with one call:
+//!
+//! - `GET /stats`: every statistic, as the JSON of the `OKAOKA_REPORT` report.
+//! - `GET /metrics`: the statistics of each tag in the Prometheus text format, with the same names
+//!   as the metrics of the `otel` feature, e.g. `okaoka_live_bytes{tag="Arena"}`.
+//!
+//! ```rust
+//! use std::{
+//!     alloc::System,
+//!     io::{Read, Write},
+//!     net::TcpStream,
+//! };
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let address = okaoka::serve_stats("127.0.0.1:0").unwrap();
+//!
+//!     let mut stream = TcpStream::connect(address).unwrap();
+//!     stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+//!     let mut response = String::new();
+//!     stream.read_to_string(&mut response).unwrap();
+//!     assert!(response.starts_with("HTTP/1.1 200 OK"));
+//!     assert!(response.contains("okaoka_live_bytes{tag=\"Arena\"} 0"));
+//! }
+//! ```
+//!
+//! [`serve_stats`] serves the backend installed with
+//! [`set_multi_global_allocator`](crate::set_multi_global_allocator), and [`serve`] any backend.
+//! Requests are answered one at a time, with a timeout of [`TIMEOUT`] for reading each of them,
+//! and the connection is closed after the response. Requests whose line or headers don't fit in
+//! [`MAX_HEAD`] bytes are answered with `400 Bad Request` or `431 Request Header Fields Too
+//! Large`. There's no authentication, so the endpoint
+//! should only be reachable by the scrapers.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Take, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    once::SetOnce,
+    report,
+    stats::{tag_stats, TagStats},
+    MultiAllocatorBackend,
+};
+
+/// Time given to a client to send its request
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest size of the request line and the headers of a request, in bytes
+pub const MAX_HEAD: usize = 8 * 1024;
+
+// Name, type, help and value of the metrics
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&TagStats) -> usize,
+);
+
+const METRICS: [Metric; 7] = [
+    (
+        "okaoka_allocations_total",
+        "counter",
+        "Number of allocations made",
+        |stats| stats.allocations,
+    ),
+    (
+        "okaoka_deallocations_total",
+        "counter",
+        "Number of deallocations made",
+        |stats| stats.deallocations,
+    ),
+    (
+        "okaoka_allocated_bytes_total",
+        "counter",
+        "Total bytes allocated",
+        |stats| stats.allocated_bytes,
+    ),
+    (
+        "okaoka_deallocated_bytes_total",
+        "counter",
+        "Total bytes deallocated",
+        |stats| stats.deallocated_bytes,
+    ),
+    (
+        "okaoka_live_allocations",
+        "gauge",
+        "Number of allocations that haven't been deallocated yet",
+        TagStats::live_allocations,
+    ),
+    (
+        "okaoka_live_bytes",
+        "gauge",
+        "Bytes currently allocated",
+        |stats| stats.live_bytes,
+    ),
+    (
+        "okaoka_peak_bytes",
+        "gauge",
+        "Highest number of bytes allocated at once",
+        |stats| stats.peak_bytes,
+    ),
+];
+
+type Serve = fn(&[SocketAddr]) -> io::Result<SocketAddr>;
+
+// `serve` for the backend of the global allocator
+static SERVE: SetOnce<Serve> = SetOnce::new();
+
+/// Serve the statistics of the global allocator on `address`, from a new thread
+///
+/// Returns the address listened on, e.g. to find the port chosen for port 0. Fails if the global
+/// allocator wasn't installed with
+/// [`set_multi_global_allocator`](crate::set_multi_global_allocator).
+pub fn serve_stats(address: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    let serve = SERVE.get().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no global allocator installed with `set_multi_global_allocator`",
+        )
+    })?;
+    serve(&address.to_socket_addrs()?.collect::<Vec<_>>())
+}
+
+/// Serve the statistics of `Backend` on `address`, from a new thread
+///
+/// Returns the address listened on, e.g. to find the port chosen for port 0.
+pub fn serve<Backend: MultiAllocatorBackend + 'static>(
+    address: impl ToSocketAddrs,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    thread::Builder::new()
+        .name("okaoka-http".into())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = respond::<Backend>(stream);
+            }
+        })?;
+    Ok(address)
+}
+
+fn serve_addresses<Backend: MultiAllocatorBackend + 'static>(
+    addresses: &[SocketAddr],
+) -> io::Result<SocketAddr> {
+    serve::<Backend>(addresses)
+}
+
+/// Make [`serve_stats`] serve `Backend`, called by the global allocator before `main`
+#[doc(hidden)]
+pub fn register<Backend: MultiAllocatorBackend + 'static>() {
+    let _ = SERVE.set(serve_addresses::<Backend>);
+}
+
+/// Stream whose reads fail once its deadline has passed, so that a client sending its request
+/// slowly can't hold the server for more than [`TIMEOUT`]
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+type HeadReader<'a> = BufReader<Take<Deadline<'a>>>;
+
+fn respond<Backend: MultiAllocatorBackend>(stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(
+        Deadline {
+            stream: &stream,
+            deadline: Instant::now() + TIMEOUT,
+        }
+        .take(MAX_HEAD as u64),
+    );
+    let head = read_head(&mut reader)?;
+    let (status, content_type, body) = match &head {
+        Ok(request) => route::<Backend>(request),
+        Err(status) => (*status, "text/plain", format!("{status}\n")),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len()
+    )?;
+    if head.is_err() {
+        // Closing with unread bytes resets the connection, which can lose the response, so the
+        // rest of the request is skipped, within the same limits
+        stream.shutdown(Shutdown::Write)?;
+        let mut rest = reader.into_inner().into_inner().take(MAX_HEAD as u64);
+        let _ = io::copy(&mut rest, &mut io::sink());
+    }
+    Ok(())
+}
+
+/// Read the request line and skip the headers, `Err` with the status to answer if they don't fit
+/// in [`MAX_HEAD`] bytes
+fn read_head(reader: &mut HeadReader<'_>) -> io::Result<Result<String, &'static str>> {
+    let mut request = String::new();
+    if !read_head_line(reader, &mut request)? {
+        return Ok(Err("400 Bad Request"));
+    }
+    // The headers are ignored
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if !read_head_line(reader, &mut header)? {
+            return Ok(Err("431 Request Header Fields Too Large"));
+        }
+        if header.trim().is_empty() {
+            return Ok(Ok(request));
+        }
+    }
+}
+
+/// Read a line of the head, `false` if it doesn't end before [`MAX_HEAD`]
+///
+/// A line cut by the end of the stream is taken as the last one.
+fn read_head_line(reader: &mut HeadReader<'_>, line: &mut String) -> io::Result<bool> {
+    reader.read_line(line)?;
+    Ok(line.ends_with('\n') || reader.get_ref().limit() > 0)
+}
+
+/// Status, content type and body of the response to `request`
+fn route<Backend: MultiAllocatorBackend>(request: &str) -> (&'static str, &'static str, String) {
+    let mut words = request.split_whitespace();
+    let method = words.next().unwrap_or_default();
+    let path = words.next().unwrap_or_default();
+    let path = path.split_once('?').map_or(path, |(path, _query)| path);
+    match (method, path) {
+        ("GET", "/stats") => ("200 OK", "application/json", report::json::<Backend>()),
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics::<Backend>()),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".into()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".into(),
+        ),
+    }
+}
+
+/// Statistics of every tag of `Backend` in the Prometheus text format
+fn metrics<Backend: MultiAllocatorBackend>() -> String {
+    let stats: Vec<_> = Backend::TAGS
+        .iter()
+        .map(|&tag| (Backend::tag_name(tag), tag_stats::<Backend>(tag)))
+        .collect();
+    let mut metrics = String::new();
+    for (name, kind, help, value) in METRICS {
+        metrics += &format!("# HELP {name} {help}\n# TYPE {name} {kind}\n");
+        for (tag, stats) in &stats {
+            metrics += &format!("{name}{{tag=\"{tag}\"}} {}\n", value(stats));
+        }
+    }
+    metrics
+}
+
+#[cfg(test)]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::{alloc::System, io::Read};
+
+    use super::*;
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        System => System,
+    }
+
+    fn request(request: &[u8]) -> String {
+        let address = serve::<Backend>("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn oversized_heads_are_rejected() {
+        let line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD));
+        assert!(request(line.as_bytes()).starts_with("HTTP/1.1 400 Bad Request"));
+
+        let headers = format!("GET /stats HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD));
+        assert!(request(headers.as_bytes()).starts_with("HTTP/1.1 431 "));
+
+        let small = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert!(request(small).starts_with("HTTP/1.1 200 OK"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Routing of the allocations of interrupt handlers
+//!
+//! With the `interrupt` feature, the allocations made while the hook installed with
+//! [`route_interrupts`] reports that the code runs in an interrupt handler go to the allocator
+//! routed to interrupts, whatever the tag selected by the code it interrupted, so the handlers of
+//! firmware only allocate from a lock-free pool that they can't deadlock on:
+//!
+//! ```rust
+//! use std::{
+//!     alloc::System,
+//!     sync::atomic::{AtomicBool, Ordering},
+//! };
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Interrupt => System,
+//! }
+//!
+//! // Stands in for reading the active interrupt, e.g. from `IPSR` on Cortex-M
+//! static IN_INTERRUPT: AtomicBool = AtomicBool::new(false);
+//!
+//! fn in_interrupt() -> bool {
+//!     IN_INTERRUPT.load(Ordering::Relaxed)
+//! }
+//!
+//! fn main() {
+//!     // Rejected, there's no allocator with this tag
+//!     assert!(okaoka::interrupt::route_interrupts::<GlobalAllocator>(in_interrupt, 7u16).is_err());
+//!     okaoka::interrupt::route_interrupts::<GlobalAllocator>(
+//!         in_interrupt,
+//!         AllocatorTag::Interrupt as u16,
+//!     )
+//!     .unwrap();
+//!
+//!     IN_INTERRUPT.store(true, Ordering::Relaxed);
+//!     assert_eq!(okaoka::interrupt::interrupt_route(), Some(AllocatorTag::Interrupt as u16));
+//!     let _event = Box::new(10); // Allocated with `Interrupt`
+//!     IN_INTERRUPT.store(false, Ordering::Relaxed);
+//! }
+//! ```
+//!
+//! The route takes precedence over the current tag, the tag of an instance and the alignment
+//! routes. Reallocations and deallocations stay with the allocator of the block, so a handler
+//! must not reallocate or free the blocks of the code it interrupted. With the
+//! `single-allocator` feature, the route is ignored.
+
+use core::fmt;
+
+use crate::{once::SetOnce, InvalidTagError, MultiAllocatorBackend, TagRepr};
+
+// Hook telling whether the code runs in an interrupt handler, and the raw tag of the allocator
+// of interrupts
+static INTERRUPT_ROUTE: SetOnce<(fn() -> bool, u16)> = SetOnce::new();
+
+/// Error returned by [`route_interrupts`]
+#[derive(Debug, Clone, Copy)]
+pub enum RouteError {
+    /// A route is already installed, `in_interrupt` is given back
+    Installed(fn() -> bool),
+    /// The tag doesn't belong to any allocator of the backend
+    InvalidTag(InvalidTagError),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Installed(_) => write!(f, "interrupts are already routed"),
+            Self::InvalidTag(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl core::error::Error for RouteError {}
+
+/// Route the allocations made while `in_interrupt` returns `true` to the allocator of `Backend`,
+/// the backend of the global allocator, identified by the raw `allocator_tag`, for the rest of
+/// the program
+///
+/// `in_interrupt` is called on every allocation, so it must be fast and must not allocate.
+/// Fails if `allocator_tag` is neither a tag of `Backend` nor the tag of a slot of its registry,
+/// or if a route is already installed.
+pub fn route_interrupts<Backend: MultiAllocatorBackend>(
+    in_interrupt: fn() -> bool,
+    allocator_tag: impl Into<u16>,
+) -> Result<(), RouteError> {
+    let raw_tag = allocator_tag.into();
+    let declared =
+        Backend::Repr::from_raw(raw_tag).is_some_and(|tag| Backend::Tag::try_from(tag).is_ok());
+    if !declared && Backend::dynamic_slot(raw_tag).is_none() {
+        return Err(RouteError::InvalidTag(InvalidTagError::new(raw_tag)));
+    }
+    INTERRUPT_ROUTE
+        .set((in_interrupt, raw_tag))
+        .map_err(|(in_interrupt, _)| RouteError::Installed(in_interrupt))
+}
+
+/// Raw tag of the allocator interrupts are routed to, if the code runs in an interrupt handler
+#[inline(always)]
+pub fn interrupt_route() -> Option<u16> {
+    let (in_interrupt, raw_tag) = INTERRUPT_ROUTE.get()?;
+    in_interrupt().then_some(raw_tag)
+}
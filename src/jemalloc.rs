@@ -0,0 +1,186 @@
+//! Statistics of jemalloc arenas
+//!
+//! The statistics of a tag only count the bytes requested by the user, while the memory used by
+//! the allocator behind it also includes fragmentation, metadata and pages that were freed but not
+//! yet returned to the system. With the `jemalloc-stats` feature, the tags backed by
+//! [`Jemalloc`](jemallocator::Jemalloc) also get the statistics of jemalloc, read with `mallctl`,
+//! so the gap between the two is visible. Backends created with
+//! [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get
+//! `jemalloc_arena_stats(tag)`:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Fast => jemallocator::Jemalloc,
+//! }
+//!
+//! fn main() {
+//!     let mut buffer = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Fast, || buffer = vec![0u8; 1 << 20]);
+//!
+//!     let tracked = GlobalAllocator::fast_stats().live_bytes;
+//!     let jemalloc = GlobalAllocator::jemalloc_arena_stats(AllocatorTag::Fast).unwrap();
+//!     println!("{tracked} bytes tracked, {} bytes resident", jemalloc.unwrap().resident);
+//!     let system = GlobalAllocator::jemalloc_arena_stats(AllocatorTag::System).unwrap();
+//!     assert!(system.is_none());
+//! }
+//! ```
+//!
+//! `Jemalloc` shares its arenas between every tag, so its tags get the statistics of every arena.
+//! A tag whose allocator uses a dedicated arena, e.g. created with `arenas.create` and selected
+//! with `MALLOCX_ARENA`, gets the statistics of that arena once it's given with
+//! [`set_tag_arena`].
+
+use std::{
+    ffi::{c_void, CStr},
+    io,
+    sync::RwLock,
+};
+
+use crate::MultiAllocatorBackend;
+
+/// Index that selects the statistics of every arena merged together
+const ARENAS_ALL: u32 = 4096;
+
+/// Statistics of a jemalloc arena, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JemallocStats {
+    /// Bytes in physically resident pages
+    pub resident: usize,
+    /// Bytes in the extents mapped by the allocator
+    pub mapped: usize,
+    /// Bytes in pages that were freed but not yet returned to the system
+    pub dirty: usize,
+}
+
+// Dedicated arena of each raw tag that has one
+static ARENAS: RwLock<Vec<(u16, u32)>> = RwLock::new(Vec::new());
+
+/// Read the statistics of the allocator identified by the raw `allocator_tag` from `arena`
+///
+/// This also marks the tag as backed by jemalloc.
+pub fn set_tag_arena(allocator_tag: impl Into<u16>, arena: u32) {
+    let allocator_tag = allocator_tag.into();
+    let mut arenas = ARENAS.write().unwrap_or_else(|e| e.into_inner());
+    arenas.retain(|&(tag, _)| tag != allocator_tag);
+    arenas.push((allocator_tag, arena));
+}
+
+/// Arenas, locked for writing while the process forks
+#[cfg(all(unix, feature = "fork-safety"))]
+pub(crate) fn lock_arenas() -> std::sync::RwLockWriteGuard<'static, Vec<(u16, u32)>> {
+    ARENAS.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Dedicated arena of the allocator identified by the raw `allocator_tag`
+pub fn tag_arena(allocator_tag: impl Into<u16>) -> Option<u32> {
+    let allocator_tag = allocator_tag.into();
+    ARENAS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|&&(tag, _)| tag == allocator_tag)
+        .map(|&(_, arena)| arena)
+}
+
+/// Read the value of type `T` named `name` with `mallctl`
+fn read<T: Default>(name: &CStr) -> io::Result<T> {
+    let mut value = T::default();
+    let mut len = std::mem::size_of::<T>();
+    let code = unsafe {
+        jemalloc_sys::mallctl(
+            name.as_ptr(),
+            (&raw mut value).cast::<c_void>(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    match code {
+        0 => Ok(value),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+/// Refresh the statistics cached by jemalloc
+fn advance_epoch() -> io::Result<()> {
+    let mut epoch = 1u64;
+    let mut len = std::mem::size_of::<u64>();
+    let code = unsafe {
+        jemalloc_sys::mallctl(
+            c"epoch".as_ptr(),
+            (&raw mut epoch).cast::<c_void>(),
+            &mut len,
+            (&raw mut epoch).cast::<c_void>(),
+            len,
+        )
+    };
+    match code {
+        0 => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+/// Statistics of `arena`, or of every arena merged together if it's `None`
+///
+/// Fails if jemalloc was built without statistics, or if the arena doesn't exist.
+pub fn arena_stats(arena: Option<u32>) -> io::Result<JemallocStats> {
+    advance_epoch()?;
+    let arena = arena.unwrap_or(ARENAS_ALL);
+    let stat = |name: &str| {
+        let name = format!("stats.arenas.{arena}.{name}\0");
+        let name = CStr::from_bytes_with_nul(name.as_bytes()).expect("no nul in the name");
+        read::<usize>(name)
+    };
+    let page: usize = read(c"arenas.page")?;
+    Ok(JemallocStats {
+        resident: stat("resident")?,
+        mapped: stat("mapped")?,
+        dirty: stat("pdirty")? * page,
+    })
+}
+
+/// Statistics of jemalloc for the allocator identified by `tag`, `None` if it isn't backed by
+/// jemalloc
+pub fn tag_stats<Backend: MultiAllocatorBackend>(
+    tag: Backend::Tag,
+) -> io::Result<Option<JemallocStats>> {
+    match tag_arena(Backend::raw_tag(tag)) {
+        Some(arena) => arena_stats(Some(arena)).map(Some),
+        None if Backend::is_jemalloc(tag) => arena_stats(None).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Detection of [`Jemalloc`](jemallocator::Jemalloc) among the allocators of the entries, by
+/// autoref: `(&allocator).is_jemalloc()` resolves to `ViaJemalloc` for `Jemalloc` and to
+/// `ViaOther` otherwise
+#[doc(hidden)]
+pub mod dispatch {
+    pub trait ViaJemalloc {
+        fn is_jemalloc(&self) -> bool;
+    }
+
+    impl ViaJemalloc for jemallocator::Jemalloc {
+        #[inline(always)]
+        fn is_jemalloc(&self) -> bool {
+            true
+        }
+    }
+
+    pub trait ViaOther {
+        fn is_jemalloc(&self) -> bool;
+    }
+
+    impl<A> ViaOther for &A {
+        #[inline(always)]
+        fn is_jemalloc(&self) -> bool {
+            false
+        }
+    }
+}
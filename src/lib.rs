@@ -1,169 +1,4656 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![deny(unsafe_op_in_unsafe_fn)]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+#![cfg_attr(
+    all(
+        feature = "thread-local",
+        not(any(feature = "single-allocator", feature = "single-thread"))
+    ),
+    feature(thread_local)
+)]
+#![cfg_attr(
+    feature = "debugger",
+    debugger_visualizer(gdb_script_file = "../debugger/okaoka.py")
+)]
 
-use std::{
+#[cfg(feature = "accounting")]
+pub mod accounting;
+mod allocate_in;
+#[cfg(feature = "assertions")]
+pub mod assertions;
+#[cfg(feature = "criterion")]
+pub mod bench;
+#[cfg(feature = "buddy")]
+pub mod buddy;
+#[cfg(feature = "bumpalo")]
+pub mod bump;
+#[cfg(feature = "cap")]
+pub mod cap;
+#[cfg(feature = "checksum")]
+pub mod checksum;
+mod clone_in;
+#[cfg(feature = "console")]
+pub mod console;
+#[cfg(feature = "debugger")]
+pub mod debugger;
+#[cfg(feature = "env-log")]
+pub mod env_log;
+#[cfg(all(
+    windows,
+    feature = "fiber-local",
+    not(any(feature = "single-allocator", feature = "single-thread"))
+))]
+mod fiber_local;
+#[cfg(all(unix, feature = "fork-safety"))]
+pub mod fork;
+#[cfg(feature = "frame")]
+pub mod frame;
+mod handle;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "interrupt")]
+pub mod interrupt;
+#[cfg(feature = "jemalloc-stats")]
+pub mod jemalloc;
+#[cfg(feature = "lifetimes")]
+pub mod lifetimes;
+#[cfg(feature = "live")]
+pub mod live;
+#[cfg(feature = "log")]
+pub mod logging;
+#[cfg(feature = "migrate")]
+pub mod migrate;
+#[cfg(feature = "mimalloc-heaps")]
+pub mod mimalloc;
+#[cfg(any(feature = "proptest", feature = "arbitrary"))]
+pub mod model;
+#[cfg(feature = "mte")]
+pub mod mte;
+#[cfg(all(unix, feature = "never-reuse"))]
+pub mod never_reuse;
+mod once;
+#[cfg(feature = "std")]
+pub mod oom;
+#[cfg(all(unix, any(feature = "purge", feature = "never-reuse")))]
+mod os;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "owner")]
+pub mod owner;
+#[cfg(feature = "panic-hook")]
+pub mod panic_hook;
+#[cfg(all(unix, feature = "persistent"))]
+pub mod persistent;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+#[cfg(all(unix, feature = "purge"))]
+pub mod purge;
+#[cfg(feature = "random-tags")]
+pub mod random_tags;
+#[cfg(feature = "randomized")]
+pub mod randomized;
+pub mod registry;
+#[cfg(feature = "stats")]
+pub mod report;
+#[cfg(feature = "ring")]
+pub mod ring;
+#[cfg(feature = "rss")]
+pub mod rss;
+#[cfg(all(feature = "sanitizer", not(feature = "single-allocator")))]
+mod sanitizer;
+#[cfg(feature = "shared-core")]
+pub mod shared;
+#[cfg(all(unix, feature = "signal-dump"))]
+pub mod signal_dump;
+#[cfg(feature = "signal-scope")]
+pub mod signal_scope;
+#[cfg(feature = "size-classes")]
+pub mod size_classes;
+#[cfg(feature = "slab")]
+pub mod slab;
+#[cfg(feature = "stats")]
+pub mod stats;
+#[cfg(feature = "stats-alloc")]
+pub mod stats_alloc;
+mod sync;
+#[cfg(any(
+    all(feature = "stats", feature = "owner"),
+    feature = "profiling",
+    feature = "thread-stats",
+    feature = "size-classes"
+))]
+mod table;
+#[cfg(any(feature = "tag-source", not(feature = "std")))]
+mod tag_source;
+mod tagged_drop;
+#[cfg(all(test, feature = "std"))]
+// Most of the tests using it are disabled with `single-allocator`
+#[cfg_attr(feature = "single-allocator", allow(dead_code))]
+mod test_util;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "thread-arenas")]
+pub mod thread_arenas;
+#[cfg(feature = "thread-routing")]
+pub mod thread_routing;
+#[cfg(feature = "thread-stats")]
+pub mod thread_stats;
+#[cfg(feature = "tlsf")]
+pub mod tlsf;
+#[cfg(feature = "trace")]
+pub mod trace;
+mod unknown_tag;
+#[cfg(all(feature = "valgrind", not(feature = "single-allocator")))]
+mod valgrind;
+#[cfg(feature = "stats")]
+pub mod watchdog;
+
+pub use allocate_in::{AllocateIn, AllocatingIn};
+pub use clone_in::{clone_into, CloneIn};
+pub use handle::TagHandle;
+#[cfg(feature = "allocator-api")]
+pub use handle::{aliases, ConstTagHandle, NewBoxIn, NewIn};
+#[cfg(feature = "http")]
+pub use http::serve_stats;
+#[cfg(feature = "panic-hook")]
+pub use panic_hook::install_panic_hook;
+#[cfg(any(feature = "tag-source", not(feature = "std")))]
+pub use tag_source::{set_tag_source, TagSource};
+pub use tagged_drop::{drop_in, Tagged, TaggedDrop};
+pub use unknown_tag::{
+    set_unknown_tag_hook, set_unknown_tag_mode, unknown_tag_count, unknown_tag_mode, UnknownTagMode,
+};
+
+#[doc(hidden)]
+pub extern crate alloc as __alloc;
+#[cfg(loom)]
+#[doc(hidden)]
+pub use loom as __loom;
+#[doc(hidden)]
+pub use paste;
+
+// The statistics of owners, callsites and threads are kept in constant statics, which can't hold
+// the atomics of loom
+#[cfg(all(
+    loom,
+    any(
+        all(feature = "stats", feature = "owner"),
+        feature = "profiling",
+        feature = "thread-stats"
+    )
+))]
+compile_error!(
+    "the `owner`, `profiling` and `thread-stats` statistics aren't supported under loom"
+);
+
+// Pages decommitted with `VirtualFree` inside the blocks of an allocator would fault once it
+// reuses them, as nothing commits them again
+#[cfg(all(feature = "purge", not(unix)))]
+compile_error!("the `purge` feature needs `madvise`, which is only available on unix");
+
+extern crate alloc;
+
+use alloc::{borrow::ToOwned, boxed::Box, string::String, vec, vec::Vec};
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicU16, AtomicU8};
+use core::{
     alloc::{GlobalAlloc, Layout},
-    cell::UnsafeCell,
     marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
 };
+#[cfg(feature = "std")]
+use std::sync::OnceLock;
 
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        feature = "thread-local",
+        all(windows, feature = "fiber-local")
+    ))
+))]
 thread_local! {
-    static ALLOCATOR_TAG: UnsafeCell<u8> = UnsafeCell::new(0);
+    /// Tag of the current allocator, `None` means the default allocator of the backend
+    static ALLOCATOR_TAG: core::cell::UnsafeCell<Option<u16>> =
+        const { core::cell::UnsafeCell::new(None) };
 }
 
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        feature = "thread-local",
+        all(windows, feature = "fiber-local")
+    ))
+))]
 #[inline(always)]
-fn get_allocator_tag() -> u8 {
+fn load_builtin_tag() -> Option<u16> {
     ALLOCATOR_TAG.with(|tag| unsafe { *tag.get() })
 }
 
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        feature = "thread-local",
+        all(windows, feature = "fiber-local")
+    ))
+))]
 #[inline(always)]
-fn set_allocator_tag(new_tag: u8) {
+fn store_builtin_tag(new_tag: Option<u16>) {
     ALLOCATOR_TAG.with(|tag| unsafe { *tag.get() = new_tag });
 }
 
+// The threads of a loom model run on the same OS thread, so they need the thread-locals of loom to
+// have a tag each
+#[cfg(all(
+    feature = "std",
+    loom,
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        feature = "thread-local",
+        all(windows, feature = "fiber-local")
+    ))
+))]
+loom::thread_local! {
+    /// Tag of the current allocator, `None` means the default allocator of the backend
+    static ALLOCATOR_TAG: loom::cell::UnsafeCell<Option<u16>> = loom::cell::UnsafeCell::new(None);
+}
+
+#[cfg(all(
+    feature = "std",
+    loom,
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        feature = "thread-local",
+        all(windows, feature = "fiber-local")
+    ))
+))]
+#[inline(always)]
+fn load_builtin_tag() -> Option<u16> {
+    ALLOCATOR_TAG.with(|tag| tag.with(|tag| unsafe { *tag }))
+}
+
+#[cfg(all(
+    feature = "std",
+    loom,
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        feature = "thread-local",
+        all(windows, feature = "fiber-local")
+    ))
+))]
+#[inline(always)]
+fn store_builtin_tag(new_tag: Option<u16>) {
+    ALLOCATOR_TAG.with(|tag| tag.with_mut(|tag| unsafe { *tag = new_tag }));
+}
+
+// Without `std` there's no thread-local, so the tag is only stored by the installed `TagSource`
+#[cfg(not(any(
+    feature = "std",
+    feature = "single-allocator",
+    feature = "single-thread",
+    feature = "thread-local",
+    all(windows, feature = "fiber-local")
+)))]
+#[inline(always)]
+fn load_builtin_tag() -> Option<u16> {
+    None
+}
+
+#[cfg(not(any(
+    feature = "std",
+    feature = "single-allocator",
+    feature = "single-thread",
+    feature = "thread-local",
+    all(windows, feature = "fiber-local")
+)))]
+#[inline(always)]
+fn store_builtin_tag(_new_tag: Option<u16>) {}
+
+// `#[thread_local]` statics are accessed directly, without the lazy initialization and the
+// closure of `thread_local!`, so reading the tag is a single TLS load
+#[cfg(all(
+    feature = "thread-local",
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        all(windows, feature = "fiber-local")
+    ))
+))]
+#[thread_local]
+static ALLOCATOR_TAG: core::cell::Cell<Option<u16>> = core::cell::Cell::new(None);
+
+#[cfg(all(
+    feature = "thread-local",
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        all(windows, feature = "fiber-local")
+    ))
+))]
+#[inline(always)]
+fn load_builtin_tag() -> Option<u16> {
+    ALLOCATOR_TAG.get()
+}
+
+#[cfg(all(
+    feature = "thread-local",
+    not(any(
+        feature = "single-allocator",
+        feature = "single-thread",
+        all(windows, feature = "fiber-local")
+    ))
+))]
+#[inline(always)]
+fn store_builtin_tag(new_tag: Option<u16>) {
+    ALLOCATOR_TAG.set(new_tag);
+}
+
+// Fibers move between threads, so the tag is stored in their fiber-local storage
+#[cfg(all(
+    windows,
+    feature = "fiber-local",
+    not(any(feature = "single-allocator", feature = "single-thread"))
+))]
+#[inline(always)]
+fn load_builtin_tag() -> Option<u16> {
+    fiber_local::load()
+}
+
+#[cfg(all(
+    windows,
+    feature = "fiber-local",
+    not(any(feature = "single-allocator", feature = "single-thread"))
+))]
+#[inline(always)]
+fn store_builtin_tag(new_tag: Option<u16>) {
+    fiber_local::store(new_tag);
+}
+
+// Without threads the tag doesn't need to be thread-local, a plain static is enough
+#[cfg(all(
+    feature = "single-thread",
+    not(any(feature = "single-allocator", feature = "critical-section"))
+))]
+struct SingleThreadTag(core::cell::Cell<Option<u16>>);
+
+// SAFETY: the `single-thread` feature requires the program to allocate from a single thread
+#[cfg(all(
+    feature = "single-thread",
+    not(any(feature = "single-allocator", feature = "critical-section"))
+))]
+unsafe impl Sync for SingleThreadTag {}
+
+#[cfg(all(
+    feature = "single-thread",
+    not(any(feature = "single-allocator", feature = "critical-section"))
+))]
+static ALLOCATOR_TAG: SingleThreadTag = SingleThreadTag(core::cell::Cell::new(None));
+
+#[cfg(all(
+    feature = "single-thread",
+    not(any(feature = "single-allocator", feature = "critical-section"))
+))]
+#[inline(always)]
+fn load_builtin_tag() -> Option<u16> {
+    ALLOCATOR_TAG.0.get()
+}
+
+#[cfg(all(
+    feature = "single-thread",
+    not(any(feature = "single-allocator", feature = "critical-section"))
+))]
+#[inline(always)]
+fn store_builtin_tag(new_tag: Option<u16>) {
+    ALLOCATOR_TAG.0.set(new_tag);
+}
+
+// An interrupt may switch allocators in the middle of an access of the code it interrupts, so
+// the static is only accessed inside a critical section
+#[cfg(all(feature = "critical-section", not(feature = "single-allocator")))]
+static ALLOCATOR_TAG: critical_section::Mutex<core::cell::Cell<Option<u16>>> =
+    critical_section::Mutex::new(core::cell::Cell::new(None));
+
+#[cfg(all(feature = "critical-section", not(feature = "single-allocator")))]
+#[inline(always)]
+fn load_builtin_tag() -> Option<u16> {
+    critical_section::with(|cs| ALLOCATOR_TAG.borrow(cs).get())
+}
+
+#[cfg(all(feature = "critical-section", not(feature = "single-allocator")))]
+#[inline(always)]
+fn store_builtin_tag(new_tag: Option<u16>) {
+    critical_section::with(|cs| ALLOCATOR_TAG.borrow(cs).set(new_tag));
+}
+
+#[cfg(all(
+    any(feature = "tag-source", not(feature = "std")),
+    not(feature = "single-allocator")
+))]
+#[inline(always)]
+fn get_allocator_tag() -> Option<u16> {
+    match tag_source::installed() {
+        Some(source) => source.get(),
+        None => load_builtin_tag(),
+    }
+}
+
+#[cfg(all(
+    any(feature = "tag-source", not(feature = "std")),
+    not(feature = "single-allocator")
+))]
+#[inline(always)]
+fn set_allocator_tag(new_tag: Option<u16>) {
+    match tag_source::installed() {
+        Some(source) => source.set(new_tag),
+        None => store_builtin_tag(new_tag),
+    }
+}
+
+#[cfg(all(
+    feature = "std",
+    not(any(feature = "tag-source", feature = "single-allocator"))
+))]
+#[inline(always)]
+fn get_allocator_tag() -> Option<u16> {
+    load_builtin_tag()
+}
+
+#[cfg(all(
+    feature = "std",
+    not(any(feature = "tag-source", feature = "single-allocator"))
+))]
+#[inline(always)]
+fn set_allocator_tag(new_tag: Option<u16>) {
+    store_builtin_tag(new_tag);
+}
+
+// With a single allocator there's nothing to switch, so the current tag isn't stored
+#[cfg(feature = "single-allocator")]
+#[inline(always)]
+fn get_allocator_tag() -> Option<u16> {
+    None
+}
+
+#[cfg(feature = "single-allocator")]
+#[inline(always)]
+fn set_allocator_tag(_new_tag: Option<u16>) {}
+
 /// Allocator that allows you to use multiple allocators and switch between them at runtime
 ///
 /// It uses a hidden tag to keep track of which allocator was used so that it can use the same
-/// allocator for deallocation.
+/// allocator for deallocation. Reallocations also stay in the allocator of the original
+/// allocation, which can resize the block in place with [`MultiAllocatorBackend::grow`] and
+/// [`MultiAllocatorBackend::shrink`].
 ///
 /// The hidden tag is put before any allocation. The following diagram shows the memory layout:
+///
+/// ```text
 /// -------------------
 /// | Tag | Data .... |
 /// -------------------
 ///       ^---- we return a pointer to this address
-pub struct MultiAllocator<T>(PhantomData<T>);
+/// ```
+///
+/// With the `single-allocator` feature, every allocation goes directly to the
+/// [`MultiAllocatorBackend::DEFAULT_TAG`] allocator, without the hidden tag, and switching
+/// allocators does nothing. This is meant for release builds that ship with a single allocator
+/// while keeping the calls to this crate in the code.
+///
+/// With the `single-thread` feature, the current tag is stored in a global instead of a
+/// thread-local, which makes switching allocators cheaper. Only use it in programs that never
+/// allocate from more than one thread, e.g. single-threaded CLI tools or wasm, since the threads
+/// of any other program would overwrite each other's tag.
+///
+/// With the `critical-section` feature as well, the global is only accessed inside a
+/// [`critical_section`](https://docs.rs/critical-section) section, for single-core firmware
+/// whose interrupt handlers allocate or switch allocators. The firmware provides the
+/// implementation of the critical section, e.g. `cortex-m` with its `critical-section-single-core`
+/// feature, and can then scope the allocations of a task to a static arena while the rest go to
+/// its general heap:
+///
+/// ```rust,ignore
+/// #![no_std]
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     Heap => embedded_alloc::LlffHeap::empty(),
+///     Arena => StaticArena::new(),
+/// }
+///
+/// fn handle_frame(frame: &[u8]) {
+///     GlobalAllocator::with(AllocatorTag::Arena, || {
+///         let packet = decode(frame); // Allocated in the static arena
+///         send(packet);
+///     });
+/// }
+/// ```
+///
+/// With the `owner` feature, the hidden tag also records the owner of the allocation, see the
+/// `owner` module. With the `accounting` feature, it records the accounting token that the
+/// allocation is charged to, see the `accounting` module. With the `profiling` feature, it
+/// records the callsite of the allocation, see the `profiling` module. With the `lifetimes`
+/// feature, it records the time of the allocation, see the `lifetimes` module. With the `live`
+/// feature, it links the allocation into the list of live blocks, see the `live` module.
+///
+/// With the `fiber-local` feature, on Windows, the current tag is stored in fiber-local storage
+/// instead of a thread-local, so it follows the fibers that an application moves between threads
+/// instead of staying with the thread. Other platforms keep the thread-local.
+///
+/// With the `tag-source` feature, the current tag can be stored somewhere else than a
+/// thread-local by installing a `TagSource` with `set_tag_source`, e.g. in task-local or
+/// per-CPU storage provided by a runtime.
+///
+/// Without the default `std` feature, the crate is `no_std` and has no thread-local, so the
+/// current tag is stored by the `TagSource` installed with `set_tag_source`, e.g. per-core
+/// storage or the thread-local storage of an RTOS, and stays the default tag until one is
+/// installed, unless the `single-thread` feature stores it in a global. The OOM strategies, the
+/// pools, `LazyAllocator` and `EnvDefault` need `std`, and every feature except
+/// `single-allocator`, `single-thread`, `critical-section`, `thread-local`, `fiber-local`,
+/// `tag-source`, `allocator-api`, `interrupt` and `debugger` enables it.
+///
+/// Besides the global allocator, any number of instances can be created, of the same or of
+/// different backends, e.g. by libraries that shouldn't replace the global allocator. An
+/// instance can be given its own tag with [`Self::set_tag`], which it uses instead of the current
+/// tag of the thread. With the `allocator-api` feature (nightly only), instances implement
+/// [`Allocator`](std::alloc::Allocator), e.g. for `Vec::new_in(&allocator)`.
+///
+/// ```rust
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// use okaoka::MultiAllocator;
+///
+/// okaoka::create_multi_allocator_backend! {
+///     ParserBackend,
+///     ParserTag,
+///     System => System,
+///     Arena => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     let parser = MultiAllocator::<ParserBackend>::for_tag(ParserTag::Arena);
+///     let layout = Layout::new::<u64>();
+///     unsafe {
+///         let ptr = parser.alloc(layout); // Allocated with jemalloc
+///         assert!(!ptr.is_null());
+///         parser.dealloc(ptr, layout);
+///     }
+///     assert_eq!(parser.tag(), Some(ParserTag::Arena));
+/// }
+/// ```
+///
+/// Installed as the global allocator, it runs under Miri with Tree Borrows
+/// (`MIRIFLAGS=-Zmiri-tree-borrows`): under Stacked Borrows, the pointer a `Box` gives back to
+/// `dealloc` only grants access to the contents of the box, not to the hidden tag before them.
+/// `ci/miri.sh` runs the checks of the crate. Allocators behind FFI, like jemalloc, can't run
+/// under Miri.
+pub struct MultiAllocator<T> {
+    // Raw tag of the instance, `NO_TAG` to use the current tag of the thread
+    tag: AtomicU32,
+    _backend: PhantomData<T>,
+}
+
+/// Tag of an instance of [`MultiAllocator`] that uses the current tag of the thread
+const NO_TAG: u32 = u32::MAX;
 
 impl<T> MultiAllocator<T> {
     pub const fn new() -> Self {
-        Self(PhantomData)
+        Self {
+            tag: AtomicU32::new(NO_TAG),
+            _backend: PhantomData,
+        }
+    }
+}
+
+impl<Backend: MultiAllocatorBackend> MultiAllocator<Backend> {
+    /// Instance that allocates with the allocator identified by `tag`
+    pub fn for_tag(tag: Backend::Tag) -> Self {
+        let allocator = Self::new();
+        allocator.set_tag(Some(tag));
+        allocator
+    }
+
+    /// Allocate with the allocator identified by `tag` instead of the current allocator of the
+    /// thread, `None` going back to the latter
+    ///
+    /// Blocks are always deallocated by the allocator they were allocated with. With the
+    /// `single-allocator` feature, the tag is ignored.
+    pub fn set_tag(&self, tag: Option<Backend::Tag>) {
+        let raw_tag = tag.map_or(NO_TAG, |tag| Backend::raw_tag(tag).into());
+        self.tag.store(raw_tag, Ordering::Relaxed);
+    }
+
+    /// Tag of the instance, set with [`Self::set_tag`]
+    pub fn tag(&self) -> Option<Backend::Tag> {
+        match self.tag.load(Ordering::Relaxed) {
+            NO_TAG => None,
+            raw_tag => Some(tag_from_raw::<Backend>(raw_tag as u16)),
+        }
+    }
+
+    /// Allocator of the next allocation of `layout`
+    #[cfg(not(feature = "single-allocator"))]
+    #[inline(always)]
+    fn target(&self, layout: Layout) -> Target<Backend> {
+        #[cfg(feature = "interrupt")]
+        if let Some(raw_tag) = interrupt::interrupt_route() {
+            return Target::for_alloc(raw_tag);
+        }
+        #[cfg(feature = "signal-scope")]
+        if let Some(raw_tag) = signal_scope::signal_route() {
+            return Target::for_alloc(raw_tag);
+        }
+        if let Some(tag) = Backend::aligned_route(layout) {
+            return Target::Tag(tag);
+        }
+        match self.tag.load(Ordering::Relaxed) {
+            NO_TAG => Target::current(),
+            raw_tag => Target::for_alloc(raw_tag as u16),
+        }
+    }
+}
+
+impl<T> Default for MultiAllocator<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+#[cfg(not(feature = "single-allocator"))]
 unsafe impl<Backend> GlobalAlloc for MultiAllocator<Backend>
 where
     Backend: MultiAllocatorBackend,
 {
-    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
-        // Make the tag size the same size as the alignment so that
-        // we can keep the same alignment for the data.
-        let tag_size = layout.align();
-        let new_layout =
-            unsafe { Layout::from_size_align_unchecked(layout.size() + tag_size, layout.align()) };
-        let allocator_tag = get_allocator_tag();
-        let ptr = unsafe { Backend::alloc(allocator_tag.into(), new_layout) };
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        let tag_size = tag_size::<Backend>(layout);
+        let new_layout = unsafe { block_layout(layout.size(), tag_size, layout.align()) };
+        let target = self.target(layout);
+        #[cfg(feature = "assertions")]
+        assertions::check_alloc::<Backend>(layout, target.raw_tag());
+        #[cfg(feature = "testing")]
+        if testing::should_fail(target.raw_tag()) {
+            return core::ptr::null_mut();
+        }
+        #[cfg(feature = "cap")]
+        if !cap::charge(new_layout.size()) {
+            return core::ptr::null_mut();
+        }
+        let (target, ptr, raw_tag) = match unsafe { target.alloc(new_layout) } {
+            (ptr, raw_tag) if !ptr.is_null() => (target, ptr, raw_tag),
+            _ => {
+                #[cfg(feature = "std")]
+                let recovered = {
+                    let attempt = |raw_tag| {
+                        let target = Target::<Backend>::for_alloc(raw_tag);
+                        let (ptr, raw_tag) = unsafe { target.alloc(new_layout) };
+                        (!ptr.is_null()).then_some((target, ptr, raw_tag))
+                    };
+                    oom::recover::<Backend, _>(target.raw_tag(), layout, attempt)
+                };
+                // Without `std` there are no OOM strategies
+                #[cfg(not(feature = "std"))]
+                let recovered = None;
+                match recovered {
+                    Some(block) => block,
+                    None => {
+                        #[cfg(feature = "cap")]
+                        cap::credit(new_layout.size());
+                        return core::ptr::null_mut();
+                    }
+                }
+            }
+        };
         // Write the allocator tag to the tag address
-        unsafe { std::ptr::write(ptr, allocator_tag) };
+        unsafe { write_header::<Backend>(ptr, raw_tag, layout.size()) };
+        #[cfg(feature = "assertions")]
+        assertions::record_alloc(layout.size());
+        #[cfg(feature = "env-log")]
+        unsafe {
+            let raw_tag = raw_tag.to_raw();
+            env_log::log::<Backend>("alloc", raw_tag, layout.size(), ptr.add(tag_size), ptr)
+        };
+        #[cfg(feature = "log")]
+        logging::record::<Backend>("alloc", raw_tag.to_raw(), layout.size(), unsafe {
+            ptr.add(tag_size)
+        });
+        #[cfg(feature = "stats")]
+        unsafe {
+            target.record_alloc(ptr, layout.size())
+        };
+        #[cfg(feature = "size-classes")]
+        target.record_class_alloc(new_layout);
+        #[cfg(not(feature = "stats"))]
+        let _ = target;
+        #[cfg(feature = "sanitizer")]
+        unsafe {
+            sanitizer::poison(ptr, tag_size)
+        };
+        #[cfg(feature = "valgrind")]
+        valgrind::malloclike(unsafe { ptr.add(tag_size) }, layout.size(), tag_size);
+        #[cfg(feature = "trace")]
+        trace::record_alloc(unsafe { ptr.add(tag_size) }, layout, raw_tag.to_raw());
         // Return a pointer to the address just after the tag
-        unsafe { ptr.add(tag_size) }
+        let ptr = unsafe { ptr.add(tag_size) };
+        #[cfg(feature = "mte")]
+        let ptr = unsafe { mte::color(ptr, layout.size()) };
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
-        let tag_size = layout.align();
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        let tag_size = tag_size::<Backend>(layout);
+        // The granules of the block get back the memory tag of its allocator
+        #[cfg(feature = "mte")]
+        let ptr = unsafe { mte::uncolor(ptr, tag_size, layout.size()) };
         // Subtract `tag_size` to get the original pointer
-        let new_ptr = unsafe { ptr.sub(tag_size) };
+        let new_ptr = block_start(ptr, tag_size);
+        // The allocator of the block may reuse the tag
+        #[cfg(feature = "sanitizer")]
+        unsafe {
+            sanitizer::unpoison(new_ptr, tag_size)
+        };
         // Re-construct the layout with `tag_size`
-        let new_layout =
-            unsafe { Layout::from_size_align_unchecked(layout.size() + tag_size, layout.align()) };
+        let new_layout = unsafe { block_layout(layout.size(), tag_size, layout.align()) };
         // Check the allocator tag used for this allocation
-        let tag = unsafe { std::ptr::read(new_ptr) };
+        let tag = unsafe { Backend::Repr::read(new_ptr) };
+        #[cfg(feature = "checksum")]
+        unsafe {
+            let checksum = new_ptr.add(checksum_offset::<Backend>());
+            checksum::check::<Backend>(checksum, tag.to_raw(), layout.size(), ptr)
+        };
+        let target = Target::<Backend>::from_raw(tag.to_raw());
+        #[cfg(feature = "assertions")]
+        assertions::record_dealloc(layout.size());
+        #[cfg(feature = "env-log")]
+        unsafe {
+            env_log::log::<Backend>("dealloc", tag.to_raw(), layout.size(), ptr, new_ptr)
+        };
+        #[cfg(feature = "stats")]
+        unsafe {
+            target.record_dealloc(new_ptr, layout.size())
+        };
+        #[cfg(feature = "size-classes")]
+        target.record_class_dealloc(new_layout);
+        #[cfg(feature = "accounting")]
+        unsafe {
+            accounting::credit(new_ptr.add(token_offset::<Backend>()), layout.size())
+        };
+        #[cfg(feature = "lifetimes")]
+        unsafe {
+            target.record_lifetime(new_ptr)
+        };
+        #[cfg(feature = "live")]
+        unsafe {
+            Backend::live_list().unlink(new_ptr.add(node_offset::<Backend>()))
+        };
+        #[cfg(feature = "valgrind")]
+        valgrind::freelike(ptr, layout.size());
+        #[cfg(feature = "trace")]
+        trace::record_dealloc(ptr);
+        #[cfg(feature = "cap")]
+        cap::credit(new_layout.size());
+
+        unsafe {
+            target.dealloc(new_ptr, new_layout);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let tag_size = tag_size::<Backend>(layout);
+        // The block is resized with the memory tag of its allocator, and colored again after
+        #[cfg(feature = "mte")]
+        let (colored, ptr) = (ptr, unsafe { mte::uncolor(ptr, tag_size, layout.size()) });
+        let old_ptr = block_start(ptr, tag_size);
+        // The tag is copied with the block if it moves
+        #[cfg(feature = "sanitizer")]
+        unsafe {
+            sanitizer::unpoison(old_ptr, tag_size)
+        };
+        let old_layout = unsafe { block_layout(layout.size(), tag_size, layout.align()) };
+        let new_block_size = unsafe { block_layout(new_size, tag_size, layout.align()) }.size();
+        let tag = unsafe { Backend::Repr::read(old_ptr) };
+        #[cfg(feature = "checksum")]
+        unsafe {
+            let checksum = old_ptr.add(checksum_offset::<Backend>());
+            checksum::check::<Backend>(checksum, tag.to_raw(), layout.size(), ptr)
+        };
+        let target = Target::<Backend>::from_raw(tag.to_raw());
+        #[cfg(feature = "assertions")]
+        assertions::check_alloc::<Backend>(
+            unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) },
+            tag.to_raw(),
+        );
+        #[cfg(feature = "testing")]
+        if testing::should_fail(tag.to_raw()) {
+            #[cfg(feature = "sanitizer")]
+            unsafe {
+                sanitizer::poison(old_ptr, tag_size)
+            };
+            #[cfg(feature = "mte")]
+            unsafe {
+                mte::recolor(colored, layout.size())
+            };
+            return core::ptr::null_mut();
+        }
+        // Charged the bytes it adds now, credited the bytes it removes once resized
+        #[cfg(feature = "cap")]
+        let grown = new_block_size.saturating_sub(old_layout.size());
+        #[cfg(feature = "cap")]
+        if !cap::charge(grown) {
+            #[cfg(feature = "sanitizer")]
+            unsafe {
+                sanitizer::poison(old_ptr, tag_size)
+            };
+            #[cfg(feature = "mte")]
+            unsafe {
+                mte::recolor(colored, layout.size())
+            };
+            return core::ptr::null_mut();
+        }
+        // The block may move, so it's linked again once resized
+        #[cfg(feature = "live")]
+        unsafe {
+            Backend::live_list().unlink(old_ptr.add(node_offset::<Backend>()))
+        };
+        // The block is declared again once resized, with the contents copied from the old one
+        // marked as defined, as their state isn't known
+        #[cfg(feature = "valgrind")]
+        valgrind::freelike(ptr, layout.size());
+        // The tag is part of the block, so it's kept by the backend
+        let resize = || {
+            let new_ptr = if new_size >= layout.size() {
+                unsafe { target.grow(old_ptr, old_layout, new_block_size) }
+            } else {
+                unsafe { target.shrink(old_ptr, old_layout, new_block_size) }
+            };
+            (!new_ptr.is_null()).then_some(new_ptr)
+        };
+        let new_ptr = match resize() {
+            Some(new_ptr) => new_ptr,
+            None => {
+                #[cfg(feature = "std")]
+                let recovered = {
+                    let new_layout =
+                        unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+                    // Only the pressure hook and the retry apply, the block stays in its allocator
+                    let attempt = |raw_tag| (raw_tag == tag.to_raw()).then(resize).flatten();
+                    oom::recover::<Backend, _>(tag.to_raw(), new_layout, attempt)
+                };
+                #[cfg(not(feature = "std"))]
+                let recovered = None;
+                match recovered {
+                    Some(new_ptr) => new_ptr,
+                    None => {
+                        #[cfg(feature = "live")]
+                        unsafe {
+                            let node = old_ptr.add(node_offset::<Backend>());
+                            Backend::live_list().link(node, layout.size())
+                        };
+                        #[cfg(feature = "sanitizer")]
+                        unsafe {
+                            sanitizer::poison(old_ptr, tag_size)
+                        };
+                        #[cfg(feature = "valgrind")]
+                        {
+                            valgrind::malloclike(ptr, layout.size(), tag_size);
+                            valgrind::make_defined(ptr, layout.size());
+                        }
+                        #[cfg(feature = "mte")]
+                        unsafe {
+                            mte::recolor(colored, layout.size())
+                        };
+                        #[cfg(feature = "cap")]
+                        cap::credit(grown);
+                        return core::ptr::null_mut();
+                    }
+                }
+            }
+        };
+        #[cfg(feature = "live")]
+        unsafe {
+            Backend::live_list().link(new_ptr.add(node_offset::<Backend>()), new_size)
+        };
+        #[cfg(feature = "checksum")]
+        unsafe {
+            let checksum = new_ptr.add(checksum_offset::<Backend>());
+            checksum::write(checksum, tag.to_raw(), new_size)
+        };
+        #[cfg(feature = "cap")]
+        cap::credit(old_layout.size().saturating_sub(new_block_size));
+        #[cfg(feature = "assertions")]
+        {
+            assertions::record_dealloc(layout.size());
+            assertions::record_alloc(new_size);
+        }
+        #[cfg(feature = "env-log")]
+        unsafe {
+            let ptr = new_ptr.add(tag_size);
+            env_log::log::<Backend>("realloc", tag.to_raw(), new_size, ptr, new_ptr)
+        };
+        #[cfg(feature = "log")]
+        logging::record::<Backend>("realloc", tag.to_raw(), new_size, unsafe {
+            new_ptr.add(tag_size)
+        });
+        #[cfg(feature = "stats")]
+        unsafe {
+            target.record_dealloc(new_ptr, layout.size());
+            target.record_alloc(new_ptr, new_size);
+        }
+        #[cfg(feature = "size-classes")]
+        {
+            target.record_class_dealloc(old_layout);
+            let new_layout = unsafe { block_layout(new_size, tag_size, layout.align()) };
+            target.record_class_alloc(new_layout);
+        }
+        #[cfg(feature = "accounting")]
+        unsafe {
+            accounting::recharge(
+                new_ptr.add(token_offset::<Backend>()),
+                layout.size(),
+                new_size,
+            )
+        };
+        #[cfg(feature = "sanitizer")]
+        unsafe {
+            sanitizer::poison(new_ptr, tag_size)
+        };
+        #[cfg(feature = "valgrind")]
+        {
+            let ptr = unsafe { new_ptr.add(tag_size) };
+            valgrind::malloclike(ptr, new_size, tag_size);
+            valgrind::make_defined(ptr, new_size.min(layout.size()));
+        }
+        #[cfg(feature = "trace")]
+        trace::record_realloc(ptr, unsafe { new_ptr.add(tag_size) }, new_size);
+        let new_ptr = unsafe { new_ptr.add(tag_size) };
+        #[cfg(feature = "mte")]
+        let new_ptr = unsafe { mte::color(new_ptr, new_size) };
+        new_ptr
+    }
+}
+
+#[cfg(feature = "single-allocator")]
+unsafe impl<Backend> GlobalAlloc for MultiAllocator<Backend>
+where
+    Backend: MultiAllocatorBackend,
+{
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        #[cfg(feature = "assertions")]
+        assertions::check_alloc::<Backend>(layout, Backend::raw_tag(Backend::DEFAULT_TAG));
+        #[cfg(feature = "testing")]
+        if testing::should_fail(Backend::raw_tag(Backend::DEFAULT_TAG)) {
+            return core::ptr::null_mut();
+        }
+        #[cfg(feature = "cap")]
+        if !cap::charge(layout.size()) {
+            return core::ptr::null_mut();
+        }
+        let ptr = unsafe { Backend::alloc(Backend::DEFAULT_TAG, layout) };
+        #[cfg(feature = "cap")]
+        if ptr.is_null() {
+            cap::credit(layout.size());
+        }
+        #[cfg(feature = "assertions")]
+        if !ptr.is_null() {
+            assertions::record_alloc(layout.size());
+        }
+        #[cfg(feature = "env-log")]
+        if !ptr.is_null() {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            unsafe {
+                env_log::log::<Backend>("alloc", raw_tag, layout.size(), ptr, core::ptr::null())
+            };
+        }
+        #[cfg(feature = "log")]
+        if !ptr.is_null() {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            logging::record::<Backend>("alloc", raw_tag, layout.size(), ptr);
+        }
+        #[cfg(feature = "trace")]
+        if !ptr.is_null() {
+            trace::record_alloc(ptr, layout, Backend::raw_tag(Backend::DEFAULT_TAG));
+        }
+        #[cfg(feature = "stats")]
+        if !ptr.is_null() {
+            Backend::counters(Backend::DEFAULT_TAG).record_alloc(layout.size());
+        }
+        ptr
+    }
 
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        #[cfg(feature = "assertions")]
+        assertions::record_dealloc(layout.size());
+        #[cfg(feature = "env-log")]
         unsafe {
-            Backend::dealloc(tag.into(), new_ptr, new_layout);
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            env_log::log::<Backend>("dealloc", raw_tag, layout.size(), ptr, core::ptr::null())
+        };
+        #[cfg(feature = "stats")]
+        Backend::counters(Backend::DEFAULT_TAG).record_dealloc(layout.size());
+        #[cfg(feature = "trace")]
+        trace::record_dealloc(ptr);
+        #[cfg(feature = "cap")]
+        cap::credit(layout.size());
+        unsafe { Backend::dealloc(Backend::DEFAULT_TAG, ptr, layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        #[cfg(feature = "assertions")]
+        assertions::check_alloc::<Backend>(
+            unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) },
+            Backend::raw_tag(Backend::DEFAULT_TAG),
+        );
+        #[cfg(feature = "testing")]
+        if testing::should_fail(Backend::raw_tag(Backend::DEFAULT_TAG)) {
+            return core::ptr::null_mut();
+        }
+        #[cfg(feature = "cap")]
+        if !cap::charge(new_size.saturating_sub(layout.size())) {
+            return core::ptr::null_mut();
+        }
+        let new_ptr = if new_size >= layout.size() {
+            unsafe { Backend::grow(Backend::DEFAULT_TAG, ptr, layout, new_size) }
+        } else {
+            unsafe { Backend::shrink(Backend::DEFAULT_TAG, ptr, layout, new_size) }
+        };
+        #[cfg(feature = "cap")]
+        match new_ptr.is_null() {
+            true => cap::credit(new_size.saturating_sub(layout.size())),
+            false => cap::credit(layout.size().saturating_sub(new_size)),
+        }
+        #[cfg(feature = "assertions")]
+        if !new_ptr.is_null() {
+            assertions::record_dealloc(layout.size());
+            assertions::record_alloc(new_size);
+        }
+        #[cfg(feature = "env-log")]
+        if !new_ptr.is_null() {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            unsafe {
+                env_log::log::<Backend>("realloc", raw_tag, new_size, new_ptr, core::ptr::null())
+            };
+        }
+        #[cfg(feature = "log")]
+        if !new_ptr.is_null() {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            logging::record::<Backend>("realloc", raw_tag, new_size, new_ptr);
         }
+        #[cfg(feature = "stats")]
+        if !new_ptr.is_null() {
+            Backend::counters(Backend::DEFAULT_TAG).record_dealloc(layout.size());
+            Backend::counters(Backend::DEFAULT_TAG).record_alloc(new_size);
+        }
+        #[cfg(feature = "trace")]
+        if !new_ptr.is_null() {
+            trace::record_realloc(ptr, new_ptr, new_size);
+        }
+        new_ptr
     }
 }
 
-pub trait MultiAllocatorBackend {
-    type Tag: Copy + Into<u8> + From<u8>;
+#[cfg(feature = "allocator-api")]
+unsafe impl<Backend: MultiAllocatorBackend> core::alloc::Allocator for MultiAllocator<Backend> {
+    fn allocate(
+        &self,
+        layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        handle::allocate(self, layout)
+    }
 
-    unsafe fn alloc(tag: Self::Tag, layout: Layout) -> *mut u8;
-    unsafe fn dealloc(tag: Self::Tag, ptr: *mut u8, layout: Layout);
+    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: Layout) {
+        unsafe { handle::deallocate(self, ptr, layout) }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        unsafe { handle::resize(self, ptr, old_layout, new_layout) }
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: core::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
+        unsafe { handle::resize(self, ptr, old_layout, new_layout) }
+    }
 }
 
-#[macro_export]
-macro_rules! create_multi_allocator_backend {
-    ($name:ident, $enum_name:ident, $($tag_name:ident => $allocator_name:ident),+$(,)?) => {
-        #[derive(Copy, Clone)]
-        #[repr(u8)]
-        enum $enum_name {
-            $($tag_name),+
-            ,__END,
+#[cfg(not(feature = "single-allocator"))]
+impl<Backend: MultiAllocatorBackend> MultiAllocator<Backend> {
+    /// Allocate `out.len()` blocks of memory with the same layout with the current allocator
+    ///
+    /// Like [`MultiAllocatorBackend::alloc_batch`] but with the hidden tag, so each block can
+    /// also be deallocated with [`GlobalAlloc::dealloc`]. Returns how many blocks were
+    /// allocated, their pointers are stored at the start of `out`.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::alloc`].
+    pub unsafe fn alloc_batch(&self, layout: Layout, out: &mut [*mut u8]) -> usize {
+        let tag_size = tag_size::<Backend>(layout);
+        let new_layout = unsafe { block_layout(layout.size(), tag_size, layout.align()) };
+        let target = self.target(layout);
+        #[cfg(feature = "assertions")]
+        assertions::check_alloc::<Backend>(layout, target.raw_tag());
+        #[cfg(feature = "testing")]
+        if testing::should_fail(target.raw_tag()) {
+            return 0;
         }
-
-        impl From<u8> for $enum_name {
-            fn from(raw_tag: u8) -> Self {
-                assert!(raw_tag < $enum_name::__END as u8);
-                unsafe { std::mem::transmute(raw_tag) }
+        #[cfg(feature = "cap")]
+        if !new_layout
+            .size()
+            .checked_mul(out.len())
+            .is_some_and(cap::charge)
+        {
+            return 0;
+        }
+        let (allocated, raw_tag) = unsafe { target.alloc_batch(new_layout, out) };
+        #[cfg(feature = "cap")]
+        cap::credit(new_layout.size() * (out.len() - allocated));
+        for ptr in &mut out[..allocated] {
+            unsafe {
+                write_header::<Backend>(*ptr, raw_tag, layout.size());
+                #[cfg(feature = "assertions")]
+                assertions::record_alloc(layout.size());
+                #[cfg(feature = "env-log")]
+                env_log::log::<Backend>(
+                    "alloc",
+                    raw_tag.to_raw(),
+                    layout.size(),
+                    ptr.add(tag_size),
+                    *ptr,
+                );
+                #[cfg(feature = "log")]
+                logging::record::<Backend>(
+                    "alloc",
+                    raw_tag.to_raw(),
+                    layout.size(),
+                    ptr.add(tag_size),
+                );
+                #[cfg(feature = "stats")]
+                target.record_alloc(*ptr, layout.size());
+                #[cfg(feature = "size-classes")]
+                target.record_class_alloc(new_layout);
+                #[cfg(feature = "sanitizer")]
+                sanitizer::poison(*ptr, tag_size);
+                *ptr = ptr.add(tag_size);
+                #[cfg(feature = "valgrind")]
+                valgrind::malloclike(*ptr, layout.size(), tag_size);
+                #[cfg(feature = "trace")]
+                trace::record_alloc(*ptr, layout, raw_tag.to_raw());
+                #[cfg(feature = "mte")]
+                {
+                    *ptr = mte::color(*ptr, layout.size());
+                }
             }
         }
+        allocated
+    }
 
-        impl From<$enum_name> for u8 {
-            fn from(tag: $enum_name) -> Self {
-                tag as u8
+    /// Deallocate blocks of memory with the same layout allocated by this allocator
+    ///
+    /// Consecutive blocks allocated with the same allocator are deallocated with a single call
+    /// to [`MultiAllocatorBackend::dealloc_batch`]. The contents of `ptrs` are unspecified
+    /// afterwards.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::dealloc`] for every pointer of `ptrs`.
+    pub unsafe fn dealloc_batch(&self, ptrs: &mut [*mut u8], layout: Layout) {
+        let tag_size = tag_size::<Backend>(layout);
+        let new_layout = unsafe { block_layout(layout.size(), tag_size, layout.align()) };
+        #[cfg(feature = "cap")]
+        cap::credit(new_layout.size() * ptrs.len());
+        // Replace the pointers with the original ones, which start at the tag
+        for ptr in ptrs.iter_mut() {
+            #[cfg(feature = "mte")]
+            {
+                *ptr = unsafe { mte::uncolor(*ptr, tag_size, layout.size()) };
+            }
+            #[cfg(feature = "valgrind")]
+            valgrind::freelike(*ptr, layout.size());
+            #[cfg(feature = "trace")]
+            trace::record_dealloc(*ptr);
+            *ptr = block_start(*ptr, tag_size);
+            #[cfg(feature = "sanitizer")]
+            unsafe {
+                sanitizer::unpoison(*ptr, tag_size)
+            };
+            #[cfg(feature = "checksum")]
+            unsafe {
+                let raw_tag = Backend::Repr::read(*ptr).to_raw();
+                let checksum = ptr.add(checksum_offset::<Backend>());
+                checksum::check::<Backend>(checksum, raw_tag, layout.size(), ptr.add(tag_size))
+            };
+        }
+        let raw_tag_of = |ptr: *mut u8| unsafe { Backend::Repr::read(ptr) }.to_raw();
+        let mut rest = &ptrs[..];
+        while let Some(&first) = rest.first() {
+            let raw_tag = raw_tag_of(first);
+            let len = rest
+                .iter()
+                .position(|&ptr| raw_tag_of(ptr) != raw_tag)
+                .unwrap_or(rest.len());
+            let (same_tag, others) = rest.split_at(len);
+            let target = Target::<Backend>::from_raw(raw_tag);
+            #[cfg(feature = "assertions")]
+            for _ in same_tag {
+                assertions::record_dealloc(layout.size());
+            }
+            #[cfg(feature = "env-log")]
+            for &block in same_tag {
+                let ptr = unsafe { block.add(tag_size) };
+                unsafe { env_log::log::<Backend>("dealloc", raw_tag, layout.size(), ptr, block) };
             }
+            #[cfg(any(
+                feature = "stats",
+                feature = "accounting",
+                feature = "lifetimes",
+                feature = "live"
+            ))]
+            for &block in same_tag {
+                #[cfg(feature = "stats")]
+                unsafe {
+                    target.record_dealloc(block, layout.size())
+                };
+                #[cfg(feature = "size-classes")]
+                target.record_class_dealloc(new_layout);
+                #[cfg(feature = "accounting")]
+                unsafe {
+                    accounting::credit(block.add(token_offset::<Backend>()), layout.size())
+                };
+                #[cfg(feature = "lifetimes")]
+                unsafe {
+                    target.record_lifetime(block)
+                };
+                #[cfg(feature = "live")]
+                unsafe {
+                    Backend::live_list().unlink(block.add(node_offset::<Backend>()))
+                };
+            }
+            unsafe { target.dealloc_batch(same_tag, new_layout) };
+            rest = others;
+        }
+    }
+}
+
+#[cfg(feature = "single-allocator")]
+impl<Backend: MultiAllocatorBackend> MultiAllocator<Backend> {
+    /// Allocate `out.len()` blocks of memory with the same layout with the default allocator
+    ///
+    /// Returns how many blocks were allocated, their pointers are stored at the start of `out`.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::alloc`].
+    pub unsafe fn alloc_batch(&self, layout: Layout, out: &mut [*mut u8]) -> usize {
+        #[cfg(feature = "assertions")]
+        assertions::check_alloc::<Backend>(layout, Backend::raw_tag(Backend::DEFAULT_TAG));
+        #[cfg(feature = "testing")]
+        if testing::should_fail(Backend::raw_tag(Backend::DEFAULT_TAG)) {
+            return 0;
+        }
+        #[cfg(feature = "cap")]
+        if !layout
+            .size()
+            .checked_mul(out.len())
+            .is_some_and(cap::charge)
+        {
+            return 0;
+        }
+        let allocated = unsafe { Backend::alloc_batch(Backend::DEFAULT_TAG, layout, out) };
+        #[cfg(feature = "cap")]
+        cap::credit(layout.size() * (out.len() - allocated));
+        #[cfg(feature = "assertions")]
+        for _ in 0..allocated {
+            assertions::record_alloc(layout.size());
+        }
+        #[cfg(feature = "env-log")]
+        for &ptr in &out[..allocated] {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            unsafe {
+                env_log::log::<Backend>("alloc", raw_tag, layout.size(), ptr, core::ptr::null())
+            };
+        }
+        #[cfg(feature = "log")]
+        for &ptr in &out[..allocated] {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            logging::record::<Backend>("alloc", raw_tag, layout.size(), ptr);
+        }
+        #[cfg(feature = "stats")]
+        for _ in 0..allocated {
+            Backend::counters(Backend::DEFAULT_TAG).record_alloc(layout.size());
         }
+        #[cfg(feature = "trace")]
+        for &ptr in &out[..allocated] {
+            trace::record_alloc(ptr, layout, Backend::raw_tag(Backend::DEFAULT_TAG));
+        }
+        allocated
+    }
 
-        struct $name;
+    /// Deallocate blocks of memory with the same layout allocated by this allocator
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::dealloc`] for every pointer of `ptrs`.
+    pub unsafe fn dealloc_batch(&self, ptrs: &mut [*mut u8], layout: Layout) {
+        #[cfg(feature = "cap")]
+        cap::credit(layout.size() * ptrs.len());
+        #[cfg(feature = "assertions")]
+        for _ in ptrs.iter() {
+            assertions::record_dealloc(layout.size());
+        }
+        #[cfg(feature = "env-log")]
+        for &ptr in ptrs.iter() {
+            let raw_tag = Backend::raw_tag(Backend::DEFAULT_TAG);
+            unsafe {
+                env_log::log::<Backend>("dealloc", raw_tag, layout.size(), ptr, core::ptr::null())
+            };
+        }
+        #[cfg(feature = "stats")]
+        for _ in ptrs.iter() {
+            Backend::counters(Backend::DEFAULT_TAG).record_dealloc(layout.size());
+        }
+        #[cfg(feature = "trace")]
+        for &ptr in ptrs.iter() {
+            trace::record_dealloc(ptr);
+        }
+        unsafe { Backend::dealloc_batch(Backend::DEFAULT_TAG, ptrs, layout) }
+    }
+}
 
-        impl okaoka::MultiAllocatorBackend for $name {
-            type Tag = $enum_name;
+impl<Backend: MultiAllocatorBackend> MultiAllocator<Backend> {
+    /// Allocate `len` boxes in a single batch with the current allocator
+    ///
+    /// The value of each box is `init(index)`.
+    ///
+    /// # Safety
+    ///
+    /// `self` must be the global allocator, as the boxes are deallocated through it when dropped.
+    pub unsafe fn alloc_boxes<T>(
+        &self,
+        len: usize,
+        mut init: impl FnMut(usize) -> T,
+    ) -> Vec<Box<T>> {
+        let layout = Layout::new::<T>();
+        if layout.size() == 0 {
+            return (0..len).map(|index| Box::new(init(index))).collect();
+        }
 
-            #[inline(always)]
-            unsafe fn alloc(tag: Self::Tag, layout: std::alloc::Layout) -> *mut u8 {
-                use std::alloc::GlobalAlloc;
-                match tag {
-                    $($enum_name::$tag_name => $allocator_name.alloc(layout)),+
-                    ,$enum_name::__END => unreachable!(),
+        let mut boxes = Vec::with_capacity(len);
+        let mut ptrs = vec![core::ptr::null_mut(); len];
+        let allocated = unsafe { self.alloc_batch(layout, &mut ptrs) };
+        if allocated < len {
+            unsafe { self.dealloc_batch(&mut ptrs[..allocated], layout) };
+            alloc::alloc::handle_alloc_error(layout);
+        }
+
+        // Deallocates the blocks that aren't boxes yet if `init` panics
+        struct Pending<'a, Backend: MultiAllocatorBackend> {
+            allocator: &'a MultiAllocator<Backend>,
+            ptrs: &'a [*mut u8],
+            layout: Layout,
+        }
+
+        impl<Backend: MultiAllocatorBackend> Drop for Pending<'_, Backend> {
+            fn drop(&mut self) {
+                for &ptr in self.ptrs {
+                    unsafe { self.allocator.dealloc(ptr, self.layout) }
                 }
             }
+        }
 
-            #[inline(always)]
-            unsafe fn dealloc(tag: Self::Tag, ptr: *mut u8, layout: std::alloc::Layout) {
-                use std::alloc::GlobalAlloc;
-                match tag {
-                    $($enum_name::$tag_name => $allocator_name.dealloc(ptr, layout)),+
-                    ,$enum_name::__END => unreachable!(),
-                }
+        let mut pending = Pending {
+            allocator: self,
+            ptrs: &ptrs,
+            layout,
+        };
+        while let Some((&ptr, rest)) = pending.ptrs.split_first() {
+            let value = init(boxes.len());
+            pending.ptrs = rest;
+            let ptr = ptr.cast::<T>();
+            unsafe {
+                ptr.write(value);
+                boxes.push(Box::from_raw(ptr));
             }
         }
-    };
+        boxes
+    }
 }
 
-#[macro_export]
-macro_rules! set_multi_global_allocator {
-    ($name:ident, $enum_name:ident, $($tag_name:ident => $allocator_name:ident),+$(,)?) => {
-        okaoka::create_multi_allocator_backend!{
-            $name,
-            $enum_name,
-            $($tag_name => $allocator_name),+
+/// Allocator identified by a raw tag, either a tag of the backend or a slot of its registry
+#[cfg(not(feature = "single-allocator"))]
+enum Target<Backend: MultiAllocatorBackend> {
+    Tag(Backend::Tag),
+    Slot(u16, &'static registry::Slot),
+}
+
+#[cfg(not(feature = "single-allocator"))]
+impl<Backend: MultiAllocatorBackend> Clone for Target<Backend> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+#[cfg(not(feature = "single-allocator"))]
+impl<Backend: MultiAllocatorBackend> Copy for Target<Backend> {}
+
+#[cfg(not(feature = "single-allocator"))]
+impl<Backend: MultiAllocatorBackend> Target<Backend> {
+    /// Allocator selected by the current thread
+    #[inline(always)]
+    fn current() -> Self {
+        #[cfg(feature = "random-tags")]
+        if let Some(tag) = random_tags::pick::<Backend>() {
+            return Self::Tag(tag);
+        }
+        match get_allocator_tag() {
+            Some(raw_tag) => Self::for_alloc(raw_tag),
+            #[cfg(feature = "thread-routing")]
+            None => match thread_routing::thread_route() {
+                Some(raw_tag) => Self::for_alloc(raw_tag),
+                None => Self::Tag(Backend::default_tag()),
+            },
+            #[cfg(not(feature = "thread-routing"))]
+            None => Self::Tag(Backend::default_tag()),
         }
+    }
 
-        #[global_allocator]
-        static ALLOCATOR: okaoka::MultiAllocator<$name> = okaoka::MultiAllocator::new();
+    /// Allocator identified by `raw_tag` for new blocks, see [`UnknownTagMode`] for tags that
+    /// aren't valid
+    #[inline(always)]
+    fn for_alloc(raw_tag: u16) -> Self {
+        match Backend::dynamic_slot(raw_tag) {
+            Some(slot) if !slot.is_empty() => Self::Slot(raw_tag, slot),
+            Some(_) => Self::Tag(unknown_tag::fallback::<Backend>(raw_tag)),
+            None => {
+                let tag = Backend::Repr::from_raw(raw_tag).and_then(|tag| tag.try_into().ok());
+                Self::Tag(tag.unwrap_or_else(|| unknown_tag::fallback::<Backend>(raw_tag)))
+            }
+        }
+    }
 
-        impl $name {
-            pub fn with(tag: <$name as okaoka::MultiAllocatorBackend>::Tag, mut closure: impl FnMut()) {
-                okaoka::with_allocator(tag.into(), closure);
+    /// Allocator identified by `raw_tag`, panicking if it isn't valid
+    #[inline(always)]
+    fn from_raw(raw_tag: u16) -> Self {
+        match Backend::dynamic_slot(raw_tag) {
+            Some(slot) => Self::Slot(raw_tag, slot),
+            None => Self::Tag(tag_from_raw::<Backend>(raw_tag)),
+        }
+    }
+
+    /// Raw tag of the allocator
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    #[inline(always)]
+    fn raw_tag(self) -> u16 {
+        match self {
+            Self::Tag(tag) => Backend::raw_tag(tag),
+            Self::Slot(raw_tag, _) => raw_tag,
+        }
+    }
+
+    /// Tag written before the blocks allocated in `generation`
+    #[inline(always)]
+    fn repr(self, generation: usize) -> Backend::Repr {
+        let raw_tag = match self {
+            Self::Tag(tag) => return tag.into(),
+            // The lowest bit of the raw tag of a slot is the generation
+            Self::Slot(raw_tag, _) => raw_tag & !1 | generation as u16,
+        };
+        match Backend::Repr::from_raw(raw_tag) {
+            Some(repr) => repr,
+            None => panic!("{}", InvalidTagError::new(raw_tag)),
+        }
+    }
+
+    /// Allocator of a slot in the generation of `raw_tag`, panicking if there's none
+    #[inline(always)]
+    fn slot_allocator(raw_tag: u16, slot: &registry::Slot) -> &registry::DynAllocator {
+        match slot.allocator(usize::from(raw_tag & 1)) {
+            Some(allocator) => allocator,
+            None => panic!("{}", InvalidTagError::new(raw_tag)),
+        }
+    }
+
+    /// Allocate a block, returning it with its tag
+    #[inline(always)]
+    unsafe fn alloc(self, layout: Layout) -> (*mut u8, Backend::Repr) {
+        match self {
+            Self::Tag(tag) => (unsafe { Backend::alloc(tag, layout) }, tag.into()),
+            Self::Slot(raw_tag, slot) => {
+                let generation = slot.enter(1);
+                let raw_tag = raw_tag & !1 | generation as u16;
+                let ptr = unsafe { Self::slot_allocator(raw_tag, slot).alloc(layout) };
+                if ptr.is_null() {
+                    slot.leave(generation, 1);
+                }
+                (ptr, self.repr(generation))
             }
         }
-    };
-}
+    }
 
-/// Set the given allocator inside the closure, restoring the previous allocator after returning
-///
+    #[inline(always)]
+    unsafe fn dealloc(self, ptr: *mut u8, layout: Layout) {
+        match self {
+            Self::Tag(tag) => unsafe { Backend::dealloc(tag, ptr, layout) },
+            Self::Slot(raw_tag, slot) => {
+                unsafe { Self::slot_allocator(raw_tag, slot).dealloc(ptr, layout) };
+                slot.leave(usize::from(raw_tag & 1), 1);
+            }
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn grow(self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match self {
+            Self::Tag(tag) => unsafe { Backend::grow(tag, ptr, layout, new_size) },
+            Self::Slot(raw_tag, slot) => unsafe {
+                Self::slot_allocator(raw_tag, slot).realloc(ptr, layout, new_size)
+            },
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn shrink(self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match self {
+            Self::Tag(tag) => unsafe { Backend::shrink(tag, ptr, layout, new_size) },
+            Self::Slot(raw_tag, slot) => unsafe {
+                Self::slot_allocator(raw_tag, slot).realloc(ptr, layout, new_size)
+            },
+        }
+    }
+
+    /// Allocate blocks, returning how many were allocated with their tag
+    unsafe fn alloc_batch(self, layout: Layout, out: &mut [*mut u8]) -> (usize, Backend::Repr) {
+        match self {
+            Self::Tag(tag) => (
+                unsafe { Backend::alloc_batch(tag, layout, out) },
+                tag.into(),
+            ),
+            Self::Slot(raw_tag, slot) => {
+                let generation = slot.enter(out.len());
+                let raw_tag = raw_tag & !1 | generation as u16;
+                let allocator = Self::slot_allocator(raw_tag, slot);
+                let mut allocated = 0;
+                for block in out.iter_mut() {
+                    let ptr = unsafe { allocator.alloc(layout) };
+                    if ptr.is_null() {
+                        break;
+                    }
+                    *block = ptr;
+                    allocated += 1;
+                }
+                slot.leave(generation, out.len() - allocated);
+                (allocated, self.repr(generation))
+            }
+        }
+    }
+
+    unsafe fn dealloc_batch(self, ptrs: &[*mut u8], layout: Layout) {
+        match self {
+            Self::Tag(tag) => unsafe { Backend::dealloc_batch(tag, ptrs, layout) },
+            Self::Slot(..) => {
+                for &ptr in ptrs {
+                    unsafe { self.dealloc(ptr, layout) }
+                }
+            }
+        }
+    }
+
+    /// Record the allocation of `block`, whose hidden tag has been written
+    ///
+    /// Allocators of slots don't have statistics.
+    ///
+    /// # Safety
+    ///
+    /// `block` must point to the hidden tag of a block allocated by this allocator.
+    #[cfg(feature = "stats")]
+    #[inline(always)]
+    unsafe fn record_alloc(self, block: *const u8, size: usize) {
+        if let Self::Tag(tag) = self {
+            Backend::counters(tag).record_alloc(size);
+            #[cfg(feature = "owner")]
+            if let Some(counters) = unsafe { Self::owner_counters(tag, block) } {
+                counters.record_alloc(size);
+            }
+            #[cfg(feature = "profiling")]
+            if let Some(counters) = unsafe { Self::callsite_counters(tag, block) } {
+                counters.record_alloc(size);
+            }
+            #[cfg(feature = "thread-stats")]
+            if let Some(counters) = unsafe { Self::thread_counters(tag, block) } {
+                counters.record_alloc(size);
+            }
+        }
+        #[cfg(not(any(feature = "owner", feature = "profiling", feature = "thread-stats")))]
+        let _ = block;
+    }
+
+    /// # Safety
+    ///
+    /// Same contract as [`Self::record_alloc`].
+    #[cfg(feature = "stats")]
+    #[inline(always)]
+    unsafe fn record_dealloc(self, block: *const u8, size: usize) {
+        if let Self::Tag(tag) = self {
+            Backend::counters(tag).record_dealloc(size);
+            #[cfg(feature = "owner")]
+            if let Some(counters) = unsafe { Self::owner_counters(tag, block) } {
+                counters.record_dealloc(size);
+            }
+            #[cfg(feature = "profiling")]
+            if let Some(counters) = unsafe { Self::callsite_counters(tag, block) } {
+                counters.record_dealloc(size);
+            }
+            #[cfg(feature = "thread-stats")]
+            if let Some(counters) = unsafe { Self::thread_counters(tag, block) } {
+                counters.record_dealloc(size);
+            }
+        }
+        #[cfg(not(any(feature = "owner", feature = "profiling", feature = "thread-stats")))]
+        let _ = block;
+    }
+
+    /// Record the lifetime of `block`, which is being deallocated
+    ///
+    /// Allocators of slots don't have histograms.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`Self::record_alloc`].
+    #[cfg(feature = "lifetimes")]
+    #[inline(always)]
+    unsafe fn record_lifetime(self, block: *const u8) {
+        if let Self::Tag(tag) = self {
+            let age = unsafe { lifetimes::age(block.add(timestamp_offset::<Backend>())) };
+            Backend::lifetimes(tag).record(age);
+        }
+    }
+
+    /// Record the allocation of a block of `layout`, including the hidden tag, in its size class
+    ///
+    /// Allocators of slots and allocators without size classes aren't recorded.
+    #[cfg(feature = "size-classes")]
+    #[inline(always)]
+    fn record_class_alloc(self, layout: Layout) {
+        if let Self::Tag(tag) = self {
+            if let Some(class_size) = Backend::size_class(tag, layout) {
+                size_classes::record_alloc(Backend::raw_tag(tag), layout, class_size);
+            }
+        }
+    }
+
+    /// Same as [`Self::record_class_alloc`] for a deallocation
+    #[cfg(feature = "size-classes")]
+    #[inline(always)]
+    fn record_class_dealloc(self, layout: Layout) {
+        if let Self::Tag(tag) = self {
+            if let Some(class_size) = Backend::size_class(tag, layout) {
+                size_classes::record_dealloc(Backend::raw_tag(tag), layout, class_size);
+            }
+        }
+    }
+
+    /// Statistics of `tag` and the owner of `block`
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`Self::record_alloc`].
+    #[cfg(all(feature = "stats", feature = "owner"))]
+    #[inline(always)]
+    unsafe fn owner_counters(
+        tag: Backend::Tag,
+        block: *const u8,
+    ) -> Option<&'static stats::Counters> {
+        let owner = unsafe { owner::read(block.add(Backend::Repr::SIZE)) };
+        Backend::owner_counters().get_or_insert(Backend::raw_tag(tag), owner)
+    }
+
+    /// Statistics of `tag` and the callsite of `block`
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`Self::record_alloc`].
+    #[cfg(feature = "profiling")]
+    #[inline(always)]
+    unsafe fn callsite_counters(
+        tag: Backend::Tag,
+        block: *const u8,
+    ) -> Option<&'static stats::Counters> {
+        let callsite = unsafe { block.add(callsite_offset::<Backend>()) };
+        unsafe { profiling::counters(Backend::raw_tag(tag), callsite) }
+    }
+
+    /// Statistics of `tag` and the thread that allocated `block`
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`Self::record_alloc`].
+    #[cfg(feature = "thread-stats")]
+    #[inline(always)]
+    unsafe fn thread_counters(
+        tag: Backend::Tag,
+        block: *const u8,
+    ) -> Option<&'static stats::Counters> {
+        let thread = unsafe { block.add(thread_offset::<Backend>()) };
+        unsafe { thread_stats::counters(Backend::raw_tag(tag), thread) }
+    }
+}
+
+/// Convert a raw tag to a tag of `Backend`, panicking if it isn't valid
+#[inline(always)]
+fn tag_from_raw<Backend: MultiAllocatorBackend>(raw_tag: u16) -> Backend::Tag {
+    match Backend::Repr::from_raw(raw_tag).and_then(|tag| Backend::Tag::try_from(tag).ok()) {
+        Some(tag) => tag,
+        None => panic!("{}", InvalidTagError::new(raw_tag)),
+    }
+}
+
+/// Size of the hidden tag put before an allocation with the given layout
+///
+/// Make the tag size at least the same size as the alignment so that we can keep the same
+/// alignment for the data. Both are powers of two, so the larger one is a multiple of the other.
+/// With the `owner`, `accounting`, `profiling`, `lifetimes`, `live`, `thread-stats` and `checksum`
+/// features, the owner, the accounting token, the callsite, the time of the allocation, the node
+/// in the list of live blocks, the thread and the checksum follow the tag and the size is rounded
+/// up to a power of two.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+fn tag_size<Backend: MultiAllocatorBackend>(layout: Layout) -> usize {
+    layout.align().max(min_tag_size::<Backend>())
+}
+
+/// Size of the hidden tag put before an allocation aligned to at most this size
+///
+/// With the `mte` feature, it's at least a granule, so that the block starts on a granule.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+const fn min_tag_size<Backend: MultiAllocatorBackend>() -> usize {
+    let size = (checksum_offset::<Backend>() + CHECKSUM_SIZE).next_power_of_two();
+    #[cfg(feature = "mte")]
+    if size < mte::GRANULE {
+        return mte::GRANULE;
+    }
+    size
+}
+
+/// Layout of the block holding `size` bytes after a hidden tag of `tag_size` bytes
+///
+/// With the `mte` feature, the block is rounded up to granules and aligned to them.
+///
+/// # Safety
+///
+/// Same contract as [`Layout::from_size_align_unchecked`] for the layout of the block.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+unsafe fn block_layout(size: usize, tag_size: usize, align: usize) -> Layout {
+    #[cfg(feature = "mte")]
+    let (size, align) = (size.next_multiple_of(mte::GRANULE), align.max(mte::GRANULE));
+    unsafe { Layout::from_size_align_unchecked(size + tag_size, align) }
+}
+
+/// Raw tag of the block at `ptr` allocated with `layout`
+///
+/// # Safety
+///
+/// `ptr` must be a live block allocated with `layout` by a `MultiAllocator<Backend>`.
+#[cfg(all(
+    any(feature = "proptest", feature = "arbitrary"),
+    not(feature = "single-allocator")
+))]
+unsafe fn block_raw_tag<Backend: MultiAllocatorBackend>(ptr: *const u8, layout: Layout) -> u16 {
+    let tag_size = tag_size::<Backend>(layout);
+    let block = block_start(ptr.cast_mut(), tag_size);
+    #[cfg(feature = "mte")]
+    let block = unsafe { mte::with_memory_tag(block) };
+    #[cfg(feature = "sanitizer")]
+    return unsafe {
+        sanitizer::with_unpoisoned(block, tag_size, || Backend::Repr::read(block).to_raw())
+    };
+    #[cfg(not(feature = "sanitizer"))]
+    unsafe {
+        Backend::Repr::read(block).to_raw()
+    }
+}
+
+/// Size of the owner in the hidden tag, after the tag
+#[cfg(all(feature = "owner", not(feature = "single-allocator")))]
+const OWNER_SIZE: usize = owner::SIZE;
+
+#[cfg(all(not(feature = "owner"), not(feature = "single-allocator")))]
+const OWNER_SIZE: usize = 0;
+
+/// Size of the accounting token in the hidden tag, after the owner
+#[cfg(all(feature = "accounting", not(feature = "single-allocator")))]
+const TOKEN_SIZE: usize = accounting::SIZE;
+
+#[cfg(all(not(feature = "accounting"), not(feature = "single-allocator")))]
+const TOKEN_SIZE: usize = 0;
+
+/// Size of the callsite in the hidden tag, after the accounting token
+#[cfg(all(feature = "profiling", not(feature = "single-allocator")))]
+const CALLSITE_SIZE: usize = profiling::SIZE;
+
+#[cfg(all(not(feature = "profiling"), not(feature = "single-allocator")))]
+const CALLSITE_SIZE: usize = 0;
+
+/// Size of the time of the allocation in the hidden tag, after the callsite
+#[cfg(all(feature = "lifetimes", not(feature = "single-allocator")))]
+const TIMESTAMP_SIZE: usize = lifetimes::SIZE;
+
+#[cfg(all(not(feature = "lifetimes"), not(feature = "single-allocator")))]
+const TIMESTAMP_SIZE: usize = 0;
+
+/// Size of the node in the list of live blocks in the hidden tag, after the time of the
+/// allocation
+#[cfg(all(feature = "live", not(feature = "single-allocator")))]
+const NODE_SIZE: usize = live::SIZE;
+
+#[cfg(all(not(feature = "live"), not(feature = "single-allocator")))]
+const NODE_SIZE: usize = 0;
+
+/// Size of the thread in the hidden tag, after the node in the list of live blocks
+#[cfg(all(feature = "thread-stats", not(feature = "single-allocator")))]
+const THREAD_SIZE: usize = thread_stats::SIZE;
+
+#[cfg(all(not(feature = "thread-stats"), not(feature = "single-allocator")))]
+const THREAD_SIZE: usize = 0;
+
+/// Size of the checksum of the hidden tag, after the thread
+#[cfg(all(feature = "checksum", not(feature = "single-allocator")))]
+const CHECKSUM_SIZE: usize = checksum::SIZE;
+
+#[cfg(all(not(feature = "checksum"), not(feature = "single-allocator")))]
+const CHECKSUM_SIZE: usize = 0;
+
+/// Offset of the accounting token in the hidden tag
+#[cfg(all(feature = "accounting", not(feature = "single-allocator")))]
+#[inline(always)]
+const fn token_offset<Backend: MultiAllocatorBackend>() -> usize {
+    Backend::Repr::SIZE + OWNER_SIZE
+}
+
+/// Offset of the callsite in the hidden tag
+#[cfg(all(feature = "profiling", not(feature = "single-allocator")))]
+#[inline(always)]
+const fn callsite_offset<Backend: MultiAllocatorBackend>() -> usize {
+    Backend::Repr::SIZE + OWNER_SIZE + TOKEN_SIZE
+}
+
+/// Offset of the time of the allocation in the hidden tag
+#[cfg(all(feature = "lifetimes", not(feature = "single-allocator")))]
+#[inline(always)]
+const fn timestamp_offset<Backend: MultiAllocatorBackend>() -> usize {
+    Backend::Repr::SIZE + OWNER_SIZE + TOKEN_SIZE + CALLSITE_SIZE
+}
+
+/// Offset of the node in the list of live blocks in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+const fn node_offset<Backend: MultiAllocatorBackend>() -> usize {
+    Backend::Repr::SIZE + OWNER_SIZE + TOKEN_SIZE + CALLSITE_SIZE + TIMESTAMP_SIZE
+}
+
+/// Offset of the thread in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+const fn thread_offset<Backend: MultiAllocatorBackend>() -> usize {
+    node_offset::<Backend>() + NODE_SIZE
+}
+
+/// Start of the block of the user pointer `ptr`, before its hidden tag of `tag_size` bytes
+///
+/// The block pointer keeps the provenance of `ptr`, which covers the whole block.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+fn block_start(ptr: *mut u8, tag_size: usize) -> *mut u8 {
+    ptr.with_addr(ptr.addr() - tag_size)
+}
+
+/// Offset of the checksum in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+const fn checksum_offset<Backend: MultiAllocatorBackend>() -> usize {
+    thread_offset::<Backend>() + THREAD_SIZE
+}
+
+/// Write the hidden tag of a block of `size` bytes allocated with the allocator identified by
+/// `raw_tag`
+///
+/// With the `accounting` feature, the block is charged to the current accounting token. With the
+/// `live` feature, it's added to the list of live blocks. With the `checksum` feature, the tag and
+/// the size are checksummed.
+///
+/// # Safety
+///
+/// `block` must be valid for writing the hidden tag.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+unsafe fn write_header<Backend: MultiAllocatorBackend>(
+    block: *mut u8,
+    raw_tag: Backend::Repr,
+    size: usize,
+) {
+    unsafe { raw_tag.write(block) };
+    #[cfg(feature = "owner")]
+    unsafe {
+        owner::write(block.add(Backend::Repr::SIZE), owner::current_owner())
+    };
+    #[cfg(feature = "accounting")]
+    unsafe {
+        accounting::charge(block.add(token_offset::<Backend>()), size)
+    };
+    #[cfg(not(any(feature = "accounting", feature = "live", feature = "checksum")))]
+    let _ = size;
+    #[cfg(feature = "profiling")]
+    unsafe {
+        profiling::write(
+            block.add(callsite_offset::<Backend>()),
+            profiling::current_index(),
+        )
+    };
+    #[cfg(feature = "lifetimes")]
+    unsafe {
+        lifetimes::write(block.add(timestamp_offset::<Backend>()))
+    };
+    #[cfg(feature = "live")]
+    unsafe {
+        Backend::live_list().link(block.add(node_offset::<Backend>()), size)
+    };
+    #[cfg(feature = "thread-stats")]
+    unsafe {
+        thread_stats::write(
+            block.add(thread_offset::<Backend>()),
+            thread_stats::current_index(),
+        )
+    };
+    #[cfg(feature = "checksum")]
+    unsafe {
+        checksum::write(
+            block.add(checksum_offset::<Backend>()),
+            raw_tag.to_raw(),
+            size,
+        )
+    };
+}
+
+/// Allocator that is constructed on first use
+///
+/// Useful for allocators that need runtime construction and can't be put in a `static` directly.
+/// `init` runs once, on the first allocation, with the default allocator set so that any
+/// allocation it performs doesn't recurse into the allocator being initialized. Because of this,
+/// a `LazyAllocator` must not be the default allocator itself.
+///
+/// # Example
+///
+/// ```rust
+/// use okaoka::LazyAllocator;
+/// use std::alloc::System;
+///
+/// fn make_pool() -> System {
+///     let _config = String::from("reading the config allocates");
+///     System
+/// }
+///
+/// static POOL: LazyAllocator<System> = LazyAllocator::new(make_pool);
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Pool => POOL,
+/// }
+///
+/// fn main() {
+///     GlobalAllocator::with(AllocatorTag::Pool, || {
+///         let _x = Box::new(10); // `make_pool` runs here
+///     });
+/// }
+/// ```
+#[cfg(feature = "std")]
+pub struct LazyAllocator<A, F = fn() -> A> {
+    allocator: OnceLock<A>,
+    init: F,
+}
+
+#[cfg(feature = "std")]
+impl<A, F> LazyAllocator<A, F>
+where
+    F: Fn() -> A,
+{
+    pub const fn new(init: F) -> Self {
+        Self {
+            allocator: OnceLock::new(),
+            init,
+        }
+    }
+
+    /// Get the underlying allocator, initializing it if needed
+    pub fn get(&self) -> &A {
+        self.allocator.get_or_init(|| {
+            let mut allocator = None;
+            with_allocator_tag(None, || allocator = Some((self.init)()));
+            allocator.unwrap()
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+unsafe impl<A, F> GlobalAlloc for LazyAllocator<A, F>
+where
+    A: GlobalAlloc,
+    F: Fn() -> A,
+{
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.get().alloc(layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.get().dealloc(ptr, layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { self.get().realloc(ptr, layout, new_size) }
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+}
+
+/// Integer type used to store tags, either `u8` or `u16`
+///
+/// The hidden tag put before every allocation takes at least this many bytes.
+pub trait TagRepr: Copy + sealed::Sealed {
+    /// Size of the tag in bytes
+    const SIZE: usize;
+
+    /// Widen the tag to the representation used to store the current tag of a thread
+    fn to_raw(self) -> u16;
+
+    /// Narrow a raw tag, returning `None` if it doesn't fit
+    fn from_raw(raw_tag: u16) -> Option<Self>;
+
+    /// Read a tag from `ptr`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reading `SIZE` bytes. It doesn't need to be aligned.
+    unsafe fn read(ptr: *const u8) -> Self;
+
+    /// Write the tag to `ptr`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writing `SIZE` bytes. It doesn't need to be aligned.
+    unsafe fn write(self, ptr: *mut u8);
+}
+
+macro_rules! impl_tag_repr {
+    ($($repr:ident),+) => {
+        $(
+            impl TagRepr for $repr {
+                const SIZE: usize = core::mem::size_of::<$repr>();
+
+                #[inline(always)]
+                fn to_raw(self) -> u16 {
+                    self.into()
+                }
+
+                #[inline(always)]
+                fn from_raw(raw_tag: u16) -> Option<Self> {
+                    raw_tag.try_into().ok()
+                }
+
+                #[inline(always)]
+                unsafe fn read(ptr: *const u8) -> Self {
+                    unsafe { core::ptr::read_unaligned(ptr.cast()) }
+                }
+
+                #[inline(always)]
+                unsafe fn write(self, ptr: *mut u8) {
+                    unsafe { core::ptr::write_unaligned(ptr.cast(), self) }
+                }
+            }
+        )+
+    };
+}
+
+impl_tag_repr!(u8, u16);
+
+pub trait MultiAllocatorBackend {
+    /// Integer type used to store tags
+    type Repr: TagRepr;
+
+    type Tag: Copy + Into<Self::Repr> + TryFrom<Self::Repr> + 'static;
+
+    /// Every tag of the backend, in declaration order
+    const TAGS: &'static [Self::Tag];
+
+    /// Tag of the allocator used by threads that haven't selected one
+    const DEFAULT_TAG: Self::Tag;
+
+    /// Tag of the allocator used by threads that haven't selected one, resolved at runtime
+    ///
+    /// Defaults to [`Self::DEFAULT_TAG`].
+    #[inline(always)]
+    fn default_tag() -> Self::Tag {
+        Self::DEFAULT_TAG
+    }
+
+    /// Name of the allocator identified by `tag`
+    fn tag_name(tag: Self::Tag) -> &'static str;
+
+    /// Number of allocators in the backend
+    fn tag_count() -> usize {
+        Self::TAGS.len()
+    }
+
+    /// Position of `tag` in [`Self::TAGS`]
+    fn tag_index(tag: Self::Tag) -> usize;
+
+    /// Raw value of `tag`, as taken by [`with_allocator`] and [`AllocatorGuard::new`]
+    #[inline(always)]
+    fn raw_tag(tag: Self::Tag) -> u16 {
+        let raw_tag: Self::Repr = tag.into();
+        raw_tag.to_raw()
+    }
+
+    /// Statistics of the allocator identified by `tag`
+    #[cfg(feature = "stats")]
+    fn counters(tag: Self::Tag) -> &'static stats::Counters;
+
+    /// Statistics of the pairs of tag and owner
+    #[cfg(all(feature = "stats", feature = "owner"))]
+    fn owner_counters() -> &'static stats::OwnerCounters;
+
+    /// Lifetime histogram of the allocator identified by `tag`
+    #[cfg(feature = "lifetimes")]
+    fn lifetimes(tag: Self::Tag) -> &'static lifetimes::Histogram;
+
+    /// List of the live blocks of the backend
+    #[cfg(feature = "live")]
+    fn live_list() -> &'static live::LiveList;
+
+    /// Whether the allocator identified by `tag` is [`Jemalloc`](jemallocator::Jemalloc)
+    #[cfg(feature = "jemalloc-stats")]
+    fn is_jemalloc(tag: Self::Tag) -> bool;
+
+    /// Allocator identified by `tag` if it's a [`mimalloc::MiHeap`]
+    #[cfg(feature = "mimalloc-heaps")]
+    fn mimalloc_heap(tag: Self::Tag) -> Option<&'static mimalloc::MiHeap>;
+
+    /// Size of the block used by the allocator identified by `tag` for a request of `layout`,
+    /// `None` if it doesn't implement [`size_classes::SizeClasses`]
+    #[cfg(feature = "size-classes")]
+    fn size_class(tag: Self::Tag, layout: Layout) -> Option<usize>;
+
+    /// Frame state of the allocator identified by `tag`
+    #[cfg(feature = "frame")]
+    fn frame(tag: Self::Tag) -> &'static frame::Frame;
+
+    /// Reset the allocator identified by `tag` if it's a [`frame::FrameAlloc`], returning the
+    /// bytes it used
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`frame::FrameAlloc::reset`].
+    #[cfg(feature = "frame")]
+    unsafe fn reset_frame(tag: Self::Tag) -> Option<usize>;
+
+    /// Tag of the allocator that serves `layout` whatever the current allocator, if any
+    ///
+    /// Defaults to `None`. Backends created with the `route_aligned = (N, Tag)` option of
+    /// [`create_multi_allocator_backend`] send the blocks aligned to more than `N` bytes to `Tag`.
+    ///
+    /// ```rust
+    /// use std::alloc::{Layout, System};
+    ///
+    /// use okaoka::MultiAllocatorBackend;
+    ///
+    /// okaoka::set_multi_global_allocator! {
+    ///     GlobalAllocator,
+    ///     AllocatorTag,
+    ///     route_aligned = (64, Aligned),
+    ///     System => System,
+    ///     Aligned => jemallocator::Jemalloc,
+    /// }
+    ///
+    /// fn main() {
+    ///     let page = Layout::from_size_align(4096, 4096).unwrap();
+    ///     assert_eq!(GlobalAllocator::aligned_route(page), Some(AllocatorTag::Aligned));
+    ///     assert_eq!(GlobalAllocator::aligned_route(Layout::new::<u64>()), None);
+    ///
+    ///     // Allocated with jemalloc
+    ///     let ptr = unsafe { std::alloc::alloc(page) };
+    ///     assert_eq!(ptr as usize % 4096, 0);
+    ///     unsafe { std::alloc::dealloc(ptr, page) };
+    /// }
+    /// ```
+    #[inline(always)]
+    fn aligned_route(layout: Layout) -> Option<Self::Tag> {
+        let _ = layout;
+        None
+    }
+
+    /// Slot of the registry of allocators added at runtime identified by `raw_tag`
+    ///
+    /// Raw tags of slots must not belong to any tag of [`Self::TAGS`]. Defaults to `None`, as
+    /// backends don't have a registry unless created with the `dynamic = N` option of
+    /// [`create_multi_allocator_backend`]. See the `registry` module.
+    #[inline(always)]
+    fn dynamic_slot(raw_tag: u16) -> Option<&'static registry::Slot> {
+        let _ = raw_tag;
+        None
+    }
+
+    /// Allocate memory with the allocator identified by `tag`
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::alloc`].
+    unsafe fn alloc(tag: Self::Tag, layout: Layout) -> *mut u8;
+
+    /// Deallocate memory with the allocator identified by `tag`
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::dealloc`]. `ptr` must have been allocated by the allocator
+    /// identified by `tag`.
+    unsafe fn dealloc(tag: Self::Tag, ptr: *mut u8, layout: Layout);
+
+    /// Allocate `out.len()` blocks of memory with the same layout with the allocator identified
+    /// by `tag`
+    ///
+    /// Returns how many blocks were allocated, their pointers are stored at the start of `out`.
+    /// Allocation stops at the first failure. Defaults to calling [`Self::alloc`] for each
+    /// block, backends that can allocate several blocks at once, e.g. pools that take a lock,
+    /// should override it.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::alloc`].
+    unsafe fn alloc_batch(tag: Self::Tag, layout: Layout, out: &mut [*mut u8]) -> usize {
+        for (allocated, slot) in out.iter_mut().enumerate() {
+            let ptr = unsafe { Self::alloc(tag, layout) };
+            if ptr.is_null() {
+                return allocated;
+            }
+            *slot = ptr;
+        }
+        out.len()
+    }
+
+    /// Deallocate blocks of memory with the same layout with the allocator identified by `tag`
+    ///
+    /// Defaults to calling [`Self::dealloc`] for each block.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`Self::dealloc`] for every pointer of `ptrs`.
+    unsafe fn dealloc_batch(tag: Self::Tag, ptrs: &[*mut u8], layout: Layout) {
+        for &ptr in ptrs {
+            unsafe { Self::dealloc(tag, ptr, layout) }
+        }
+    }
+
+    /// Grow a block of memory allocated by the allocator identified by `tag` to `new_size` bytes
+    ///
+    /// Like `Allocator::grow`, the contents are kept and the block may be moved. Defaults to
+    /// allocating a new block, copying the contents and deallocating the old block, backends
+    /// that can grow blocks in place should override it.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::realloc`], with `new_size` not smaller than
+    /// `layout.size()`. `ptr` must have been allocated by the allocator identified by `tag`.
+    unsafe fn grow(tag: Self::Tag, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { realloc_by_copy::<Self>(tag, ptr, layout, new_size) }
+    }
+
+    /// Shrink a block of memory allocated by the allocator identified by `tag` to `new_size`
+    /// bytes
+    ///
+    /// Like `Allocator::shrink`, the contents up to `new_size` are kept and the block may be
+    /// moved. Defaults to allocating a new block, copying the contents and deallocating the old
+    /// block, backends that can shrink blocks in place should override it.
+    ///
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::realloc`], with `new_size` not larger than
+    /// `layout.size()`. `ptr` must have been allocated by the allocator identified by `tag`.
+    unsafe fn shrink(tag: Self::Tag, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { realloc_by_copy::<Self>(tag, ptr, layout, new_size) }
+    }
+}
+
+/// Move a block of memory to a new block of `new_size` bytes of the same allocator
+///
+/// # Safety
+///
+/// Same contract as [`MultiAllocatorBackend::grow`].
+unsafe fn realloc_by_copy<Backend: MultiAllocatorBackend + ?Sized>(
+    tag: Backend::Tag,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+    let new_ptr = unsafe { Backend::alloc(tag, new_layout) };
+    if !new_ptr.is_null() {
+        unsafe {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            Backend::dealloc(tag, ptr, layout);
+        }
+    }
+    new_ptr
+}
+
+/// Error returned when converting a raw tag that doesn't belong to any allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTagError {
+    raw_tag: u16,
+}
+
+impl InvalidTagError {
+    #[doc(hidden)]
+    pub fn new(raw_tag: u16) -> Self {
+        Self { raw_tag }
+    }
+
+    /// The raw tag that failed to convert
+    pub fn raw_tag(&self) -> u16 {
+        self.raw_tag
+    }
+}
+
+impl core::fmt::Display for InvalidTagError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "invalid allocator tag: {}", self.raw_tag)
+    }
+}
+
+impl core::error::Error for InvalidTagError {}
+
+/// Default allocator selected by an environment variable
+///
+/// The variable is read once, on the first allocation that uses the default allocator, which
+/// usually happens before `main`. If it isn't set or it doesn't contain the name of a tag,
+/// [`MultiAllocatorBackend::DEFAULT_TAG`] is used. Allocations made while the variable is being
+/// read use [`MultiAllocatorBackend::DEFAULT_TAG`] as well.
+///
+/// This is used by the `default_from_env` option of [`create_multi_allocator_backend`].
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     default_from_env = "OKAOKA_ALLOCATOR",
+///     System => System,
+///     Jemalloc => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     // `OKAOKA_ALLOCATOR=Jemalloc ./app` makes jemalloc the default allocator
+///     let _x = Box::new(10);
+/// }
+/// ```
+#[cfg(feature = "std")]
+pub struct EnvDefault {
+    state: AtomicU8,
+    tag: AtomicU16,
+}
+
+#[cfg(feature = "std")]
+impl EnvDefault {
+    const UNRESOLVED: u8 = 0;
+    const RESOLVING: u8 = 1;
+    const RESOLVED: u8 = 2;
+
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(Self::UNRESOLVED),
+            tag: AtomicU16::new(0),
+        }
+    }
+
+    /// Get the default tag of `Backend`, reading `var` if it hasn't been read yet
+    pub fn get<Backend: MultiAllocatorBackend>(&self, var: &str) -> Backend::Tag {
+        match self.state.load(Ordering::Acquire) {
+            Self::RESOLVED => {
+                return tag_from_raw::<Backend>(self.tag.load(Ordering::Relaxed));
+            }
+            Self::RESOLVING => return Backend::DEFAULT_TAG,
+            _ => {}
+        }
+        if self
+            .state
+            .compare_exchange(
+                Self::UNRESOLVED,
+                Self::RESOLVING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            // Another thread is reading the variable
+            return Backend::DEFAULT_TAG;
+        }
+
+        // Reading the variable allocates, those allocations see `RESOLVING` and use the default
+        // tag instead of recursing here.
+        let tag = std::env::var(var)
+            .ok()
+            .and_then(|name| {
+                Backend::TAGS
+                    .iter()
+                    .copied()
+                    .find(|&tag| Backend::tag_name(tag) == name)
+            })
+            .unwrap_or(Backend::DEFAULT_TAG);
+        self.tag.store(Backend::raw_tag(tag), Ordering::Relaxed);
+        self.state.store(Self::RESOLVED, Ordering::Release);
+        tag
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for EnvDefault {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Error returned when parsing a tag from a name that doesn't match any allocator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseTagError {
+    name: String,
+}
+
+impl ParseTagError {
+    #[doc(hidden)]
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+        }
+    }
+}
+
+impl core::fmt::Display for ParseTagError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "unknown allocator tag `{}`", self.name)
+    }
+}
+
+impl core::error::Error for ParseTagError {}
+
+#[cfg(feature = "stats")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_stats {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "stats"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_stats {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(all(feature = "stats", feature = "owner"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_owner_stats {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(all(feature = "stats", feature = "owner")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_owner_stats {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "allocator-api")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_allocator_api {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "allocator-api"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_allocator_api {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "lifetimes")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_lifetimes {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "lifetimes"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_lifetimes {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "jemalloc-stats")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_jemalloc_stats {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "jemalloc-stats"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_jemalloc_stats {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "size-classes")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_size_classes {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "size-classes"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_size_classes {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "thread-stats")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_thread_stats {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "thread-stats"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_thread_stats {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "live")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_live {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "live"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_live {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "testing")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_testing {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "testing"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_testing {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "debugger")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_debugger {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "debugger"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_debugger {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "frame")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_frame {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "frame"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_frame {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "bumpalo")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_bumpalo {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "bumpalo"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_bumpalo {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "http")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_http {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "http"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_http {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "mimalloc-heaps")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_mimalloc_heaps {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "mimalloc-heaps"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_mimalloc_heaps {
+    ($($tokens:tt)*) => {};
+}
+
+#[cfg(feature = "shared-core")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_shared_core {
+    ($($tokens:tt)*) => {
+        $($tokens)*
+    };
+}
+
+#[cfg(not(feature = "shared-core"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_shared_core {
+    ($($tokens:tt)*) => {};
+}
+
+/// Create a [`MultiAllocatorBackend`] that dispatches to the given allocators
+///
+/// The generated code refers to this crate through `$crate`, so it keeps working when the crate is
+/// renamed in `Cargo.toml` or re-exported by another crate.
+///
+/// Each allocator can be any expression that evaluates to a [`GlobalAlloc`] value, e.g. a unit
+/// struct or a path to a `static`. The expression is evaluated on every allocation, so stateful
+/// allocators should be declared with `Name => static Type = init`, which creates a `static` of
+/// type `Type`, including any generic arguments, initialized with the constant expression `init`.
+/// Allocators that need runtime construction can be wrapped in a [`LazyAllocator`] static.
+///
+/// ```rust
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// /// Allocator that rounds every allocation up to `N` bytes
+/// struct Rounding<const N: usize, A>(A);
+///
+/// unsafe impl<const N: usize, A: GlobalAlloc> GlobalAlloc for Rounding<N, A> {
+///     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+///         let size = layout.size().next_multiple_of(N);
+///         self.0.alloc(Layout::from_size_align_unchecked(size, layout.align()))
+///     }
+///
+///     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+///         let size = layout.size().next_multiple_of(N);
+///         self.0.dealloc(ptr, Layout::from_size_align_unchecked(size, layout.align()))
+///     }
+/// }
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Rounded => static Rounding<64, System> = Rounding(System),
+/// }
+///
+/// fn main() {
+///     GlobalAllocator::with(AllocatorTag::Rounded, || {
+///         let _x = Box::new(10);
+///     });
+/// }
+/// ```
+///
+/// Entries can be conditionally compiled with `#[cfg(...)]` attributes. Tags are numbered in
+/// declaration order among the enabled entries only.
+///
+/// Tags can be given explicit discriminants with `Name = 3 => Allocator` so that their value
+/// doesn't change when entries are added or removed.
+///
+/// An allocator that is only available in some builds can be declared with
+/// `Name(cfg: predicate, fallback: Other) => Allocator`. Unlike `#[cfg(...)]` on the entry, the tag
+/// always exists, so tags are numbered the same in every build, but when `predicate` doesn't hold
+/// its allocations go to the allocator of the `Other` tag.
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Jemalloc(cfg: feature = "jemalloc", fallback: System) => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     // Allocated with `System` unless built with the `jemalloc` feature
+///     GlobalAllocator::with(AllocatorTag::Jemalloc, || {
+///         let _x = Box::new(10);
+///     });
+/// }
+/// ```
+///
+/// With the `slab` feature, an entry declared with `Name(slab: [32, 64, 128]) => Allocator` serves
+/// the blocks of the declared sizes from slabs carved from chunks of `Allocator`, and forwards the
+/// other blocks to `Allocator`, or rejects them with `Name(slab: [...], others: Reject)`. See the
+/// `slab` module.
+///
+/// With the `randomized` feature, an entry declared with `Name(randomized) => Allocator` places
+/// the blocks of its tag at random offsets in blocks of `Allocator` and frees them in a random
+/// order, with gaps of up to 256 bytes or the bytes given with
+/// `Name(randomized, max_gap: 4096) => Allocator`. See the `randomized` module.
+///
+/// The first entry is the default allocator of every thread. Another entry can be chosen by
+/// prefixing it with `default`, e.g. `default Std => System`. When entries are gated with
+/// `#[cfg(...)]`, each configuration can mark its own default, and the configurations where no
+/// entry marked as `default` is enabled use the first enabled entry.
+///
+/// Options can be given before the entries:
+///
+/// - `default_from_env = "VAR"`: the default allocator is the one named by the environment
+///   variable `VAR`, if it's set to the name of a tag. See [`EnvDefault`].
+/// - `route_aligned = (N, Tag)`: send the blocks aligned to more than `N` bytes, like SIMD or
+///   page-aligned buffers, to the allocator of `Tag`, whatever the current allocator, e.g. an
+///   allocator whose blocks are naturally aligned like `buddy::Buddy`. The hidden tag of such a
+///   block still takes as many bytes as its alignment.
+/// - `repr = u16`: store tags as `u16` instead of `u8`, for backends with more than 256
+///   allocators or discriminants that don't fit in a byte. See [`TagRepr`].
+/// - `dispatch = table`: dispatch allocations through arrays of function pointers indexed by tag
+///   instead of a `match`. This can be faster for backends with many allocators, the `dispatch`
+///   benchmark compares both.
+/// - `dynamic = N`: give the backend a [`registry::Registry`] of `N` allocators added at runtime,
+///   which take the `2 * N` highest raw tags of the representation. No tag of the entries may
+///   use them. The backend gets `register(allocator)`, `unregister(tag)`, `swap(tag, allocator)`
+///   and `reclaim(tag)`, see the `registry` module.
+/// - `collections`: with the `allocator-api` feature, give each entry a `<Tag>Handle` alias of
+///   `ConstTagHandle`, and the `<Tag>Vec<T>`, `<Tag>VecDeque<T>` and `<Tag>Box<T>` aliases of
+///   the collections that use it, e.g. `ArenaVec<T>` for `Arena`. See `NewIn` for their
+///   constructors. Ignored without the feature.
+/// - `static_name = NAME`: name of the `#[global_allocator]` static added by
+///   [`set_multi_global_allocator`]. Ignored by this macro.
+///
+/// [`MultiAllocatorBackend::grow`] and [`MultiAllocatorBackend::shrink`] are forwarded to the
+/// `realloc` method of the allocators, so allocators that resize blocks in place keep doing so.
+///
+/// The backend is usable on its own, without installing it as the global allocator with
+/// [`set_multi_global_allocator`]. It gets:
+///
+/// - `with(tag, closure)`, like [`with_allocator`] but taking a tag of the backend, and
+///   `with_const::<TAG>(closure)`, like [`with_allocator_const`] but checking `TAG` at compile
+///   time.
+/// - `drop_in(tag, value)`, like [`drop_in`], `clone_into(tag, &value)`, like [`clone_into`], and
+///   `tagged(tag, value)`, like [`Tagged::new`], but taking a tag of the backend.
+/// - `guard(tag)`, a constructor for [`AllocatorGuard`], and one `<tag>_guard()` shorthand per
+///   entry, e.g. `arena_guard()` for `Arena`.
+/// - `push(tag)`, like [`push_allocator`] but taking a tag of the backend.
+/// - `handle(tag)`, a constructor for [`TagHandle`], and one `<tag>_handle()` shorthand per entry.
+///
+/// With the `stats` feature, the backend gets `stats(tag)`, `all_stats()` and one
+/// `<tag>_stats()` accessor per entry, e.g. `arena_stats()` for `Arena`. See the `stats` module.
+/// With the `owner` feature as well, it gets `owner_stats(tag, owner)` and `all_owner_stats()`.
+/// With the `thread-stats` feature, it gets `all_thread_stats()`, see the `thread_stats` module.
+/// With the `jemalloc-stats` feature, it gets `jemalloc_arena_stats(tag)`, see the `jemalloc`
+/// module. With the `mimalloc-heaps` feature, it gets `mimalloc_heap(tag)`, see the `mimalloc`
+/// module. With the `size-classes` feature, it gets `size_classes(tag)`, see the `size_classes`
+/// module.
+///
+/// With the `lifetimes` feature, the backend gets `lifetimes(tag)`, the histogram of the lifetimes
+/// of the blocks deallocated by the allocator. See the `lifetimes` module.
+///
+/// With the `live` feature, the backend gets `largest_live(n)`, the largest blocks that haven't
+/// been deallocated yet. See the `live` module.
+///
+/// With the `testing` feature, the backend gets `check_balanced(tag, closure)`, which panics if
+/// the closure leaks memory in the allocator. See the `testing` module.
+///
+/// With the `frame` feature, the backend gets `begin_frame(tag)`, `end_frame(tag)`,
+/// `set_frame_budget(tag, budget)` and `frame_history(tag)`, which reset the allocators that
+/// implement `frame::FrameAlloc` at the end of each frame. See the `frame` module.
+///
+/// With the `bumpalo` feature, the backend gets `with_arena_scope(tag, closure)`, which also
+/// gives the closure a `bumpalo::Bump` allocating with the tag. See the `bump` module.
+///
+/// Attributes, including doc comments, placed before the backend or tag enum names are forwarded
+/// to the generated items.
+///
+/// The tag enum implements `Debug`, `PartialEq`, `Eq` and `Hash`, as well as `Display` and
+/// `FromStr` using the names of the variants. Raw tags are converted back with `TryFrom`. The
+/// backend lists every tag in [`MultiAllocatorBackend::TAGS`].
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// okaoka::set_multi_global_allocator! {
+///     /// Allocator facade of the application
+///     GlobalAllocator,
+///     /// Allocators available to the application
+///     #[derive(PartialOrd, Ord)]
+///     AllocatorTag,
+///     System = 0 => System,
+///     #[cfg(unix)]
+///     default Fast = 4 => jemallocator::Jemalloc,
+///     #[cfg(not(unix))]
+///     Fast = 4 => System,
+/// }
+///
+/// fn main() {
+///     use okaoka::MultiAllocatorBackend;
+///
+///     for tag in GlobalAllocator::TAGS {
+///         println!("{}", GlobalAllocator::tag_name(*tag));
+///     }
+///     assert_eq!(GlobalAllocator::tag_count(), 2);
+///
+///     let tag: AllocatorTag = "Fast".parse().unwrap();
+///     assert_eq!(tag.to_string(), "Fast");
+///     GlobalAllocator::with(tag, || {
+///         let _x = Box::new(10);
+///     });
+/// }
+/// ```
+#[macro_export]
+macro_rules! create_multi_allocator_backend {
+    // Every entry has been parsed, generate the backend
+    (
+        @parse
+        {
+            { $(#[$name_meta:meta])* $name:ident, $(#[$enum_meta:meta])* $enum_name:ident }
+            $repr:ident
+        }
+        [$({ [$($cfg:meta),*] $tag_name:ident [$($discriminant:literal)?] $allocator:expr })+]
+        $defaults:tt
+        $options:tt
+    ) => {
+        $(#[$enum_meta])*
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        #[repr($repr)]
+        enum $enum_name {
+            $($(#[cfg($cfg)])* $tag_name $(= $discriminant)?,)+
+        }
+
+        impl $enum_name {
+            /// Number of tags
+            const COUNT: usize = <$name as $crate::MultiAllocatorBackend>::TAGS.len();
+        }
+
+        impl TryFrom<$repr> for $enum_name {
+            type Error = $crate::InvalidTagError;
+
+            #[allow(non_upper_case_globals)]
+            fn try_from(raw_tag: $repr) -> Result<Self, Self::Error> {
+                $($(#[cfg($cfg)])* const $tag_name: $repr = $enum_name::$tag_name as $repr;)+
+                match raw_tag {
+                    $($(#[cfg($cfg)])* $tag_name => Ok($enum_name::$tag_name),)+
+                    _ => Err($crate::InvalidTagError::new(raw_tag.into())),
+                }
+            }
+        }
+
+        impl From<$enum_name> for $repr {
+            fn from(tag: $enum_name) -> Self {
+                tag as $repr
+            }
+        }
+
+        impl core::fmt::Display for $enum_name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.write_str(<$name as $crate::MultiAllocatorBackend>::tag_name(*self))
+            }
+        }
+
+        impl core::str::FromStr for $enum_name {
+            type Err = $crate::ParseTagError;
+
+            fn from_str(name: &str) -> Result<Self, Self::Err> {
+                match name {
+                    $($(#[cfg($cfg)])* stringify!($tag_name) => Ok($enum_name::$tag_name),)+
+                    _ => Err($crate::ParseTagError::new(name)),
+                }
+            }
+        }
+
+        $(#[$name_meta])*
+        struct $name;
+
+        impl $crate::MultiAllocatorBackend for $name {
+            type Repr = $repr;
+
+            type Tag = $enum_name;
+
+            const TAGS: &'static [Self::Tag] = &[$($(#[cfg($cfg)])* $enum_name::$tag_name),+];
+
+            $crate::create_multi_allocator_backend!(@default $enum_name $defaults);
+
+            $crate::create_multi_allocator_backend!(@default_from_env $options);
+            $crate::create_multi_allocator_backend!(@aligned_route $enum_name $options);
+
+            $crate::create_multi_allocator_backend!(@dynamic_slot $name $options);
+
+            fn tag_count() -> usize {
+                $enum_name::COUNT
+            }
+
+            fn tag_name(tag: Self::Tag) -> &'static str {
+                match tag {
+                    $($(#[cfg($cfg)])* $enum_name::$tag_name => stringify!($tag_name),)+
+                }
+            }
+
+            #[inline(always)]
+            fn tag_index(tag: Self::Tag) -> usize {
+                // Mirrors the tag enum without explicit discriminants
+                #[allow(non_camel_case_types, clippy::enum_variant_names)]
+                enum Index {
+                    $($(#[cfg($cfg)])* $tag_name,)+
+                }
+
+                match tag {
+                    $($(#[cfg($cfg)])* $enum_name::$tag_name => Index::$tag_name as usize,)+
+                }
+            }
+
+            $crate::__if_stats! {
+                #[inline(always)]
+                fn counters(tag: Self::Tag) -> &'static $crate::stats::Counters {
+                    $crate::__static! {
+                        COUNTERS: [$crate::stats::Counters; $enum_name::COUNT] =
+                            each $crate::stats::Counters::new()
+                    }
+                    &COUNTERS[<Self as $crate::MultiAllocatorBackend>::tag_index(tag)]
+                }
+            }
+
+            $crate::__if_owner_stats! {
+                fn owner_counters() -> &'static $crate::stats::OwnerCounters {
+                    static OWNER_COUNTERS: $crate::stats::OwnerCounters =
+                        $crate::stats::OwnerCounters::new();
+                    &OWNER_COUNTERS
+                }
+            }
+
+            $crate::__if_lifetimes! {
+                fn lifetimes(tag: Self::Tag) -> &'static $crate::lifetimes::Histogram {
+                    static HISTOGRAMS: [$crate::lifetimes::Histogram; $enum_name::COUNT] =
+                        [const { $crate::lifetimes::Histogram::new() }; $enum_name::COUNT];
+                    &HISTOGRAMS[<Self as $crate::MultiAllocatorBackend>::tag_index(tag)]
+                }
+            }
+
+            $crate::__if_live! {
+                fn live_list() -> &'static $crate::live::LiveList {
+                    static LIVE_LIST: $crate::live::LiveList = $crate::live::LiveList::new();
+                    &LIVE_LIST
+                }
+            }
+
+            $crate::__if_jemalloc_stats! {
+                fn is_jemalloc(tag: Self::Tag) -> bool {
+                    #[allow(unused_imports)]
+                    use $crate::jemalloc::dispatch::{ViaJemalloc as _, ViaOther as _};
+                    match tag {
+                        $(
+                            $(#[cfg($cfg)])*
+                            $enum_name::$tag_name => (&$allocator).is_jemalloc(),
+                        )+
+                    }
+                }
+            }
+
+            $crate::__if_mimalloc_heaps! {
+                fn mimalloc_heap(tag: Self::Tag) -> Option<&'static $crate::mimalloc::MiHeap> {
+                    #[allow(unused_imports)]
+                    use $crate::mimalloc::dispatch::{ViaMiHeap as _, ViaOther as _};
+                    match tag {
+                        $(
+                            $(#[cfg($cfg)])*
+                            $enum_name::$tag_name => (&$allocator).mimalloc_heap(),
+                        )+
+                    }
+                }
+            }
+
+            $crate::__if_size_classes! {
+                #[inline(always)]
+                fn size_class(tag: Self::Tag, layout: core::alloc::Layout) -> Option<usize> {
+                    #[allow(unused_imports)]
+                    use $crate::size_classes::dispatch::{ViaOther as _, ViaSizeClasses as _};
+                    match tag {
+                        $(
+                            $(#[cfg($cfg)])*
+                            $enum_name::$tag_name => (&$allocator).size_class(layout),
+                        )+
+                    }
+                }
+            }
+
+            $crate::__if_frame! {
+                fn frame(tag: Self::Tag) -> &'static $crate::frame::Frame {
+                    static FRAMES: [$crate::frame::Frame; $enum_name::COUNT] =
+                        [const { $crate::frame::Frame::new() }; $enum_name::COUNT];
+                    &FRAMES[<Self as $crate::MultiAllocatorBackend>::tag_index(tag)]
+                }
+
+                unsafe fn reset_frame(tag: Self::Tag) -> Option<usize> {
+                    #[allow(unused_imports)]
+                    use $crate::frame::dispatch::{ViaFrameAlloc as _, ViaGlobalAlloc as _};
+                    match tag {
+                        $(
+                            $(#[cfg($cfg)])*
+                            $enum_name::$tag_name => unsafe { (&$allocator).reset_frame() },
+                        )+
+                    }
+                }
+            }
+
+            #[inline(always)]
+            unsafe fn alloc(tag: Self::Tag, layout: core::alloc::Layout) -> *mut u8 {
+                $crate::create_multi_allocator_backend!(
+                    @dispatch_alloc $options $enum_name tag layout
+                    [$({ [$($cfg),*] $tag_name $allocator })+]
+                )
+            }
+
+            #[inline(always)]
+            unsafe fn dealloc(tag: Self::Tag, ptr: *mut u8, layout: core::alloc::Layout) {
+                $crate::create_multi_allocator_backend!(
+                    @dispatch_dealloc $options $enum_name tag ptr layout
+                    [$({ [$($cfg),*] $tag_name $allocator })+]
+                )
+            }
+
+            #[inline(always)]
+            unsafe fn grow(
+                tag: Self::Tag,
+                ptr: *mut u8,
+                layout: core::alloc::Layout,
+                new_size: usize,
+            ) -> *mut u8 {
+                $crate::create_multi_allocator_backend!(
+                    @dispatch_realloc $options $enum_name tag ptr layout new_size
+                    [$({ [$($cfg),*] $tag_name $allocator })+]
+                )
+            }
+
+            #[inline(always)]
+            unsafe fn shrink(
+                tag: Self::Tag,
+                ptr: *mut u8,
+                layout: core::alloc::Layout,
+                new_size: usize,
+            ) -> *mut u8 {
+                $crate::create_multi_allocator_backend!(
+                    @dispatch_realloc $options $enum_name tag ptr layout new_size
+                    [$({ [$($cfg),*] $tag_name $allocator })+]
+                )
+            }
+        }
+
+        $crate::paste::paste! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Set the allocator identified by `tag` inside the closure, restoring the
+                /// previous allocator after returning
+                #[track_caller]
+                pub fn with(tag: $enum_name, closure: impl FnMut()) {
+                    use $crate::MultiAllocatorBackend;
+                    $crate::with_allocator(<Self as MultiAllocatorBackend>::raw_tag(tag), closure);
+                }
+
+                /// Like `with`, with the tag known at compile time
+                ///
+                /// `TAG` is the raw value of a tag, e.g. `{ Tag::Arena as u16 }`, and is checked
+                /// at compile time.
+                #[inline(always)]
+                #[track_caller]
+                pub fn with_const<const TAG: u16>(closure: impl FnMut()) {
+                    const {
+                        let tags = <$name as $crate::MultiAllocatorBackend>::TAGS;
+                        let mut i = 0;
+                        while i < tags.len() && tags[i] as u16 != TAG {
+                            i += 1;
+                        }
+                        assert!(i < tags.len(), "invalid allocator tag");
+                    }
+                    $crate::with_allocator_const::<TAG>(closure);
+                }
+
+                /// Drop `value` with the allocator identified by `tag`, like `okaoka::drop_in`
+                #[track_caller]
+                pub fn drop_in<T>(tag: $enum_name, value: T) {
+                    use $crate::MultiAllocatorBackend;
+                    $crate::drop_in(<Self as MultiAllocatorBackend>::raw_tag(tag), value);
+                }
+
+                /// Clone `value` with the allocator identified by `tag`, like `okaoka::clone_into`
+                #[track_caller]
+                pub fn clone_into<T: $crate::CloneIn>(tag: $enum_name, value: &T) -> T {
+                    use $crate::MultiAllocatorBackend;
+                    $crate::clone_into(<Self as MultiAllocatorBackend>::raw_tag(tag), value)
+                }
+
+                /// Box `value` with the allocator identified by `tag`, like `okaoka::Tagged::new`
+                #[track_caller]
+                pub fn tagged<T>(tag: $enum_name, value: T) -> $crate::Tagged<T> {
+                    use $crate::MultiAllocatorBackend;
+                    $crate::Tagged::new(<Self as MultiAllocatorBackend>::raw_tag(tag), value)
+                }
+
+                /// Set the allocator identified by `tag` until the returned guard is dropped
+                #[track_caller]
+                pub fn guard(tag: $enum_name) -> $crate::AllocatorGuard {
+                    use $crate::MultiAllocatorBackend;
+                    $crate::AllocatorGuard::new(<Self as MultiAllocatorBackend>::raw_tag(tag))
+                }
+
+                /// Set the allocator identified by `tag` until the returned token is popped, like
+                /// `okaoka::push_allocator`
+                #[track_caller]
+                pub fn push(tag: $enum_name) -> $crate::ScopeToken {
+                    use $crate::MultiAllocatorBackend;
+                    $crate::push_allocator(<Self as MultiAllocatorBackend>::raw_tag(tag))
+                }
+
+                $(
+                    $(#[cfg($cfg)])*
+                    #[doc = concat!(
+                        "Set the `", stringify!($tag_name), "` allocator until the guard is dropped"
+                    )]
+                    #[track_caller]
+                    pub fn [<$tag_name:snake _guard>]() -> $crate::AllocatorGuard {
+                        Self::guard($enum_name::$tag_name)
+                    }
+                )+
+
+                /// Handle that allocates directly with the allocator identified by `tag`
+                pub const fn handle(tag: $enum_name) -> $crate::TagHandle<Self> {
+                    $crate::TagHandle::new(tag)
+                }
+
+                $(
+                    $(#[cfg($cfg)])*
+                    #[doc = concat!("Handle to the `", stringify!($tag_name), "` allocator")]
+                    pub const fn [<$tag_name:snake _handle>]() -> $crate::TagHandle<Self> {
+                        Self::handle($enum_name::$tag_name)
+                    }
+                )+
+            }
+        }
+
+        $crate::__if_stats! {
+            $crate::paste::paste! {
+                #[allow(dead_code)]
+                impl $name {
+                    /// Statistics of the allocator identified by `tag`
+                    pub fn stats(tag: $enum_name) -> $crate::stats::TagStats {
+                        $crate::stats::tag_stats::<Self>(tag)
+                    }
+
+                    /// Statistics of every allocator
+                    pub fn all_stats(
+                    ) -> std::collections::HashMap<$enum_name, $crate::stats::TagStats> {
+                        $crate::stats::all_stats::<Self>()
+                    }
+
+                    $(
+                        $(#[cfg($cfg)])*
+                        #[doc = concat!("Statistics of the `", stringify!($tag_name), "` tag")]
+                        pub fn [<$tag_name:snake _stats>]() -> $crate::stats::TagStats {
+                            Self::stats($enum_name::$tag_name)
+                        }
+                    )+
+                }
+            }
+        }
+
+        $crate::__if_owner_stats! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Statistics of the allocations of `owner` with the allocator identified by `tag`
+                pub fn owner_stats(tag: $enum_name, owner: u16) -> $crate::stats::TagStats {
+                    $crate::stats::owner_stats::<Self>(tag, owner)
+                }
+
+                /// Statistics of every pair of tag and owner that has allocated
+                pub fn all_owner_stats(
+                ) -> std::collections::HashMap<($enum_name, u16), $crate::stats::TagStats> {
+                    $crate::stats::all_owner_stats::<Self>()
+                }
+            }
+        }
+
+        $crate::__if_jemalloc_stats! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Statistics of jemalloc for the allocator identified by `tag`, `None` if it
+                /// isn't backed by jemalloc
+                pub fn jemalloc_arena_stats(
+                    tag: $enum_name,
+                ) -> std::io::Result<Option<$crate::jemalloc::JemallocStats>> {
+                    $crate::jemalloc::tag_stats::<Self>(tag)
+                }
+            }
+        }
+
+        $crate::__if_mimalloc_heaps! {
+            #[allow(dead_code)]
+            impl $name {
+                /// mimalloc heaps of the allocator identified by `tag`, `None` if it isn't a
+                /// `MiHeap`
+                pub fn mimalloc_heap(
+                    tag: $enum_name,
+                ) -> Option<&'static $crate::mimalloc::MiHeap> {
+                    $crate::mimalloc::tag_heap::<Self>(tag)
+                }
+            }
+        }
+
+        $crate::__if_size_classes! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Statistics of the size classes of the allocator identified by `tag`
+                pub fn size_classes(tag: $enum_name) -> Vec<$crate::size_classes::SizeClassStats> {
+                    $crate::size_classes::tag_size_classes::<Self>(tag)
+                }
+            }
+        }
+
+        $crate::__if_thread_stats! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Statistics of every pair of tag and thread name that has allocated
+                pub fn all_thread_stats(
+                ) -> std::collections::HashMap<($enum_name, &'static str), $crate::stats::TagStats>
+                {
+                    $crate::thread_stats::all_thread_stats::<Self>()
+                }
+            }
+        }
+
+        $crate::__if_lifetimes! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Lifetime histogram of the allocator identified by `tag`
+                pub fn lifetimes(tag: $enum_name) -> $crate::lifetimes::LifetimeHistogram {
+                    use $crate::MultiAllocatorBackend;
+                    <Self as MultiAllocatorBackend>::lifetimes(tag).snapshot()
+                }
+            }
+        }
+
+        $crate::__if_live! {
+            #[allow(dead_code)]
+            impl $name {
+                /// The `n` largest live blocks, largest first
+                pub fn largest_live(n: usize) -> Vec<$crate::live::AllocationInfo<$enum_name>> {
+                    $crate::live::largest_live::<Self>(n)
+                }
+            }
+        }
+
+        $crate::__if_testing! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Call the closure, panicking if the allocator identified by `tag` has more live
+                /// bytes afterwards than before
+                #[track_caller]
+                pub fn check_balanced<R>(tag: $enum_name, closure: impl FnOnce() -> R) -> R {
+                    $crate::testing::check_balanced::<Self, R>(tag, closure)
+                }
+            }
+        }
+
+        $crate::__if_frame! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Start a frame of the allocator identified by `tag`
+                pub fn begin_frame(tag: $enum_name) {
+                    $crate::frame::begin_frame::<Self>(tag);
+                }
+
+                /// End the frame of the allocator identified by `tag`, resetting it and
+                /// returning the bytes it used
+                ///
+                /// # Safety
+                ///
+                /// No block allocated by the allocator may be used after the frame ends.
+                pub unsafe fn end_frame(tag: $enum_name) -> usize {
+                    unsafe { $crate::frame::end_frame::<Self>(tag) }
+                }
+
+                /// Set the maximum bytes of a frame of the allocator identified by `tag`
+                pub fn set_frame_budget(tag: $enum_name, budget: Option<usize>) {
+                    $crate::frame::set_frame_budget::<Self>(tag, budget);
+                }
+
+                /// Bytes used by the last frames of the allocator identified by `tag`, oldest
+                /// first
+                pub fn frame_history(tag: $enum_name) -> Vec<usize> {
+                    $crate::frame::frame_history::<Self>(tag)
+                }
+            }
+        }
+
+        $crate::__if_bumpalo! {
+            #[allow(dead_code)]
+            impl $name {
+                /// Call the closure with the allocator identified by `tag` and a `bumpalo::Bump`
+                /// allocating its chunks with it
+                #[track_caller]
+                pub fn with_arena_scope<R>(
+                    tag: $enum_name,
+                    closure: impl FnOnce(&$crate::bump::Bump) -> R,
+                ) -> R {
+                    $crate::bump::with_arena_scope::<Self, R>(tag, closure)
+                }
+            }
+        }
+
+        $crate::create_multi_allocator_backend!(
+            @collections $name $enum_name $options [$({ [$($cfg),*] $tag_name })+]
+        );
+
+        $crate::create_multi_allocator_backend!(@registry $name $repr $options);
+
+        $crate::create_multi_allocator_backend!(@global_allocator $name $options);
+
+        $crate::create_multi_allocator_backend!(
+            @debug_info $name $enum_name $options [$({ [$($cfg),*] $tag_name })+]
+        );
+    };
+
+    // No entry is marked as `default`, use the first one
+    (@default $enum_name:ident []) => {
+        const DEFAULT_TAG: Self::Tag = Self::TAGS[0];
+    };
+
+    (@default $enum_name:ident [$({ [$($cfg:meta),*] $tag_name:ident })+]) => {
+        $($(#[cfg($cfg)])* const DEFAULT_TAG: Self::Tag = $enum_name::$tag_name;)+
+
+        // None of the entries marked as `default` is enabled, use the first one
+        #[cfg(not(any($(all($($cfg),*)),+)))]
+        const DEFAULT_TAG: Self::Tag = Self::TAGS[0];
+    };
+
+    (@default_from_env [(default_from_env = $var:literal) $($options:tt)*]) => {
+        fn default_tag() -> Self::Tag {
+            static ENV_DEFAULT: $crate::EnvDefault = $crate::EnvDefault::new();
+            ENV_DEFAULT.get::<Self>($var)
+        }
+    };
+
+    (@default_from_env [$option:tt $($options:tt)*]) => {
+        $crate::create_multi_allocator_backend!(@default_from_env [$($options)*]);
+    };
+
+    (@default_from_env []) => {};
+
+    (
+        @aligned_route $enum_name:ident
+        [(route_aligned = ($align:literal, $tag_name:ident)) $($options:tt)*]
+    ) => {
+        #[inline(always)]
+        fn aligned_route(layout: core::alloc::Layout) -> Option<Self::Tag> {
+            (layout.align() > $align).then_some($enum_name::$tag_name)
+        }
+    };
+
+    (@aligned_route $enum_name:ident [$option:tt $($options:tt)*]) => {
+        $crate::create_multi_allocator_backend!(@aligned_route $enum_name [$($options)*]);
+    };
+
+    (@aligned_route $enum_name:ident []) => {};
+
+    (@dynamic_slot $name:ident [(dynamic = $capacity:literal) $($options:tt)*]) => {
+        #[inline(always)]
+        fn dynamic_slot(raw_tag: u16) -> Option<&'static $crate::registry::Slot> {
+            $crate::paste::paste! {
+                match raw_tag.checked_sub($name::FIRST_DYNAMIC_TAG) {
+                    // Each slot has a raw tag for each generation
+                    Some(index) => [<__ $name:upper _REGISTRY>].slot(index as usize / 2),
+                    None => None,
+                }
+            }
+        }
+    };
+
+    (@dynamic_slot $name:ident [$option:tt $($options:tt)*]) => {
+        $crate::create_multi_allocator_backend!(@dynamic_slot $name [$($options)*]);
+    };
+
+    (@dynamic_slot $name:ident []) => {};
+
+    // The slots of the registry take the highest raw tags of the representation, two per slot
+    (@registry $name:ident $repr:ident [(dynamic = $capacity:literal) $($options:tt)*]) => {
+        $crate::paste::paste! {
+            $crate::__static! {
+                [<__ $name:upper _REGISTRY>]: $crate::registry::Registry<$capacity> =
+                    $crate::registry::Registry::new()
+            }
+        }
+
+        #[allow(dead_code)]
+        impl $name {
+            /// Raw tag of the first slot of the registry
+            const FIRST_DYNAMIC_TAG: u16 = {
+                assert!(
+                    $capacity > 0 && 2 * $capacity <= <$repr>::MAX as u32 + 1,
+                    "invalid registry capacity"
+                );
+                (<$repr>::MAX as u32 + 1 - 2 * $capacity) as u16
+            };
+
+            /// Register `allocator` in a free slot of the registry, returning its tag
+            ///
+            /// Gives `allocator` back if every slot is taken.
+            pub fn register(
+                allocator: $crate::__alloc::boxed::Box<$crate::registry::DynAllocator>,
+            ) -> Result<$crate::registry::DynamicTag, $crate::__alloc::boxed::Box<$crate::registry::DynAllocator>> {
+                $crate::paste::paste! {
+                    let index = [<__ $name:upper _REGISTRY>].insert(allocator)?;
+                }
+                Ok($crate::registry::DynamicTag::new(Self::FIRST_DYNAMIC_TAG + 2 * index as u16))
+            }
+
+            /// Replace the allocator registered with `tag` for new allocations
+            ///
+            /// Blocks allocated before keep being deallocated by the replaced allocator until
+            /// `reclaim` takes it. Returns the allocator replaced by the previous swap, if any.
+            /// Gives `allocator` back if the allocator replaced by the previous swap still has
+            /// live blocks.
+            pub fn swap(
+                tag: $crate::registry::DynamicTag,
+                allocator: $crate::__alloc::boxed::Box<$crate::registry::DynAllocator>,
+            ) -> Result<
+                Option<$crate::__alloc::boxed::Box<$crate::registry::DynAllocator>>,
+                $crate::__alloc::boxed::Box<$crate::registry::DynAllocator>,
+            > {
+                let Some(index) = tag.raw_tag().checked_sub(Self::FIRST_DYNAMIC_TAG) else {
+                    return Err(allocator);
+                };
+                $crate::paste::paste! {
+                    [<__ $name:upper _REGISTRY>].swap(index as usize / 2, allocator)
+                }
+            }
+
+            /// Take the allocator replaced by the last swap of `tag` once all its blocks are
+            /// deallocated
+            pub fn reclaim(
+                tag: $crate::registry::DynamicTag,
+            ) -> Option<$crate::__alloc::boxed::Box<$crate::registry::DynAllocator>> {
+                let index = tag.raw_tag().checked_sub(Self::FIRST_DYNAMIC_TAG)?;
+                $crate::paste::paste! {
+                    [<__ $name:upper _REGISTRY>].reclaim(index as usize / 2)
+                }
+            }
+
+            /// Remove the allocator registered with `tag`, returning it
+            ///
+            /// Allocating with `tag` afterwards is handled like allocating with an unknown tag, as
+            /// set by `okaoka::set_unknown_tag_mode`, until another allocator is registered with
+            /// the same tag.
+            ///
+            /// # Safety
+            ///
+            /// No thread may be allocating with `tag`, and every block allocated with `tag` must
+            /// have been deallocated, since the allocator is dropped with the returned box.
+            pub unsafe fn unregister(
+                tag: $crate::registry::DynamicTag,
+            ) -> Option<$crate::__alloc::boxed::Box<$crate::registry::DynAllocator>> {
+                let index = tag.raw_tag().checked_sub(Self::FIRST_DYNAMIC_TAG)?;
+                $crate::paste::paste! {
+                    unsafe { [<__ $name:upper _REGISTRY>].remove(index as usize / 2) }
+                }
+            }
+        }
+
+        const _: () = {
+            let tags = <$name as $crate::MultiAllocatorBackend>::TAGS;
+            let mut i = 0;
+            while i < tags.len() {
+                assert!(
+                    (tags[i] as u16) < $name::FIRST_DYNAMIC_TAG,
+                    "allocator tag overlaps the tags of the registry"
+                );
+                i += 1;
+            }
+        };
+    };
+
+    (@registry $name:ident $repr:ident [$option:tt $($options:tt)*]) => {
+        $crate::create_multi_allocator_backend!(@registry $name $repr [$($options)*]);
+    };
+
+    (@registry $name:ident $repr:ident []) => {};
+
+    // Dispatch with a table of function pointers indexed by the position of the tag
+    (
+        @dispatch_alloc [(dispatch = table) $($options:tt)*]
+        $enum_name:ident $tag:ident $layout:ident
+        [$({ [$($cfg:meta),*] $tag_name:ident $allocator:expr })+]
+    ) => {{
+        use $crate::MultiAllocatorBackend;
+        type Alloc = unsafe fn(core::alloc::Layout) -> *mut u8;
+        static TABLE: [Alloc; $enum_name::COUNT] = [$(
+            $(#[cfg($cfg)])*
+            {
+                unsafe fn alloc(layout: core::alloc::Layout) -> *mut u8 {
+                    use core::alloc::GlobalAlloc;
+                    unsafe { $allocator.alloc(layout) }
+                }
+                alloc
+            },
+        )+];
+        unsafe { TABLE[<Self as MultiAllocatorBackend>::tag_index($tag)]($layout) }
+    }};
+
+    (
+        @dispatch_alloc [$option:tt $($options:tt)*] $enum_name:ident $tag:ident $layout:ident
+        $entries:tt
+    ) => {
+        $crate::create_multi_allocator_backend!(
+            @dispatch_alloc [$($options)*] $enum_name $tag $layout $entries
+        )
+    };
+
+    (
+        @dispatch_alloc [] $enum_name:ident $tag:ident $layout:ident
+        [$({ [$($cfg:meta),*] $tag_name:ident $allocator:expr })+]
+    ) => {{
+        use core::alloc::GlobalAlloc;
+        match $tag {
+            $($(#[cfg($cfg)])* $enum_name::$tag_name => unsafe { $allocator.alloc($layout) },)+
+        }
+    }};
+
+    (
+        @dispatch_dealloc [(dispatch = table) $($options:tt)*]
+        $enum_name:ident $tag:ident $ptr:ident $layout:ident
+        [$({ [$($cfg:meta),*] $tag_name:ident $allocator:expr })+]
+    ) => {{
+        use $crate::MultiAllocatorBackend;
+        type Dealloc = unsafe fn(*mut u8, core::alloc::Layout);
+        static TABLE: [Dealloc; $enum_name::COUNT] = [$(
+            $(#[cfg($cfg)])*
+            {
+                unsafe fn dealloc(ptr: *mut u8, layout: core::alloc::Layout) {
+                    use core::alloc::GlobalAlloc;
+                    unsafe { $allocator.dealloc(ptr, layout) }
+                }
+                dealloc
+            },
+        )+];
+        unsafe { TABLE[<Self as MultiAllocatorBackend>::tag_index($tag)]($ptr, $layout) }
+    }};
+
+    (
+        @dispatch_dealloc [$option:tt $($options:tt)*]
+        $enum_name:ident $tag:ident $ptr:ident $layout:ident $entries:tt
+    ) => {
+        $crate::create_multi_allocator_backend!(
+            @dispatch_dealloc [$($options)*] $enum_name $tag $ptr $layout $entries
+        )
+    };
+
+    (
+        @dispatch_dealloc [] $enum_name:ident $tag:ident $ptr:ident $layout:ident
+        [$({ [$($cfg:meta),*] $tag_name:ident $allocator:expr })+]
+    ) => {{
+        use core::alloc::GlobalAlloc;
+        match $tag {
+            $(
+                $(#[cfg($cfg)])*
+                $enum_name::$tag_name => unsafe { $allocator.dealloc($ptr, $layout) },
+            )+
+        }
+    }};
+
+    (
+        @dispatch_realloc [(dispatch = table) $($options:tt)*]
+        $enum_name:ident $tag:ident $ptr:ident $layout:ident $new_size:ident
+        [$({ [$($cfg:meta),*] $tag_name:ident $allocator:expr })+]
+    ) => {{
+        use $crate::MultiAllocatorBackend;
+        type Realloc = unsafe fn(*mut u8, core::alloc::Layout, usize) -> *mut u8;
+        static TABLE: [Realloc; $enum_name::COUNT] = [$(
+            $(#[cfg($cfg)])*
+            {
+                unsafe fn realloc(
+                    ptr: *mut u8,
+                    layout: core::alloc::Layout,
+                    new_size: usize,
+                ) -> *mut u8 {
+                    use core::alloc::GlobalAlloc;
+                    unsafe { $allocator.realloc(ptr, layout, new_size) }
+                }
+                realloc
+            },
+        )+];
+        unsafe { TABLE[<Self as MultiAllocatorBackend>::tag_index($tag)]($ptr, $layout, $new_size) }
+    }};
+
+    (
+        @dispatch_realloc [$option:tt $($options:tt)*]
+        $enum_name:ident $tag:ident $ptr:ident $layout:ident $new_size:ident $entries:tt
+    ) => {
+        $crate::create_multi_allocator_backend!(
+            @dispatch_realloc [$($options)*] $enum_name $tag $ptr $layout $new_size $entries
+        )
+    };
+
+    (
+        @dispatch_realloc [] $enum_name:ident $tag:ident $ptr:ident $layout:ident
+        $new_size:ident [$({ [$($cfg:meta),*] $tag_name:ident $allocator:expr })+]
+    ) => {{
+        use core::alloc::GlobalAlloc;
+        match $tag {
+            $(
+                $(#[cfg($cfg)])*
+                $enum_name::$tag_name => unsafe { $allocator.realloc($ptr, $layout, $new_size) },
+            )+
+        }
+    }};
+
+    (
+        @parse $header:tt $entries:tt $defaults:tt [$($options:tt)*]
+        dispatch = $dispatch:ident
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header $entries $defaults
+            [$($options)* (dispatch = $dispatch)]
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @collections $name:ident $enum_name:ident [(collections) $($options:tt)*]
+        [$({ [$($cfg:meta),*] $tag_name:ident })+]
+    ) => {
+        $crate::__if_allocator_api! {
+            $crate::paste::paste! {
+                $(
+                    $(#[cfg($cfg)])*
+                    #[allow(dead_code)]
+                    type [<$tag_name Handle>] =
+                        $crate::ConstTagHandle<$name, { $enum_name::$tag_name as u16 }>;
+
+                    $(#[cfg($cfg)])*
+                    #[allow(dead_code)]
+                    type [<$tag_name Vec>]<T> = $crate::aliases::Vec<T, [<$tag_name Handle>]>;
+
+                    $(#[cfg($cfg)])*
+                    #[allow(dead_code)]
+                    type [<$tag_name VecDeque>]<T> =
+                        $crate::aliases::VecDeque<T, [<$tag_name Handle>]>;
+
+                    $(#[cfg($cfg)])*
+                    #[allow(dead_code)]
+                    type [<$tag_name Box>]<T> = $crate::aliases::Box<T, [<$tag_name Handle>]>;
+                )+
+            }
+        }
+    };
+
+    (@collections $name:ident $enum_name:ident [$option:tt $($options:tt)*] $entries:tt) => {
+        $crate::create_multi_allocator_backend!(
+            @collections $name $enum_name [$($options)*] $entries
+        );
+    };
+
+    (@collections $name:ident $enum_name:ident [] $entries:tt) => {};
+
+    // Only `set_multi_global_allocator` adds the `(global)` option, always first
+    (@global_allocator $name:ident [(global) $($options:tt)*]) => {
+        $crate::create_multi_allocator_backend!(@global_static $name [$($options)*]);
+
+        $crate::__if_shared_core! {
+            #[no_mangle]
+            pub extern "C" fn okaoka_abi_version() -> u32 {
+                $crate::shared::ABI_VERSION
+            }
+
+            #[no_mangle]
+            pub extern "C" fn okaoka_get_tag() -> i32 {
+                $crate::shared::host::get_tag()
+            }
+
+            #[no_mangle]
+            pub extern "C" fn okaoka_set_tag(tag: i32) {
+                $crate::shared::host::set_tag(tag)
+            }
+
+            /// # Safety
+            ///
+            /// Same contract as `GlobalAlloc::alloc`.
+            #[no_mangle]
+            pub unsafe extern "C" fn okaoka_alloc(size: usize, align: usize) -> *mut u8 {
+                unsafe { $crate::shared::host::alloc::<$name>(size, align) }
+            }
+
+            /// # Safety
+            ///
+            /// Same contract as `GlobalAlloc::dealloc`.
+            #[no_mangle]
+            pub unsafe extern "C" fn okaoka_dealloc(ptr: *mut u8, size: usize, align: usize) {
+                unsafe { $crate::shared::host::dealloc::<$name>(ptr, size, align) }
+            }
+
+            /// # Safety
+            ///
+            /// Same contract as `GlobalAlloc::realloc`.
+            #[no_mangle]
+            pub unsafe extern "C" fn okaoka_realloc(
+                ptr: *mut u8,
+                size: usize,
+                align: usize,
+                new_size: usize,
+            ) -> *mut u8 {
+                unsafe { $crate::shared::host::realloc::<$name>(ptr, size, align, new_size) }
+            }
+        }
+
+        $crate::__if_stats! {
+            const _: () = {
+                extern "C" fn report() {
+                    $crate::report::at_exit::<$name>();
+                }
+
+                extern "C" fn register() {
+                    $crate::report::register(report);
+                    $crate::__if_http! {
+                        $crate::http::register::<$name>();
+                    }
+                }
+
+                // Call `register` before `main`, like the constructors of C++ statics
+                #[used]
+                #[cfg_attr(
+                    any(
+                        target_os = "linux",
+                        target_os = "android",
+                        target_os = "freebsd",
+                        target_os = "netbsd",
+                        target_os = "openbsd",
+                        target_os = "dragonfly",
+                        target_os = "illumos",
+                    ),
+                    link_section = ".init_array"
+                )]
+                #[cfg_attr(target_vendor = "apple", link_section = "__DATA,__mod_init_func")]
+                #[cfg_attr(windows, link_section = ".CRT$XCU")]
+                static REGISTER: extern "C" fn() = register;
+            };
+        }
+
+        impl $name {
+            /// Allocate `len` boxes in a single batch with the current allocator
+            ///
+            /// The value of each box is `init(index)`.
+            pub fn alloc_boxes<T>(
+                len: usize,
+                init: impl FnMut(usize) -> T,
+            ) -> $crate::__alloc::vec::Vec<$crate::__alloc::boxed::Box<T>> {
+                // SAFETY: the backend is installed as the global allocator
+                unsafe { $crate::MultiAllocator::<$name>::new().alloc_boxes(len, init) }
+            }
+        }
+    };
+
+    // Only `set_multi_global_allocator_for_tests` adds the `(global_for_tests)` option, always
+    // first
+    (@global_allocator $name:ident [(global_for_tests) $($options:tt)*]) => {
+        #[cfg(test)]
+        $crate::create_multi_allocator_backend!(@global_allocator $name [(global) $($options)*]);
+    };
+
+    (@global_allocator $name:ident $options:tt) => {};
+
+    // Only the global allocator exports its debug info, which has a fixed symbol
+    (
+        @debug_info $name:ident $enum_name:ident [(global) $($options:tt)*]
+        [$({ [$($cfg:meta),*] $tag_name:ident })+]
+    ) => {
+        $crate::__if_debugger! {
+            const _: () = {
+                #[no_mangle]
+                #[used]
+                static OKAOKA_DEBUG_INFO: $crate::debugger::DebugInfo<{ $enum_name::COUNT }> =
+                    $crate::debugger::DebugInfo::new::<$name>([$(
+                        $(#[cfg($cfg)])*
+                        $crate::debugger::TagInfo::new(
+                            $enum_name::$tag_name as u16,
+                            stringify!($tag_name),
+                        ),
+                    )+]);
+            };
+        }
+    };
+
+    (
+        @debug_info $name:ident $enum_name:ident [(global_for_tests) $($options:tt)*]
+        $entries:tt
+    ) => {
+        #[cfg(test)]
+        $crate::create_multi_allocator_backend!(@debug_info $name $enum_name [(global)] $entries);
+    };
+
+    (@debug_info $name:ident $enum_name:ident $options:tt $entries:tt) => {};
+
+    (@global_static $name:ident [(static_name = $static_name:ident) $($options:tt)*]) => {
+        #[global_allocator]
+        static $static_name: $crate::MultiAllocator<$name> = $crate::MultiAllocator::new();
+    };
+
+    (@global_static $name:ident [$option:tt $($options:tt)*]) => {
+        $crate::create_multi_allocator_backend!(@global_static $name [$($options)*]);
+    };
+
+    // Keep the static in an anonymous scope so that it can't collide with other items
+    (@global_static $name:ident []) => {
+        const _: () = {
+            #[global_allocator]
+            static ALLOCATOR: $crate::MultiAllocator<$name> = $crate::MultiAllocator::new();
+        };
+    };
+
+    (
+        @parse $header:tt $entries:tt $defaults:tt [$($options:tt)*]
+        static_name = $static_name:ident
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header $entries $defaults
+            [$($options)* (static_name = $static_name)]
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @parse $header:tt $entries:tt $defaults:tt [$($options:tt)*]
+        collections
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header $entries $defaults
+            [$($options)* (collections)]
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @parse $header:tt $entries:tt $defaults:tt [$($options:tt)*]
+        dynamic = $capacity:literal
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header $entries $defaults
+            [$($options)* (dynamic = $capacity)]
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @parse $header:tt $entries:tt $defaults:tt [$($options:tt)*]
+        route_aligned = ($align:literal, $tag_name:ident)
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header $entries $defaults
+            [$($options)* (route_aligned = ($align, $tag_name))]
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @parse $header:tt $entries:tt $defaults:tt [$($options:tt)*]
+        default_from_env = $var:literal
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header $entries $defaults
+            [$($options)* (default_from_env = $var)]
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @parse { $header:tt $old_repr:ident } $entries:tt $defaults:tt $options:tt
+        repr = $repr:ident
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse { $header $repr } $entries $defaults $options
+            $($($rest)*)?
+        }
+    };
+
+    // Record the default entry and parse it as a regular one
+    (
+        @parse $header:tt $entries:tt [$($defaults:tt)*] $options:tt
+        $(#[cfg($cfg:meta)])* default $tag_name:ident $($rest:tt)*
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header
+            $entries
+            [$($defaults)* { [$($cfg),*] $tag_name }]
+            $options
+            $(#[cfg($cfg)])* $tag_name $($rest)*
+        }
+    };
+
+    // Entry that declares the static of the allocator, named after the backend and the tag
+    (
+        @parse
+        { { $(#[$name_meta:meta])* $name:ident, $($enum_header:tt)* } $repr:ident }
+        [$($entries:tt)*] $defaults:tt $options:tt
+        $(#[cfg($cfg:meta)])* $tag_name:ident $(= $discriminant:literal)?
+        => static $allocator_type:ty = $init:expr
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::paste::paste! {
+            $(#[cfg($cfg)])*
+            #[allow(non_upper_case_globals)]
+            static [<__ $name _ $tag_name>]: $allocator_type = $init;
+
+            $crate::create_multi_allocator_backend! {
+                @parse
+                { { $(#[$name_meta])* $name, $($enum_header)* } $repr }
+                [
+                    $($entries)*
+                    { [$($cfg),*] $tag_name [$($discriminant)?] [<__ $name _ $tag_name>] }
+                ]
+                $defaults
+                $options
+                $($($rest)*)?
+            }
+        }
+    };
+
+    // Entry whose allocator is only present when `$predicate` holds, using the allocator of the
+    // `$fallback` tag otherwise
+    (
+        @parse
+        {
+            { $(#[$name_meta:meta])* $name:ident, $(#[$enum_meta:meta])* $enum_name:ident }
+            $repr:ident
+        }
+        [$($entries:tt)*] $defaults:tt $options:tt
+        $(#[cfg($cfg:meta)])* $tag_name:ident(cfg: $predicate:meta, fallback: $fallback:ident)
+        $(= $discriminant:literal)? => $allocator:expr
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::paste::paste! {
+            $(#[cfg($cfg)])*
+            #[allow(non_camel_case_types)]
+            struct [<__ $name _ $tag_name>];
+
+            $(#[cfg($cfg)])*
+            #[cfg($predicate)]
+            unsafe impl core::alloc::GlobalAlloc for [<__ $name _ $tag_name>] {
+                #[inline(always)]
+                unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+                    unsafe { $allocator.alloc(layout) }
+                }
+
+                #[inline(always)]
+                unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+                    unsafe { $allocator.dealloc(ptr, layout) }
+                }
+
+                #[inline(always)]
+                unsafe fn realloc(
+                    &self,
+                    ptr: *mut u8,
+                    layout: core::alloc::Layout,
+                    new_size: usize,
+                ) -> *mut u8 {
+                    unsafe { $allocator.realloc(ptr, layout, new_size) }
+                }
+            }
+
+            $(#[cfg($cfg)])*
+            #[cfg(not($predicate))]
+            unsafe impl core::alloc::GlobalAlloc for [<__ $name _ $tag_name>] {
+                #[inline(always)]
+                unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+                    use $crate::MultiAllocatorBackend;
+                    let tag = $enum_name::$fallback;
+                    unsafe { <$name as MultiAllocatorBackend>::alloc(tag, layout) }
+                }
+
+                #[inline(always)]
+                unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+                    use $crate::MultiAllocatorBackend;
+                    let tag = $enum_name::$fallback;
+                    unsafe { <$name as MultiAllocatorBackend>::dealloc(tag, ptr, layout) }
+                }
+
+                #[inline(always)]
+                unsafe fn realloc(
+                    &self,
+                    ptr: *mut u8,
+                    layout: core::alloc::Layout,
+                    new_size: usize,
+                ) -> *mut u8 {
+                    use $crate::MultiAllocatorBackend;
+                    let tag = $enum_name::$fallback;
+                    if new_size >= layout.size() {
+                        unsafe { <$name as MultiAllocatorBackend>::grow(tag, ptr, layout, new_size) }
+                    } else {
+                        unsafe {
+                            <$name as MultiAllocatorBackend>::shrink(tag, ptr, layout, new_size)
+                        }
+                    }
+                }
+            }
+
+            $crate::create_multi_allocator_backend! {
+                @parse
+                { { $(#[$name_meta])* $name, $(#[$enum_meta])* $enum_name } $repr }
+                [
+                    $($entries)*
+                    { [$($cfg),*] $tag_name [$($discriminant)?] [<__ $name _ $tag_name>] }
+                ]
+                $defaults
+                $options
+                $($($rest)*)?
+            }
+        }
+    };
+
+    // Entry whose allocator serves the declared sizes from slabs, see the `slab` module
+    (
+        @parse
+        { { $(#[$name_meta:meta])* $name:ident, $($enum_header:tt)* } $repr:ident }
+        [$($entries:tt)*] $defaults:tt $options:tt
+        $(#[cfg($cfg:meta)])*
+        $tag_name:ident(slab: [$($size:literal),+ $(,)?] $(, others: $others:ident)?)
+        $(= $discriminant:literal)? => $allocator:expr
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::paste::paste! {
+            $(#[cfg($cfg)])*
+            #[allow(non_upper_case_globals)]
+            static [<__ $name _ $tag_name>]: $crate::slab::Slab<{ [$($size),+].len() }> =
+                $crate::slab::Slab::new([$($size),+], &$allocator)
+                    $(.others($crate::slab::Others::$others))?;
+
+            $crate::create_multi_allocator_backend! {
+                @parse
+                { { $(#[$name_meta])* $name, $($enum_header)* } $repr }
+                [
+                    $($entries)*
+                    { [$($cfg),*] $tag_name [$($discriminant)?] [<__ $name _ $tag_name>] }
+                ]
+                $defaults
+                $options
+                $($($rest)*)?
+            }
+        }
+    };
+
+    // Entry whose allocator places the blocks at random, see the `randomized` module
+    (
+        @parse
+        { { $(#[$name_meta:meta])* $name:ident, $($enum_header:tt)* } $repr:ident }
+        [$($entries:tt)*] $defaults:tt $options:tt
+        $(#[cfg($cfg:meta)])*
+        $tag_name:ident(randomized $(, max_gap: $max_gap:literal)?)
+        $(= $discriminant:literal)? => $allocator:expr
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::paste::paste! {
+            $(#[cfg($cfg)])*
+            #[allow(non_upper_case_globals)]
+            static [<__ $name _ $tag_name>]: $crate::randomized::Randomized =
+                $crate::randomized::Randomized::new(&$allocator)$(.max_gap($max_gap))?;
+
+            $crate::create_multi_allocator_backend! {
+                @parse
+                { { $(#[$name_meta])* $name, $($enum_header)* } $repr }
+                [
+                    $($entries)*
+                    { [$($cfg),*] $tag_name [$($discriminant)?] [<__ $name _ $tag_name>] }
+                ]
+                $defaults
+                $options
+                $($($rest)*)?
+            }
+        }
+    };
+
+    (
+        @parse $header:tt [$($entries:tt)*] $defaults:tt $options:tt
+        $(#[cfg($cfg:meta)])* $tag_name:ident $(= $discriminant:literal)? => $allocator:expr
+        $(, $($rest:tt)*)?
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse $header
+            [$($entries)* { [$($cfg),*] $tag_name [$($discriminant)?] $allocator }]
+            $defaults
+            $options
+            $($($rest)*)?
+        }
+    };
+
+    (
+        @global
+        $(#[$name_meta:meta])* $name:ident,
+        $(#[$enum_meta:meta])* $enum_name:ident,
+        $($entries:tt)+
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse
+            { { $(#[$name_meta])* $name, $(#[$enum_meta])* $enum_name } u8 }
+            []
+            []
+            [(global)]
+            $($entries)+
+        }
+    };
+
+    (
+        @global_for_tests
+        $(#[$name_meta:meta])* $name:ident,
+        $(#[$enum_meta:meta])* $enum_name:ident,
+        $($entries:tt)+
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse
+            { { $(#[$name_meta])* $name, $(#[$enum_meta])* $enum_name } u8 }
+            []
+            []
+            [(global_for_tests)]
+            $($entries)+
+        }
+    };
+
+    (
+        $(#[$name_meta:meta])* $name:ident,
+        $(#[$enum_meta:meta])* $enum_name:ident,
+        $($entries:tt)+
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @parse
+            { { $(#[$name_meta])* $name, $(#[$enum_meta])* $enum_name } u8 }
+            []
+            []
+            []
+            $($entries)+
+        }
+    };
+}
+
+/// Create a [`MultiAllocatorBackend`] and install it as the global allocator
+///
+/// Takes the same input as [`create_multi_allocator_backend`] and adds a `#[global_allocator]`
+/// static wrapping the backend in a [`MultiAllocator`]. The static is unnamed unless a name is
+/// given with the `static_name = NAME` option.
+///
+/// As the backend is known to be the global allocator, it also gets `alloc_boxes(len, init)`,
+/// which allocates many boxes with a single call to [`MultiAllocatorBackend::alloc_batch`].
+///
+/// With the `shared-core` feature, it also exports the C ABI used by plugins to allocate through
+/// it, see the `shared` module.
+///
+/// With the `stats` feature, the program reports the statistics of every tag when it exits if
+/// the `OKAOKA_REPORT` environment variable is set, see the `report` module.
+///
+/// With the `debugger` feature, it exports the layout of the hidden tags for the debugger
+/// commands of the `debugger` module.
+///
+/// # Example
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Jemalloc => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     GlobalAllocator::with(AllocatorTag::Jemalloc, || {
+///         let _x = Box::new(10); // Allocated with jemalloc
+///         let nodes = GlobalAllocator::alloc_boxes(1000, |index| index as u64);
+///         assert_eq!(*nodes[999], 999);
+///     });
+/// }
+/// ```
+#[macro_export]
+macro_rules! set_multi_global_allocator {
+    (
+        $(#[$name_meta:meta])* $name:ident,
+        $(#[$enum_meta:meta])* $enum_name:ident,
+        $($entries:tt)+
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @global
+            $(#[$name_meta])* $name,
+            $(#[$enum_meta])* $enum_name,
+            $($entries)+
+        }
+    };
+}
+
+/// Like [`set_multi_global_allocator`], installing the global allocator only in `cfg(test)`
+/// builds
+///
+/// The backend, the tag enum and their methods exist in every build, so a library can switch
+/// allocators in its code and check the statistics in its unit tests, while its release builds
+/// keep the global allocator chosen by the application. Outside of tests, allocations go to the
+/// global allocator of the application whatever the current tag.
+///
+/// Integration tests are separate crates, so they have to install the allocator themselves.
+///
 /// # Example
 ///
 /// ```rust
+/// use std::alloc::System;
+///
+/// okaoka::set_multi_global_allocator_for_tests! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Jemalloc => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     GlobalAllocator::with(AllocatorTag::Jemalloc, || {
+///         // Allocated with jemalloc in unit tests only
+///         let _x = Box::new(10);
+///     });
+/// }
+/// ```
+#[macro_export]
+macro_rules! set_multi_global_allocator_for_tests {
+    (
+        $(#[$name_meta:meta])* $name:ident,
+        $(#[$enum_meta:meta])* $enum_name:ident,
+        $($entries:tt)+
+    ) => {
+        $crate::create_multi_allocator_backend! {
+            @global_for_tests
+            $(#[$name_meta])* $name,
+            $(#[$enum_meta])* $enum_name,
+            $($entries)+
+        }
+    };
+}
+
+/// Set the given allocator inside the closure, restoring the previous allocator after returning
+///
+/// # Example
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # use okaoka::with_allocator;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Jemalloc => jemallocator::Jemalloc,
+/// # }
+/// # fn main() {
 /// with_allocator(AllocatorTag::Jemalloc as u8, || {
 ///   // jemalloc is the default allocator inside this closure
 /// });
 /// // The previous allocator is restored here
+/// # }
 /// ```
 ///
 /// If `allocator_tag` is not a valid tag for the current allocator backend, the allocator will
-/// panic during allocation.
-pub fn with_allocator(allocator_tag: u8, mut closure: impl FnMut()) {
-    let old_tag = get_allocator_tag();
-    set_allocator_tag(allocator_tag);
+/// panic during allocation, unless configured otherwise with [`set_unknown_tag_mode`].
+///
+/// Built with `--cfg loom`, the current allocator is stored in the thread-locals of loom, so each
+/// thread of a model has its own, and the registry and the counters use the atomics of loom. They
+/// only exist inside a model, so the backend can't be the global allocator then, and allocations
+/// go through a [`MultiAllocator`] value instead. See `tests/loom.rs`.
+#[inline(always)]
+#[track_caller]
+pub fn with_allocator(allocator_tag: impl Into<u16>, closure: impl FnMut()) {
+    with_allocator_tag(Some(allocator_tag.into()), closure);
+}
+
+/// Like [`with_allocator`], with the tag known at compile time
+///
+/// Switching the allocator compiles down to saving the current tag and storing `TAG`, with no
+/// conversion of the tag at runtime.
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Jemalloc => jemallocator::Jemalloc,
+/// # }
+/// # fn main() {
+/// GlobalAllocator::with_const::<{ AllocatorTag::Jemalloc as u16 }>(|| {
+///     // jemalloc is the default allocator inside this closure
+/// });
+/// # }
+/// ```
+#[inline(always)]
+#[track_caller]
+pub fn with_allocator_const<const TAG: u16>(closure: impl FnMut()) {
+    with_allocator_tag(Some(TAG), closure);
+}
+
+#[inline(always)]
+#[track_caller]
+fn with_allocator_tag(allocator_tag: Option<u16>, mut closure: impl FnMut()) {
+    let _guard = AllocatorGuard::with_tag(allocator_tag);
     closure();
-    set_allocator_tag(old_tag);
+}
+
+/// Set the given allocator until the returned token is given to [`pop_allocator`]
+///
+/// A lower-level alternative to [`with_allocator`] for code that can't wrap its work in a
+/// closure, like code driven by C callbacks where one callback starts the work and another ends
+/// it:
+///
+/// ```rust
+/// # use std::alloc::System;
+/// use okaoka::{pop_allocator, push_allocator};
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Jemalloc => jemallocator::Jemalloc,
+/// # }
+/// # fn main() {
+/// let token = push_allocator(AllocatorTag::Jemalloc as u8);
+/// // jemalloc is the default allocator until the token is popped
+/// let _x = Box::new(10);
+/// pop_allocator(token);
+/// // The previous allocator is restored here
+/// # }
+/// ```
+///
+/// Tokens must be popped in the reverse order they were pushed. With debug assertions and the
+/// `std` feature, popping a token out of order panics. Dropping a token pops it too. Sending a token
+/// to another thread doesn't compile, so it's always popped on the thread that pushed it.
+#[inline(always)]
+#[track_caller]
+pub fn push_allocator(allocator_tag: impl Into<u16>) -> ScopeToken {
+    ScopeToken::push(Some(allocator_tag.into()))
+}
+
+/// Restore the allocator set before `token` was pushed with [`push_allocator`]
+#[inline(always)]
+pub fn pop_allocator(token: ScopeToken) {
+    drop(token);
+}
+
+#[cfg(all(debug_assertions, feature = "std", not(loom)))]
+std::thread_local! {
+    /// Number of tokens pushed and not popped by the thread
+    static PUSHED_TOKENS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+#[cfg(all(debug_assertions, feature = "std", loom))]
+loom::thread_local! {
+    /// Number of tokens pushed and not popped by the thread of the model
+    static PUSHED_TOKENS: loom::cell::Cell<usize> = loom::cell::Cell::new(0);
+}
+
+/// Scope of an allocator set by [`push_allocator`], ended by [`pop_allocator`]
+#[must_use = "the allocator is restored when the token is dropped"]
+pub struct ScopeToken {
+    _guard: AllocatorGuard,
+    // Number of tokens the thread had pushed with this one
+    #[cfg(all(debug_assertions, feature = "std"))]
+    pushed: usize,
+}
+
+impl ScopeToken {
+    #[inline(always)]
+    #[track_caller]
+    fn push(allocator_tag: Option<u16>) -> Self {
+        Self {
+            _guard: AllocatorGuard::with_tag(allocator_tag),
+            #[cfg(all(debug_assertions, feature = "std"))]
+            pushed: PUSHED_TOKENS.with(|pushed| {
+                pushed.set(pushed.get() + 1);
+                pushed.get()
+            }),
+        }
+    }
+}
+
+impl Drop for ScopeToken {
+    #[inline(always)]
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, feature = "std"))]
+        PUSHED_TOKENS.with(|pushed| {
+            // Not checked while unwinding, but restored in case the panic is caught
+            assert!(
+                self.pushed == pushed.get() || std::thread::panicking(),
+                "allocator scope popped before the {} pushed after it",
+                pushed.get().saturating_sub(self.pushed)
+            );
+            pushed.set(self.pushed - 1);
+        });
+    }
+}
+
+/// Sets an allocator for the current thread, restoring the previous allocator when dropped
+///
+/// Guards must be dropped in the reverse order they were created, which is what happens when they
+/// are kept in local variables. A guard can't be sent to another thread. Creating and dropping a
+/// guard doesn't allocate and is async-signal-safe, see the `signal_scope` module.
+///
+/// # Example
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Jemalloc => jemallocator::Jemalloc,
+/// # }
+/// # fn main() {
+/// {
+///     let _guard = GlobalAllocator::jemalloc_guard();
+///     // jemalloc is the default allocator until the end of this scope
+///     let _x = Box::new(10);
+/// }
+/// // The previous allocator is restored here
+/// # }
+/// ```
+pub struct AllocatorGuard {
+    old_tag: Option<u16>,
+    #[cfg(feature = "profiling")]
+    old_callsite: Option<&'static core::panic::Location<'static>>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl AllocatorGuard {
+    /// Set the allocator identified by the raw `allocator_tag`
+    ///
+    /// If `allocator_tag` is not a valid tag for the current allocator backend, the allocator will
+    /// panic during allocation, unless configured otherwise with [`set_unknown_tag_mode`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn new(allocator_tag: impl Into<u16>) -> Self {
+        Self::with_tag(Some(allocator_tag.into()))
+    }
+
+    #[inline(always)]
+    #[track_caller]
+    fn with_tag(allocator_tag: Option<u16>) -> Self {
+        let old_tag = get_allocator_tag();
+        set_allocator_tag(allocator_tag);
+        #[cfg(feature = "panic-hook")]
+        panic_hook::push_scope(allocator_tag);
+        Self {
+            old_tag,
+            #[cfg(feature = "profiling")]
+            old_callsite: profiling::replace_callsite(Some(core::panic::Location::caller())),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for AllocatorGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        #[cfg(feature = "panic-hook")]
+        panic_hook::pop_scope();
+        #[cfg(feature = "profiling")]
+        profiling::replace_callsite(self.old_callsite);
+        set_allocator_tag(self.old_tag);
+    }
+}
+
+#[cfg(all(
+    test,
+    debug_assertions,
+    feature = "std",
+    not(feature = "single-allocator")
+))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    #[test]
+    fn scope_tokens_dropped_while_unwinding() {
+        let outer = push_allocator(1u16);
+        let result = panic::catch_unwind(|| {
+            let _inner = push_allocator(2u16);
+            panic!("caught");
+        });
+        assert!(result.is_err());
+        assert_eq!(get_allocator_tag(), Some(1));
+        pop_allocator(outer);
+        assert_eq!(get_allocator_tag(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "allocator scope popped before the 1 pushed after it")]
+    fn scope_tokens_popped_out_of_order() {
+        let outer = push_allocator(1u16);
+        let inner = push_allocator(2u16);
+        pop_allocator(outer);
+        pop_allocator(inner);
+    }
+
+    create_multi_allocator_backend! {
+        DisabledDefaultBackend,
+        DisabledDefaultTag,
+        System => std::alloc::System,
+        #[cfg(any())]
+        default Fast => std::alloc::System,
+        #[cfg(not(any()))]
+        Fast => std::alloc::System,
+    }
+
+    #[test]
+    fn first_tag_is_default_without_enabled_default() {
+        assert_eq!(
+            DisabledDefaultBackend::DEFAULT_TAG,
+            DisabledDefaultTag::System
+        );
+    }
 }
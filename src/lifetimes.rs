@@ -0,0 +1,151 @@
+//! Histograms of allocation lifetimes
+//!
+//! With the `lifetimes` feature, the hidden tag put before every allocation also records when it
+//! was made, and each deallocation adds the lifetime of the block to a histogram of its tag.
+//! Short-lived allocations that churn are good candidates for an arena, long-lived ones aren't.
+//! Backends created with [`create_multi_allocator_backend`](crate::create_multi_allocator_backend)
+//! get `lifetimes(tag)`:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::with(AllocatorTag::Arena, || {
+//!         for _ in 0..10 {
+//!             drop(Box::new(10));
+//!         }
+//!     });
+//!
+//!     let lifetimes = GlobalAllocator::lifetimes(AllocatorTag::Arena);
+//!     assert_eq!(lifetimes.total(), 10);
+//!     for (limit, count) in lifetimes.iter() {
+//!         println!("<= {limit:?}: {count}");
+//!     }
+//! }
+//! ```
+//!
+//! A reallocated block keeps the time of its first allocation. With the `single-allocator`
+//! feature, there's no hidden tag, so nothing is recorded.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Upper limits of the buckets of a histogram, the last bucket has no limit
+pub const LIMITS: [Duration; 8] = [
+    Duration::from_micros(1),
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+/// Number of buckets of a histogram
+pub const BUCKETS: usize = LIMITS.len() + 1;
+
+/// Size of the timestamp in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = std::mem::size_of::<u64>();
+
+/// Snapshot of the lifetime histogram of a tag
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LifetimeHistogram {
+    /// Number of blocks whose lifetime fell in each bucket, see [`LIMITS`]
+    pub counts: [usize; BUCKETS],
+}
+
+impl LifetimeHistogram {
+    /// Number of blocks recorded
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Upper limit and count of each bucket, `Duration::MAX` for the last one
+    pub fn iter(&self) -> impl Iterator<Item = (Duration, usize)> + '_ {
+        LIMITS
+            .iter()
+            .copied()
+            .chain([Duration::MAX])
+            .zip(self.counts.iter().copied())
+    }
+}
+
+/// Live lifetime histogram of a tag
+pub struct Histogram {
+    counts: [AtomicUsize; BUCKETS],
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicUsize::new(0) }; BUCKETS],
+        }
+    }
+
+    /// Add a block that lived for `nanos` nanoseconds
+    #[cfg(not(feature = "single-allocator"))]
+    #[inline(always)]
+    pub(crate) fn record(&self, nanos: u64) {
+        let lifetime = Duration::from_nanos(nanos);
+        let bucket = LIMITS.partition_point(|&limit| limit < lifetime);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the histogram
+    pub fn snapshot(&self) -> LifetimeHistogram {
+        LifetimeHistogram {
+            counts: std::array::from_fn(|bucket| self.counts[bucket].load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Nanoseconds since the first allocation
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+fn now() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_nanos() as u64
+}
+
+/// Write the current time to `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for writing `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn write(ptr: *mut u8) {
+    unsafe { std::ptr::write_unaligned(ptr.cast(), now()) }
+}
+
+/// Nanoseconds since the time written at `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for reading `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn age(ptr: *const u8) -> u64 {
+    now().saturating_sub(unsafe { std::ptr::read_unaligned(ptr.cast::<u64>()) })
+}
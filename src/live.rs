@@ -0,0 +1,317 @@
+//! Registry of live allocations
+//!
+//! With the `live` feature, every block allocated by [`MultiAllocator`](crate::MultiAllocator)
+//! is linked into a list of the live blocks of its backend, through the hidden tag, and unlinked
+//! when it's deallocated. Backends created with
+//! [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get
+//! `largest_live(n)`, which answers "what exactly is using all this memory?" at runtime, e.g.
+//! from a debug endpoint:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut buffer = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || buffer = vec![0u8; 1 << 20]);
+//!
+//!     let largest = GlobalAllocator::largest_live(3);
+//!     assert_eq!(largest[0].size, 1 << 20);
+//!     assert_eq!(largest[0].tag, Some(AllocatorTag::Arena));
+//!
+//!     // Every live block
+//!     let all = GlobalAllocator::largest_live(usize::MAX);
+//!     assert_eq!(all[0].size, 1 << 20);
+//! }
+//! ```
+//!
+//! The list is protected by a lock, so every allocation and deallocation goes through it, which
+//! makes this feature meant for debugging. The age of a block is known with the `lifetimes`
+//! feature, and its callsite with the `profiling` feature. With the `single-allocator` feature,
+//! there's no hidden tag, so no block is registered.
+//!
+//! The links of a block are written through the pointers its neighbours were allocated with,
+//! which Miri rejects once the block is owned by a `Box`, so this feature is only checked with
+//! Miri on blocks allocated with `MultiAllocator` values.
+
+use std::{panic::Location, sync::Mutex, time::Duration};
+
+use crate::MultiAllocatorBackend;
+
+/// Size of the links and of the size of a block in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = 3 * std::mem::size_of::<usize>();
+
+/// Live allocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AllocationInfo<Tag> {
+    /// Size requested by the user, without the hidden tag
+    pub size: usize,
+    /// Raw tag of the allocator
+    pub raw_tag: u16,
+    /// Tag of the allocator, `None` for the allocators added at runtime
+    pub tag: Option<Tag>,
+    /// Time since the allocation, with the `lifetimes` feature
+    pub age: Option<Duration>,
+    /// Callsite of the allocation, with the `profiling` feature, serialized as `file:line:column`
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_callsite"))]
+    pub callsite: Option<&'static Location<'static>>,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_callsite<S: serde::Serializer>(
+    callsite: &Option<&'static Location<'static>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match callsite {
+        Some(location) => serializer.collect_str(location),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg_attr(feature = "single-allocator", allow(dead_code))]
+pub(crate) struct Head {
+    // First node of the list, null when it's empty
+    first: *mut u8,
+    // Number of nodes in the list
+    len: usize,
+}
+
+// SAFETY: nodes are only accessed with the lock held
+unsafe impl Send for Head {}
+
+/// List of the live blocks of a backend
+///
+/// The node of a block is in its hidden tag, made of the previous node, the next node and the
+/// size of the block, all unaligned.
+pub struct LiveList {
+    #[cfg_attr(feature = "single-allocator", allow(dead_code))]
+    head: Mutex<Head>,
+}
+
+impl LiveList {
+    pub const fn new() -> Self {
+        Self {
+            head: Mutex::new(Head {
+                first: std::ptr::null_mut(),
+                len: 0,
+            }),
+        }
+    }
+
+    #[cfg(not(feature = "single-allocator"))]
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, Head> {
+        self.head.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for LiveList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(feature = "single-allocator"))]
+mod node {
+    use std::ptr;
+
+    const PREV: usize = 0;
+    const NEXT: usize = 1;
+    const SIZE: usize = 2;
+
+    // Links are stored as pointers, so they keep their provenance
+    #[inline(always)]
+    pub(super) unsafe fn get<T>(node: *mut u8, field: usize) -> T {
+        let field = unsafe { node.cast::<usize>().add(field).cast::<T>() };
+        // The nodes of the other blocks are poisoned
+        #[cfg(feature = "sanitizer")]
+        return unsafe {
+            crate::sanitizer::with_unpoisoned(field.cast(), size_of::<T>(), || {
+                ptr::read_unaligned(field)
+            })
+        };
+        #[cfg(not(feature = "sanitizer"))]
+        unsafe {
+            ptr::read_unaligned(field)
+        }
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn set<T>(node: *mut u8, field: usize, value: T) {
+        let field = unsafe { node.cast::<usize>().add(field).cast::<T>() };
+        #[cfg(feature = "sanitizer")]
+        unsafe {
+            crate::sanitizer::with_unpoisoned(field.cast(), size_of::<T>(), || {
+                ptr::write_unaligned(field, value)
+            })
+        };
+        #[cfg(not(feature = "sanitizer"))]
+        unsafe {
+            ptr::write_unaligned(field, value)
+        };
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn prev(node: *mut u8) -> *mut u8 {
+        unsafe { get(node, PREV) }
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn next(node: *mut u8) -> *mut u8 {
+        unsafe { get(node, NEXT) }
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn size(node: *mut u8) -> usize {
+        unsafe { get(node, SIZE) }
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn set_prev(node: *mut u8, prev: *mut u8) {
+        unsafe { set(node, PREV, prev) }
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn set_next(node: *mut u8, next: *mut u8) {
+        unsafe { set(node, NEXT, next) }
+    }
+
+    #[inline(always)]
+    pub(super) unsafe fn set_size(node: *mut u8, size: usize) {
+        unsafe { set(node, SIZE, size) }
+    }
+}
+
+#[cfg(not(feature = "single-allocator"))]
+impl LiveList {
+    /// Add the block of `size` bytes whose node is at `node`
+    ///
+    /// # Safety
+    ///
+    /// `node` must be valid for writing `SIZE` bytes and not be in the list.
+    pub(crate) unsafe fn link(&self, node: *mut u8, size: usize) {
+        let mut head = self.lock();
+        unsafe {
+            node::set_size(node, size);
+            node::set_prev(node, std::ptr::null_mut());
+            node::set_next(node, head.first);
+            if !head.first.is_null() {
+                node::set_prev(head.first, node);
+            }
+        }
+        head.first = node;
+        head.len += 1;
+    }
+
+    /// Remove the block whose node is at `node`
+    ///
+    /// # Safety
+    ///
+    /// `node` must be in the list.
+    pub(crate) unsafe fn unlink(&self, node: *mut u8) {
+        let mut head = self.lock();
+        unsafe {
+            let prev = node::prev(node);
+            let next = node::next(node);
+            if prev.is_null() {
+                head.first = next;
+            } else {
+                node::set_next(prev, next);
+            }
+            if !next.is_null() {
+                node::set_prev(next, prev);
+            }
+        }
+        head.len -= 1;
+    }
+}
+
+/// Contents of the hidden tag of a live block, read with the lock held
+#[cfg(not(feature = "single-allocator"))]
+#[derive(Clone, Copy)]
+struct Raw {
+    size: usize,
+    raw_tag: u16,
+    #[cfg(feature = "lifetimes")]
+    age: u64,
+    #[cfg(feature = "profiling")]
+    callsite: u16,
+}
+
+/// The `n` largest live blocks of `Backend`, largest first
+#[cfg(not(feature = "single-allocator"))]
+pub fn largest_live<Backend: MultiAllocatorBackend>(n: usize) -> Vec<AllocationInfo<Backend::Tag>> {
+    use crate::TagRepr;
+
+    // Allocated before taking the lock again, as allocating with the lock held would deadlock,
+    // and no larger than the list, as `n` may be e.g. `usize::MAX` for every block
+    let n = n.min(Backend::live_list().lock().len);
+    let mut largest: Vec<Raw> = Vec::with_capacity(n);
+    if n > 0 {
+        let head = Backend::live_list().lock();
+        let mut node = head.first;
+        while !node.is_null() {
+            let size = unsafe { node::size(node) };
+            if largest.len() < n || largest[n - 1].size < size {
+                let block = unsafe { node.sub(crate::node_offset::<Backend>()) };
+                let read = || Raw {
+                    size,
+                    raw_tag: unsafe { Backend::Repr::read(block) }.to_raw(),
+                    #[cfg(feature = "lifetimes")]
+                    age: unsafe {
+                        crate::lifetimes::age(block.add(crate::timestamp_offset::<Backend>()))
+                    },
+                    #[cfg(feature = "profiling")]
+                    callsite: unsafe {
+                        std::ptr::read_unaligned(
+                            block.add(crate::callsite_offset::<Backend>()).cast(),
+                        )
+                    },
+                };
+                #[cfg(feature = "sanitizer")]
+                let raw = unsafe {
+                    crate::sanitizer::with_unpoisoned(block, crate::node_offset::<Backend>(), read)
+                };
+                #[cfg(not(feature = "sanitizer"))]
+                let raw = read();
+                if largest.len() == n {
+                    largest.pop();
+                }
+                let index = largest.partition_point(|other| other.size >= size);
+                largest.insert(index, raw);
+            }
+            node = unsafe { node::next(node) };
+        }
+    }
+
+    largest
+        .into_iter()
+        .map(|raw| AllocationInfo {
+            size: raw.size,
+            raw_tag: raw.raw_tag,
+            tag: Backend::Repr::from_raw(raw.raw_tag).and_then(|tag| tag.try_into().ok()),
+            #[cfg(feature = "lifetimes")]
+            age: Some(Duration::from_nanos(raw.age)),
+            #[cfg(not(feature = "lifetimes"))]
+            age: None,
+            #[cfg(feature = "profiling")]
+            callsite: crate::profiling::location(raw.callsite),
+            #[cfg(not(feature = "profiling"))]
+            callsite: None,
+        })
+        .collect()
+}
+
+/// The `n` largest live blocks of `Backend`, always empty with the `single-allocator` feature
+#[cfg(feature = "single-allocator")]
+pub fn largest_live<Backend: MultiAllocatorBackend>(n: usize) -> Vec<AllocationInfo<Backend::Tag>> {
+    let _ = n;
+    Vec::new()
+}
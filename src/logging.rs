@@ -0,0 +1,174 @@
+//! Allocation events and summaries through the `log` facade
+//!
+//! With the `log` feature, the allocations and reallocations of
+//! [`MultiAllocator`](crate::MultiAllocator) are logged with the target [`TARGET`], or the one
+//! given to [`set_target`], when they're at least as large as the threshold given to
+//! [`set_threshold`], at the `Info` level, or when they're sampled with the rate given to
+//! [`set_sample_rate`], at the `Debug` level. Both are disabled by default. [`spawn_summaries`]
+//! also logs the statistics of each tag periodically, at the `Info` level:
+//!
+//! ```rust
+//! use std::{alloc::System, time::Duration};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     // A logger, e.g. `env_logger::init()`
+//!     okaoka::logging::set_threshold(1 << 20);
+//!     okaoka::logging::set_sample_rate(1000);
+//!     okaoka::logging::spawn_summaries::<GlobalAllocator>(Duration::from_secs(60)).unwrap();
+//!
+//!     // INFO okaoka::alloc: alloc 4194304 bytes at 0x7f3a5c000b70 with Arena
+//!     GlobalAllocator::with(AllocatorTag::Arena, || drop(vec![0u8; 4 << 20]));
+//! }
+//! ```
+//!
+//! ```text
+//! INFO okaoka::alloc: Arena: 12 live allocations, 1180 live bytes, 4194304 peak bytes
+//! ```
+//!
+//! The allocations made by the logger while it logs an event aren't logged themselves. With the
+//! `single-allocator` feature, every event is attributed to the default tag.
+
+use std::{
+    cell::Cell,
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::Level;
+
+use crate::{once::SetOnce, stats::tag_stats, MultiAllocatorBackend, TagRepr};
+
+/// Target of the records without [`set_target`]
+pub const TARGET: &str = "okaoka::alloc";
+
+static CUSTOM_TARGET: SetOnce<&'static str> = SetOnce::new();
+
+// Size from which an allocation is logged, `usize::MAX` to disable
+static THRESHOLD: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// One allocation out of `SAMPLE_RATE` is logged, 0 to disable
+static SAMPLE_RATE: AtomicUsize = AtomicUsize::new(0);
+
+static SAMPLE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Whether the thread is logging an event
+    static LOGGING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Log the records with `target` instead of [`TARGET`]
+///
+/// Fails with `target` if a target was already set.
+pub fn set_target(target: &'static str) -> Result<(), &'static str> {
+    CUSTOM_TARGET.set(target)
+}
+
+/// Target of the records
+pub fn target() -> &'static str {
+    CUSTOM_TARGET.get().unwrap_or(TARGET)
+}
+
+/// Log the allocations of `bytes` or more, `usize::MAX` to stop
+pub fn set_threshold(bytes: usize) {
+    THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+/// Log one allocation out of every `rate`, 0 to stop
+pub fn set_sample_rate(rate: usize) {
+    SAMPLE_RATE.store(rate, Ordering::Relaxed);
+}
+
+/// Log the statistics of each tag of `Backend` every `interval`, from a new thread
+pub fn spawn_summaries<Backend: MultiAllocatorBackend + 'static>(
+    interval: Duration,
+) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new()
+        .name("okaoka-log".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            log_summary::<Backend>();
+        })
+}
+
+/// Log the statistics of each tag of `Backend` now
+pub fn log_summary<Backend: MultiAllocatorBackend>() {
+    let target = target();
+    if !log::log_enabled!(target: target, Level::Info) {
+        return;
+    }
+    for &tag in Backend::TAGS {
+        let stats = tag_stats::<Backend>(tag);
+        log::info!(
+            target: target,
+            "{}: {} live allocations, {} live bytes, {} peak bytes",
+            Backend::tag_name(tag),
+            stats.live_allocations(),
+            stats.live_bytes,
+            stats.peak_bytes
+        );
+    }
+}
+
+/// Log the event if it's above the threshold or sampled
+#[inline(always)]
+pub(crate) fn record<Backend: MultiAllocatorBackend>(
+    event: &str,
+    raw_tag: u16,
+    size: usize,
+    ptr: *mut u8,
+) {
+    let level = if size >= THRESHOLD.load(Ordering::Relaxed) {
+        Level::Info
+    } else if sampled() {
+        Level::Debug
+    } else {
+        return;
+    };
+    record_slow::<Backend>(level, event, raw_tag, size, ptr);
+}
+
+/// Whether the allocation is one of those sampled
+#[inline(always)]
+fn sampled() -> bool {
+    match SAMPLE_RATE.load(Ordering::Relaxed) {
+        0 => false,
+        rate => SAMPLE_COUNTER
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(rate),
+    }
+}
+
+#[cold]
+#[inline(never)]
+fn record_slow<Backend: MultiAllocatorBackend>(
+    level: Level,
+    event: &str,
+    raw_tag: u16,
+    size: usize,
+    ptr: *mut u8,
+) {
+    // Logging may allocate, which must not log again
+    if LOGGING
+        .try_with(|logging| logging.replace(true))
+        .unwrap_or(true)
+    {
+        return;
+    }
+    let target = target();
+    if log::log_enabled!(target: target, level) {
+        let name = Backend::Repr::from_raw(raw_tag)
+            .and_then(|tag| Backend::Tag::try_from(tag).ok())
+            .map_or("an unknown allocator", Backend::tag_name);
+        log::log!(target: target, level, "{event} {size} bytes at {ptr:p} with {name}");
+    }
+    let _ = LOGGING.try_with(|logging| logging.set(false));
+}
@@ -0,0 +1,140 @@
+//! Moving values between allocators
+//!
+//! With the `migrate` feature, values that implement [`MigrateAlloc`] can be rebuilt with
+//! another allocator, e.g. to keep the results of a computation made with a transient arena.
+//! The copy is deep: containers rebuild their storage with the target allocator and migrate
+//! their elements.
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::migrate::MigrateAlloc;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut results = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || {
+//!         results = vec!["first".to_string(), "second".to_string()];
+//!     });
+//!     // The vector and both strings are now allocated with `System`
+//!     let results = results.migrate_to(AllocatorTag::System as u8);
+//!     assert_eq!(results, ["first", "second"]);
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hash},
+};
+
+use crate::AllocatorGuard;
+
+/// Value that can be rebuilt with another allocator
+pub trait MigrateAlloc: Sized {
+    /// Rebuild the value with the allocator identified by the raw `allocator_tag`
+    ///
+    /// The memory of the old value is deallocated by the allocator it was allocated with.
+    fn migrate_to(self, allocator_tag: impl Into<u16>) -> Self;
+}
+
+// Values without storage of their own are moved as they are
+macro_rules! impl_migrate_alloc_by_move {
+    ($($ty:ty),+) => {
+        $(
+            impl MigrateAlloc for $ty {
+                #[inline(always)]
+                fn migrate_to(self, _allocator_tag: impl Into<u16>) -> Self {
+                    self
+                }
+            }
+        )+
+    };
+}
+
+impl_migrate_alloc_by_move!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    &'static str
+);
+
+impl<T: MigrateAlloc> MigrateAlloc for Option<T> {
+    fn migrate_to(self, allocator_tag: impl Into<u16>) -> Self {
+        self.map(|value| value.migrate_to(allocator_tag))
+    }
+}
+
+impl MigrateAlloc for String {
+    fn migrate_to(self, allocator_tag: impl Into<u16>) -> Self {
+        let _guard = AllocatorGuard::new(allocator_tag);
+        let mut string = String::with_capacity(self.len());
+        string.push_str(&self);
+        string
+    }
+}
+
+impl<T: MigrateAlloc> MigrateAlloc for Box<T> {
+    fn migrate_to(self, allocator_tag: impl Into<u16>) -> Self {
+        let allocator_tag = allocator_tag.into();
+        let value = (*self).migrate_to(allocator_tag);
+        let _guard = AllocatorGuard::new(allocator_tag);
+        Box::new(value)
+    }
+}
+
+impl<T: MigrateAlloc> MigrateAlloc for Vec<T> {
+    fn migrate_to(self, allocator_tag: impl Into<u16>) -> Self {
+        let allocator_tag = allocator_tag.into();
+        let mut vec = {
+            let _guard = AllocatorGuard::new(allocator_tag);
+            Vec::with_capacity(self.len())
+        };
+        vec.extend(
+            self.into_iter()
+                .map(|value| value.migrate_to(allocator_tag)),
+        );
+        vec
+    }
+}
+
+impl<K, V, S> MigrateAlloc for HashMap<K, V, S>
+where
+    K: MigrateAlloc + Eq + Hash,
+    V: MigrateAlloc,
+    S: BuildHasher + Clone,
+{
+    fn migrate_to(self, allocator_tag: impl Into<u16>) -> Self {
+        let allocator_tag = allocator_tag.into();
+        let mut map = {
+            let _guard = AllocatorGuard::new(allocator_tag);
+            HashMap::with_capacity_and_hasher(self.len(), self.hasher().clone())
+        };
+        for (key, value) in self {
+            map.insert(
+                key.migrate_to(allocator_tag),
+                value.migrate_to(allocator_tag),
+            );
+        }
+        map
+    }
+}
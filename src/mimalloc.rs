@@ -0,0 +1,287 @@
+//! Per-tag mimalloc heaps
+//!
+//! With the `mimalloc-heaps` feature, an entry declared with `Name => static MiHeap =
+//! MiHeap::new()` allocates the blocks of its tag in a `mi_heap_t` of its own, created by
+//! mimalloc for each thread that allocates with the tag. The memory of a tag can then be freed at
+//! once with [`MiHeap::destroy`], e.g. at the end of a request, and its statistics come straight
+//! from mimalloc with [`MiHeap::stats`]. Backends created with
+//! [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get
+//! `mimalloc_heap(tag)`:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::mimalloc::MiHeap;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Request => static MiHeap = MiHeap::new(),
+//! }
+//!
+//! fn main() {
+//!     let heap = GlobalAllocator::mimalloc_heap(AllocatorTag::Request).unwrap();
+//!     GlobalAllocator::with(AllocatorTag::Request, || {
+//!         let body = vec![0u8; 4096];
+//!         std::mem::forget(body); // Freed with the heap
+//!     });
+//!     assert!(heap.stats().used >= 4096);
+//!
+//!     // SAFETY: nothing allocated by this thread with `Request` is used afterwards
+//!     unsafe { heap.destroy() };
+//!     assert_eq!(heap.stats().used, 0);
+//!     assert!(GlobalAllocator::mimalloc_heap(AllocatorTag::System).is_none());
+//! }
+//! ```
+//!
+//! A heap belongs to the thread that created it: blocks can be deallocated from any thread, but
+//! [`MiHeap::stats`] and [`MiHeap::destroy`] only cover the heap of the calling thread. The heap
+//! of a thread is deleted when the thread exits, moving its live blocks to the default heap of
+//! mimalloc. Destroying a heap doesn't go through the global allocator, so the statistics of the
+//! `stats` feature still count its blocks as live. A process has at most [`MAX_HEAPS`] `MiHeap`
+//! allocators, the next ones allocate from the default heap of mimalloc.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::Cell,
+    ffi::c_void,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use libmimalloc_sys::{
+    mi_free, mi_heap_area_t, mi_heap_delete, mi_heap_destroy, mi_heap_get_default,
+    mi_heap_malloc_aligned, mi_heap_new, mi_heap_realloc_aligned, mi_heap_t, mi_heap_visit_blocks,
+    mi_heap_zalloc_aligned,
+};
+
+use crate::MultiAllocatorBackend;
+
+/// Most [`MiHeap`] allocators with a heap of their own in a process
+pub const MAX_HEAPS: usize = 64;
+
+const UNASSIGNED: usize = usize::MAX;
+
+// Next slot of the heaps of the threads to give to a `MiHeap`
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+// Heaps of the thread, by slot, deleted when the thread exits before mimalloc deletes them
+struct ThreadHeaps([Cell<*mut mi_heap_t>; MAX_HEAPS]);
+
+impl Drop for ThreadHeaps {
+    fn drop(&mut self) {
+        for heap in &self.0 {
+            let heap = heap.replace(ptr::null_mut());
+            if !heap.is_null() {
+                unsafe { mi_heap_delete(heap) };
+            }
+        }
+    }
+}
+
+thread_local! {
+    static HEAPS: ThreadHeaps =
+        const { ThreadHeaps([const { Cell::new(ptr::null_mut()) }; MAX_HEAPS]) };
+}
+
+/// Statistics of a mimalloc heap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MiHeapStats {
+    /// Number of live blocks
+    pub blocks: usize,
+    /// Bytes of the live blocks, rounded up to their size class
+    pub used: usize,
+    /// Bytes committed for the blocks of the heap
+    pub committed: usize,
+}
+
+/// Allocator giving a tag its own mimalloc heap on each thread, see the module documentation
+pub struct MiHeap {
+    // Slot of the heaps of the threads, `UNASSIGNED` until the first allocation
+    slot: AtomicUsize,
+}
+
+impl MiHeap {
+    /// Allocator creating a mimalloc heap on each thread, on its first allocation with it
+    ///
+    /// It takes one of the [`MAX_HEAPS`] slots of the process on its first allocation, and
+    /// keeps it. Once every slot is taken, the next `MiHeap` allocators allocate from the
+    /// default heap of mimalloc, with no heap of their own to read the statistics of or to
+    /// destroy.
+    pub const fn new() -> Self {
+        Self {
+            slot: AtomicUsize::new(UNASSIGNED),
+        }
+    }
+
+    /// Slot of the heaps of this allocator, `None` if every slot is taken
+    #[inline(always)]
+    fn slot(&self) -> Option<usize> {
+        match self.slot.load(Ordering::Relaxed) {
+            UNASSIGNED => self.assign_slot(),
+            slot => Some(slot),
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn assign_slot(&self) -> Option<usize> {
+        let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        if slot >= MAX_HEAPS {
+            return None;
+        }
+        // Another thread may have assigned one first, wasting this one
+        match self
+            .slot
+            .compare_exchange(UNASSIGNED, slot, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => Some(slot),
+            Err(current) => Some(current),
+        }
+    }
+
+    /// Heap of the calling thread, created if needed, or the default heap if it can't be
+    #[inline(always)]
+    fn heap(&self) -> *mut mi_heap_t {
+        let heap = self.slot().and_then(|slot| {
+            HEAPS
+                .try_with(|heaps| {
+                    let heap = &heaps.0[slot];
+                    if heap.get().is_null() {
+                        heap.set(unsafe { mi_heap_new() });
+                    }
+                    heap.get()
+                })
+                .ok()
+        });
+        match heap {
+            Some(heap) if !heap.is_null() => heap,
+            // No slot left, the thread is exiting or mimalloc is out of memory
+            _ => unsafe { mi_heap_get_default() },
+        }
+    }
+
+    /// Call `f` with the heap of the calling thread, if it has one
+    fn with_thread_heap<R>(&self, f: impl FnOnce(&Cell<*mut mi_heap_t>) -> R) -> Option<R> {
+        let slot = match self.slot.load(Ordering::Relaxed) {
+            UNASSIGNED => return None,
+            slot => slot,
+        };
+        HEAPS
+            .try_with(|heaps| {
+                let heap = &heaps.0[slot];
+                (!heap.get().is_null()).then(|| f(heap))
+            })
+            .ok()
+            .flatten()
+    }
+
+    /// Statistics of the heap of the calling thread, read from mimalloc
+    pub fn stats(&self) -> MiHeapStats {
+        self.with_thread_heap(|heap| {
+            let mut stats = MiHeapStats::default();
+            unsafe {
+                mi_heap_visit_blocks(
+                    heap.get(),
+                    false,
+                    Some(visit_area),
+                    (&mut stats as *mut MiHeapStats).cast(),
+                )
+            };
+            stats
+        })
+        .unwrap_or_default()
+    }
+
+    /// Free every block of the heap of the calling thread at once, with `mi_heap_destroy`
+    ///
+    /// The next allocation of the thread creates a new heap.
+    ///
+    /// # Safety
+    ///
+    /// No block allocated with this allocator by the calling thread may be used or deallocated
+    /// afterwards, unless it was reallocated by another thread.
+    pub unsafe fn destroy(&self) {
+        self.with_thread_heap(|heap| unsafe { mi_heap_destroy(heap.replace(ptr::null_mut())) });
+    }
+}
+
+impl Default for MiHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe extern "C" fn visit_area(
+    _heap: *const mi_heap_t,
+    area: *const mi_heap_area_t,
+    _block: *mut c_void,
+    _block_size: usize,
+    stats: *mut c_void,
+) -> bool {
+    let (area, stats) = unsafe { (&*area, &mut *stats.cast::<MiHeapStats>()) };
+    // `used` is the number of live blocks of the area
+    stats.blocks += area.used;
+    stats.used += area.used * area.block_size;
+    stats.committed += area.committed;
+    true
+}
+
+unsafe impl GlobalAlloc for MiHeap {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { mi_heap_malloc_aligned(self.heap(), layout.size(), layout.align()).cast() }
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        unsafe { mi_heap_zalloc_aligned(self.heap(), layout.size(), layout.align()).cast() }
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe { mi_free(ptr.cast()) }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { mi_heap_realloc_aligned(self.heap(), ptr.cast(), new_size, layout.align()).cast() }
+    }
+}
+
+/// Heap of the allocator identified by `tag`, `None` if it isn't a [`MiHeap`]
+pub fn tag_heap<Backend: MultiAllocatorBackend>(tag: Backend::Tag) -> Option<&'static MiHeap> {
+    Backend::mimalloc_heap(tag)
+}
+
+/// Detection of [`MiHeap`] among the allocators of the entries, by autoref:
+/// `(&allocator).mimalloc_heap()` resolves to `ViaMiHeap` for `MiHeap` and to `ViaOther`
+/// otherwise
+#[doc(hidden)]
+pub mod dispatch {
+    use super::MiHeap;
+
+    pub trait ViaMiHeap {
+        fn mimalloc_heap(&'static self) -> Option<&'static MiHeap>;
+    }
+
+    impl ViaMiHeap for MiHeap {
+        #[inline(always)]
+        fn mimalloc_heap(&'static self) -> Option<&'static MiHeap> {
+            Some(self)
+        }
+    }
+
+    pub trait ViaOther {
+        fn mimalloc_heap(&self) -> Option<&'static MiHeap>;
+    }
+
+    impl<A> ViaOther for &A {
+        #[inline(always)]
+        fn mimalloc_heap(&self) -> Option<&'static MiHeap> {
+            None
+        }
+    }
+}
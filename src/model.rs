@@ -0,0 +1,331 @@
+//! Property testing against a reference model
+//!
+//! With the `proptest` feature, this module provides `proptest` strategies generating layouts
+//! and sequences of [`Op`]s, which allocate, reallocate and deallocate blocks with several tags
+//! inside nested scopes. With the `arbitrary` feature, [`Op`] implements `Arbitrary` for fuzzers
+//! instead. [`check`] runs the operations with the allocators of a backend and compares the
+//! blocks with a model of what they should be, to test a custom backend, see `ops`.
+//!
+//! Operations refer to tags by their index in [`MultiAllocatorBackend::TAGS`] and to blocks by
+//! their index among the live blocks, both wrapping around, so every sequence is valid for every
+//! backend. [`check`] verifies that the blocks are aligned, don't overlap and keep their contents,
+//! including when reallocated, and that each block is allocated by the allocator of its tag, or
+//! of its scope, unless the layout is routed by alignment. Allocations returning null are
+//! skipped, as an allocator may run out of memory. With the `single-allocator` feature, the
+//! allocators of the blocks aren't checked.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+};
+
+use crate::{AllocatorGuard, MultiAllocator, MultiAllocatorBackend};
+
+/// Largest alignment of the generated layouts
+pub const MAX_ALIGN: usize = 4096;
+
+/// Largest size of the generated layouts
+pub const MAX_SIZE: usize = 1 << 16;
+
+/// Operation run by [`check`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Allocate a block with the allocator of the tag at index `tag`, or of the current scope
+    /// if `None`
+    Alloc { tag: Option<usize>, layout: Layout },
+    /// Resize the live block at index `block` to `new_size` bytes
+    Realloc { block: usize, new_size: usize },
+    /// Deallocate the live block at index `block`
+    Dealloc { block: usize },
+    /// Enter a scope selecting the allocator of the tag at index `tag`
+    Enter { tag: usize },
+    /// Leave the innermost scope, if any
+    Leave,
+}
+
+/// Operation of [`check`] whose result doesn't match the model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelError {
+    /// Index of the operation
+    pub step: usize,
+    /// Operation
+    pub op: Op,
+    /// Difference with the model
+    pub message: String,
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step {} ({:?}): {}", self.step, self.op, self.message)
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+/// Live block of the model
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    /// Raw tag of the allocator expected to own the block, `None` if it isn't known
+    #[cfg_attr(feature = "single-allocator", allow(dead_code))]
+    raw_tag: Option<u16>,
+    /// Byte the block is filled with
+    fill: u8,
+}
+
+/// Run the operations with the allocators of `Backend`, checking the blocks against the model
+///
+/// The blocks still live and the scopes still entered at the end are released before returning.
+pub fn check<Backend: MultiAllocatorBackend>(ops: &[Op]) -> Result<(), ModelError> {
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut scopes: Vec<(u16, AllocatorGuard)> = Vec::new();
+    let result = ops.iter().enumerate().try_for_each(|(step, &op)| {
+        let error = |message: String| ModelError { step, op, message };
+        run::<Backend>(op, step, &mut blocks, &mut scopes).map_err(error)
+    });
+    for block in blocks {
+        unsafe { MultiAllocator::<Backend>::new().dealloc(block.ptr, block.layout) };
+    }
+    // Guards restore the scope they replaced, so they're dropped innermost first
+    while scopes.pop().is_some() {}
+    result
+}
+
+fn run<Backend: MultiAllocatorBackend>(
+    op: Op,
+    step: usize,
+    blocks: &mut Vec<Block>,
+    scopes: &mut Vec<(u16, AllocatorGuard)>,
+) -> Result<(), String> {
+    let tag = |index: usize| {
+        let tags = Backend::TAGS;
+        (!tags.is_empty()).then(|| tags[index % tags.len()])
+    };
+    match op {
+        Op::Alloc { tag: index, layout } => {
+            let allocator = MultiAllocator::<Backend>::new();
+            let expected = match index {
+                Some(index) => {
+                    let Some(tag) = tag(index) else {
+                        return Ok(());
+                    };
+                    allocator.set_tag(Some(tag));
+                    Some(Backend::raw_tag(tag))
+                }
+                None => scopes.last().map(|&(raw_tag, _)| raw_tag),
+            };
+            let expected = match Backend::aligned_route(layout) {
+                Some(tag) => Some(Backend::raw_tag(tag)),
+                None => expected,
+            };
+            let ptr = unsafe { allocator.alloc(layout) };
+            if ptr.is_null() {
+                return Ok(());
+            }
+            let block = Block {
+                ptr,
+                layout,
+                raw_tag: expected,
+                fill: step as u8,
+            };
+            check_placement(&block, blocks)?;
+            #[cfg(not(feature = "single-allocator"))]
+            check_tag::<Backend>(&block)?;
+            unsafe { ptr.write_bytes(block.fill, layout.size()) };
+            blocks.push(block);
+        }
+        Op::Realloc { block, new_size } => {
+            if blocks.is_empty() {
+                return Ok(());
+            }
+            let index = block % blocks.len();
+            let Some(new_layout) = Layout::from_size_align(new_size, blocks[index].layout.align())
+                .ok()
+                .filter(|layout| layout.size() > 0)
+            else {
+                return Ok(());
+            };
+            let old = &blocks[index];
+            let ptr =
+                unsafe { MultiAllocator::<Backend>::new().realloc(old.ptr, old.layout, new_size) };
+            if ptr.is_null() {
+                return check_contents(old);
+            }
+            let mut block = blocks.swap_remove(index);
+            let kept = block.layout.size().min(new_size);
+            block.ptr = ptr;
+            block.layout = Layout::from_size_align(kept, new_layout.align()).unwrap();
+            // The allocator of the block doesn't change
+            let result = check_contents(&block).and_then(|()| check_placement(&block, blocks));
+            #[cfg(not(feature = "single-allocator"))]
+            let result = result.and_then(|()| check_tag::<Backend>(&block));
+            block.layout = new_layout;
+            unsafe { ptr.write_bytes(block.fill, new_size) };
+            blocks.push(block);
+            result?;
+        }
+        Op::Dealloc { block } => {
+            if blocks.is_empty() {
+                return Ok(());
+            }
+            let block = blocks.swap_remove(block % blocks.len());
+            let result = check_contents(&block);
+            unsafe { MultiAllocator::<Backend>::new().dealloc(block.ptr, block.layout) };
+            result?;
+        }
+        Op::Enter { tag: index } => {
+            if let Some(tag) = tag(index) {
+                let raw_tag = Backend::raw_tag(tag);
+                scopes.push((raw_tag, AllocatorGuard::new(raw_tag)));
+            }
+        }
+        Op::Leave => {
+            scopes.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Check that `block` is aligned and doesn't overlap the other blocks
+fn check_placement(block: &Block, blocks: &[Block]) -> Result<(), String> {
+    let start = block.ptr as usize;
+    if !start.is_multiple_of(block.layout.align()) {
+        return Err(format!(
+            "{:p} isn't aligned to {}",
+            block.ptr,
+            block.layout.align()
+        ));
+    }
+    let end = start + block.layout.size();
+    match blocks.iter().find(|other| {
+        let other_start = other.ptr as usize;
+        start < other_start + other.layout.size() && other_start < end
+    }) {
+        Some(other) => Err(format!(
+            "{:p} of {} bytes overlaps {:p} of {} bytes",
+            block.ptr,
+            block.layout.size(),
+            other.ptr,
+            other.layout.size()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check that `block` still holds the bytes it was filled with
+fn check_contents(block: &Block) -> Result<(), String> {
+    let contents = unsafe { std::slice::from_raw_parts(block.ptr, block.layout.size()) };
+    match contents.iter().position(|&byte| byte != block.fill) {
+        Some(offset) => Err(format!("byte {offset} of {:p} was overwritten", block.ptr)),
+        None => Ok(()),
+    }
+}
+
+/// Check that `block` was allocated by the allocator expected by the model
+#[cfg(not(feature = "single-allocator"))]
+fn check_tag<Backend: MultiAllocatorBackend>(block: &Block) -> Result<(), String> {
+    #[cfg(feature = "random-tags")]
+    if crate::random_tags::seed().is_some() {
+        return Ok(());
+    }
+    let Some(expected) = block.raw_tag else {
+        return Ok(());
+    };
+    let raw_tag = unsafe { crate::block_raw_tag::<Backend>(block.ptr, block.layout) };
+    if raw_tag != expected {
+        return Err(format!(
+            "{:p} was allocated with tag {raw_tag} instead of {expected}",
+            block.ptr
+        ));
+    }
+    Ok(())
+}
+
+/// Strategy generating layouts of up to [`MAX_SIZE`] bytes aligned to up to [`MAX_ALIGN`],
+/// mostly small ones
+#[cfg(feature = "proptest")]
+pub fn layout() -> impl proptest::strategy::Strategy<Value = Layout> {
+    use proptest::prelude::*;
+
+    let size = prop_oneof![
+        4 => 1..=64usize,
+        2 => 1..=4096usize,
+        1 => 1..=MAX_SIZE,
+    ];
+    let align = prop_oneof![
+        4 => 0..=4u32,
+        1 => 0..=MAX_ALIGN.trailing_zeros(),
+    ];
+    (size, align).prop_map(|(size, align)| Layout::from_size_align(size, 1 << align).unwrap())
+}
+
+/// Strategy generating operations, mostly allocations and deallocations
+#[cfg(feature = "proptest")]
+pub fn op() -> impl proptest::strategy::Strategy<Value = Op> {
+    use proptest::prelude::*;
+
+    prop_oneof![
+        4 => (proptest::option::of(any::<usize>()), layout())
+            .prop_map(|(tag, layout)| Op::Alloc { tag, layout }),
+        2 => (any::<usize>(), 1..=MAX_SIZE)
+            .prop_map(|(block, new_size)| Op::Realloc { block, new_size }),
+        3 => any::<usize>().prop_map(|block| Op::Dealloc { block }),
+        1 => any::<usize>().prop_map(|tag| Op::Enter { tag }),
+        1 => Just(Op::Leave),
+    ]
+}
+
+/// Strategy generating sequences of operations with a length in `len`
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// use proptest::prelude::*;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Arena => System,
+/// }
+///
+/// fn main() {
+///     proptest!(ProptestConfig::with_cases(64), |(ops in okaoka::model::ops(0..64))| {
+///         okaoka::model::check::<GlobalAllocator>(&ops)
+///             .map_err(|error| TestCaseError::fail(error.to_string()))?;
+///     });
+/// }
+/// ```
+#[cfg(feature = "proptest")]
+pub fn ops(
+    len: impl Into<proptest::collection::SizeRange>,
+) -> impl proptest::strategy::Strategy<Value = Vec<Op>> {
+    proptest::collection::vec(op(), len)
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Op {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=10u8)? {
+            0..=3 => {
+                let size = u.int_in_range(1..=MAX_SIZE)?;
+                let align = 1 << u.int_in_range(0..=MAX_ALIGN.trailing_zeros())?;
+                Op::Alloc {
+                    tag: u.arbitrary()?,
+                    layout: Layout::from_size_align(size, align).unwrap(),
+                }
+            }
+            4..=5 => Op::Realloc {
+                block: u.arbitrary()?,
+                new_size: u.int_in_range(1..=MAX_SIZE)?,
+            },
+            6..=8 => Op::Dealloc {
+                block: u.arbitrary()?,
+            },
+            9 => Op::Enter {
+                tag: u.arbitrary()?,
+            },
+            _ => Op::Leave,
+        })
+    }
+}
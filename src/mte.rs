@@ -0,0 +1,250 @@
+//! Hardware memory tagging on aarch64
+//!
+//! With the `mte` feature, on aarch64 Linux, [`enable`] turns on the Memory Tagging Extension
+//! for the process, before it spawns any thread, and every block allocated afterwards by
+//! [`MultiAllocator`](crate::MultiAllocator) gets a random memory tag, set on its granules of 16
+//! bytes and in the top bits of the pointer returned. The hidden tag keeps the memory tag given
+//! by the allocator of the block, so an access through the pointer faults when it overflows into
+//! the next block or the hidden tag, or after the block is freed, as its granules get the memory
+//! tag of the allocator back. Faults are reported synchronously, as a `SIGSEGV` at the faulting
+//! access, which makes this meant for debug and staging builds:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//! }
+//!
+//! fn main() {
+//!     if let Err(error) = okaoka::mte::enable() {
+//!         println!("running without MTE: {error}");
+//!     }
+//!     let buffer = vec![0u8; 100];
+//!     assert_eq!(buffer.iter().sum::<u8>(), 0);
+//! }
+//! ```
+//!
+//! Only memory mapped with `PROT_MTE` holds memory tags, so the allocators of the backend have
+//! to provide it, like glibc with `GLIBC_TUNABLES=glibc.mem.tagging=1` or an arena mapped with
+//! `PROT_MTE`. Elsewhere the tags are ignored and nothing faults. The size of every block is
+//! rounded up to whole granules and its hidden tag aligned to them, on every target and before
+//! [`enable`] as well, so that blocks allocated before it can be deallocated after it.
+//! Reallocations get a new memory tag, like allocations. With the `single-allocator` feature,
+//! blocks aren't tagged.
+
+// Blocks aren't tagged with `single-allocator`
+#![cfg_attr(feature = "single-allocator", allow(dead_code))]
+
+use std::io;
+
+/// Size of the granules of memory that share a memory tag
+pub const GRANULE: usize = 16;
+
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+mod imp {
+    use std::{
+        arch::asm,
+        fs, io,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use super::GRANULE;
+
+    const HWCAP2_MTE: libc::c_ulong = 1 << 18;
+    const PR_SET_TAGGED_ADDR_CTRL: libc::c_int = 55;
+    const PR_TAGGED_ADDR_ENABLE: libc::c_ulong = 1;
+    const PR_MTE_TCF_SYNC: libc::c_ulong = 1 << 1;
+    const PR_MTE_TAG_SHIFT: u32 = 3;
+
+    /// Bits of the memory tag in a pointer
+    const TAG_SHIFT: u32 = 56;
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+
+    pub(super) fn enable() -> io::Result<()> {
+        if unsafe { libc::getauxval(libc::AT_HWCAP2) } & HWCAP2_MTE == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the CPU doesn't support MTE",
+            ));
+        }
+        // The control only applies to the calling thread and the threads it spawns afterwards
+        if threads()? > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "MTE must be enabled before spawning threads",
+            ));
+        }
+        // Synchronous faults, with random tags excluding 0, the tag of untagged memory
+        let control =
+            PR_TAGGED_ADDR_ENABLE | PR_MTE_TCF_SYNC | (0xfffe << PR_MTE_TAG_SHIFT) as libc::c_ulong;
+        if unsafe { libc::prctl(PR_SET_TAGGED_ADDR_CTRL, control, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ENABLED.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Number of threads of the process
+    fn threads() -> io::Result<usize> {
+        fs::read_to_string("/proc/self/status")?
+            .lines()
+            .find_map(|line| line.strip_prefix("Threads:"))
+            .and_then(|threads| threads.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no thread count"))
+    }
+
+    #[inline(always)]
+    pub(super) fn is_enabled() -> bool {
+        ENABLED.load(Ordering::Acquire)
+    }
+
+    /// Pointer to the first of `granules` granules with a random memory tag other than the tag of
+    /// `ptr`, set on the granules
+    #[target_feature(enable = "mte")]
+    pub(super) unsafe fn color(ptr: *mut u8, granules: usize) -> *mut u8 {
+        let exclude = 1u64 << ((ptr.addr() >> TAG_SHIFT) & 0xf);
+        let addr: usize;
+        unsafe {
+            asm!(
+                "irg {addr}, {ptr}, {exclude}",
+                addr = lateout(reg) addr,
+                ptr = in(reg) ptr.addr(),
+                exclude = in(reg) exclude,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        let tagged = ptr.with_addr(addr);
+        unsafe { set_tags(tagged, granules) };
+        tagged
+    }
+
+    /// Set the memory tag of `ptr` on the `granules` granules starting at `ptr`
+    #[target_feature(enable = "mte")]
+    pub(super) unsafe fn set_tags(ptr: *mut u8, granules: usize) {
+        for granule in 0..granules {
+            unsafe {
+                asm!(
+                    "stg {ptr}, [{ptr}]",
+                    ptr = in(reg) ptr.add(granule * GRANULE),
+                    options(nostack, preserves_flags),
+                );
+            }
+        }
+    }
+
+    /// `ptr` with the memory tag of its granule
+    #[target_feature(enable = "mte")]
+    pub(super) unsafe fn memory_tag(ptr: *mut u8) -> *mut u8 {
+        let mut tagged = ptr;
+        unsafe {
+            asm!(
+                "ldg {tagged}, [{tagged}]",
+                tagged = inout(reg) tagged,
+                options(readonly, nostack, preserves_flags),
+            );
+        }
+        tagged
+    }
+}
+
+/// Turn on MTE for the process, with synchronous tag check faults
+///
+/// It only applies to the calling thread and the threads spawned afterwards, so it must be
+/// called while the process has a single thread, typically at the start of `main`. Fails if the
+/// process already has other threads, if the target isn't aarch64 Linux, or if the CPU or the
+/// kernel doesn't support MTE, in which case blocks stay untagged.
+pub fn enable() -> io::Result<()> {
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    return imp::enable();
+    #[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "MTE is only supported on aarch64 Linux",
+    ))
+}
+
+/// Whether [`enable`] succeeded
+#[inline(always)]
+pub fn is_enabled() -> bool {
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    return imp::is_enabled();
+    #[cfg(not(all(target_arch = "aarch64", target_os = "linux")))]
+    false
+}
+
+/// Number of granules holding `size` bytes
+#[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+#[inline(always)]
+const fn granules(size: usize) -> usize {
+    size.div_ceil(GRANULE)
+}
+
+/// Give the block of `size` bytes at `ptr`, just allocated, a random memory tag, returning the
+/// pointer to give to the user
+///
+/// # Safety
+///
+/// `ptr` must be a block of `size` bytes rounded up to granules, aligned to a granule.
+#[inline(always)]
+pub(crate) unsafe fn color(ptr: *mut u8, size: usize) -> *mut u8 {
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    if is_enabled() {
+        return unsafe { imp::color(ptr, granules(size)) };
+    }
+    let _ = size;
+    ptr
+}
+
+/// Give the block of `size` bytes at `ptr`, after a hidden tag of `tag_size` bytes, the memory
+/// tag of its hidden tag back, returning the pointer to the block with that tag
+///
+/// # Safety
+///
+/// `ptr` must be a block of `size` bytes returned by [`color`] after a hidden tag of `tag_size`
+/// bytes.
+#[inline(always)]
+pub(crate) unsafe fn uncolor(ptr: *mut u8, tag_size: usize, size: usize) -> *mut u8 {
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    if is_enabled() {
+        return unsafe {
+            let ptr = imp::memory_tag(ptr.sub(tag_size)).add(tag_size);
+            imp::set_tags(ptr, granules(size));
+            ptr
+        };
+    }
+    let _ = (tag_size, size);
+    ptr
+}
+
+/// Set the memory tag of `ptr` on the block of `size` bytes at `ptr` again, after [`uncolor`]
+///
+/// # Safety
+///
+/// `ptr` must be a block of `size` bytes returned by [`color`].
+#[inline(always)]
+pub(crate) unsafe fn recolor(ptr: *mut u8, size: usize) {
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    if is_enabled() {
+        unsafe { imp::set_tags(ptr, granules(size)) };
+    }
+    let _ = (ptr, size);
+}
+
+/// `ptr` with the memory tag of its granule, to read the hidden tag at `ptr`
+///
+/// # Safety
+///
+/// `ptr` must be the hidden tag of a live block.
+#[cfg(any(feature = "proptest", feature = "arbitrary"))]
+#[inline(always)]
+pub(crate) unsafe fn with_memory_tag(ptr: *const u8) -> *const u8 {
+    #[cfg(all(target_arch = "aarch64", target_os = "linux"))]
+    if is_enabled() {
+        return unsafe { imp::memory_tag(ptr.cast_mut()) };
+    }
+    ptr
+}
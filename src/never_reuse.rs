@@ -0,0 +1,189 @@
+//! Debug allocator that never reuses addresses
+//!
+//! With the `never-reuse` feature, on unix, [`NeverReuse`] gives every block its own pages, taken
+//! one after the other from a large reservation of address space, and makes them inaccessible
+//! when the block is freed instead of handing them out again. Any use of a freed block then
+//! faults on the spot, unlike with poisoning or quarantines, which only catch the uses that
+//! happen to touch the poison or to occur before the block leaves the quarantine. A page left
+//! inaccessible between blocks also catches most overflows. Every block takes at least two pages
+//! of address space, so it's meant for debugging a tag, not for production:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::never_reuse::NeverReuse;
+//!
+//! static DEBUG: NeverReuse = NeverReuse::new();
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Suspect => DEBUG,
+//! }
+//!
+//! fn main() {
+//!     let mut addresses = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Suspect, || {
+//!         for _ in 0..10 {
+//!             let value = Box::new(10);
+//!             addresses.push(&*value as *const i32 as usize);
+//!             // Reading `*value` after this line would crash
+//!         }
+//!     });
+//!     addresses.dedup();
+//!     assert_eq!(addresses.len(), 10);
+//! }
+//! ```
+//!
+//! Allocations fail once the reservation is exhausted, [`DEFAULT_RESERVATION`] by default.
+//! Reallocating always moves the block, so stale pointers to the old block fault as well.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::os::page_size;
+
+/// Address space reserved by [`NeverReuse::new`], 1 TiB on 64-bit targets and 1 GiB on others
+#[cfg(target_pointer_width = "64")]
+pub const DEFAULT_RESERVATION: usize = 1 << 40;
+#[cfg(not(target_pointer_width = "64"))]
+pub const DEFAULT_RESERVATION: usize = 1 << 30;
+
+/// Allocator that never reuses the addresses of freed blocks, see the module documentation
+pub struct NeverReuse {
+    reservation: usize,
+    // Start of the reservation, null until the first allocation
+    base: AtomicPtr<u8>,
+    // Bytes of the reservation handed out, including the inaccessible pages between blocks
+    used: AtomicUsize,
+}
+
+impl NeverReuse {
+    /// Allocator reserving [`DEFAULT_RESERVATION`] bytes of address space
+    pub const fn new() -> Self {
+        Self::with_reservation(DEFAULT_RESERVATION)
+    }
+
+    /// Allocator reserving `bytes` of address space
+    pub const fn with_reservation(bytes: usize) -> Self {
+        Self {
+            reservation: bytes,
+            base: AtomicPtr::new(ptr::null_mut()),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes of address space reserved
+    pub const fn reservation(&self) -> usize {
+        self.reservation
+    }
+
+    /// Bytes of address space used by the blocks allocated so far, freed or not
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Start of the reservation, reserving it if needed, null if that fails
+    fn base(&self) -> *mut u8 {
+        let base = self.base.load(Ordering::Acquire);
+        if !base.is_null() {
+            return base;
+        }
+        let reserved = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                self.reservation,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if reserved == libc::MAP_FAILED {
+            return ptr::null_mut();
+        }
+        let reserved = reserved.cast::<u8>();
+        match self.base.compare_exchange(
+            ptr::null_mut(),
+            reserved,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => reserved,
+            // Another thread reserved it first
+            Err(base) => {
+                unsafe { libc::munmap(reserved.cast(), self.reservation) };
+                base
+            }
+        }
+    }
+}
+
+impl Default for NeverReuse {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Bytes of the pages holding a block of `size` bytes
+fn pages_len(size: usize, page_size: usize) -> Option<usize> {
+    Some(size.max(1).checked_add(page_size - 1)? & !(page_size - 1))
+}
+
+unsafe impl GlobalAlloc for NeverReuse {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.base();
+        if base.is_null() {
+            return ptr::null_mut();
+        }
+        let page_size = page_size();
+        let Some(len) = pages_len(layout.size(), page_size) else {
+            return ptr::null_mut();
+        };
+        let align = layout.align().max(page_size);
+        let mut used = self.used.load(Ordering::Relaxed);
+        let start = loop {
+            let start = used + base.wrapping_add(used).align_offset(align);
+            // The page after the block stays inaccessible
+            let end = match start.checked_add(len + page_size) {
+                Some(end) if end <= self.reservation => end,
+                _ => return ptr::null_mut(),
+            };
+            match self
+                .used
+                .compare_exchange_weak(used, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break start,
+                Err(current) => used = current,
+            }
+        };
+        let block = unsafe { base.add(start) };
+        let protected =
+            unsafe { libc::mprotect(block.cast(), len, libc::PROT_READ | libc::PROT_WRITE) };
+        if protected != 0 {
+            return ptr::null_mut();
+        }
+        block
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(len) = pages_len(layout.size(), page_size()) else {
+            return;
+        };
+        // Replace the pages with inaccessible ones, which also releases their memory
+        unsafe {
+            libc::mmap(
+                ptr.cast(),
+                len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+                -1,
+                0,
+            )
+        };
+    }
+}
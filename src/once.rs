@@ -0,0 +1,48 @@
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+/// Value set at most once, like `OnceLock::set` but without `std`
+pub(crate) struct SetOnce<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: the value is written once, before `SET` is published, and only read afterwards
+unsafe impl<T: Send + Sync> Sync for SetOnce<T> {}
+
+impl<T: Copy> SetOnce<T> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(UNSET),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Set the value, returning `value` back if it's already set, or being set by another thread
+    pub(crate) fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(SET, Ordering::Release);
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "single-allocator", allow(dead_code))]
+    #[inline(always)]
+    pub(crate) fn get(&self) -> Option<T> {
+        (self.state.load(Ordering::Acquire) == SET)
+            .then(|| unsafe { (*self.value.get()).assume_init() })
+    }
+}
@@ -0,0 +1,209 @@
+//! Recovery from allocation failures
+//!
+//! By default, an allocation that fails returns null and the standard library aborts with
+//! [`handle_alloc_error`](std::alloc::handle_alloc_error). Each tag can be given an
+//! [`OomStrategy`] with [`set_oom_strategy`], whose steps are tried in order until one of them
+//! succeeds:
+//!
+//! 1. Call the pressure hook, e.g. to drop caches.
+//! 2. Retry the allocation once.
+//! 3. Allocate with the allocator of another tag, which then also deallocates the block.
+//! 4. Dump the allocator state to stderr and abort the process.
+//!
+//! ```rust
+//! use std::{
+//!     alloc::{GlobalAlloc, Layout, System},
+//!     ptr,
+//!     sync::atomic::{AtomicUsize, Ordering},
+//! };
+//!
+//! use okaoka::oom::OomStrategy;
+//!
+//! /// Allocator that is always out of memory
+//! struct Exhausted;
+//!
+//! unsafe impl GlobalAlloc for Exhausted {
+//!     unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+//!         ptr::null_mut()
+//!     }
+//!
+//!     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+//! }
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Pool => Exhausted,
+//! }
+//!
+//! static PRESSURE: AtomicUsize = AtomicUsize::new(0);
+//!
+//! fn main() {
+//!     let strategy = OomStrategy::new()
+//!         .pressure_hook(|_tag, _layout| {
+//!             PRESSURE.fetch_add(1, Ordering::Relaxed);
+//!         })
+//!         .retry()
+//!         .fallback(AllocatorTag::System as u8);
+//!     okaoka::oom::set_oom_strategy(AllocatorTag::Pool as u8, strategy);
+//!
+//!     GlobalAllocator::with(AllocatorTag::Pool, || {
+//!         let x = Box::new(10); // Allocated with `System`
+//!         assert_eq!(*x, 10);
+//!     });
+//!     println!("{} allocations failed", PRESSURE.load(Ordering::Relaxed));
+//! }
+//! ```
+//!
+//! Reallocations stay in the allocator of the original allocation, so the fallback step doesn't
+//! apply to them. With the `single-allocator` feature, strategies are ignored.
+
+use std::{alloc::Layout, sync::RwLock};
+
+/// Hook called with the raw tag and the layout of an allocation that failed
+pub type PressureHook = fn(u16, Layout);
+
+/// Steps taken when an allocation of a tag fails, see the module documentation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OomStrategy {
+    pressure_hook: Option<PressureHook>,
+    retry: bool,
+    fallback: Option<u16>,
+    abort: bool,
+}
+
+impl OomStrategy {
+    /// Strategy that returns null right away, the default
+    pub const fn new() -> Self {
+        Self {
+            pressure_hook: None,
+            retry: false,
+            fallback: None,
+            abort: false,
+        }
+    }
+
+    /// Call `hook` first
+    ///
+    /// The hook is called inside the allocator with the current allocator unchanged, so it
+    /// should avoid allocating.
+    pub const fn pressure_hook(mut self, hook: PressureHook) -> Self {
+        self.pressure_hook = Some(hook);
+        self
+    }
+
+    /// Retry the allocation once
+    pub const fn retry(mut self) -> Self {
+        self.retry = true;
+        self
+    }
+
+    /// Allocate with the allocator identified by the raw `allocator_tag`
+    pub fn fallback(mut self, allocator_tag: impl Into<u16>) -> Self {
+        self.fallback = Some(allocator_tag.into());
+        self
+    }
+
+    /// Dump the allocator state to stderr and abort when every other step failed
+    pub const fn abort_with_dump(mut self) -> Self {
+        self.abort = true;
+        self
+    }
+}
+
+// Strategy of each raw tag that has one
+static STRATEGIES: RwLock<Vec<(u16, OomStrategy)>> = RwLock::new(Vec::new());
+
+/// Set the strategy of the allocator identified by the raw `allocator_tag`
+pub fn set_oom_strategy(allocator_tag: impl Into<u16>, strategy: OomStrategy) {
+    let allocator_tag = allocator_tag.into();
+    let mut strategies = STRATEGIES.write().unwrap_or_else(|e| e.into_inner());
+    strategies.retain(|&(tag, _)| tag != allocator_tag);
+    strategies.push((allocator_tag, strategy));
+}
+
+/// Strategies, locked for writing while the process forks
+#[cfg(all(unix, feature = "fork-safety"))]
+pub(crate) fn lock_strategies() -> std::sync::RwLockWriteGuard<'static, Vec<(u16, OomStrategy)>> {
+    STRATEGIES.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Strategy of the allocator identified by the raw `allocator_tag`
+pub fn oom_strategy(allocator_tag: impl Into<u16>) -> OomStrategy {
+    let allocator_tag = allocator_tag.into();
+    STRATEGIES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .find(|&&(tag, _)| tag == allocator_tag)
+        .map_or(OomStrategy::new(), |&(_, strategy)| strategy)
+}
+
+/// Strategy of `raw_tag` from inside the allocator, which must not wait for the lock since the
+/// failed allocation may have been made while setting a strategy
+#[cfg(not(feature = "single-allocator"))]
+fn find(raw_tag: u16) -> OomStrategy {
+    match STRATEGIES.try_read() {
+        Ok(strategies) => strategies
+            .iter()
+            .find(|&&(tag, _)| tag == raw_tag)
+            .map_or(OomStrategy::new(), |&(_, strategy)| strategy),
+        Err(_) => OomStrategy::new(),
+    }
+}
+
+/// Apply the strategy of `raw_tag` to an allocation that failed
+///
+/// `attempt` allocates with the allocator identified by the raw tag it's given, returning `None`
+/// on failure.
+#[cfg(not(feature = "single-allocator"))]
+#[cold]
+pub(crate) fn recover<Backend: crate::MultiAllocatorBackend, T>(
+    raw_tag: u16,
+    layout: Layout,
+    mut attempt: impl FnMut(u16) -> Option<T>,
+) -> Option<T> {
+    let strategy = find(raw_tag);
+    if let Some(hook) = strategy.pressure_hook {
+        hook(raw_tag, layout);
+    }
+    let retried = strategy.retry.then(|| attempt(raw_tag)).flatten();
+    let block = retried.or_else(|| strategy.fallback.and_then(&mut attempt));
+    if block.is_none() && strategy.abort {
+        dump::<Backend>(raw_tag, layout);
+        std::process::abort();
+    }
+    block
+}
+
+/// Write the failed allocation and the statistics of every tag to stderr, without allocating
+#[cfg(not(feature = "single-allocator"))]
+fn dump<Backend: crate::MultiAllocatorBackend>(raw_tag: u16, layout: Layout) {
+    use std::io::Write;
+
+    use crate::TagRepr;
+
+    let mut stderr = std::io::stderr().lock();
+    let tag_name = Backend::Repr::from_raw(raw_tag)
+        .and_then(|tag| Backend::Tag::try_from(tag).ok())
+        .map_or("unknown", Backend::tag_name);
+    let _ = writeln!(
+        stderr,
+        "okaoka: out of memory allocating {} bytes aligned to {} with {tag_name} ({raw_tag})",
+        layout.size(),
+        layout.align()
+    );
+    #[cfg(feature = "stats")]
+    for &tag in Backend::TAGS {
+        let stats = Backend::counters(tag).snapshot();
+        let _ = writeln!(
+            stderr,
+            "  {}: {} live allocations, {} live bytes, {} peak bytes",
+            Backend::tag_name(tag),
+            stats.live_allocations(),
+            stats.live_bytes,
+            stats.peak_bytes
+        );
+    }
+}
@@ -0,0 +1,19 @@
+//! Helpers shared by the modules calling the OS
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Size of the pages of the OS
+pub(crate) fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+                size if size > 0 => size as usize,
+                _ => 4096,
+            };
+            PAGE_SIZE.store(page_size, Ordering::Relaxed);
+            page_size
+        }
+        page_size => page_size,
+    }
+}
@@ -0,0 +1,234 @@
+//! OpenTelemetry metrics
+//!
+//! With the `otel` feature, [`register_metrics`] registers observable instruments that report the
+//! statistics of every tag of a backend to an OpenTelemetry [`Meter`], so they're exported with
+//! the other metrics of the application, e.g. through OTLP. Each measurement has a `tag`
+//! attribute with the name of the tag:
+//!
+//! | Instrument                 | Kind    | Unit |
+//! |----------------------------|---------|------|
+//! | `okaoka.allocations`       | counter |      |
+//! | `okaoka.deallocations`     | counter |      |
+//! | `okaoka.allocated_bytes`   | counter | `By` |
+//! | `okaoka.deallocated_bytes` | counter | `By` |
+//! | `okaoka.live_allocations`  | gauge   |      |
+//! | `okaoka.live_bytes`        | gauge   | `By` |
+//! | `okaoka.peak_bytes`        | gauge   | `By` |
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let meter = opentelemetry::global::meter("my-app");
+//!     okaoka::otel::register_metrics::<GlobalAllocator>(&meter);
+//! }
+//! ```
+//!
+//! The statistics are read when the meter provider collects them, so nothing is done on
+//! allocation.
+
+use opentelemetry::{
+    metrics::{AsyncInstrument, Meter},
+    KeyValue,
+};
+
+use crate::{stats::TagStats, MultiAllocatorBackend};
+
+/// Register the instruments reporting the statistics of `Backend` with `meter`
+pub fn register_metrics<Backend: MultiAllocatorBackend + 'static>(meter: &Meter) {
+    meter
+        .u64_observable_counter("okaoka.allocations")
+        .with_description("Number of allocations made")
+        .with_callback(observe::<Backend>(|stats| stats.allocations))
+        .build();
+    meter
+        .u64_observable_counter("okaoka.deallocations")
+        .with_description("Number of deallocations made")
+        .with_callback(observe::<Backend>(|stats| stats.deallocations))
+        .build();
+    meter
+        .u64_observable_counter("okaoka.allocated_bytes")
+        .with_description("Total bytes allocated")
+        .with_unit("By")
+        .with_callback(observe::<Backend>(|stats| stats.allocated_bytes))
+        .build();
+    meter
+        .u64_observable_counter("okaoka.deallocated_bytes")
+        .with_description("Total bytes deallocated")
+        .with_unit("By")
+        .with_callback(observe::<Backend>(|stats| stats.deallocated_bytes))
+        .build();
+    meter
+        .u64_observable_gauge("okaoka.live_allocations")
+        .with_description("Number of allocations that haven't been deallocated yet")
+        .with_callback(observe::<Backend>(TagStats::live_allocations))
+        .build();
+    meter
+        .u64_observable_gauge("okaoka.live_bytes")
+        .with_description("Bytes currently allocated")
+        .with_unit("By")
+        .with_callback(observe::<Backend>(|stats| stats.live_bytes))
+        .build();
+    meter
+        .u64_observable_gauge("okaoka.peak_bytes")
+        .with_description("Highest number of bytes allocated at once")
+        .with_unit("By")
+        .with_callback(observe::<Backend>(|stats| stats.peak_bytes))
+        .build();
+}
+
+/// Callback observing `value` for every tag of `Backend`
+fn observe<Backend: MultiAllocatorBackend + 'static>(
+    value: fn(&TagStats) -> usize,
+) -> impl Fn(&dyn AsyncInstrument<u64>) + Send + Sync + 'static {
+    move |instrument| {
+        for &tag in Backend::TAGS {
+            let stats = Backend::counters(tag).snapshot();
+            instrument.observe(
+                value(&stats) as u64,
+                &[KeyValue::new("tag", Backend::tag_name(tag))],
+            );
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "single-allocator")))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use opentelemetry::metrics::{
+        AsyncInstrumentBuilder, Callback, InstrumentProvider, ObservableCounter, ObservableGauge,
+    };
+
+    use super::*;
+    use crate::MultiAllocator;
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        System => System,
+        Arena => System,
+    }
+
+    /// Meter provider keeping the callbacks of the instruments, to run them like an exporter
+    #[derive(Default)]
+    struct Collector {
+        callbacks: Mutex<Vec<(String, Callback<u64>)>>,
+    }
+
+    impl Collector {
+        fn keep<I>(&self, builder: AsyncInstrumentBuilder<'_, I, u64>) {
+            let name = builder.name.into_owned();
+            let mut callbacks = self.callbacks.lock().unwrap();
+            callbacks.extend(
+                builder
+                    .callbacks
+                    .into_iter()
+                    .map(|callback| (name.clone(), callback)),
+            );
+        }
+
+        /// Value of each instrument by name and tag
+        fn collect(&self) -> HashMap<(String, String), u64> {
+            let measurements = Measurements::default();
+            for (name, callback) in self.callbacks.lock().unwrap().iter() {
+                *measurements.name.lock().unwrap() = name.clone();
+                callback(&measurements);
+            }
+            measurements.values.into_inner().unwrap()
+        }
+    }
+
+    impl InstrumentProvider for Collector {
+        fn u64_observable_counter(
+            &self,
+            builder: AsyncInstrumentBuilder<'_, ObservableCounter<u64>, u64>,
+        ) -> ObservableCounter<u64> {
+            self.keep(builder);
+            ObservableCounter::new()
+        }
+
+        fn u64_observable_gauge(
+            &self,
+            builder: AsyncInstrumentBuilder<'_, ObservableGauge<u64>, u64>,
+        ) -> ObservableGauge<u64> {
+            self.keep(builder);
+            ObservableGauge::new()
+        }
+    }
+
+    #[derive(Default)]
+    struct Measurements {
+        name: Mutex<String>,
+        values: Mutex<HashMap<(String, String), u64>>,
+    }
+
+    impl AsyncInstrument<u64> for Measurements {
+        fn observe(&self, measurement: u64, attributes: &[KeyValue]) {
+            let [attribute] = attributes else {
+                panic!("unexpected attributes {attributes:?}");
+            };
+            assert_eq!(attribute.key.as_str(), "tag");
+            let key = (
+                self.name.lock().unwrap().clone(),
+                attribute.value.to_string(),
+            );
+            assert!(self
+                .values
+                .lock()
+                .unwrap()
+                .insert(key, measurement)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn exports_the_statistics_of_every_tag() {
+        let collector = Arc::new(Collector::default());
+        register_metrics::<Backend>(&Meter::new(collector.clone()));
+
+        let allocator = MultiAllocator::<Backend>::new();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let mut blocks = Vec::new();
+        crate::with_allocator(BackendTag::Arena as u8, || {
+            for _ in 0..3 {
+                blocks.push(unsafe { allocator.alloc(layout) });
+            }
+        });
+        unsafe { allocator.dealloc(blocks.pop().unwrap(), layout) };
+
+        let values = collector.collect();
+        assert_eq!(values.len(), 7 * Backend::TAGS.len());
+        let value = |name: &str, tag: &str| values[&(format!("okaoka.{name}"), tag.to_owned())];
+        assert_eq!(value("allocations", "Arena"), 3);
+        assert_eq!(value("deallocations", "Arena"), 1);
+        assert_eq!(value("live_allocations", "Arena"), 2);
+        assert_eq!(value("allocated_bytes", "Arena"), 300);
+        assert_eq!(value("deallocated_bytes", "Arena"), 100);
+        assert_eq!(value("live_bytes", "Arena"), 200);
+        assert_eq!(value("peak_bytes", "Arena"), 300);
+        assert_eq!(value("allocations", "System"), 0);
+        assert_eq!(value("live_bytes", "System"), 0);
+
+        for ptr in blocks {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(
+            collector.collect()[&("okaoka.live_bytes".to_owned(), "Arena".to_owned())],
+            0
+        );
+    }
+}
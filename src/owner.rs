@@ -0,0 +1,86 @@
+//! Owners of allocations
+//!
+//! With the `owner` feature, the hidden tag put before every allocation also records who made
+//! it: a 16-bit owner ID, e.g. the ID of a subsystem, set for the current thread with
+//! [`with_owner`]. It's independent of the allocator, so the same owner can allocate with
+//! several allocators. With the `stats` feature, statistics are also kept for each pair of tag
+//! and owner, see [`stats::owner_stats`](crate::stats::owner_stats).
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! const RENDERER: u16 = 1;
+//!
+//! fn main() {
+//!     okaoka::owner::with_owner(RENDERER, || {
+//!         assert_eq!(okaoka::owner::current_owner(), RENDERER);
+//!         GlobalAllocator::with(AllocatorTag::Arena, || {
+//!             let _x = Box::new(10); // Allocated with the arena, owned by the renderer
+//!         });
+//!     });
+//! }
+//! ```
+//!
+//! Allocations made without an owner belong to owner `0`. With the `single-allocator` feature,
+//! there's no hidden tag, so owners aren't recorded.
+
+use std::cell::Cell;
+
+/// Size of the owner ID in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = std::mem::size_of::<u16>();
+
+thread_local! {
+    /// Owner of the allocations of the current thread
+    static ALLOCATOR_OWNER: Cell<u16> = const { Cell::new(0) };
+}
+
+/// Owner of the allocations of the current thread
+#[inline(always)]
+pub fn current_owner() -> u16 {
+    ALLOCATOR_OWNER.with(Cell::get)
+}
+
+/// Set the owner of the allocations inside the closure, restoring the previous owner after
+/// returning
+pub fn with_owner(owner: u16, mut closure: impl FnMut()) {
+    struct Restore(u16);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            ALLOCATOR_OWNER.with(|owner| owner.set(self.0));
+        }
+    }
+
+    let _restore = Restore(ALLOCATOR_OWNER.with(|old_owner| old_owner.replace(owner)));
+    closure();
+}
+
+/// Write `owner` to `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for writing `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn write(ptr: *mut u8, owner: u16) {
+    unsafe { std::ptr::write_unaligned(ptr.cast(), owner) }
+}
+
+/// Read an owner from `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for reading `SIZE` bytes.
+#[cfg(all(feature = "stats", not(feature = "single-allocator")))]
+#[inline(always)]
+pub(crate) unsafe fn read(ptr: *const u8) -> u16 {
+    unsafe { std::ptr::read_unaligned(ptr.cast()) }
+}
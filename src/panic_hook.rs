@@ -0,0 +1,142 @@
+//! Allocator context in panic messages
+//!
+//! With the `panic-hook` feature, [`install_panic_hook`] adds the allocator context of the
+//! panicking thread after the message of every panic:
+//!
+//! - the current tag,
+//! - the scopes entered with [`with_allocator`](crate::with_allocator) and
+//!   [`AllocatorGuard`], outermost first,
+//! - with the `stats` feature, the live bytes of every tag.
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     okaoka::install_panic_hook::<GlobalAllocator>();
+//!     let result = std::panic::catch_unwind(|| {
+//!         GlobalAllocator::with(AllocatorTag::Arena, || panic!("out of arena"));
+//!     });
+//!     // Printed to stderr:
+//!     // allocator context of thread 'main':
+//!     //   tag: Arena (1)
+//!     //   scopes: Arena (1)
+//!     assert!(result.is_err());
+//! }
+//! ```
+
+use std::{
+    cell::Cell,
+    fmt::{self, Write},
+    panic, thread,
+};
+
+use crate::{AllocatorGuard, MultiAllocatorBackend, TagRepr};
+
+/// Number of nested scopes recorded, deeper scopes are only counted
+const MAX_SCOPES: usize = 32;
+
+thread_local! {
+    // Tags of the scopes of the thread, outermost first
+    static SCOPES: [Cell<Option<u16>>; MAX_SCOPES] = const { [const { Cell::new(None) }; MAX_SCOPES] };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Record a scope entered with `allocator_tag`
+#[inline(always)]
+pub(crate) fn push_scope(allocator_tag: Option<u16>) {
+    let depth = DEPTH.with(|depth| depth.replace(depth.get() + 1));
+    if depth < MAX_SCOPES {
+        SCOPES.with(|scopes| scopes[depth].set(allocator_tag));
+    }
+}
+
+/// Forget the innermost scope
+#[inline(always)]
+pub(crate) fn pop_scope() {
+    DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+}
+
+/// Install a panic hook that prints the allocator context of the panicking thread, then calls
+/// the previous hook
+///
+/// Tags are named after the tags of `Backend`. The context is printed with the default allocator
+/// of the backend, so panics caused by the current allocator don't panic again.
+pub fn install_panic_hook<Backend: MultiAllocatorBackend>() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // Read before the guard below adds a scope
+        let tag = crate::get_allocator_tag();
+        let depth = DEPTH.with(Cell::get);
+        let scopes: [Option<u16>; MAX_SCOPES] =
+            SCOPES.with(|scopes| std::array::from_fn(|i| scopes[i].get()));
+
+        let _guard = AllocatorGuard::with_tag(None);
+        let mut context = String::new();
+        let _ =
+            write_context::<Backend>(&mut context, tag, &scopes[..depth.min(MAX_SCOPES)], depth);
+        previous(info);
+        eprint!("{context}");
+    }));
+}
+
+fn write_context<Backend: MultiAllocatorBackend>(
+    out: &mut String,
+    tag: Option<u16>,
+    scopes: &[Option<u16>],
+    depth: usize,
+) -> fmt::Result {
+    let thread = thread::current();
+    writeln!(
+        out,
+        "allocator context of thread '{}':",
+        thread.name().unwrap_or("<unnamed>")
+    )?;
+    write!(out, "  tag: ")?;
+    write_tag::<Backend>(out, tag)?;
+    writeln!(out)?;
+
+    write!(out, "  scopes: ")?;
+    if scopes.is_empty() {
+        write!(out, "none")?;
+    }
+    for (i, scope) in scopes.iter().enumerate() {
+        if i > 0 {
+            write!(out, " > ")?;
+        }
+        write_tag::<Backend>(out, *scope)?;
+    }
+    if depth > scopes.len() {
+        write!(out, " > ({} more)", depth - scopes.len())?;
+    }
+    writeln!(out)?;
+
+    #[cfg(feature = "stats")]
+    {
+        write!(out, "  live bytes:")?;
+        for &tag in Backend::TAGS {
+            let live_bytes = Backend::counters(tag).snapshot().live_bytes;
+            write!(out, " {}={live_bytes}", Backend::tag_name(tag))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn write_tag<Backend: MultiAllocatorBackend>(out: &mut String, tag: Option<u16>) -> fmt::Result {
+    let Some(raw_tag) = tag else {
+        let tag = Backend::default_tag();
+        return write!(out, "default, {}", Backend::tag_name(tag));
+    };
+    match Backend::Repr::from_raw(raw_tag).and_then(|tag| Backend::Tag::try_from(tag).ok()) {
+        Some(tag) => write!(out, "{} ({raw_tag})", Backend::tag_name(tag)),
+        None if Backend::dynamic_slot(raw_tag).is_some() => write!(out, "dynamic ({raw_tag})"),
+        None => write!(out, "unknown ({raw_tag})"),
+    }
+}
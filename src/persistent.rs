@@ -0,0 +1,284 @@
+//! Arena backed by a memory-mapped file
+//!
+//! With the `persistent` feature, on unix, a [`PersistentArena`] carves blocks from a file mapped
+//! in memory, so the data built under its tag is written to the file and can be inspected
+//! offline, or used again by the next run of the process if it only links its parts with offsets
+//! from [`PersistentArena::base`] instead of pointers. The arena is declared empty and mapped at
+//! startup with [`PersistentArena::open`], allocations failing until then:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::persistent::PersistentArena;
+//!
+//! static STORE: PersistentArena = PersistentArena::new();
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Store => STORE,
+//! }
+//!
+//! fn main() {
+//!     let path = std::env::temp_dir().join(format!("okaoka-{}.bin", std::process::id()));
+//!     STORE.open(&path, 1 << 20, 1).unwrap();
+//!
+//!     let mut table = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Store, || {
+//!         table = Vec::<u64>::with_capacity(1000);
+//!     });
+//!     table.extend(0..1000);
+//!     let offset = table.as_ptr() as usize - STORE.base() as usize;
+//!     STORE.set_root(Some(offset));
+//!     STORE.flush().unwrap();
+//!     std::mem::forget(table);
+//!
+//!     assert_eq!(STORE.root(), Some(offset));
+//!     std::fs::remove_file(path).unwrap();
+//! }
+//! ```
+//!
+//! The file starts with a header holding a magic number, the version of the header format, the
+//! version of the data given to [`PersistentArena::open`], the size of the file, the bytes used
+//! and the root offset. Deallocating does nothing, the file only grows until it's full.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    fs::OpenOptions,
+    io,
+    mem::size_of,
+    os::fd::AsRawFd,
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Magic number at the start of the file
+pub const MAGIC: [u8; 8] = *b"OKAOKAPA";
+
+/// Version of the format of the header
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Size of the header, after which blocks are allocated
+pub const HEADER_SIZE: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    format_version: u32,
+    version: u32,
+    size: u64,
+    // End of the last block
+    used: AtomicU64,
+    // Offset of the root, 0 if there's none
+    root: AtomicU64,
+}
+
+const _: () = assert!(size_of::<Header>() <= HEADER_SIZE);
+
+/// Arena backed by a memory-mapped file, see the module documentation
+pub struct PersistentArena {
+    base: AtomicPtr<u8>,
+    size: AtomicUsize,
+    // Serializes the calls to `open`
+    opening: Mutex<()>,
+}
+
+impl PersistentArena {
+    /// Arena that isn't mapped yet
+    pub const fn new() -> Self {
+        Self {
+            base: AtomicPtr::new(ptr::null_mut()),
+            size: AtomicUsize::new(0),
+            opening: Mutex::new(()),
+        }
+    }
+
+    /// Map the file at `path`, creating it with `size` bytes if it doesn't exist or is empty
+    ///
+    /// An existing file is mapped with its own size, and keeps the blocks allocated by the
+    /// previous runs. It's rejected with [`io::ErrorKind::InvalidData`] if its header is invalid
+    /// or if it was created with another `version`. The arena can only be opened once.
+    pub fn open(&self, path: impl AsRef<Path>, size: usize, version: u32) -> io::Result<()> {
+        let _opening = self.opening.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_open() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the persistent arena is already open",
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let created = file.metadata()?.len() == 0;
+        if created {
+            file.set_len(size.max(HEADER_SIZE) as u64)?;
+        }
+        let size = usize::try_from(file.metadata()?.len())
+            .map_err(|_| invalid_data("the file is too large to be mapped"))?;
+        if size < HEADER_SIZE {
+            return Err(invalid_data("the file is too small for the header"));
+        }
+
+        let base = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let base = base.cast::<u8>();
+
+        let header = base.cast::<Header>();
+        if created {
+            unsafe {
+                header.write(Header {
+                    magic: MAGIC,
+                    format_version: FORMAT_VERSION,
+                    version,
+                    size: size as u64,
+                    used: AtomicU64::new(HEADER_SIZE as u64),
+                    root: AtomicU64::new(0),
+                })
+            };
+        } else if let Err(error) = unsafe { check(&*header, size, version) } {
+            unsafe { libc::munmap(base.cast(), size) };
+            return Err(error);
+        }
+
+        self.size.store(size, Ordering::Relaxed);
+        self.base.store(base, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether the file is mapped
+    pub fn is_open(&self) -> bool {
+        !self.base.load(Ordering::Acquire).is_null()
+    }
+
+    /// Start of the mapped file, null until it's open
+    pub fn base(&self) -> *mut u8 {
+        self.base.load(Ordering::Acquire)
+    }
+
+    /// Size of the mapped file, 0 until it's open
+    pub fn capacity(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Bytes used by the header and the blocks, 0 until the file is open
+    pub fn used(&self) -> usize {
+        self.header()
+            .map_or(0, |header| header.used.load(Ordering::Relaxed) as usize)
+    }
+
+    /// Offset of the root of the data stored in the file, from [`Self::base`]
+    pub fn root(&self) -> Option<usize> {
+        match self.header()?.root.load(Ordering::Acquire) {
+            0 => None,
+            root => Some(root as usize),
+        }
+    }
+
+    /// Set the offset of the root of the data stored in the file, ignored until it's open
+    pub fn set_root(&self, root: Option<usize>) {
+        if let Some(header) = self.header() {
+            header
+                .root
+                .store(root.unwrap_or(0) as u64, Ordering::Release);
+        }
+    }
+
+    /// Write the changes made to the mapped file back to the file
+    pub fn flush(&self) -> io::Result<()> {
+        let base = self.base();
+        if base.is_null() {
+            return Ok(());
+        }
+        match unsafe { libc::msync(base.cast(), self.capacity(), libc::MS_SYNC) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn header(&self) -> Option<&Header> {
+        let base = self.base();
+        (!base.is_null()).then(|| unsafe { &*base.cast::<Header>() })
+    }
+}
+
+impl Default for PersistentArena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Check the header of an existing file of `size` bytes
+fn check(header: &Header, size: usize, version: u32) -> io::Result<()> {
+    if header.magic != MAGIC {
+        return Err(invalid_data("the file isn't a persistent arena"));
+    }
+    if header.format_version != FORMAT_VERSION {
+        return Err(invalid_data(
+            "the header of the file has an unsupported format",
+        ));
+    }
+    if header.version != version {
+        return Err(invalid_data("the data of the file has another version"));
+    }
+    let used = header.used.load(Ordering::Relaxed);
+    if header.size != size as u64 || used < HEADER_SIZE as u64 || used > size as u64 {
+        return Err(invalid_data("the header of the file is corrupted"));
+    }
+    Ok(())
+}
+
+unsafe impl GlobalAlloc for PersistentArena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(header) = self.header() else {
+            return ptr::null_mut();
+        };
+        let base = self.base();
+        let size = self.capacity();
+        let mut used = header.used.load(Ordering::Relaxed);
+        loop {
+            let start = used as usize
+                + base
+                    .wrapping_add(used as usize)
+                    .align_offset(layout.align());
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= size => end,
+                _ => return ptr::null_mut(),
+            };
+            match header.used.compare_exchange_weak(
+                used,
+                end as u64,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return unsafe { base.add(start) },
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
@@ -0,0 +1,185 @@
+//! Thread pool with groups of workers pinned to allocators
+//!
+//! A [`Pool`] is made of groups of worker threads, each group using one allocator as the
+//! default allocator of its threads, e.g. to keep a noisy subsystem on its own heap. Closures
+//! are submitted to the group of a tag with [`Pool::spawn`]:
+//!
+//! ```rust
+//! use std::{alloc::System, sync::mpsc};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Jemalloc => jemallocator::Jemalloc,
+//! }
+//!
+//! fn main() {
+//!     let pool = okaoka::pool::Builder::new()
+//!         .group(AllocatorTag::Jemalloc as u8, 4)
+//!         .group(AllocatorTag::System as u8, 2)
+//!         .build()
+//!         .unwrap();
+//!
+//!     let (sender, receiver) = mpsc::channel();
+//!     pool.spawn(AllocatorTag::Jemalloc as u8, move || {
+//!         let squares: Vec<u64> = (0..10).map(|x| x * x).collect(); // Allocated with jemalloc
+//!         sender.send(squares.iter().sum::<u64>()).unwrap();
+//!     })
+//!     .unwrap();
+//!     assert_eq!(receiver.recv().unwrap(), 285);
+//! }
+//! ```
+//!
+//! Dropping the pool waits for the submitted closures to finish.
+
+use std::{
+    collections::HashMap,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use crate::{AllocatorGuard, InvalidTagError};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Builder of a [`Pool`]
+#[derive(Debug, Default)]
+pub struct Builder {
+    // Raw tag and number of threads of each group
+    groups: Vec<(u16, usize)>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a group of `threads` workers using the allocator identified by the raw
+    /// `allocator_tag`
+    ///
+    /// Adding a group for a tag that already has one adds `threads` workers to it.
+    pub fn group(mut self, allocator_tag: impl Into<u16>, threads: usize) -> Self {
+        self.groups.push((allocator_tag.into(), threads));
+        self
+    }
+
+    /// Spawn the workers of every group
+    pub fn build(self) -> io::Result<Pool> {
+        let mut pool = Pool {
+            groups: HashMap::new(),
+        };
+        for (allocator_tag, threads) in self.groups {
+            let group = pool
+                .groups
+                .entry(allocator_tag)
+                .or_insert_with(|| Group::new(allocator_tag));
+            for _ in 0..threads {
+                group.spawn_worker()?;
+            }
+        }
+        Ok(pool)
+    }
+}
+
+/// Workers using the same allocator
+struct Group {
+    allocator_tag: u16,
+    // `None` once the pool is dropped, which stops the workers
+    sender: Option<mpsc::Sender<Job>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Group {
+    fn new(allocator_tag: u16) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            allocator_tag,
+            sender: Some(sender),
+            receiver: Arc::new(Mutex::new(receiver)),
+            workers: Vec::new(),
+        }
+    }
+
+    fn spawn_worker(&mut self) -> io::Result<()> {
+        let allocator_tag = self.allocator_tag;
+        let receiver = self.receiver.clone();
+        let name = format!("okaoka-pool-{allocator_tag}-{}", self.workers.len());
+        let worker = thread::Builder::new().name(name).spawn(move || {
+            let _guard = AllocatorGuard::new(allocator_tag);
+            loop {
+                // The lock is released before running the job
+                let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                match job {
+                    // A panicking job is reported by the panic hook and doesn't stop the worker
+                    Ok(job) => drop(panic::catch_unwind(AssertUnwindSafe(job))),
+                    Err(mpsc::RecvError) => break,
+                }
+            }
+        })?;
+        self.workers.push(worker);
+        Ok(())
+    }
+}
+
+/// Thread pool whose groups of workers use different allocators, see the module documentation
+pub struct Pool {
+    groups: HashMap<u16, Group>,
+}
+
+impl Pool {
+    /// Run `job` on a worker of the group of the raw `allocator_tag`
+    ///
+    /// Fails if the pool has no group for `allocator_tag`, or if the group has no worker.
+    pub fn spawn(
+        &self,
+        allocator_tag: impl Into<u16>,
+        job: impl FnOnce() + Send + 'static,
+    ) -> Result<(), InvalidTagError> {
+        let allocator_tag = allocator_tag.into();
+        let sender = self
+            .groups
+            .get(&allocator_tag)
+            .filter(|group| !group.workers.is_empty())
+            .and_then(|group| group.sender.as_ref())
+            .ok_or(InvalidTagError::new(allocator_tag))?;
+        sender
+            .send(Box::new(job))
+            .map_err(|_| InvalidTagError::new(allocator_tag))
+    }
+
+    /// Number of workers of the group of the raw `allocator_tag`
+    pub fn threads(&self, allocator_tag: impl Into<u16>) -> usize {
+        self.groups
+            .get(&allocator_tag.into())
+            .map_or(0, |group| group.workers.len())
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for group in self.groups.values_mut() {
+            group.sender = None;
+        }
+        for group in self.groups.values_mut() {
+            for worker in group.workers.drain(..) {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut groups: Vec<_> = self
+            .groups
+            .values()
+            .map(|group| (group.allocator_tag, group.workers.len()))
+            .collect();
+        groups.sort_unstable();
+        f.debug_struct("Pool").field("groups", &groups).finish()
+    }
+}
@@ -0,0 +1,228 @@
+//! Statistics of allocation sites
+//!
+//! With the `profiling` feature, every allocation is attributed to a callsite: the source
+//! location of the innermost scope that switched allocators, e.g. the call to
+//! [`with_allocator`](crate::with_allocator), a backend's `with` or a guard constructor, or of
+//! the innermost [`with_callsite`]. The hidden tag of the allocation remembers its callsite, and
+//! statistics are kept for each pair of callsite and tag. This is much cheaper than capturing a
+//! backtrace on every allocation, but precise enough to find the sites that allocate the most.
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut cache = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || cache = vec![0u8; 4096]);
+//!     let mut names = Vec::new();
+//!     okaoka::profiling::with_callsite(|| names.push("first".to_string()));
+//!
+//!     let top = okaoka::profiling::callsite_stats();
+//!     assert_eq!(top.len(), 2);
+//!     assert_eq!(top[0].stats.live_bytes, 4096);
+//!     okaoka::profiling::dump::<GlobalAllocator>(std::io::stderr(), 10).unwrap();
+//! }
+//! ```
+//!
+//! Up to [`CAPACITY`] callsites and pairs of callsite and tag are recorded, allocations of other
+//! ones are only counted in the statistics of their tag. With the `single-allocator` feature,
+//! there's no hidden tag, so nothing is recorded.
+
+use std::{
+    cell::Cell,
+    io,
+    panic::Location,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use crate::{
+    stats::{Counters, TagStats},
+    table::Table,
+    MultiAllocatorBackend, TagRepr,
+};
+
+/// Maximum number of callsites and of pairs of callsite and tag
+pub const CAPACITY: usize = 1024;
+
+/// Size of the callsite in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = std::mem::size_of::<u16>();
+
+type Callsite = &'static Location<'static>;
+
+thread_local! {
+    /// Callsite of the allocations of the current thread
+    static CURRENT_CALLSITE: Cell<Option<Callsite>> = const { Cell::new(None) };
+}
+
+/// Callsite of the allocations of the current thread
+#[inline(always)]
+pub fn current_callsite() -> Option<&'static Location<'static>> {
+    CURRENT_CALLSITE.with(Cell::get)
+}
+
+/// Set the callsite of the current thread, returning the previous one
+#[inline(always)]
+pub(crate) fn replace_callsite(callsite: Option<Callsite>) -> Option<Callsite> {
+    CURRENT_CALLSITE.with(|current| current.replace(callsite))
+}
+
+/// Attribute the allocations inside the closure to the location of the call, restoring the
+/// previous callsite after returning
+#[track_caller]
+pub fn with_callsite(mut closure: impl FnMut()) {
+    struct Restore(Option<Callsite>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            replace_callsite(self.0);
+        }
+    }
+
+    let _restore = Restore(replace_callsite(Some(Location::caller())));
+    closure();
+}
+
+/// Statistics of a pair of callsite and tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CallsiteStats {
+    /// Serialized as `file:line:column`
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_location"))]
+    pub location: &'static Location<'static>,
+    /// Raw tag of the allocator
+    pub raw_tag: u16,
+    pub stats: TagStats,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_location<S: serde::Serializer>(
+    location: &&'static Location<'static>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(location)
+}
+
+// Interned callsites, the index in the hidden tag is the index in this table plus one
+static CALLSITES: [AtomicPtr<Location<'static>>; CAPACITY] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; CAPACITY];
+
+// Pairs of callsite and tag, packed with `key`
+static COUNTERS: Table<u32, Counters, CAPACITY> = Table::new([const { Counters::new() }; CAPACITY]);
+
+/// Index of `callsite` to write in the hidden tag, adding it if needed, 0 if there's no room
+///
+/// Callsites are compared by value, as the same location can have several addresses.
+#[cfg(not(feature = "single-allocator"))]
+fn intern(callsite: Callsite) -> u16 {
+    let hash = (u64::from(callsite.line()) << 32) | u64::from(callsite.column());
+    let new = ptr::from_ref(callsite).cast_mut();
+    for index in crate::table::probe(hash, CAPACITY) {
+        let entry = &CALLSITES[index];
+        let found = match entry.load(Ordering::Acquire) {
+            found if found.is_null() => {
+                match entry.compare_exchange(found, new, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => new,
+                    Err(found) => found,
+                }
+            }
+            found => found,
+        };
+        if found == new || unsafe { *found == *callsite } {
+            return index as u16 + 1;
+        }
+    }
+    0
+}
+
+/// Index of the current callsite to write in the hidden tag, 0 if there's none
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) fn current_index() -> u16 {
+    current_callsite().map_or(0, intern)
+}
+
+#[cfg(not(feature = "single-allocator"))]
+fn key(raw_tag: u16, index: u16) -> u32 {
+    (u32::from(raw_tag) << 16) | u32::from(index)
+}
+
+/// Counters of the callsite written at `ptr` with the allocator identified by `raw_tag`
+///
+/// # Safety
+///
+/// `ptr` must be valid for reading `SIZE` bytes, which don't need to be aligned.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn counters(raw_tag: u16, ptr: *const u8) -> Option<&'static Counters> {
+    match unsafe { ptr::read_unaligned(ptr.cast::<u16>()) } {
+        0 => None,
+        index => COUNTERS.get_or_insert(key(raw_tag, index)),
+    }
+}
+
+/// Write the index of a callsite to `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for writing `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn write(ptr: *mut u8, index: u16) {
+    unsafe { ptr::write_unaligned(ptr.cast(), index) }
+}
+
+/// Callsite of the index written in a hidden tag
+#[cfg(all(
+    any(feature = "live", feature = "env-log"),
+    not(feature = "single-allocator")
+))]
+pub(crate) fn location(index: u16) -> Option<&'static Location<'static>> {
+    let callsite = CALLSITES.get(usize::from(index).checked_sub(1)?)?;
+    unsafe { callsite.load(Ordering::Acquire).as_ref() }
+}
+
+/// Statistics of every pair of callsite and tag that has allocated, by decreasing live bytes
+pub fn callsite_stats() -> Vec<CallsiteStats> {
+    let mut stats: Vec<_> = COUNTERS
+        .iter()
+        .filter_map(|(key, counters)| {
+            let callsite = CALLSITES.get(usize::from(key as u16).checked_sub(1)?)?;
+            let location = unsafe { callsite.load(Ordering::Acquire).as_ref()? };
+            Some(CallsiteStats {
+                location,
+                raw_tag: (key >> 16) as u16,
+                stats: counters.snapshot(),
+            })
+        })
+        .collect();
+    stats.sort_by_key(|callsite| std::cmp::Reverse(callsite.stats.live_bytes));
+    stats
+}
+
+/// Write the `top` pairs of callsite and tag with the most live bytes to `out`, naming tags
+/// after the tags of `Backend`
+pub fn dump<Backend: MultiAllocatorBackend>(mut out: impl io::Write, top: usize) -> io::Result<()> {
+    writeln!(out, "okaoka callsites:")?;
+    for callsite in callsite_stats().iter().take(top) {
+        let tag_name = Backend::Repr::from_raw(callsite.raw_tag)
+            .and_then(|tag| Backend::Tag::try_from(tag).ok())
+            .map_or("unknown", Backend::tag_name);
+        writeln!(
+            out,
+            "  {} ({tag_name}): {} live allocations, {} live bytes, {} allocations",
+            callsite.location,
+            callsite.stats.live_allocations(),
+            callsite.stats.live_bytes,
+            callsite.stats.allocations
+        )?;
+    }
+    Ok(())
+}
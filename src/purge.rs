@@ -0,0 +1,114 @@
+//! Purging of large freed blocks
+//!
+//! Allocators that keep their memory once it's freed, like arenas, leave the pages of large
+//! freed blocks resident, so the RSS of the process stays at the peak of their tag. With the
+//! `purge` feature, on unix, [`Purge`] wraps an allocator and returns the pages of every freed
+//! block of at least `MIN_SIZE` bytes to the OS with `madvise(MADV_DONTNEED)` before deallocating
+//! it, so the RSS follows the live blocks of tags that hold big transient buffers:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::purge::Purge;
+//!
+//! // Stands in for an arena
+//! static BUFFERS: Purge<System, { 1 << 20 }> = Purge::new(System);
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Buffers => BUFFERS,
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::with(AllocatorTag::Buffers, || {
+//!         let buffer = vec![1u8; 4 << 20];
+//!         drop(buffer);
+//!     });
+//!     assert!(BUFFERS.purged_bytes() >= 3 << 20);
+//! }
+//! ```
+//!
+//! Only the pages entirely inside a block are purged, and they read as zeros once the allocator
+//! reuses them. The inner allocator must hand out private anonymous memory: the memory of a
+//! `MAP_SHARED` or file-backed mapping, like the one of `persistent::PersistentArena`, isn't
+//! freed, and the pages of a private file mapping revert to the contents of the file.
+//!
+//! Enabling the feature on another target than unix is a compile error.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Allocator purging the blocks of at least `MIN_SIZE` bytes freed with `A`, see the module
+/// documentation
+pub struct Purge<A, const MIN_SIZE: usize> {
+    inner: A,
+    purged_bytes: AtomicUsize,
+}
+
+impl<A, const MIN_SIZE: usize> Purge<A, MIN_SIZE> {
+    /// Allocator purging the pages of the blocks of at least `MIN_SIZE` bytes freed with `inner`
+    ///
+    /// `inner` must hand out private anonymous memory, like `System` or an arena over it: the
+    /// pages of a shared mapping aren't freed, and the ones of a private file mapping revert to
+    /// the contents of the file.
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            purged_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wrapped allocator
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Bytes returned to the OS so far
+    pub fn purged_bytes(&self) -> usize {
+        self.purged_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Purge the pages entirely inside the block at `ptr` of `size` bytes
+    fn purge(&self, ptr: *mut u8, size: usize) {
+        let page_size = crate::os::page_size();
+        let start = ptr.align_offset(page_size);
+        let Some(len) = size.checked_sub(start).map(|len| len & !(page_size - 1)) else {
+            return;
+        };
+        if len == 0 {
+            return;
+        }
+        let purged = unsafe { libc::madvise(ptr.add(start).cast(), len, libc::MADV_DONTNEED) };
+        if purged == 0 {
+            self.purged_bytes.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc, const MIN_SIZE: usize> GlobalAlloc for Purge<A, MIN_SIZE> {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.inner.alloc(layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        unsafe { self.inner.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.size() >= MIN_SIZE {
+            self.purge(ptr, layout.size());
+        }
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { self.inner.realloc(ptr, layout, new_size) }
+    }
+}
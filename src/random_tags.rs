@@ -0,0 +1,104 @@
+//! Random allocators for testing
+//!
+//! With the `random-tags` feature, [`enable`] makes every allocation of the threads pick a tag
+//! of the backend at random instead of their current tag, whatever allocator they selected with
+//! [`with_allocator`](crate::with_allocator). Running a test suite this way finds the code
+//! assuming that some data lives in a specific allocator. The seed of a failing run reproduces
+//! it:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let seed = okaoka::random_tags::enable_from_env();
+//!     println!("tags randomized with OKAOKA_TAG_SEED={seed}");
+//!
+//!     let mut values = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || values.extend(0..1000));
+//!     assert_eq!(values.len(), 1000);
+//! }
+//! ```
+//!
+//! The tags are drawn from a single sequence for the whole program, so a seed gives the same
+//! allocators to a single-threaded program, but the interleaving of threads changes which
+//! thread gets which tag. Only the tags declared in the backend are drawn, not the allocators
+//! added at runtime. Instances of [`MultiAllocator`](crate::MultiAllocator) with a tag set with
+//! `set_tag` and the tags routed by alignment keep their allocator, as do reallocated blocks.
+//! With the `single-allocator` feature, there's a single allocator, so nothing changes.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::MultiAllocatorBackend;
+
+/// Environment variable read by [`enable_from_env`]
+pub const SEED_VAR: &str = "OKAOKA_TAG_SEED";
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Pick the tags of the allocations at random from the sequence of `seed`, for every thread
+pub fn enable(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+    STATE.store(seed, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Pick the tags at random with the seed in [`SEED_VAR`], or a seed from the clock if it isn't
+/// set, returning the seed to print it
+///
+/// # Panics
+///
+/// If the variable isn't an integer.
+pub fn enable_from_env() -> u64 {
+    let seed = match std::env::var(SEED_VAR) {
+        Ok(seed) => seed
+            .parse()
+            .unwrap_or_else(|_| panic!("{SEED_VAR} isn't an integer: {seed:?}")),
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64),
+    };
+    enable(seed);
+    seed
+}
+
+/// Go back to allocating with the current tag of the threads
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Seed of the tags, `None` if they aren't random
+pub fn seed() -> Option<u64> {
+    ENABLED
+        .load(Ordering::Acquire)
+        .then(|| SEED.load(Ordering::Relaxed))
+}
+
+/// Random tag of `Backend` for the next allocation, `None` if the tags aren't random
+#[cfg_attr(feature = "single-allocator", allow(dead_code))]
+#[inline(always)]
+pub(crate) fn pick<Backend: MultiAllocatorBackend>() -> Option<Backend::Tag> {
+    if !ENABLED.load(Ordering::Relaxed) || Backend::TAGS.is_empty() {
+        return None;
+    }
+    // SplitMix64, whose state is a counter shared by every thread
+    let mut x = STATE
+        .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+        .wrapping_add(GOLDEN_GAMMA);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    Some(Backend::TAGS[(x % Backend::TAGS.len() as u64) as usize])
+}
@@ -0,0 +1,165 @@
+//! Randomized placement of blocks
+//!
+//! With the `randomized` feature, an entry declared with `Name(randomized) => Allocator` places
+//! every block of its tag at a random offset inside a larger block of `Allocator`, leaving a
+//! random gap of up to [`DEFAULT_MAX_GAP`] bytes before it, and parks every freed block in a
+//! random slot of a small quarantine, freeing the block it evicts instead. The distance between
+//! two blocks and the block reusing a freed one are then unpredictable, which makes heap grooming
+//! and the exploitation of an overflow or a use after free in the allocations of the tag much
+//! harder. It's meant for the tags of security-sensitive subsystems, like parsers of untrusted
+//! input:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Untrusted(randomized) => System,
+//!     Keys(randomized, max_gap: 4096) => System,
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::with(AllocatorTag::Untrusted, || {
+//!         let packets: Vec<Vec<u8>> = (0..100).map(|_| vec![0u8; 64]).collect();
+//!         let mut distances: Vec<_> = packets
+//!             .windows(2)
+//!             .map(|pair| pair[1].as_ptr() as isize - pair[0].as_ptr() as isize)
+//!             .collect();
+//!         distances.dedup();
+//!         assert!(distances.len() > 1);
+//!     });
+//! }
+//! ```
+//!
+//! The offsets are drawn from a fast generator seeded once from the random keys of the standard
+//! library, not from a cryptographic one, so they hinder exploitation without guaranteeing it
+//! fails. Every block takes up to `max_gap` bytes more, and [`QUARANTINE_SLOTS`] freed blocks are
+//! kept per tag. The `randomized` benchmark measures the overhead, run with
+//! `cargo bench --bench randomized --features randomized`. Reallocating always moves the block.
+//! With the `single-allocator` feature, every block goes to the default allocator, so nothing is
+//! randomized.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Maximum gap left before a block by [`Randomized::new`]
+pub const DEFAULT_MAX_GAP: usize = 256;
+
+/// Number of freed blocks kept in the quarantine of a [`Randomized`]
+pub const QUARANTINE_SLOTS: usize = 16;
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Size of the offset stored just before every block
+const OFFSET_SIZE: usize = std::mem::size_of::<usize>();
+
+// Freed blocks of the backing allocator, with their layout
+struct Quarantine([Option<(*mut u8, Layout)>; QUARANTINE_SLOTS]);
+
+// SAFETY: the blocks are only accessed with the lock held
+unsafe impl Send for Quarantine {}
+
+/// Allocator placing the blocks at random in the blocks of another one, see the module
+/// documentation
+pub struct Randomized {
+    backing: &'static (dyn GlobalAlloc + Sync),
+    max_gap: usize,
+    // State of the generator, 0 until the first allocation seeds it
+    state: AtomicU64,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl Randomized {
+    /// Allocator randomizing the placement of the blocks it allocates with `backing`
+    pub const fn new(backing: &'static (dyn GlobalAlloc + Sync)) -> Self {
+        Self {
+            backing,
+            max_gap: DEFAULT_MAX_GAP,
+            state: AtomicU64::new(0),
+            quarantine: Mutex::new(Quarantine([None; QUARANTINE_SLOTS])),
+        }
+    }
+
+    /// Set the maximum gap left before a block, in bytes
+    pub const fn max_gap(mut self, bytes: usize) -> Self {
+        self.max_gap = bytes;
+        self
+    }
+
+    /// Next random number
+    fn next(&self) -> u64 {
+        if self.state.load(Ordering::Relaxed) == 0 {
+            self.seed();
+        }
+        // SplitMix64
+        let mut z = self
+            .state
+            .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+            .wrapping_add(GOLDEN_GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn seed(&self) {
+        let seed = RandomState::new().hash_one(self as *const Self as usize) | 1;
+        // Another thread may have seeded it first
+        let _ = self
+            .state
+            .compare_exchange(0, seed, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    /// Alignment of the block of the backing allocator holding a block of `layout`, which the gap
+    /// is a multiple of
+    fn backing_align(layout: Layout) -> usize {
+        layout.align().max(OFFSET_SIZE)
+    }
+}
+
+unsafe impl GlobalAlloc for Randomized {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let align = Self::backing_align(layout);
+        // The gap holds at least the offset of the block
+        let gaps = self.max_gap / align;
+        let offset = align * (1 + (self.next() % (gaps as u64 + 1)) as usize);
+        let Some(size) = layout.size().checked_add(offset) else {
+            return ptr::null_mut();
+        };
+        let Ok(backing_layout) = Layout::from_size_align(size, align) else {
+            return ptr::null_mut();
+        };
+        let block = unsafe { self.backing.alloc(backing_layout) };
+        if block.is_null() {
+            return block;
+        }
+        let ptr = unsafe { block.add(offset) };
+        unsafe { ptr.sub(OFFSET_SIZE).cast::<usize>().write(offset) };
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let offset = unsafe { ptr.sub(OFFSET_SIZE).cast::<usize>().read() };
+        let block = unsafe { ptr.sub(offset) };
+        let backing_layout = unsafe {
+            Layout::from_size_align_unchecked(layout.size() + offset, Self::backing_align(layout))
+        };
+        let slot = (self.next() % QUARANTINE_SLOTS as u64) as usize;
+        let evicted = self.quarantine.lock().unwrap_or_else(|e| e.into_inner()).0[slot]
+            .replace((block, backing_layout));
+        if let Some((block, backing_layout)) = evicted {
+            unsafe { self.backing.dealloc(block, backing_layout) };
+        }
+    }
+}
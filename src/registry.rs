@@ -0,0 +1,588 @@
+//! Lock-free registry of allocators added at runtime
+//!
+//! A [`Registry`] is a fixed-capacity array of slots, each holding at most one allocator behind
+//! an atomic pointer, so finding the allocator of a slot is a single atomic load. Backends
+//! created with the `dynamic = N` option of
+//! [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) have a registry of
+//! `N` slots, which take the `2 * N` highest raw tags of the backend, one for each generation of
+//! a slot. The generation is the lowest bit of the raw tag.
+//!
+//! Those backends get `register(allocator)`, which stores an allocator in a free slot and
+//! returns its [`DynamicTag`], and `unregister(tag)`, which empties the slot again:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     dynamic = 16,
+//!     System => System,
+//! }
+//!
+//! fn main() {
+//!     // E.g. an allocator brought by a plugin
+//!     let tag = GlobalAllocator::register(Box::new(jemallocator::Jemalloc)).ok().unwrap();
+//!     okaoka::with_allocator(tag, || {
+//!         let _x = Box::new(10); // Allocated with the registered allocator
+//!     });
+//!     // SAFETY: nothing allocated by the registered allocator is alive
+//!     unsafe { GlobalAllocator::unregister(tag) };
+//! }
+//! ```
+//!
+//! The allocator behind a tag can be replaced while it's in use with `swap(tag, allocator)`,
+//! e.g. to canary a new allocator. Blocks allocated before the swap are still deallocated by the
+//! previous allocator, which is given back by `reclaim(tag)` once they are all gone:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     dynamic = 16,
+//!     System => System,
+//! }
+//!
+//! fn main() {
+//!     let tag = GlobalAllocator::register(Box::new(System)).ok().unwrap();
+//!     let mut old = None;
+//!     okaoka::with_allocator(tag, || old = Some(Box::new(10)));
+//!
+//!     GlobalAllocator::swap(tag, Box::new(jemallocator::Jemalloc)).ok().unwrap();
+//!     okaoka::with_allocator(tag, || {
+//!         let _new = Box::new(20); // Allocated with jemalloc
+//!     });
+//!     // `None`, `old` was allocated with `System`
+//!     let _ = GlobalAllocator::reclaim(tag);
+//!
+//!     drop(old); // Deallocated with `System`
+//!     let _system = GlobalAllocator::reclaim(tag);
+//! }
+//! ```
+
+use alloc::boxed::Box;
+use core::{alloc::GlobalAlloc, ptr, sync::atomic::Ordering};
+
+use crate::sync::{const_fn_unless_loom, AtomicBool, AtomicPtr, AtomicUsize};
+
+/// Allocator that can be stored in a [`Registry`]
+pub type DynAllocator = dyn GlobalAlloc + Send + Sync;
+
+/// Tag of an allocator registered at runtime
+///
+/// Returned by the `register` function of backends created with the `dynamic = N` option. Like
+/// the raw tags of the tag enum, it's selected with [`with_allocator`](crate::with_allocator) or
+/// [`AllocatorGuard::new`](crate::AllocatorGuard::new).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DynamicTag {
+    raw_tag: u16,
+}
+
+impl DynamicTag {
+    #[doc(hidden)]
+    pub const fn new(raw_tag: u16) -> Self {
+        Self { raw_tag }
+    }
+
+    /// Raw value of the tag
+    pub const fn raw_tag(self) -> u16 {
+        self.raw_tag
+    }
+}
+
+impl From<DynamicTag> for u16 {
+    fn from(tag: DynamicTag) -> Self {
+        tag.raw_tag
+    }
+}
+
+/// Slot of a [`Registry`], holding at most one allocator
+///
+/// An allocator can be swapped for another one while it's in use. New allocations go to the new
+/// allocator, while the blocks allocated before the swap are deallocated by the previous one,
+/// which is retired until they are all gone. The two allocators are told apart by the
+/// generation of the slot, which is part of the hidden tag of every block.
+pub struct Slot {
+    // Thin pointers to the boxed allocators of each generation, null when there's none
+    allocators: [AtomicPtr<Box<DynAllocator>>; 2],
+    // Generation of the allocator that new blocks are allocated with
+    generation: AtomicUsize,
+    // Number of blocks allocated by the allocator of each generation
+    live: [AtomicUsize; 2],
+    // Taken while the allocators are being replaced
+    busy: AtomicBool,
+}
+
+impl Slot {
+    const_fn_unless_loom! {
+        fn new() -> Self {
+            Self {
+                allocators: [
+                    AtomicPtr::new(ptr::null_mut()),
+                    AtomicPtr::new(ptr::null_mut()),
+                ],
+                generation: AtomicUsize::new(0),
+                live: [AtomicUsize::new(0), AtomicUsize::new(0)],
+                busy: AtomicBool::new(false),
+            }
+        }
+    }
+
+    /// Allocator that new blocks are allocated with
+    #[inline(always)]
+    pub fn get(&self) -> Option<&DynAllocator> {
+        self.allocator(self.generation.load(Ordering::SeqCst))
+    }
+
+    /// Whether there's no allocator for new blocks
+    ///
+    /// Unlike [`Self::get`], it doesn't read the allocator, which a swap followed by a reclaim
+    /// may drop in the meantime.
+    #[cfg(not(feature = "single-allocator"))]
+    #[inline(always)]
+    pub(crate) fn is_empty(&self) -> bool {
+        let generation = self.generation.load(Ordering::SeqCst);
+        self.allocators[generation]
+            .load(Ordering::Acquire)
+            .is_null()
+    }
+
+    /// Allocator of `generation`
+    #[inline(always)]
+    pub(crate) fn allocator(&self, generation: usize) -> Option<&DynAllocator> {
+        let allocator = self.allocators[generation].load(Ordering::Acquire);
+        unsafe { allocator.as_ref() }.map(|allocator| &**allocator)
+    }
+
+    /// Reserve `count` blocks in the current generation, returning it
+    ///
+    /// The generation is checked again after reserving the blocks, so that a swap can't retire
+    /// it without seeing them.
+    #[cfg(not(feature = "single-allocator"))]
+    #[inline(always)]
+    pub(crate) fn enter(&self, count: usize) -> usize {
+        loop {
+            let generation = self.generation.load(Ordering::SeqCst);
+            self.live[generation].fetch_add(count, Ordering::SeqCst);
+            if self.generation.load(Ordering::SeqCst) == generation {
+                return generation;
+            }
+            self.live[generation].fetch_sub(count, Ordering::SeqCst);
+        }
+    }
+
+    /// Release `count` blocks of `generation`
+    #[cfg(not(feature = "single-allocator"))]
+    #[inline(always)]
+    pub(crate) fn leave(&self, generation: usize, count: usize) {
+        self.live[generation].fetch_sub(count, Ordering::SeqCst);
+    }
+
+    /// Number of blocks allocated by the allocator of `generation` that are still alive
+    pub fn live_blocks(&self, generation: usize) -> usize {
+        self.live[generation].load(Ordering::SeqCst)
+    }
+
+    /// Generation of the allocator that new blocks are allocated with
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Run `f` while no other thread replaces the allocators, `None` if one is
+    fn lock<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        if self
+            .busy
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        let result = f();
+        self.busy.store(false, Ordering::Release);
+        Some(result)
+    }
+
+    /// Wait until no other thread replaces the allocators, and keep them from doing so until
+    /// [`Self::unlock_after_fork`]
+    ///
+    /// Taken before `fork`, so that the child doesn't inherit the slot locked by a thread that
+    /// doesn't exist in it, or half swapped.
+    #[cfg(feature = "fork-safety")]
+    pub(crate) fn lock_for_fork(&self) {
+        while self
+            .busy
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Let other threads replace the allocators again, after [`Self::lock_for_fork`]
+    #[cfg(feature = "fork-safety")]
+    pub(crate) fn unlock_after_fork(&self) {
+        self.busy.store(false, Ordering::Release);
+    }
+
+    /// Store `allocator` if the slot is empty, giving it back otherwise
+    fn try_insert(&self, allocator: Box<DynAllocator>) -> Result<(), Box<DynAllocator>> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let new = Box::into_raw(Box::new(allocator));
+        match self.allocators[generation].compare_exchange(
+            ptr::null_mut(),
+            new,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(*unsafe { Box::from_raw(new) }),
+        }
+    }
+
+    /// Make `allocator` the allocator of new blocks, retiring the current one
+    ///
+    /// Returns the allocator retired by the previous swap, if any. Gives `allocator` back if
+    /// the allocator retired by the previous swap still has live blocks or if another thread
+    /// is replacing the allocators.
+    fn swap(
+        &self,
+        allocator: Box<DynAllocator>,
+    ) -> Result<Option<Box<DynAllocator>>, Box<DynAllocator>> {
+        let mut allocator = Some(allocator);
+        let swapped = self.lock(|| {
+            let retired = 1 - self.generation.load(Ordering::SeqCst);
+            if self.live[retired].load(Ordering::SeqCst) != 0 {
+                return None;
+            }
+            let new = Box::into_raw(Box::new(allocator.take().unwrap()));
+            let old = self.allocators[retired].swap(new, Ordering::AcqRel);
+            self.generation.store(retired, Ordering::SeqCst);
+            Some(unsafe { Self::from_raw(old) })
+        });
+        match (swapped.flatten(), allocator) {
+            (Some(old), None) => Ok(old),
+            (_, allocator) => Err(allocator.unwrap()),
+        }
+    }
+
+    /// Take the allocator retired by the last swap once all its blocks are deallocated
+    fn reclaim(&self) -> Option<Box<DynAllocator>> {
+        self.lock(|| {
+            let retired = 1 - self.generation.load(Ordering::SeqCst);
+            if self.live[retired].load(Ordering::SeqCst) != 0 {
+                return None;
+            }
+            let old = self.allocators[retired].swap(ptr::null_mut(), Ordering::AcqRel);
+            unsafe { Self::from_raw(old) }
+        })
+        .flatten()
+    }
+
+    /// Empty the slot, returning the allocator of new blocks
+    ///
+    /// # Safety
+    ///
+    /// No thread may be using the allocators of the slot.
+    unsafe fn take(&self) -> Option<Box<DynAllocator>> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let retired = self.allocators[1 - generation].swap(ptr::null_mut(), Ordering::AcqRel);
+        drop(unsafe { Self::from_raw(retired) });
+        let allocator = self.allocators[generation].swap(ptr::null_mut(), Ordering::AcqRel);
+        unsafe { Self::from_raw(allocator) }
+    }
+
+    /// # Safety
+    ///
+    /// `allocator` must be null or come from `Box::into_raw` and not be used afterwards.
+    unsafe fn from_raw(allocator: *mut Box<DynAllocator>) -> Option<Box<DynAllocator>> {
+        if allocator.is_null() {
+            None
+        } else {
+            Some(*unsafe { Box::from_raw(allocator) })
+        }
+    }
+}
+
+/// Fixed-capacity registry of `N` allocators
+///
+/// Allocators are inserted and removed without locks, and looking one up never blocks.
+///
+/// ```rust
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// use okaoka::registry::Registry;
+///
+/// static REGISTRY: Registry<4> = Registry::new();
+///
+/// fn main() {
+///     let index = REGISTRY.insert(Box::new(System)).ok().unwrap();
+///     let allocator = REGISTRY.slot(index).unwrap().get().unwrap();
+///     let layout = Layout::new::<u64>();
+///     unsafe {
+///         let ptr = allocator.alloc(layout);
+///         allocator.dealloc(ptr, layout);
+///         assert!(REGISTRY.remove(index).is_some());
+///     }
+/// }
+/// ```
+pub struct Registry<const N: usize> {
+    slots: [Slot; N],
+}
+
+impl<const N: usize> Registry<N> {
+    #[cfg(not(loom))]
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Slot::new() }; N],
+        }
+    }
+
+    #[cfg(loom)]
+    pub fn new() -> Self {
+        Self {
+            slots: core::array::from_fn(|_| Slot::new()),
+        }
+    }
+
+    /// Slot at `index`, `None` if it's out of bounds
+    #[inline(always)]
+    pub fn slot(&self, index: usize) -> Option<&Slot> {
+        self.slots.get(index)
+    }
+
+    /// Store `allocator` in the first empty slot, returning its index
+    ///
+    /// Gives `allocator` back if every slot is taken.
+    pub fn insert(&self, mut allocator: Box<DynAllocator>) -> Result<usize, Box<DynAllocator>> {
+        for (index, slot) in self.slots.iter().enumerate() {
+            match slot.try_insert(allocator) {
+                Ok(()) => return Ok(index),
+                Err(rejected) => allocator = rejected,
+            }
+        }
+        Err(allocator)
+    }
+
+    /// Empty the slot at `index`, returning the allocator it held
+    ///
+    /// An allocator retired by [`Self::swap`] is dropped.
+    ///
+    /// # Safety
+    ///
+    /// No thread may be allocating with the allocators of the slot, and every block they
+    /// allocated must have been deallocated, since they are dropped.
+    pub unsafe fn remove(&self, index: usize) -> Option<Box<DynAllocator>> {
+        unsafe { self.slots.get(index)?.take() }
+    }
+
+    /// Replace the allocator of the slot at `index` for new allocations
+    ///
+    /// Blocks allocated before keep being deallocated by the replaced allocator, which is
+    /// retired until [`Self::reclaim`] takes it. Returns the allocator retired by the previous
+    /// swap, if there was one.
+    ///
+    /// Gives `allocator` back if the index is out of bounds, if the allocator retired by the
+    /// previous swap still has live blocks, or if the slot is being swapped by another thread.
+    pub fn swap(
+        &self,
+        index: usize,
+        allocator: Box<DynAllocator>,
+    ) -> Result<Option<Box<DynAllocator>>, Box<DynAllocator>> {
+        match self.slots.get(index) {
+            Some(slot) => slot.swap(allocator),
+            None => Err(allocator),
+        }
+    }
+
+    /// Take the allocator retired by the last swap of the slot at `index`
+    ///
+    /// Returns `None` if there's none or if some of its blocks are still alive.
+    pub fn reclaim(&self, index: usize) -> Option<Box<DynAllocator>> {
+        self.slots.get(index)?.reclaim()
+    }
+}
+
+impl<const N: usize> Default for Registry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Drop for Registry<N> {
+    fn drop(&mut self) {
+        for slot in &self.slots {
+            drop(unsafe { slot.take() });
+        }
+    }
+}
+
+#[cfg(all(
+    test,
+    feature = "std",
+    not(any(feature = "single-allocator", feature = "single-thread"))
+))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        collections::VecDeque,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::{MultiAllocator, MultiAllocatorBackend};
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        dynamic = 1,
+        System => System,
+    }
+
+    const ALLOCATORS: usize = 3;
+    #[cfg(not(miri))]
+    const ROUNDS: usize = 2000;
+    #[cfg(miri)]
+    const ROUNDS: usize = 20;
+
+    static ALLOCATED: [AtomicUsize; ALLOCATORS] = [const { AtomicUsize::new(0) }; ALLOCATORS];
+    static FREED: [AtomicUsize; ALLOCATORS] = [const { AtomicUsize::new(0) }; ALLOCATORS];
+    static DROPPED: [AtomicBool; ALLOCATORS] = [const { AtomicBool::new(false) }; ALLOCATORS];
+    // Blocks freed by another allocator than the one that made them
+    static MISROUTED: AtomicUsize = AtomicUsize::new(0);
+    // Allocators dropped while some of their blocks were alive
+    static DROPPED_LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    /// Allocator writing its id before each block, to check who frees it
+    struct Recording(usize);
+
+    impl Recording {
+        fn layout(layout: Layout) -> (Layout, usize) {
+            Layout::new::<usize>().extend(layout).unwrap()
+        }
+    }
+
+    unsafe impl GlobalAlloc for Recording {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let (layout, offset) = Self::layout(layout);
+            let block = unsafe { System.alloc(layout) };
+            if block.is_null() {
+                return block;
+            }
+            unsafe { block.cast::<usize>().write(self.0) };
+            ALLOCATED[self.0].fetch_add(1, Ordering::SeqCst);
+            unsafe { block.add(offset) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let (layout, offset) = Self::layout(layout);
+            let block = unsafe { ptr.sub(offset) };
+            if unsafe { block.cast::<usize>().read() } != self.0 {
+                MISROUTED.fetch_add(1, Ordering::SeqCst);
+            }
+            FREED[self.0].fetch_add(1, Ordering::SeqCst);
+            unsafe { System.dealloc(block, layout) };
+        }
+    }
+
+    impl Drop for Recording {
+        fn drop(&mut self) {
+            if ALLOCATED[self.0].load(Ordering::SeqCst) != FREED[self.0].load(Ordering::SeqCst) {
+                DROPPED_LIVE.fetch_add(1, Ordering::SeqCst);
+            }
+            DROPPED[self.0].store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Allocate and free with `tag` until `stop`, keeping some blocks across rounds
+    fn churn(tag: DynamicTag, stop: &AtomicBool) {
+        let allocator = MultiAllocator::<Backend>::new();
+        let layout = Layout::new::<[u64; 4]>();
+        let mut kept = VecDeque::with_capacity(ROUNDS);
+        let mut round = 0;
+        while round < ROUNDS || !stop.load(Ordering::SeqCst) {
+            crate::with_allocator(tag, || {
+                let ptr = unsafe { allocator.alloc(layout) };
+                assert!(!ptr.is_null());
+                if round % 3 == 0 && kept.len() < ROUNDS {
+                    kept.push_back(ptr);
+                } else {
+                    unsafe { allocator.dealloc(ptr, layout) };
+                }
+            });
+            // Free the oldest kept block from time to time, whatever its generation
+            if round % 5 == 0 {
+                if let Some(ptr) = kept.pop_front() {
+                    unsafe { allocator.dealloc(ptr, layout) };
+                }
+            }
+            round += 1;
+        }
+        for ptr in kept {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn swap_while_allocating() {
+        let tag = Backend::register(Box::new(Recording(0))).ok().unwrap();
+        let slot = Backend::dynamic_slot(tag.raw_tag()).unwrap();
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| churn(tag, &stop));
+            }
+            while ALLOCATED[0].load(Ordering::SeqCst) < ROUNDS {
+                thread::yield_now();
+            }
+
+            assert!(Backend::swap(tag, Box::new(Recording(1)))
+                .ok()
+                .unwrap()
+                .is_none());
+            // The blocks of `Recording(0)` are freed as the threads go on
+            let old = loop {
+                match Backend::reclaim(tag) {
+                    Some(old) => break old,
+                    None => assert!(!DROPPED[0].load(Ordering::SeqCst)),
+                }
+                thread::yield_now();
+            };
+            assert_eq!(slot.live_blocks(0), 0);
+            drop(old);
+
+            // Swap again while the threads still free blocks of `Recording(1)`
+            while ALLOCATED[1].load(Ordering::SeqCst) < ROUNDS {
+                thread::yield_now();
+            }
+            assert!(Backend::swap(tag, Box::new(Recording(2)))
+                .ok()
+                .unwrap()
+                .is_none());
+            while ALLOCATED[2].load(Ordering::SeqCst) < ROUNDS {
+                thread::yield_now();
+            }
+            stop.store(true, Ordering::SeqCst);
+        });
+
+        let old = Backend::reclaim(tag).unwrap();
+        assert_eq!(slot.live_blocks(0), 0);
+        assert_eq!(slot.live_blocks(1), 0);
+        drop(old);
+        drop(unsafe { Backend::unregister(tag) });
+
+        assert_eq!(MISROUTED.load(Ordering::SeqCst), 0);
+        assert_eq!(DROPPED_LIVE.load(Ordering::SeqCst), 0);
+        for id in 0..ALLOCATORS {
+            assert!(DROPPED[id].load(Ordering::SeqCst));
+            assert_eq!(
+                ALLOCATED[id].load(Ordering::SeqCst),
+                FREED[id].load(Ordering::SeqCst)
+            );
+        }
+    }
+}
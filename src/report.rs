@@ -0,0 +1,367 @@
+//! Usage report at process exit
+//!
+//! With the `stats` feature, programs that install their backend with
+//! [`set_multi_global_allocator`](crate::set_multi_global_allocator) report the statistics of
+//! every tag when they exit, if the `OKAOKA_REPORT` environment variable is set:
+//!
+//! - `summary`: the live allocations, live bytes and peak bytes of each tag, on stderr.
+//! - `full`: every statistic of each tag, of each pair of tag and owner with the `owner`
+//!   feature, and of each pair of tag and thread name with the `thread-stats` feature, on stderr.
+//! - a path ending with `.json`: every statistic, written to the file as JSON, e.g. to compare
+//!   the memory usage of a program between CI runs.
+//! - `rss`: with the `rss` feature, the live bytes of the tags compared with the resident memory
+//!   of the process, on stderr. See the `rss` module.
+//!
+//! ```text
+//! $ OKAOKA_REPORT=summary cargo run
+//! okaoka report:
+//!   System: 12 live allocations, 1180 live bytes, 9344 peak bytes
+//!   Arena: 0 live allocations, 0 live bytes, 4096 peak bytes
+//! ```
+//!
+//! Allocations still live at exit are usually leaks, or memory that the standard library
+//! doesn't free before exiting. The report can also be produced at any time with [`write()`], or
+//! taken as a [`Report`] to process it. With the `serde` feature, `Report` and the statistics
+//! types of this crate implement `Serialize` and `Deserialize`, e.g. to store the report of a CI
+//! run and diff it with the next one.
+
+use std::{
+    ffi::c_int,
+    fmt, fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use crate::{stats::TagStats, MultiAllocatorBackend};
+
+/// Environment variable that selects the report printed at exit
+pub const ENV_VAR: &str = "OKAOKA_REPORT";
+
+/// Kind of report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// Live allocations, live bytes and peak bytes of each tag
+    Summary,
+    /// Every statistic of each tag
+    Full,
+    /// Every statistic of each tag, as JSON written to the path
+    Json(PathBuf),
+    /// Live bytes of the tags compared with the resident memory of the process
+    #[cfg(feature = "rss")]
+    Rss,
+}
+
+impl ReportFormat {
+    /// Format selected by [`ENV_VAR`], `None` if it isn't set or isn't a format
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(ENV_VAR)?.into_string().ok()?.parse().ok()
+    }
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = ParseReportFormatError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "summary" => Ok(Self::Summary),
+            "full" => Ok(Self::Full),
+            #[cfg(feature = "rss")]
+            "rss" => Ok(Self::Rss),
+            path if path.ends_with(".json") => Ok(Self::Json(path.into())),
+            _ => Err(ParseReportFormatError),
+        }
+    }
+}
+
+/// Error returned when parsing a string that isn't a report format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseReportFormatError;
+
+impl fmt::Display for ParseReportFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if cfg!(feature = "rss") {
+            f.write_str("expected `summary`, `full`, `rss` or a path ending with `.json`")
+        } else {
+            f.write_str("expected `summary`, `full` or a path ending with `.json`")
+        }
+    }
+}
+
+impl std::error::Error for ParseReportFormatError {}
+
+/// Report the statistics of `Backend` in `format`, on stderr or to the JSON file
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// use okaoka::report::ReportFormat;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Arena => System,
+/// }
+///
+/// fn main() {
+///     okaoka::report::write::<GlobalAllocator>(&ReportFormat::Summary).unwrap();
+/// }
+/// ```
+pub fn write<Backend: MultiAllocatorBackend>(format: &ReportFormat) -> io::Result<()> {
+    match format {
+        ReportFormat::Summary => io::stderr()
+            .lock()
+            .write_all(summary::<Backend>().as_bytes()),
+        ReportFormat::Full => io::stderr().lock().write_all(full::<Backend>().as_bytes()),
+        ReportFormat::Json(path) => fs::write(path, json::<Backend>()),
+        #[cfg(feature = "rss")]
+        ReportFormat::Rss => {
+            let reconciliation = crate::rss::Reconciliation::snapshot::<Backend>()?;
+            io::stderr()
+                .lock()
+                .write_all(reconciliation.to_string().as_bytes())
+        }
+    }
+}
+
+pub(crate) fn summary<Backend: MultiAllocatorBackend>() -> String {
+    let mut report = String::from("okaoka report:\n");
+    for &tag in Backend::TAGS {
+        let stats = Backend::counters(tag).snapshot();
+        report += &format!(
+            "  {}: {} live allocations, {} live bytes, {} peak bytes\n",
+            Backend::tag_name(tag),
+            stats.live_allocations(),
+            stats.live_bytes,
+            stats.peak_bytes
+        );
+    }
+    report
+}
+
+/// Statistics of every tag of a backend, the contents of the `full` and JSON reports
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// use okaoka::report::Report;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Arena => System,
+/// }
+///
+/// fn main() {
+///     let mut buffer = Vec::new();
+///     GlobalAllocator::with(AllocatorTag::Arena, || buffer = vec![0u8; 100]);
+///     let report = Report::snapshot::<GlobalAllocator>();
+///     assert_eq!(report.tags[1].tag, "Arena");
+///     assert_eq!(report.tags[1].stats.live_bytes, 100);
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Report {
+    /// Statistics of each tag
+    pub tags: Vec<ReportEntry>,
+    /// Statistics of each pair of tag and owner, with the `owner` feature
+    pub owners: Vec<ReportEntry>,
+    /// Statistics of each pair of tag and thread name, with the `thread-stats` feature
+    pub threads: Vec<ReportEntry>,
+}
+
+/// Statistics of a tag, or of a pair of tag and owner or thread name, in a [`Report`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReportEntry {
+    /// Name of the tag, `unknown` for the allocators added at runtime
+    pub tag: String,
+    /// Owner, in [`Report::owners`]
+    pub owner: Option<u16>,
+    /// Name of the thread, in [`Report::threads`]
+    pub thread: Option<String>,
+    pub stats: TagStats,
+}
+
+impl Report {
+    /// Take a snapshot of the statistics of `Backend`
+    pub fn snapshot<Backend: MultiAllocatorBackend>() -> Self {
+        let tags = Backend::TAGS
+            .iter()
+            .map(|&tag| ReportEntry {
+                tag: Backend::tag_name(tag).into(),
+                stats: Backend::counters(tag).snapshot(),
+                ..ReportEntry::default()
+            })
+            .collect();
+        #[cfg(feature = "owner")]
+        let owners = Backend::owner_counters()
+            .iter()
+            .map(|(raw_tag, owner, counters)| ReportEntry {
+                tag: tag_name::<Backend>(raw_tag).into(),
+                owner: Some(owner),
+                thread: None,
+                stats: counters.snapshot(),
+            })
+            .collect();
+        #[cfg(not(feature = "owner"))]
+        let owners = Vec::new();
+        #[cfg(feature = "thread-stats")]
+        let threads = crate::thread_stats::thread_stats()
+            .iter()
+            .map(|thread| ReportEntry {
+                tag: tag_name::<Backend>(thread.raw_tag).into(),
+                owner: None,
+                thread: Some(thread.thread_name.into()),
+                stats: thread.stats,
+            })
+            .collect();
+        #[cfg(not(feature = "thread-stats"))]
+        let threads = Vec::new();
+        Self {
+            tags,
+            owners,
+            threads,
+        }
+    }
+}
+
+pub(crate) fn full<Backend: MultiAllocatorBackend>() -> String {
+    let snapshot = Report::snapshot::<Backend>();
+    let mut report = String::from("okaoka report:\n");
+    for entry in &snapshot.tags {
+        report += &format!("  {}:\n", entry.tag);
+        report += &full_stats("    ", &entry.stats);
+    }
+    for entry in &snapshot.owners {
+        let owner = entry.owner.unwrap_or_default();
+        report += &format!("  {} owned by {owner}:\n", entry.tag);
+        report += &full_stats("    ", &entry.stats);
+    }
+    for entry in &snapshot.threads {
+        let thread = entry.thread.as_deref().unwrap_or_default();
+        report += &format!("  {} on {thread}:\n", entry.tag);
+        report += &full_stats("    ", &entry.stats);
+    }
+    report
+}
+
+fn full_stats(indent: &str, stats: &TagStats) -> String {
+    format!(
+        "{indent}allocations: {}\n\
+         {indent}deallocations: {}\n\
+         {indent}allocated bytes: {}\n\
+         {indent}deallocated bytes: {}\n\
+         {indent}live allocations: {}\n\
+         {indent}live bytes: {}\n\
+         {indent}peak bytes: {}\n",
+        stats.allocations,
+        stats.deallocations,
+        stats.allocated_bytes,
+        stats.deallocated_bytes,
+        stats.live_allocations(),
+        stats.live_bytes,
+        stats.peak_bytes
+    )
+}
+
+pub(crate) fn json<Backend: MultiAllocatorBackend>() -> String {
+    let snapshot = Report::snapshot::<Backend>();
+    let tags: Vec<_> = snapshot
+        .tags
+        .iter()
+        .map(|entry| format!("{{\"tag\":\"{}\",{}}}", entry.tag, json_stats(&entry.stats)))
+        .collect();
+    let owners: Vec<_> = snapshot
+        .owners
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"tag\":\"{}\",\"owner\":{},{}}}",
+                entry.tag,
+                entry.owner.unwrap_or_default(),
+                json_stats(&entry.stats)
+            )
+        })
+        .collect();
+    let threads: Vec<_> = snapshot
+        .threads
+        .iter()
+        .map(|entry| {
+            format!(
+                "{{\"tag\":\"{}\",\"thread\":\"{}\",{}}}",
+                entry.tag,
+                json_escape(entry.thread.as_deref().unwrap_or_default()),
+                json_stats(&entry.stats)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"tags\":[{}],\"owners\":[{}],\"threads\":[{}]}}\n",
+        tags.join(","),
+        owners.join(","),
+        threads.join(",")
+    )
+}
+
+fn json_stats(stats: &TagStats) -> String {
+    format!(
+        "\"allocations\":{},\"deallocations\":{},\"allocated_bytes\":{},\
+         \"deallocated_bytes\":{},\"live_allocations\":{},\"live_bytes\":{},\"peak_bytes\":{}",
+        stats.allocations,
+        stats.deallocations,
+        stats.allocated_bytes,
+        stats.deallocated_bytes,
+        stats.live_allocations(),
+        stats.live_bytes,
+        stats.peak_bytes
+    )
+}
+
+/// Escape `string` to be put between quotes in JSON
+fn json_escape(string: &str) -> String {
+    string
+        .chars()
+        .map(|c| match c {
+            '"' => "\\\"".to_string(),
+            '\\' => "\\\\".to_string(),
+            c if c.is_control() => format!("\\u{:04x}", c as u32),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(any(feature = "owner", feature = "thread-stats"))]
+fn tag_name<Backend: MultiAllocatorBackend>(raw_tag: u16) -> &'static str {
+    use crate::TagRepr;
+
+    Backend::Repr::from_raw(raw_tag)
+        .and_then(|tag| Backend::Tag::try_from(tag).ok())
+        .map_or("unknown", Backend::tag_name)
+}
+
+/// Write the report selected by [`ENV_VAR`], called at exit by the global allocator
+#[doc(hidden)]
+pub fn at_exit<Backend: MultiAllocatorBackend>() {
+    if let Some(format) = ReportFormat::from_env() {
+        if let Err(error) = write::<Backend>(&format) {
+            eprintln!("okaoka: failed to write the report: {error}");
+        }
+    }
+}
+
+/// Register `report` to be called when the process exits
+///
+/// Called by a constructor of the global allocator before `main`, so it must not allocate. Miri
+/// can't call `atexit`, so the report isn't written at exit under Miri.
+#[doc(hidden)]
+pub fn register(report: extern "C" fn()) {
+    extern "C" {
+        fn atexit(callback: extern "C" fn()) -> c_int;
+    }
+    if !cfg!(miri) {
+        unsafe { atexit(report) };
+    }
+}
@@ -0,0 +1,201 @@
+//! Ring allocator for transient data
+//!
+//! With the `ring` feature, [`Ring`] carves blocks one after the other from a fixed buffer and
+//! starts over from the beginning of the buffer once it reaches the end, overwriting the oldest
+//! blocks. Deallocating does nothing, so it suits data that only lives for the next few frames or
+//! messages, as long as the buffer is larger than what they allocate in the meantime:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::ring::Ring;
+//!
+//! // SAFETY: each message is dropped before the next 64 KiB of messages are allocated
+//! static MESSAGES: Ring<{ 64 * 1024 }> = unsafe { Ring::new() };
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Messages => MESSAGES,
+//! }
+//!
+//! fn main() {
+//!     for id in 0..10_000 {
+//!         let start = MESSAGES.position();
+//!         GlobalAllocator::with(AllocatorTag::Messages, || {
+//!             let message = format!("message {id}");
+//!             assert!(!MESSAGES.is_overwritten(start));
+//!             drop(message);
+//!         });
+//!     }
+//!     MESSAGES.assert_no_overwritten_live();
+//! }
+//! ```
+//!
+//! In debug builds, the ring counts the blocks that haven't been deallocated yet in each of
+//! [`SEGMENTS`] segments of the buffer, and a block still counted when the ring wraps over its
+//! segment is recorded as overwritten while live, which [`Ring::overwritten_live`] and
+//! [`Ring::assert_no_overwritten_live`] report. Once a block has been overwritten while live, the
+//! counts are approximate. Release builds don't count blocks.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Number of segments of the buffer in which blocks are counted in debug builds
+pub const SEGMENTS: usize = 64;
+
+/// Ring allocator over a fixed buffer of `N` bytes, see the module documentation
+pub struct Ring<const N: usize> {
+    buffer: UnsafeCell<MaybeUninit<[u8; N]>>,
+    // Bytes allocated since the creation of the ring, including padding
+    head: AtomicU64,
+    // Lap of each segment in the high 32 bits and number of its blocks in the low ones
+    segments: [AtomicU64; SEGMENTS],
+    overwritten_live: AtomicUsize,
+}
+
+// SAFETY: the buffer is only handed out in blocks reserved with `head`
+unsafe impl<const N: usize> Sync for Ring<N> {}
+
+impl<const N: usize> Ring<N> {
+    /// # Safety
+    ///
+    /// Every block must be dead before the ring wraps over it, the overwritten blocks are only
+    /// detected in debug builds.
+    pub const unsafe fn new() -> Self {
+        assert!(
+            N >= SEGMENTS && N.is_multiple_of(SEGMENTS),
+            "the buffer of a ring allocator must be a multiple of the number of segments"
+        );
+        Self {
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            head: AtomicU64::new(0),
+            segments: [const { AtomicU64::new(0) }; SEGMENTS],
+            overwritten_live: AtomicUsize::new(0),
+        }
+    }
+
+    /// Size of the buffer
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Bytes allocated since the creation of the ring, including padding
+    ///
+    /// The blocks allocated after this position stay intact until [`Self::is_overwritten`]
+    /// returns true for it.
+    pub fn position(&self) -> u64 {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// Whether the ring may have wrapped over the blocks allocated after `position`
+    pub fn is_overwritten(&self, position: u64) -> bool {
+        self.position() > position + N as u64
+    }
+
+    /// Number of blocks the ring wrapped over before they were deallocated, always 0 in release
+    /// builds
+    pub fn overwritten_live(&self) -> usize {
+        self.overwritten_live.load(Ordering::Relaxed)
+    }
+
+    /// Panic if the ring wrapped over blocks before they were deallocated, see
+    /// [`Self::overwritten_live`]
+    #[track_caller]
+    pub fn assert_no_overwritten_live(&self) {
+        let overwritten = self.overwritten_live();
+        assert!(
+            overwritten == 0,
+            "the ring wrapped over {overwritten} blocks that were still allocated"
+        );
+    }
+
+    /// Start of a block of `layout` allocated at `head`, on this lap or the next one
+    fn place(&self, head: u64, layout: Layout) -> Option<u64> {
+        let base = self.buffer.get().cast::<u8>();
+        let offset = (head % N as u64) as usize;
+        let padding = base.wrapping_add(offset).align_offset(layout.align());
+        if offset
+            .checked_add(padding)
+            .and_then(|start| start.checked_add(layout.size()))
+            .is_some_and(|end| end <= N)
+        {
+            return Some(head + padding as u64);
+        }
+        let padding = base.align_offset(layout.align());
+        padding
+            .checked_add(layout.size())
+            .filter(|&end| end <= N)
+            .map(|_| head - offset as u64 + N as u64 + padding as u64)
+    }
+
+    /// Count a block allocated at `start` in the segments reserved from `head` to `end`
+    fn count_alloc(&self, head: u64, start: u64, end: u64) {
+        let segment_size = (N / SEGMENTS) as u64;
+        // Segments the ring enters, whose blocks of the previous lap are overwritten
+        for segment in head.div_ceil(segment_size)..end.div_ceil(segment_size) {
+            self.enter(segment, 0);
+        }
+        self.enter(start / segment_size, 1);
+    }
+
+    /// Bring the `segment`-th segment since the creation of the ring to its lap, adding `blocks`
+    /// to its count
+    fn enter(&self, segment: u64, blocks: u64) {
+        let lap = (segment / SEGMENTS as u64) as u32;
+        let previous = self.segments[(segment % SEGMENTS as u64) as usize]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                Some(if state >> 32 == u64::from(lap) {
+                    state + blocks
+                } else {
+                    (u64::from(lap) << 32) | blocks
+                })
+            })
+            .unwrap_or_else(|state| state);
+        if previous >> 32 != u64::from(lap) {
+            let live = (previous & u64::from(u32::MAX)) as usize;
+            self.overwritten_live.fetch_add(live, Ordering::Relaxed);
+        }
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for Ring<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let Some(start) = self.place(head, layout) else {
+                return ptr::null_mut();
+            };
+            let end = start + layout.size() as u64;
+            match self
+                .head
+                .compare_exchange_weak(head, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    if cfg!(debug_assertions) {
+                        self.count_alloc(head, start, end);
+                    }
+                    let offset = (start % N as u64) as usize;
+                    return unsafe { self.buffer.get().cast::<u8>().add(offset) };
+                }
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if cfg!(debug_assertions) {
+            let offset = unsafe { ptr.offset_from(self.buffer.get().cast::<u8>()) } as usize;
+            let segment = &self.segments[offset / (N / SEGMENTS)];
+            let _ = segment.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                (state & u64::from(u32::MAX) != 0).then(|| state - 1)
+            });
+        }
+    }
+}
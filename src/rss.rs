@@ -0,0 +1,273 @@
+//! Reconciliation of the tracked bytes with the resident memory
+//!
+//! The statistics of the tags only count the bytes requested by the user, so a process tracking
+//! 2 GiB can have a resident set of 5 GiB. With the `rss` feature, a [`Reconciliation`] compares
+//! the live bytes of every tag with the resident set size of the process, and attributes the
+//! difference to the causes that can be measured:
+//!
+//! - the hidden tags put before every block,
+//! - the bytes freed but kept by the allocators, as reported by glibc from 2.33 and, with the
+//!   `jemalloc-stats` feature, by jemalloc,
+//! - the rest, which okaoka can't see: allocations that bypass the global allocator, like
+//!   `mmap` or the allocators of C libraries, the fragmentation inside the allocators, and the
+//!   code, stacks and other mappings of the process.
+//!
+//! `OKAOKA_REPORT=rss` writes it at exit, see the `report` module, and [`Reconciliation::snapshot`]
+//! takes it at any time:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::rss::Reconciliation;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let mut buffer = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || buffer = vec![1u8; 1 << 20]);
+//!     match Reconciliation::snapshot::<GlobalAllocator>() {
+//!         Ok(reconciliation) => {
+//!             assert!(reconciliation.tracked >= 1 << 20);
+//!             eprint!("{reconciliation}");
+//!         }
+//!         Err(error) => eprintln!("no RSS on this platform: {error}"),
+//!     }
+//! }
+//! ```
+//!
+//! ```text
+//! okaoka RSS reconciliation:
+//!   resident: 5368709120 bytes
+//!   tracked: 2147483648 bytes (40.0%)
+//!     System: 1073741824 bytes
+//!     Cache: 1073741824 bytes
+//!   hidden tags: 2097152 bytes (0.0%)
+//!   retained by allocators: 805306368 bytes (15.0%)
+//!   untracked: 2413764608 bytes (45.0%)
+//! ```
+//!
+//! The resident set size is read from `/proc/self/statm` on Linux and Android, from `task_info`
+//! on macOS and iOS, and from `GetProcessMemoryInfo` on Windows, and isn't available elsewhere.
+//! The hidden tags are counted at their minimum size, without the padding of the blocks
+//! aligned to more than it.
+
+use std::{fmt, io};
+
+use crate::{stats::tag_stats, MultiAllocatorBackend};
+
+/// Resident memory of the process, split by cause, see the module documentation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Resident set size of the process
+    pub rss: usize,
+    /// Live bytes of every tag
+    pub tracked: usize,
+    /// Live bytes of each tag, by name
+    pub tags: Vec<(&'static str, usize)>,
+    /// Bytes of the hidden tags of the live blocks
+    pub headers: usize,
+    /// Bytes freed but kept by the allocators, `None` if no allocator reports them
+    pub retained: Option<usize>,
+}
+
+impl Reconciliation {
+    /// Take the live bytes of every tag of `Backend`, and the resident set size of the process
+    ///
+    /// Fails if the resident set size can't be read.
+    pub fn snapshot<Backend: MultiAllocatorBackend>() -> io::Result<Self> {
+        let mut live_allocations = 0;
+        let tags: Vec<_> = Backend::TAGS
+            .iter()
+            .map(|&tag| {
+                let stats = tag_stats::<Backend>(tag);
+                live_allocations += stats.live_allocations();
+                (Backend::tag_name(tag), stats.live_bytes)
+            })
+            .collect();
+        #[cfg(not(feature = "single-allocator"))]
+        let headers = live_allocations * crate::min_tag_size::<Backend>();
+        #[cfg(feature = "single-allocator")]
+        let headers = {
+            let _ = live_allocations;
+            0
+        };
+        Ok(Self {
+            rss: rss()?,
+            tracked: tags.iter().map(|(_, bytes)| bytes).sum(),
+            tags,
+            headers,
+            retained: retained(),
+        })
+    }
+
+    /// Resident bytes that none of the measured causes explain
+    pub fn untracked(&self) -> usize {
+        self.rss
+            .saturating_sub(self.tracked + self.headers + self.retained.unwrap_or(0))
+    }
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let share = |bytes: usize| 100.0 * bytes as f64 / self.rss.max(1) as f64;
+        writeln!(f, "okaoka RSS reconciliation:")?;
+        writeln!(f, "  resident: {} bytes", self.rss)?;
+        writeln!(
+            f,
+            "  tracked: {} bytes ({:.1}%)",
+            self.tracked,
+            share(self.tracked)
+        )?;
+        for (tag, bytes) in &self.tags {
+            writeln!(f, "    {tag}: {bytes} bytes")?;
+        }
+        writeln!(
+            f,
+            "  hidden tags: {} bytes ({:.1}%)",
+            self.headers,
+            share(self.headers)
+        )?;
+        match self.retained {
+            Some(retained) => writeln!(
+                f,
+                "  retained by allocators: {retained} bytes ({:.1}%)",
+                share(retained)
+            )?,
+            None => writeln!(f, "  retained by allocators: unknown")?,
+        }
+        writeln!(
+            f,
+            "  untracked: {} bytes ({:.1}%)",
+            self.untracked(),
+            share(self.untracked())
+        )
+    }
+}
+
+/// Resident set size of the process
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn rss() -> io::Result<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm")?;
+    // The size of the process, then its resident pages
+    let pages: usize = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, statm.clone()))?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size as usize)
+}
+
+/// Resident set size of the process
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn rss() -> io::Result<usize> {
+    let mut info = std::mem::MaybeUninit::<libc::mach_task_basic_info>::uninit();
+    let mut count = libc::MACH_TASK_BASIC_INFO_COUNT;
+    // `mach_task_self` is deprecated in favor of the `mach2` crate, for the same port
+    #[allow(deprecated)]
+    let result = unsafe {
+        libc::task_info(
+            libc::mach_task_self(),
+            libc::MACH_TASK_BASIC_INFO,
+            info.as_mut_ptr().cast(),
+            &mut count,
+        )
+    };
+    if result != libc::KERN_SUCCESS {
+        return Err(io::Error::other(format!("task_info failed with {result}")));
+    }
+    Ok(unsafe { info.assume_init() }.resident_size as usize)
+}
+
+/// Resident set size of the process
+#[cfg(windows)]
+fn rss() -> io::Result<usize> {
+    use core::ffi::c_void;
+
+    #[repr(C)]
+    #[allow(non_snake_case)]
+    struct PROCESS_MEMORY_COUNTERS {
+        cb: u32,
+        PageFaultCount: u32,
+        PeakWorkingSetSize: usize,
+        WorkingSetSize: usize,
+        QuotaPeakPagedPoolUsage: usize,
+        QuotaPagedPoolUsage: usize,
+        QuotaPeakNonPagedPoolUsage: usize,
+        QuotaNonPagedPoolUsage: usize,
+        PagefileUsage: usize,
+        PeakPagefileUsage: usize,
+    }
+
+    // `GetProcessMemoryInfo` of psapi.h, exported by kernel32 since Windows 7
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn K32GetProcessMemoryInfo(
+            process: *mut c_void,
+            counters: *mut PROCESS_MEMORY_COUNTERS,
+            cb: u32,
+        ) -> i32;
+    }
+
+    let mut counters = std::mem::MaybeUninit::<PROCESS_MEMORY_COUNTERS>::uninit();
+    let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+    if unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), counters.as_mut_ptr(), size) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The working set is the resident memory of Windows
+    Ok(unsafe { counters.assume_init() }.WorkingSetSize)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+fn rss() -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the resident set size is only read on Linux, Android, macOS, iOS and Windows",
+    ))
+}
+
+/// Free bytes in the arenas of glibc, `None` before glibc 2.33, which added `mallinfo2`
+///
+/// The older `mallinfo` counts in an `int`, which wraps above 2 GiB, so `mallinfo2` is looked up
+/// at runtime rather than linked, which would fail to load with an older glibc.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn glibc_free_bytes() -> Option<usize> {
+    let mallinfo2 = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"mallinfo2".as_ptr()) };
+    if mallinfo2.is_null() {
+        return None;
+    }
+    let mallinfo2: unsafe extern "C" fn() -> libc::mallinfo2 =
+        unsafe { std::mem::transmute(mallinfo2) };
+    Some(unsafe { mallinfo2() }.fordblks)
+}
+
+/// Bytes freed but kept by glibc and jemalloc, `None` if neither reports them
+fn retained() -> Option<usize> {
+    // Free bytes in the arenas of malloc
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    let glibc = glibc_free_bytes();
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    let glibc: Option<usize> = None;
+    #[cfg(feature = "jemalloc-stats")]
+    let jemalloc = crate::jemalloc::arena_stats(None)
+        .ok()
+        .map(|stats| stats.dirty);
+    #[cfg(not(feature = "jemalloc-stats"))]
+    let jemalloc: Option<usize> = None;
+    match (glibc, jemalloc) {
+        (None, None) => None,
+        (glibc, jemalloc) => Some(glibc.unwrap_or(0) + jemalloc.unwrap_or(0)),
+    }
+}
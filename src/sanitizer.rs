@@ -0,0 +1,55 @@
+//! AddressSanitizer annotations
+//!
+//! With the `sanitizer` feature, the hidden tag of every block is poisoned while the block is
+//! allocated, so AddressSanitizer reports an access to it as an underflow of the block instead
+//! of letting it through as part of the allocation. The feature calls the ASan runtime, so it
+//! only links when building with `-Zsanitizer=address`. With the `single-allocator` feature,
+//! there's no hidden tag, so nothing is poisoned.
+
+use std::ffi::c_void;
+
+extern "C" {
+    fn __asan_poison_memory_region(addr: *const c_void, size: usize);
+    fn __asan_unpoison_memory_region(addr: *const c_void, size: usize);
+    fn __asan_address_is_poisoned(addr: *const c_void) -> i32;
+}
+
+/// Forbid accessing `size` bytes at `ptr`
+///
+/// # Safety
+///
+/// `ptr` must be valid for `size` bytes.
+#[inline(always)]
+pub(crate) unsafe fn poison(ptr: *const u8, size: usize) {
+    unsafe { __asan_poison_memory_region(ptr.cast(), size) }
+}
+
+/// Allow accessing `size` bytes at `ptr` again
+///
+/// # Safety
+///
+/// `ptr` must be valid for `size` bytes.
+#[inline(always)]
+pub(crate) unsafe fn unpoison(ptr: *const u8, size: usize) {
+    unsafe { __asan_unpoison_memory_region(ptr.cast(), size) }
+}
+
+/// Call the closure with the `size` bytes at `ptr` accessible, poisoning them again afterwards
+/// if they were poisoned
+///
+/// # Safety
+///
+/// `ptr` must be valid for `size` bytes.
+#[cfg(any(feature = "live", feature = "proptest", feature = "arbitrary"))]
+#[inline(always)]
+pub(crate) unsafe fn with_unpoisoned<R>(ptr: *const u8, size: usize, f: impl FnOnce() -> R) -> R {
+    let poisoned = unsafe { __asan_address_is_poisoned(ptr.cast()) } != 0;
+    if poisoned {
+        unsafe { unpoison(ptr, size) };
+    }
+    let result = f();
+    if poisoned {
+        unsafe { poison(ptr, size) };
+    }
+    result
+}
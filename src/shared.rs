@@ -0,0 +1,174 @@
+//! Shared core for plugins loaded as dynamic libraries
+//!
+//! A `cdylib` plugin that links this crate gets its own copy of the current tag and its own
+//! global allocator, so memory can't be freed on the other side of the boundary and switching
+//! allocators in the plugin doesn't affect the host. With the `shared-core` feature, the host's
+//! [`set_multi_global_allocator`](crate::set_multi_global_allocator) exports a C ABI to its
+//! allocator and current tag, and plugins use [`HostAllocator`] to bind to it:
+//!
+//! - `okaoka_abi_version() -> u32`: version of the ABI, [`ABI_VERSION`].
+//! - `okaoka_get_tag() -> i32` and `okaoka_set_tag(i32)`: current tag of the thread, `-1` being
+//!   the default allocator.
+//! - `okaoka_alloc(size, align) -> *mut u8`, `okaoka_dealloc(ptr, size, align)` and
+//!   `okaoka_realloc(ptr, size, align, new_size) -> *mut u8`: [`GlobalAlloc`] through the
+//!   host's [`MultiAllocator`].
+//!
+//! The plugin installs [`HostAllocator`] as its global allocator and as its tag source, so that
+//! [`with_allocator`](crate::with_allocator) in the plugin sets the tag of the host:
+//!
+//! ```rust,ignore
+//! #[global_allocator]
+//! static ALLOCATOR: okaoka::shared::HostAllocator = okaoka::shared::HostAllocator;
+//!
+//! #[no_mangle]
+//! pub extern "C" fn plugin_init() {
+//!     assert!(okaoka::shared::HostAllocator::is_compatible());
+//!     okaoka::set_tag_source(&okaoka::shared::HostAllocator).ok().unwrap();
+//! }
+//! ```
+//!
+//! The symbols are exported from the host executable only if it's linked with
+//! `-C link-args=-rdynamic` (or the equivalent of the platform), so that the plugin can resolve
+//! them when it's loaded.
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use crate::{get_allocator_tag, set_allocator_tag, MultiAllocator, MultiAllocatorBackend};
+
+/// Version of the C ABI, bumped on every incompatible change
+pub const ABI_VERSION: u32 = 1;
+
+/// Tag passed across the ABI, `-1` being the default allocator
+fn encode_tag(tag: Option<u16>) -> i32 {
+    match tag {
+        Some(tag) => tag.into(),
+        None => -1,
+    }
+}
+
+fn decode_tag(tag: i32) -> Option<u16> {
+    tag.try_into().ok()
+}
+
+/// Implementation of the symbols exported by the host
+#[doc(hidden)]
+pub mod host {
+    use super::*;
+
+    pub fn get_tag() -> i32 {
+        encode_tag(get_allocator_tag())
+    }
+
+    pub fn set_tag(tag: i32) {
+        set_allocator_tag(decode_tag(tag));
+    }
+
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::alloc`].
+    pub unsafe fn alloc<Backend: MultiAllocatorBackend>(size: usize, align: usize) -> *mut u8 {
+        let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
+        unsafe { MultiAllocator::<Backend>::new().alloc(layout) }
+    }
+
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::dealloc`].
+    pub unsafe fn dealloc<Backend: MultiAllocatorBackend>(ptr: *mut u8, size: usize, align: usize) {
+        let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
+        unsafe { MultiAllocator::<Backend>::new().dealloc(ptr, layout) }
+    }
+
+    /// # Safety
+    ///
+    /// Same contract as [`GlobalAlloc::realloc`].
+    pub unsafe fn realloc<Backend: MultiAllocatorBackend>(
+        ptr: *mut u8,
+        size: usize,
+        align: usize,
+        new_size: usize,
+    ) -> *mut u8 {
+        let layout = unsafe { Layout::from_size_align_unchecked(size, align) };
+        unsafe { MultiAllocator::<Backend>::new().realloc(ptr, layout, new_size) }
+    }
+}
+
+extern "C" {
+    fn okaoka_abi_version() -> u32;
+    fn okaoka_get_tag() -> i32;
+    fn okaoka_set_tag(tag: i32);
+    fn okaoka_alloc(size: usize, align: usize) -> *mut u8;
+    fn okaoka_dealloc(ptr: *mut u8, size: usize, align: usize);
+    fn okaoka_realloc(ptr: *mut u8, size: usize, align: usize, new_size: usize) -> *mut u8;
+}
+
+/// Allocator of a plugin that allocates through the allocator of the host
+///
+/// Both the host and the plugin can deallocate the memory allocated by the other. As a
+/// [`TagSource`](crate::TagSource), it reads and sets the current tag of the host.
+///
+/// The host and the plugin can be the same binary, which is how this example binds to itself:
+///
+/// ```rust
+/// use std::alloc::{GlobalAlloc, Layout, System};
+///
+/// use okaoka::{shared::HostAllocator, TagSource};
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Jemalloc => jemallocator::Jemalloc,
+/// }
+///
+/// fn main() {
+///     assert!(HostAllocator::is_compatible());
+///     HostAllocator.set(Some(AllocatorTag::Jemalloc as u16));
+///     let layout = Layout::new::<u64>();
+///     unsafe {
+///         let ptr = HostAllocator.alloc(layout); // Allocated with jemalloc by the host
+///         drop(Box::from_raw(ptr.cast::<u64>())); // Deallocated by the host
+///     }
+///     HostAllocator.set(None);
+/// }
+/// ```
+pub struct HostAllocator;
+
+impl HostAllocator {
+    /// Version of the ABI exported by the host
+    pub fn abi_version() -> u32 {
+        unsafe { okaoka_abi_version() }
+    }
+
+    /// Whether the host exports the ABI of this version of the crate
+    pub fn is_compatible() -> bool {
+        Self::abi_version() == ABI_VERSION
+    }
+}
+
+unsafe impl GlobalAlloc for HostAllocator {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { okaoka_alloc(layout.size(), layout.align()) }
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { okaoka_dealloc(ptr, layout.size(), layout.align()) }
+    }
+
+    #[inline(always)]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        unsafe { okaoka_realloc(ptr, layout.size(), layout.align(), new_size) }
+    }
+}
+
+impl crate::TagSource for HostAllocator {
+    fn get(&self) -> Option<u16> {
+        decode_tag(unsafe { okaoka_get_tag() })
+    }
+
+    fn set(&self, tag: Option<u16>) {
+        unsafe { okaoka_set_tag(encode_tag(tag)) }
+    }
+}
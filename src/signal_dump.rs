@@ -0,0 +1,149 @@
+//! Heap report on `SIGUSR1`
+//!
+//! With the `signal-dump` feature, on unix, [`install_signal_dump`] makes `SIGUSR1` write the
+//! report of every tag to a file, so the memory usage of a running process can be inspected
+//! during an incident with `kill -USR1 <pid>`. The report is the one selected by `full` in the
+//! `report` module, or its JSON version if the path ends with `.json`, and it overwrites the
+//! previous one.
+//!
+//! ```rust
+//! use std::{alloc::System, fs, thread, time::Duration};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let path = std::env::temp_dir().join(format!("okaoka-{}.txt", std::process::id()));
+//!     okaoka::signal_dump::install_signal_dump::<GlobalAllocator>(&path).unwrap();
+//!
+//!     unsafe { libc::raise(libc::SIGUSR1) };
+//!     while !path.exists() {
+//!         thread::sleep(Duration::from_millis(10));
+//!     }
+//!     thread::sleep(Duration::from_millis(100));
+//!     assert!(fs::read_to_string(&path).unwrap().contains("Arena"));
+//!     fs::remove_file(path).unwrap();
+//! }
+//! ```
+//!
+//! The signal handler only sets a flag, which is async-signal-safe, and the report is written by
+//! a helper thread that checks the flag every [`POLL_INTERVAL`], so it may take that long to
+//! appear.
+
+use std::{
+    ffi::c_int,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{report, MultiAllocatorBackend};
+
+/// Interval at which the helper thread checks whether the signal was received
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Set by the signal handler, cleared by the helper thread
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// File the report is written to
+static PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+// Whether the helper thread was spawned
+static SPAWNED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(_signal: c_int) {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Write the report of `Backend` to `path` every time the process receives `SIGUSR1`
+///
+/// Calling it again only changes the path. Fails without replacing it if the application or
+/// another library already handles or ignores `SIGUSR1`.
+pub fn install_signal_dump<Backend: MultiAllocatorBackend + 'static>(
+    path: impl AsRef<Path>,
+) -> io::Result<()> {
+    let handler = handle as extern "C" fn(c_int) as libc::sighandler_t;
+    let previous = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGUSR1, &action, &mut previous) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        previous
+    };
+    if previous.sa_sigaction != libc::SIG_DFL && previous.sa_sigaction != handler {
+        restore(&previous);
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "SIGUSR1 already has a handler",
+        ));
+    }
+
+    *PATH.lock().unwrap_or_else(|e| e.into_inner()) = Some(path.as_ref().to_owned());
+
+    if !SPAWNED.swap(true, Ordering::AcqRel) {
+        if let Err(error) = thread::Builder::new()
+            .name("okaoka-signal-dump".into())
+            .spawn(watch::<Backend>)
+        {
+            SPAWNED.store(false, Ordering::Release);
+            restore(&previous);
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Put back the action of `SIGUSR1` replaced by [`install_signal_dump`]
+fn restore(previous: &libc::sigaction) {
+    unsafe { libc::sigaction(libc::SIGUSR1, previous, std::ptr::null_mut()) };
+}
+
+/// Lock of the path, held while the process forks
+#[cfg(feature = "fork-safety")]
+pub(crate) fn lock_path() -> std::sync::MutexGuard<'static, Option<PathBuf>> {
+    PATH.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Spawn the helper thread again on the next [`install_signal_dump`] of the child of a `fork`,
+/// which doesn't have it
+#[cfg(feature = "fork-safety")]
+pub(crate) fn forget_helper() {
+    REQUESTED.store(false, Ordering::Relaxed);
+    SPAWNED.store(false, Ordering::Release);
+}
+
+/// Body of the helper thread
+fn watch<Backend: MultiAllocatorBackend>() {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        if !REQUESTED.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        let Some(path) = PATH.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+            continue;
+        };
+        let report = match path.extension() {
+            Some(extension) if extension == "json" => report::json::<Backend>(),
+            _ => report::full::<Backend>(),
+        };
+        if let Err(error) = fs::write(&path, report) {
+            eprintln!(
+                "okaoka: failed to write the report to {}: {error}",
+                path.display()
+            );
+        }
+    }
+}
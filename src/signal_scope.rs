@@ -0,0 +1,108 @@
+//! Allocator scopes for signal handlers
+//!
+//! Switching allocators with [`with_allocator`](crate::with_allocator) or an
+//! [`AllocatorGuard`](crate::AllocatorGuard) never allocates: the current tag is a
+//! thread-local initialized at compile time, without a destructor to register, so it's
+//! async-signal-safe, unless a [`TagSource`](crate::TagSource) that isn't is installed. A signal
+//! handler interrupting a switch sees the tag before or after it, and its own scopes are restored
+//! before it returns.
+//!
+//! With the `signal-scope` feature, [`signal_scope`] goes further for handlers that can't avoid
+//! allocating: the allocations made inside it go to the given allocator, e.g. a pool reserved
+//! ahead of time, whatever the tag of an instance, the alignment routes or the installed
+//! `TagSource` would select, so the handler never allocates from the allocator of the code it
+//! interrupted, which may be holding its lock:
+//!
+//! ```rust
+//! use std::{
+//!     alloc::{GlobalAlloc, Layout, System},
+//!     sync::atomic::{AtomicUsize, Ordering},
+//! };
+//!
+//! // Stands in for a pool reserved ahead of time, counting its allocations
+//! struct Reserved;
+//!
+//! static RESERVED_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+//!
+//! unsafe impl GlobalAlloc for Reserved {
+//!     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+//!         RESERVED_ALLOCS.fetch_add(1, Ordering::Relaxed);
+//!         unsafe { System.alloc(layout) }
+//!     }
+//!
+//!     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+//!         unsafe { System.dealloc(ptr, layout) }
+//!     }
+//! }
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Reserved => Reserved,
+//! }
+//!
+//! extern "C" fn handle(_signal: libc::c_int) {
+//!     okaoka::signal_scope::signal_scope(AllocatorTag::Reserved as u8, || {
+//!         let _message = Box::new([0u8; 64]); // Allocated with `Reserved`
+//!     });
+//! }
+//!
+//! fn main() {
+//!     // Switching allocators doesn't allocate
+//!     GlobalAllocator::with(AllocatorTag::Reserved, || {
+//!         let _guard = GlobalAllocator::system_guard();
+//!         okaoka::signal_scope::signal_scope(AllocatorTag::System as u8, || {});
+//!     });
+//!     assert_eq!(RESERVED_ALLOCS.load(Ordering::Relaxed), 0);
+//!
+//!     unsafe {
+//!         libc::signal(libc::SIGUSR1, handle as extern "C" fn(libc::c_int) as libc::sighandler_t);
+//!         libc::raise(libc::SIGUSR1);
+//!     }
+//!     assert_eq!(RESERVED_ALLOCS.load(Ordering::Relaxed), 1);
+//! }
+//! ```
+//!
+//! Scopes nest, the innermost one selecting the allocator. Reallocations and deallocations stay
+//! with the allocator of the block, so a handler must not reallocate or free the blocks of the
+//! code it interrupted. The thread-local of a library loaded with `dlopen` may be allocated by the
+//! C library on its first access from a thread, so such a library should switch allocators once
+//! on each of its threads before any signal arrives. With the `single-allocator` feature, scopes
+//! are ignored.
+
+use std::cell::Cell;
+
+thread_local! {
+    /// Tag of the innermost signal scope of the thread
+    static SIGNAL_TAG: Cell<Option<u16>> = const { Cell::new(None) };
+}
+
+/// Route the allocations made inside the closure to the allocator identified by the raw
+/// `allocator_tag`, restoring the previous scope after returning
+///
+/// Setting the scope doesn't allocate and is async-signal-safe.
+#[inline(always)]
+pub fn signal_scope<R>(allocator_tag: impl Into<u16>, closure: impl FnOnce() -> R) -> R {
+    // Restores the outer scope, even when unwinding
+    struct Restore(Option<u16>);
+
+    impl Drop for Restore {
+        #[inline(always)]
+        fn drop(&mut self) {
+            let _ = SIGNAL_TAG.try_with(|tag| tag.set(self.0));
+        }
+    }
+
+    let outer = SIGNAL_TAG
+        .try_with(|tag| tag.replace(Some(allocator_tag.into())))
+        .unwrap_or(None);
+    let _restore = Restore(outer);
+    closure()
+}
+
+/// Raw tag of the allocator of the innermost signal scope of the thread, if any
+#[inline(always)]
+pub fn signal_route() -> Option<u16> {
+    SIGNAL_TAG.try_with(Cell::get).unwrap_or(None)
+}
@@ -0,0 +1,227 @@
+//! Statistics of size classes
+//!
+//! Allocators like jemalloc round every request up to one of their size classes, so a block uses
+//! more memory than requested. With the `size-classes` feature, the blocks of the tags whose
+//! allocator implements [`SizeClasses`] are counted in the size class they were rounded up to,
+//! which measures the fragmentation caused by the rounding for each tag. It's implemented for
+//! [`Jemalloc`](jemallocator::Jemalloc), and can be implemented for other allocators, e.g. with
+//! `mi_good_size` for mimalloc. Backends created with
+//! [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get
+//! `size_classes(tag)`:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Fast => jemallocator::Jemalloc,
+//! }
+//!
+//! fn main() {
+//!     let mut buffers = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Fast, || {
+//!         buffers = (0..10).map(|_| vec![0u8; 1000]).collect::<Vec<_>>();
+//!     });
+//!
+//!     for class in GlobalAllocator::size_classes(AllocatorTag::Fast) {
+//!         println!(
+//!             "{} bytes: {} live blocks, {} bytes wasted",
+//!             class.class_size,
+//!             class.live_allocations(),
+//!             class.wasted_bytes()
+//!         );
+//!     }
+//!     assert!(GlobalAllocator::size_classes(AllocatorTag::System).is_empty());
+//! }
+//! ```
+//!
+//! The requested size of a block includes its hidden tag, since that's what the allocator
+//! rounds up. Up to [`CAPACITY`] pairs of tag and size class are recorded, allocations of other
+//! ones aren't counted. With the `single-allocator` feature, nothing is recorded.
+
+use std::{
+    alloc::Layout,
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{table::Table, MultiAllocatorBackend, TagRepr};
+
+/// Maximum number of pairs of tag and size class
+pub const CAPACITY: usize = 1024;
+
+/// Allocator that rounds requests up to size classes
+pub trait SizeClasses {
+    /// Size of the block actually used for a request of `layout`
+    fn size_class(&self, layout: Layout) -> usize;
+}
+
+impl SizeClasses for jemallocator::Jemalloc {
+    fn size_class(&self, layout: Layout) -> usize {
+        let flags = jemalloc_sys::MALLOCX_ALIGN(layout.align());
+        match unsafe { jemalloc_sys::nallocx(layout.size(), flags) } {
+            0 => layout.size(),
+            size => size,
+        }
+    }
+}
+
+/// Statistics of a pair of tag and size class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SizeClassStats {
+    /// Raw tag of the allocator
+    pub raw_tag: u16,
+    /// Size of the blocks of the class
+    pub class_size: usize,
+    /// Number of allocations made
+    pub allocations: usize,
+    /// Number of deallocations made
+    pub deallocations: usize,
+    /// Bytes requested by the blocks that haven't been deallocated yet
+    pub live_requested_bytes: usize,
+}
+
+impl SizeClassStats {
+    /// Number of allocations that haven't been deallocated yet
+    pub fn live_allocations(&self) -> usize {
+        self.allocations.saturating_sub(self.deallocations)
+    }
+
+    /// Bytes used by the blocks that haven't been deallocated yet
+    pub fn live_class_bytes(&self) -> usize {
+        self.live_allocations() * self.class_size
+    }
+
+    /// Bytes lost to the rounding of the blocks that haven't been deallocated yet
+    pub fn wasted_bytes(&self) -> usize {
+        self.live_class_bytes()
+            .saturating_sub(self.live_requested_bytes)
+    }
+}
+
+struct Counters {
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    live_requested_bytes: AtomicUsize,
+}
+
+// Pairs of tag and size class, packed with `key`
+static COUNTERS: Table<u64, Counters, CAPACITY> = Table::new(
+    [const {
+        Counters {
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            live_requested_bytes: AtomicUsize::new(0),
+        }
+    }; CAPACITY],
+);
+
+#[cfg(not(feature = "single-allocator"))]
+fn key(raw_tag: u16, class_size: usize) -> u64 {
+    (u64::from(raw_tag) << 48) | (class_size as u64 & ((1 << 48) - 1))
+}
+
+/// Record the allocation of a block of `layout` rounded up to `class_size` by the allocator
+/// identified by `raw_tag`
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) fn record_alloc(raw_tag: u16, layout: Layout, class_size: usize) {
+    if let Some(counters) = COUNTERS.get_or_insert(key(raw_tag, class_size)) {
+        counters.allocations.fetch_add(1, Ordering::Relaxed);
+        counters
+            .live_requested_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Record the deallocation of a block recorded by [`record_alloc`]
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) fn record_dealloc(raw_tag: u16, layout: Layout, class_size: usize) {
+    if let Some(counters) = COUNTERS.get_or_insert(key(raw_tag, class_size)) {
+        counters.deallocations.fetch_add(1, Ordering::Relaxed);
+        counters
+            .live_requested_bytes
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Statistics of every pair of tag and size class that has allocated, by tag and size class
+pub fn size_class_stats() -> Vec<SizeClassStats> {
+    let mut stats: Vec<_> = COUNTERS
+        .iter()
+        .map(|(key, counters)| SizeClassStats {
+            raw_tag: (key >> 48) as u16,
+            class_size: (key & ((1 << 48) - 1)) as usize,
+            allocations: counters.allocations.load(Ordering::Relaxed),
+            deallocations: counters.deallocations.load(Ordering::Relaxed),
+            live_requested_bytes: counters.live_requested_bytes.load(Ordering::Relaxed),
+        })
+        .collect();
+    stats.sort_by_key(|class| (class.raw_tag, class.class_size));
+    stats
+}
+
+/// Statistics of the size classes of the allocator identified by `tag`, by size class
+pub fn tag_size_classes<Backend: MultiAllocatorBackend>(tag: Backend::Tag) -> Vec<SizeClassStats> {
+    let raw_tag = Backend::raw_tag(tag);
+    size_class_stats()
+        .into_iter()
+        .filter(|class| class.raw_tag == raw_tag)
+        .collect()
+}
+
+/// Write the statistics of every pair of tag and size class to `out`, naming tags after the
+/// tags of `Backend`
+pub fn dump<Backend: MultiAllocatorBackend>(mut out: impl io::Write) -> io::Result<()> {
+    writeln!(out, "okaoka size classes:")?;
+    for class in size_class_stats() {
+        let tag_name = Backend::Repr::from_raw(class.raw_tag)
+            .and_then(|tag| Backend::Tag::try_from(tag).ok())
+            .map_or("unknown", Backend::tag_name);
+        writeln!(
+            out,
+            "  {tag_name} {} bytes: {} live allocations, {} requested bytes, {} wasted bytes",
+            class.class_size,
+            class.live_allocations(),
+            class.live_requested_bytes,
+            class.wasted_bytes()
+        )?;
+    }
+    Ok(())
+}
+
+/// Dispatch of [`MultiAllocatorBackend::size_class`] to the allocators of the entries, by
+/// autoref: `(&allocator).size_class(layout)` resolves to `ViaSizeClasses` for allocators that
+/// implement [`SizeClasses`] and to `ViaOther` otherwise
+#[doc(hidden)]
+pub mod dispatch {
+    use std::alloc::Layout;
+
+    use super::SizeClasses;
+
+    pub trait ViaSizeClasses {
+        fn size_class(&self, layout: Layout) -> Option<usize>;
+    }
+
+    impl<A: SizeClasses> ViaSizeClasses for A {
+        #[inline(always)]
+        fn size_class(&self, layout: Layout) -> Option<usize> {
+            Some(SizeClasses::size_class(self, layout))
+        }
+    }
+
+    pub trait ViaOther {
+        fn size_class(&self, layout: Layout) -> Option<usize>;
+    }
+
+    impl<A> ViaOther for &A {
+        #[inline(always)]
+        fn size_class(&self, _layout: Layout) -> Option<usize> {
+            None
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! Slab allocators with sizes declared in the backend
+//!
+//! With the `slab` feature, an entry declared with `Name(slab: [32, 64, 128]) => Allocator`
+//! serves the blocks of up to 128 bytes from slabs of fixed-size objects, rounding them up to the
+//! smallest declared size that fits. Slabs are carved from chunks allocated with `Allocator`, and
+//! freed objects are reused by later allocations of the same size, which suits workloads that
+//! allocate lots of small objects of a few sizes, like interners and graphs. Other blocks are
+//! forwarded to `Allocator`, or rejected with `Name(slab: [...], others: Reject)`:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Nodes(slab: [32, 64, 128]) => System,
+//! }
+//!
+//! struct Node {
+//!     value: u64,
+//!     next: Option<Box<Node>>,
+//! }
+//!
+//! fn main() {
+//!     let mut list = None;
+//!     GlobalAllocator::with(AllocatorTag::Nodes, || {
+//!         for value in 0..1000 {
+//!             list = Some(Box::new(Node { value, next: list.take() }));
+//!         }
+//!         // Forwarded to `System`
+//!         let _buffer = vec![0u8; 4096];
+//!     });
+//!     assert_eq!(list.unwrap().value, 999);
+//! }
+//! ```
+//!
+//! The size of a block includes its hidden tag. Chunks are kept by the slab once allocated, so
+//! its memory usage is the peak of each size.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    sync::Mutex,
+};
+
+/// Minimum size of the chunks allocated for a slab
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Alignment of the chunks, which bounds the alignment of the objects
+const CHUNK_ALIGN: usize = 4096;
+
+/// What a [`Slab`] does with the blocks that don't fit any of its sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Others {
+    /// Allocate them with the backing allocator
+    Forward,
+    /// Fail to allocate them
+    Reject,
+}
+
+// Free objects of a size, linked through their first bytes
+struct FreeList(*mut u8);
+
+// SAFETY: the objects are only accessed with the lock held
+unsafe impl Send for FreeList {}
+
+/// Allocator serving `N` sizes from slabs, see the module documentation
+pub struct Slab<const N: usize> {
+    sizes: [usize; N],
+    free_lists: [Mutex<FreeList>; N],
+    backing: &'static (dyn GlobalAlloc + Sync),
+    others: Others,
+}
+
+impl<const N: usize> Slab<N> {
+    /// Slab of objects of `sizes` bytes, in increasing order, allocating its chunks and the
+    /// other blocks with `backing`
+    pub const fn new(sizes: [usize; N], backing: &'static (dyn GlobalAlloc + Sync)) -> Self {
+        let mut index = 0;
+        while index < N {
+            assert!(sizes[index] > 0, "slab sizes must not be 0");
+            assert!(
+                index == 0 || sizes[index - 1] < sizes[index],
+                "slab sizes must be in increasing order"
+            );
+            index += 1;
+        }
+        Self {
+            sizes,
+            free_lists: [const { Mutex::new(FreeList(ptr::null_mut())) }; N],
+            backing,
+            others: Others::Forward,
+        }
+    }
+
+    /// Set what to do with the blocks that don't fit any size
+    pub const fn others(mut self, others: Others) -> Self {
+        self.others = others;
+        self
+    }
+
+    /// Declared sizes
+    pub const fn sizes(&self) -> &[usize; N] {
+        &self.sizes
+    }
+
+    /// Index of the smallest size that fits `layout`, `None` if there's none
+    fn size_index(&self, layout: Layout) -> Option<usize> {
+        self.sizes.iter().position(|&size| {
+            // Objects are aligned to the largest power of two dividing their size
+            let align = (size & size.wrapping_neg()).min(CHUNK_ALIGN);
+            layout.size() <= size && layout.align() <= align
+        })
+    }
+
+    /// Add a chunk of objects of the size at `index` to `free_list`, returning false if the
+    /// backing allocator failed
+    fn grow(&self, index: usize, free_list: &mut FreeList) -> bool {
+        let size = self.sizes[index].max(std::mem::size_of::<*mut u8>());
+        let objects = (CHUNK_SIZE / size).max(1);
+        let layout = unsafe { Layout::from_size_align_unchecked(objects * size, CHUNK_ALIGN) };
+        let chunk = unsafe { self.backing.alloc(layout) };
+        if chunk.is_null() {
+            return false;
+        }
+        for object in (0..objects).rev() {
+            let object = unsafe { chunk.add(object * size) };
+            unsafe { ptr::write_unaligned(object.cast(), free_list.0) };
+            free_list.0 = object;
+        }
+        true
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for Slab<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(index) = self.size_index(layout) else {
+            return match self.others {
+                Others::Forward => unsafe { self.backing.alloc(layout) },
+                Others::Reject => ptr::null_mut(),
+            };
+        };
+        let mut free_list = self.free_lists[index]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if free_list.0.is_null() && !self.grow(index, &mut free_list) {
+            return ptr::null_mut();
+        }
+        let object = free_list.0;
+        free_list.0 = unsafe { ptr::read_unaligned(object.cast()) };
+        object
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(index) = self.size_index(layout) else {
+            return unsafe { self.backing.dealloc(ptr, layout) };
+        };
+        let mut free_list = self.free_lists[index]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        unsafe { ptr::write_unaligned(ptr.cast(), free_list.0) };
+        free_list.0 = ptr;
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        match (self.size_index(layout), self.size_index(new_layout)) {
+            // Same object
+            (Some(old), Some(new)) if old == new => ptr,
+            // Both forwarded
+            (None, None) => unsafe { self.backing.realloc(ptr, layout, new_size) },
+            _ => {
+                let new_ptr = unsafe { self.alloc(new_layout) };
+                if !new_ptr.is_null() {
+                    unsafe {
+                        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                        self.dealloc(ptr, layout);
+                    }
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, Counted};
+
+    /// Blocks allocated and freed by the slab backed by `Counted<id>`
+    fn counts(id: usize) -> (usize, usize) {
+        (test_util::allocated(id), test_util::freed(id))
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn declared_sizes_never_reach_the_backing_allocator() {
+        // Slabs keep their chunks, so they're statics, like in a backend
+        static BACKING: Counted<10> = Counted;
+        static SLAB: Slab<3> = Slab::new([32, 64, 128], &BACKING);
+        let blocks: Vec<_> = (0..100)
+            .flat_map(|_| [layout(24), layout(64), layout(100)])
+            .map(|layout| (unsafe { SLAB.alloc(layout) }, layout))
+            .collect();
+        assert!(blocks.iter().all(|(ptr, _)| !ptr.is_null()));
+        // A chunk for each size
+        assert_eq!(counts(10), (3, 0));
+        for (ptr, layout) in blocks {
+            unsafe { SLAB.dealloc(ptr, layout) };
+        }
+        assert_eq!(counts(10), (3, 0));
+    }
+
+    #[test]
+    fn other_sizes_are_forwarded() {
+        static BACKING: Counted<11> = Counted;
+        static SLAB: Slab<3> = Slab::new([32, 64, 128], &BACKING);
+        // Too large, then too aligned for any object
+        for (blocks, layout) in [layout(4096), Layout::from_size_align(32, 256).unwrap()]
+            .into_iter()
+            .enumerate()
+        {
+            let ptr = unsafe { SLAB.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(counts(11), (blocks + 1, blocks));
+            unsafe { SLAB.dealloc(ptr, layout) };
+            assert_eq!(counts(11), (blocks + 1, blocks + 1));
+        }
+    }
+
+    #[test]
+    fn other_sizes_are_rejected() {
+        static BACKING: Counted<12> = Counted;
+        static SLAB: Slab<3> = Slab::new([32, 64, 128], &BACKING).others(Others::Reject);
+        assert!(unsafe { SLAB.alloc(layout(4096)) }.is_null());
+        assert_eq!(counts(12), (0, 0));
+        let ptr = unsafe { SLAB.alloc(layout(32)) };
+        assert!(!ptr.is_null());
+        unsafe { SLAB.dealloc(ptr, layout(32)) };
+    }
+
+    #[test]
+    fn freed_objects_are_reused() {
+        static BACKING: Counted<13> = Counted;
+        static SLAB: Slab<3> = Slab::new([32, 64, 128], &BACKING);
+        let first = unsafe { SLAB.alloc(layout(48)) };
+        let second = unsafe { SLAB.alloc(layout(48)) };
+        unsafe { SLAB.dealloc(first, layout(48)) };
+        // The object of 64 bytes freed last, whatever the size asked for
+        assert_eq!(unsafe { SLAB.alloc(layout(64)) }, first);
+        unsafe {
+            SLAB.dealloc(first, layout(64));
+            SLAB.dealloc(second, layout(48));
+        }
+        assert_eq!(counts(13), (1, 0));
+    }
+
+    #[test]
+    fn realloc_copies_across_sizes() {
+        static BACKING: Counted<14> = Counted;
+        static SLAB: Slab<3> = Slab::new([32, 64, 128], &BACKING);
+        let contents: Vec<u8> = (0..100).collect();
+        unsafe {
+            let ptr = SLAB.alloc(layout(32));
+            ptr.copy_from_nonoverlapping(contents.as_ptr(), 32);
+            // Same object
+            assert_eq!(SLAB.realloc(ptr, layout(32), 20), ptr);
+
+            // To the objects of 128 bytes
+            let ptr = SLAB.realloc(ptr, layout(20), 100);
+            assert_eq!(std::slice::from_raw_parts(ptr, 20), &contents[..20]);
+            ptr.copy_from_nonoverlapping(contents.as_ptr(), 100);
+
+            // Forwarded
+            let ptr = SLAB.realloc(ptr, layout(100), 4096);
+            assert_eq!(counts(14), (3, 0));
+            assert_eq!(std::slice::from_raw_parts(ptr, 100), &contents[..]);
+
+            // Back to the objects of 64 bytes
+            let ptr = SLAB.realloc(ptr, layout(4096), 64);
+            assert_eq!(counts(14), (4, 1));
+            assert_eq!(std::slice::from_raw_parts(ptr, 64), &contents[..64]);
+            SLAB.dealloc(ptr, layout(64));
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Per-tag allocation statistics
+//!
+//! Every [`MultiAllocatorBackend`] keeps a set of [`Counters`] for each of its tags, updated by
+//! [`MultiAllocator`](crate::MultiAllocator) on every allocation and deallocation. Backends
+//! created with [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get
+//! typed accessors for them:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::with(AllocatorTag::Arena, || {
+//!         let _x = Box::new(10u64);
+//!     });
+//!
+//!     let stats = GlobalAllocator::arena_stats();
+//!     assert_eq!(stats.allocations, 1);
+//!     assert_eq!(stats.live_bytes, 0);
+//!     assert_eq!(GlobalAllocator::all_stats()[&AllocatorTag::Arena], stats);
+//! }
+//! ```
+//!
+//! Allocation rates over a sliding window are measured with a [`RateMeter`].
+
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "owner")]
+use crate::table::Table;
+use crate::{
+    sync::{const_fn_unless_loom, AtomicUsize},
+    MultiAllocatorBackend,
+};
+
+/// Snapshot of the statistics of a tag
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TagStats {
+    /// Number of allocations made
+    pub allocations: usize,
+    /// Number of deallocations made
+    pub deallocations: usize,
+    /// Total bytes allocated
+    pub allocated_bytes: usize,
+    /// Total bytes deallocated
+    pub deallocated_bytes: usize,
+    /// Bytes currently allocated
+    pub live_bytes: usize,
+    /// Highest value `live_bytes` has reached
+    pub peak_bytes: usize,
+}
+
+impl TagStats {
+    /// Number of allocations that haven't been deallocated yet
+    pub fn live_allocations(&self) -> usize {
+        self.allocations.saturating_sub(self.deallocations)
+    }
+}
+
+/// Live statistics of a tag
+///
+/// Sizes are the ones requested by the user, without the hidden tag.
+pub struct Counters {
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    deallocated_bytes: AtomicUsize,
+    live_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+}
+
+impl Counters {
+    const_fn_unless_loom! {
+        pub fn new() -> Self {
+            Self {
+                allocations: AtomicUsize::new(0),
+                deallocations: AtomicUsize::new(0),
+                allocated_bytes: AtomicUsize::new(0),
+                deallocated_bytes: AtomicUsize::new(0),
+                live_bytes: AtomicUsize::new(0),
+                peak_bytes: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn record_alloc(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+        // Wraps around like the counter, for the counters of a thread deallocating more than it
+        // allocated, see the `assertions` module
+        let live_bytes = self
+            .live_bytes
+            .fetch_add(size, Ordering::Relaxed)
+            .wrapping_add(size);
+        self.peak_bytes.fetch_max(live_bytes, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn record_dealloc(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.deallocated_bytes.fetch_add(size, Ordering::Relaxed);
+        self.live_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    /// Bytes currently allocated, wrapped around if more bytes were deallocated
+    #[cfg(feature = "assertions")]
+    #[inline(always)]
+    pub(crate) fn live_bytes(&self) -> usize {
+        self.live_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Take a snapshot of the counters
+    ///
+    /// Each counter is read independently, so a snapshot taken while other threads allocate may
+    /// be slightly inconsistent.
+    pub fn snapshot(&self) -> TagStats {
+        TagStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            deallocated_bytes: self.deallocated_bytes.load(Ordering::Relaxed),
+            live_bytes: self.live_bytes.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics of the pairs of tag and owner of a backend
+///
+/// Holds up to [`Self::CAPACITY`] pairs, the allocations of other pairs are only counted in the
+/// statistics of their tag. Pairs are added without locks on their first allocation.
+#[cfg(feature = "owner")]
+pub struct OwnerCounters {
+    // Pairs of tag and owner, packed with `Self::key`
+    counters: Table<u64, Counters, { Self::CAPACITY }>,
+}
+
+#[cfg(feature = "owner")]
+impl OwnerCounters {
+    /// Maximum number of pairs
+    pub const CAPACITY: usize = 256;
+
+    pub const fn new() -> Self {
+        Self {
+            counters: Table::new([const { Counters::new() }; Self::CAPACITY]),
+        }
+    }
+
+    fn key(raw_tag: u16, owner: u16) -> u64 {
+        (((raw_tag as u64) << 16) | owner as u64) + 1
+    }
+
+    /// Counters of the pair, adding it if needed, `None` if there's no room for it
+    #[cfg(not(feature = "single-allocator"))]
+    #[inline(always)]
+    pub(crate) fn get_or_insert(&self, raw_tag: u16, owner: u16) -> Option<&Counters> {
+        self.counters.get_or_insert(Self::key(raw_tag, owner))
+    }
+
+    /// Counters of the pair, `None` if it hasn't allocated
+    pub fn get(&self, raw_tag: u16, owner: u16) -> Option<&Counters> {
+        self.counters.get(Self::key(raw_tag, owner))
+    }
+
+    /// Raw tag, owner and counters of every pair that has allocated
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16, &Counters)> {
+        self.counters
+            .iter()
+            .map(|(key, counters)| (((key - 1) >> 16) as u16, (key - 1) as u16, counters))
+    }
+}
+
+#[cfg(feature = "owner")]
+impl Default for OwnerCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics of the allocator identified by `tag`
+pub fn tag_stats<Backend: MultiAllocatorBackend>(tag: Backend::Tag) -> TagStats {
+    Backend::counters(tag).snapshot()
+}
+
+/// Statistics of every allocator of `Backend`
+pub fn all_stats<Backend>() -> HashMap<Backend::Tag, TagStats>
+where
+    Backend: MultiAllocatorBackend,
+    Backend::Tag: Eq + std::hash::Hash,
+{
+    Backend::TAGS
+        .iter()
+        .map(|&tag| (tag, tag_stats::<Backend>(tag)))
+        .collect()
+}
+
+/// Statistics of the allocations of `owner` with the allocator identified by `tag`
+#[cfg(feature = "owner")]
+pub fn owner_stats<Backend: MultiAllocatorBackend>(tag: Backend::Tag, owner: u16) -> TagStats {
+    Backend::owner_counters()
+        .get(Backend::raw_tag(tag), owner)
+        .map(Counters::snapshot)
+        .unwrap_or_default()
+}
+
+/// Statistics of every pair of tag and owner of `Backend` that has allocated
+#[cfg(feature = "owner")]
+pub fn all_owner_stats<Backend>() -> HashMap<(Backend::Tag, u16), TagStats>
+where
+    Backend: MultiAllocatorBackend,
+    Backend::Tag: Eq + std::hash::Hash,
+{
+    Backend::TAGS
+        .iter()
+        .flat_map(|&tag| {
+            let raw_tag = Backend::raw_tag(tag);
+            Backend::owner_counters()
+                .iter()
+                .filter(move |&(pair_tag, _, _)| pair_tag == raw_tag)
+                .map(move |(_, owner, counters)| ((tag, owner), counters.snapshot()))
+        })
+        .collect()
+}
+
+/// Allocation rate of a tag
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rate {
+    /// Allocations made per second
+    pub allocations_per_sec: f64,
+    /// Bytes allocated per second
+    pub bytes_per_sec: f64,
+}
+
+/// Allocation rates of the tags of `Backend` over a sliding window
+///
+/// Rates are computed from snapshots of the statistics taken by [`Self::sample`], so the
+/// allocator pays nothing for them. The window spans the samples taken during the last
+/// `window`, e.g. a dashboard that samples every second with a window of 10 seconds shows the
+/// rates of the last 10 seconds.
+///
+/// ```rust
+/// use std::{alloc::System, thread, time::Duration};
+///
+/// use okaoka::stats::RateMeter;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Arena => System,
+/// }
+///
+/// fn main() {
+///     let mut meter = RateMeter::<GlobalAllocator>::new(Duration::from_secs(10));
+///     meter.sample();
+///     GlobalAllocator::with(AllocatorTag::Arena, || {
+///         for _ in 0..100 {
+///             drop(Box::new(10u64));
+///         }
+///     });
+///     thread::sleep(Duration::from_millis(10));
+///     meter.sample();
+///
+///     let rate = meter.rate(AllocatorTag::Arena);
+///     assert!(rate.allocations_per_sec > 0.0);
+///     assert!(rate.bytes_per_sec >= 8.0 * rate.allocations_per_sec);
+/// }
+/// ```
+pub struct RateMeter<Backend: MultiAllocatorBackend> {
+    window: Duration,
+    // Time of each sample and allocations and allocated bytes of each tag, oldest first
+    samples: VecDeque<(Instant, Vec<(usize, usize)>)>,
+    _backend: PhantomData<Backend>,
+}
+
+impl<Backend: MultiAllocatorBackend> RateMeter<Backend> {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+            _backend: PhantomData,
+        }
+    }
+
+    /// Take a snapshot of the statistics, forgetting the ones that left the window
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        let totals = Backend::TAGS
+            .iter()
+            .map(|&tag| {
+                let stats = tag_stats::<Backend>(tag);
+                (stats.allocations, stats.allocated_bytes)
+            })
+            .collect();
+        self.samples.push_back((now, totals));
+        // The window starts at the newest sample taken at least `window` ago
+        while let Some((time, _)) = self.samples.get(1) {
+            if now.duration_since(*time) < self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Rate of the allocator identified by `tag` between the oldest and the newest samples,
+    /// zero until two samples have been taken
+    pub fn rate(&self, tag: Backend::Tag) -> Rate {
+        let (Some((start, first)), Some((end, last))) = (self.samples.front(), self.samples.back())
+        else {
+            return Rate::default();
+        };
+        let seconds = end.duration_since(*start).as_secs_f64();
+        if seconds == 0.0 {
+            return Rate::default();
+        }
+        let index = Backend::tag_index(tag);
+        Rate {
+            allocations_per_sec: (last[index].0 - first[index].0) as f64 / seconds,
+            bytes_per_sec: (last[index].1 - first[index].1) as f64 / seconds,
+        }
+    }
+
+    /// Rates of every allocator
+    pub fn rates(&self) -> HashMap<Backend::Tag, Rate>
+    where
+        Backend::Tag: Eq + std::hash::Hash,
+    {
+        Backend::TAGS
+            .iter()
+            .map(|&tag| (tag, self.rate(tag)))
+            .collect()
+    }
+}
@@ -0,0 +1,154 @@
+//! Compatibility with the API of `stats_alloc`
+//!
+//! With the `stats-alloc` feature, [`Region`] and [`Stats`] mirror the types of the `stats_alloc`
+//! crate over the statistics of every tag of a [`MultiAllocator`], so the benchmarks and tests
+//! measuring allocations with `stats_alloc` keep working after switching to this crate, once
+//! their imports point to this module and the global allocator is named with `static_name`:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::stats_alloc::Region;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     static_name = GLOBAL,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let region = Region::new(&GLOBAL);
+//!     let mut values = Vec::new();
+//!     GlobalAllocator::with(AllocatorTag::Arena, || values = vec![0u64; 128]);
+//!     let change = region.change();
+//!     assert_eq!(change.allocations, 1);
+//!     assert_eq!(change.bytes_allocated, 1024);
+//! }
+//! ```
+//!
+//! The statistics are those of the `stats` feature, summed over the tags of the backend, without
+//! the allocators added at runtime. They don't count reallocations apart: a reallocation counts
+//! as a deallocation of the old size and an allocation of the new one, so `reallocations` and
+//! `bytes_reallocated` are always 0. Like with `stats_alloc`, the statistics are global, so a
+//! region also counts the allocations of the other threads.
+
+use std::ops;
+
+use crate::{MultiAllocator, MultiAllocatorBackend};
+
+/// Allocation statistics, like `stats_alloc::Stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Stats {
+    /// Number of allocations made
+    pub allocations: usize,
+    /// Number of deallocations made
+    pub deallocations: usize,
+    /// Always 0, reallocations are counted as a deallocation and an allocation
+    pub reallocations: usize,
+    /// Total bytes allocated
+    pub bytes_allocated: usize,
+    /// Total bytes deallocated
+    pub bytes_deallocated: usize,
+    /// Always 0, reallocations are counted as a deallocation and an allocation
+    pub bytes_reallocated: isize,
+}
+
+impl ops::Add for Stats {
+    type Output = Stats;
+
+    fn add(mut self, rhs: Stats) -> Stats {
+        self += rhs;
+        self
+    }
+}
+
+impl ops::AddAssign for Stats {
+    fn add_assign(&mut self, rhs: Stats) {
+        self.allocations += rhs.allocations;
+        self.deallocations += rhs.deallocations;
+        self.reallocations += rhs.reallocations;
+        self.bytes_allocated += rhs.bytes_allocated;
+        self.bytes_deallocated += rhs.bytes_deallocated;
+        self.bytes_reallocated += rhs.bytes_reallocated;
+    }
+}
+
+impl ops::Sub for Stats {
+    type Output = Stats;
+
+    fn sub(mut self, rhs: Stats) -> Stats {
+        self -= rhs;
+        self
+    }
+}
+
+impl ops::SubAssign for Stats {
+    fn sub_assign(&mut self, rhs: Stats) {
+        self.allocations -= rhs.allocations;
+        self.deallocations -= rhs.deallocations;
+        self.reallocations -= rhs.reallocations;
+        self.bytes_allocated -= rhs.bytes_allocated;
+        self.bytes_deallocated -= rhs.bytes_deallocated;
+        self.bytes_reallocated -= rhs.bytes_reallocated;
+    }
+}
+
+impl<Backend: MultiAllocatorBackend> MultiAllocator<Backend> {
+    /// Statistics of every tag of the backend, like `stats_alloc::StatsAlloc::stats`
+    pub fn stats(&self) -> Stats {
+        Backend::TAGS
+            .iter()
+            .map(|&tag| {
+                let stats = Backend::counters(tag).snapshot();
+                Stats {
+                    allocations: stats.allocations,
+                    deallocations: stats.deallocations,
+                    bytes_allocated: stats.allocated_bytes,
+                    bytes_deallocated: stats.deallocated_bytes,
+                    ..Stats::default()
+                }
+            })
+            .fold(Stats::default(), ops::Add::add)
+    }
+}
+
+/// Statistics of an allocator since a point in time, like `stats_alloc::Region`
+pub struct Region<'a, Backend: MultiAllocatorBackend> {
+    allocator: &'a MultiAllocator<Backend>,
+    initial_stats: Stats,
+}
+
+impl<'a, Backend: MultiAllocatorBackend> Region<'a, Backend> {
+    /// Region starting now
+    pub fn new(allocator: &'a MultiAllocator<Backend>) -> Self {
+        Self {
+            allocator,
+            initial_stats: allocator.stats(),
+        }
+    }
+
+    /// Statistics at the start of the region
+    pub fn initial(&self) -> Stats {
+        self.initial_stats
+    }
+
+    /// Statistics since the start of the region
+    pub fn change(&self) -> Stats {
+        self.allocator.stats() - self.initial_stats
+    }
+
+    /// Statistics since the start of the region, starting it again
+    pub fn change_and_reset(&mut self) -> Stats {
+        let stats = self.allocator.stats();
+        let change = stats - self.initial_stats;
+        self.initial_stats = stats;
+        change
+    }
+
+    /// Start the region again
+    pub fn reset(&mut self) {
+        self.initial_stats = self.allocator.stats();
+    }
+}
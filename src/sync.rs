@@ -0,0 +1,64 @@
+//! Primitives of the tag storage, the registry and the counters
+//!
+//! They come from loom when built with `--cfg loom`, so that loom can model-check the code
+//! switching allocators and reading the statistics. The atomics of loom can only be created at
+//! runtime, inside a model, so the statics holding them are lazy statics of loom, declared with
+//! `__static!`.
+
+#[cfg(not(loom))]
+pub(crate) use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize};
+
+/// Constructor that is `const`, except under loom
+macro_rules! const_fn_unless_loom {
+    ($(#[$meta:meta])* $vis:vis fn $name:ident() -> $ret:ty $body:block) => {
+        #[cfg(not(loom))]
+        $(#[$meta])*
+        $vis const fn $name() -> $ret $body
+
+        #[cfg(loom)]
+        $(#[$meta])*
+        $vis fn $name() -> $ret $body
+    };
+}
+
+pub(crate) use const_fn_unless_loom;
+
+/// Static of a backend, or a lazy static of loom under loom
+///
+/// `each` repeats the initializer for every element of an array.
+#[cfg(not(loom))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __static {
+    ($(#[$meta:meta])* $name:ident: [$ty:ty; $count:expr] = each $init:expr) => {
+        $(#[$meta])*
+        static $name: [$ty; $count] = [const { $init }; $count];
+    };
+
+    ($(#[$meta:meta])* $name:ident: $ty:ty = $init:expr) => {
+        $(#[$meta])*
+        static $name: $ty = $init;
+    };
+}
+
+#[cfg(loom)]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __static {
+    ($(#[$meta:meta])* $name:ident: [$ty:ty; $count:expr] = each $init:expr) => {
+        $crate::__loom::lazy_static! {
+            $(#[$meta])*
+            static ref $name: [$ty; $count] = core::array::from_fn(|_| $init);
+        }
+    };
+
+    ($(#[$meta:meta])* $name:ident: $ty:ty = $init:expr) => {
+        $crate::__loom::lazy_static! {
+            $(#[$meta])*
+            static ref $name: $ty = $init;
+        }
+    };
+}
@@ -0,0 +1,115 @@
+//! Lock-free table of counters, keyed by a pair of a tag and something else
+//!
+//! Used by the statistics that split the ones of a tag, by owner, callsite, thread or size
+//! class. Entries are added with open addressing on their first allocation and never removed,
+//! so a lookup is a few atomic loads and the values can be borrowed for `'static`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Entries to probe for `hash` in a table of `n` entries, starting at its hash
+pub(crate) fn probe(hash: u64, n: usize) -> impl Iterator<Item = usize> {
+    let start = ((hash.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize) % n;
+    (0..n).map(move |offset| (start + offset) % n)
+}
+
+/// Table of up to `N` values of type `V`, keyed by non-zero keys of type `K`
+pub(crate) struct Table<K, V, const N: usize> {
+    // Key of each entry, 0 when the entry is empty
+    keys: [AtomicU64; N],
+    values: [V; N],
+    _key: std::marker::PhantomData<K>,
+}
+
+impl<K, V, const N: usize> Table<K, V, N>
+where
+    K: Copy + Eq + Into<u64> + TryFrom<u64>,
+{
+    /// Table with the initial value of every entry
+    pub(crate) const fn new(values: [V; N]) -> Self {
+        Self {
+            keys: [const { AtomicU64::new(0) }; N],
+            values,
+            _key: std::marker::PhantomData,
+        }
+    }
+
+    /// Value of `key`, adding it if needed, `None` if there's no room for it
+    #[cfg_attr(feature = "single-allocator", allow(dead_code))]
+    #[inline(always)]
+    pub(crate) fn get_or_insert(&self, key: K) -> Option<&V> {
+        let key = key.into();
+        debug_assert_ne!(key, 0);
+        for index in probe(key, N) {
+            let entry = &self.keys[index];
+            let found = match entry.load(Ordering::Acquire) {
+                0 => match entry.compare_exchange(0, key, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => key,
+                    Err(found) => found,
+                },
+                found => found,
+            };
+            if found == key {
+                return Some(&self.values[index]);
+            }
+        }
+        None
+    }
+
+    /// Value of `key`, `None` if it hasn't been added
+    #[cfg_attr(not(feature = "owner"), allow(dead_code))]
+    pub(crate) fn get(&self, key: K) -> Option<&V> {
+        let key = key.into();
+        for index in probe(key, N) {
+            match self.keys[index].load(Ordering::Acquire) {
+                0 => return None,
+                found if found == key => return Some(&self.values[index]),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Key and value of every entry that has been added
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (K, &V)> {
+        self.keys
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(key, value)| match key.load(Ordering::Acquire) {
+                0 => None,
+                key => Some((K::try_from(key).ok()?, value)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::Table;
+
+    #[test]
+    fn entries_are_added_until_full() {
+        let table = Table::<u32, AtomicUsize, 4>::new([const { AtomicUsize::new(0) }; 4]);
+        for key in 1..=4 {
+            table
+                .get_or_insert(key)
+                .unwrap()
+                .fetch_add(key as usize, Ordering::Relaxed);
+        }
+        assert!(table.get_or_insert(5).is_none());
+        assert!(table.get(5).is_none());
+        // The same entry for the same key
+        table
+            .get_or_insert(3)
+            .unwrap()
+            .fetch_add(3, Ordering::Relaxed);
+        assert_eq!(table.get(3).unwrap().load(Ordering::Relaxed), 6);
+
+        let mut entries: Vec<_> = table
+            .iter()
+            .map(|(key, value)| (key, value.load(Ordering::Relaxed)))
+            .collect();
+        entries.sort();
+        assert_eq!(entries, [(1, 1), (2, 2), (3, 6), (4, 4)]);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::once::SetOnce;
+
+/// Storage of the current tag, replacing the built-in thread-local
+///
+/// By default the current tag belongs to the thread, which is inaccurate when a work-stealing
+/// executor moves tasks between threads, since a task that sets an allocator and yields leaves
+/// its tag behind on the thread. A runtime can install a source that keeps the tag somewhere
+/// else, e.g. in the current task or in per-CPU storage, with [`set_tag_source`].
+///
+/// The methods are called on every allocation and when switching allocators, so they must be
+/// fast and must not allocate.
+///
+/// # Example
+///
+/// ```rust
+/// use std::{alloc::System, cell::Cell};
+///
+/// use okaoka::TagSource;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+///     Jemalloc => jemallocator::Jemalloc,
+/// }
+///
+/// thread_local! {
+///     // Stands in for the storage of the current task of a runtime
+///     static TASK_TAG: Cell<Option<u16>> = const { Cell::new(None) };
+/// }
+///
+/// struct TaskTagSource;
+///
+/// impl TagSource for TaskTagSource {
+///     fn get(&self) -> Option<u16> {
+///         TASK_TAG.with(Cell::get)
+///     }
+///
+///     fn set(&self, tag: Option<u16>) {
+///         TASK_TAG.with(|task_tag| task_tag.set(tag));
+///     }
+/// }
+///
+/// fn main() {
+///     okaoka::set_tag_source(&TaskTagSource).ok().unwrap();
+///     GlobalAllocator::with(AllocatorTag::Jemalloc, || {
+///         // The tag of jemalloc is stored in `TASK_TAG`
+///         let _x = Box::new(10);
+///     });
+/// }
+/// ```
+pub trait TagSource: Sync {
+    /// Tag of the current allocator, `None` means the default allocator of the backend
+    fn get(&self) -> Option<u16>;
+
+    /// Set the tag of the current allocator
+    fn set(&self, tag: Option<u16>);
+}
+
+static TAG_SOURCE: SetOnce<&'static dyn TagSource> = SetOnce::new();
+
+/// Install `source` as the storage of the current tag for the rest of the program
+///
+/// It should be installed before any allocator is set, e.g. at the start of `main`, since the
+/// tags stored in the built-in thread-local until then are ignored. Returns `source` back if a
+/// source is already installed.
+pub fn set_tag_source(source: &'static dyn TagSource) -> Result<(), &'static dyn TagSource> {
+    TAG_SOURCE.set(source)
+}
+
+/// Installed tag source, if any
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) fn installed() -> Option<&'static dyn TagSource> {
+    TAG_SOURCE.get()
+}
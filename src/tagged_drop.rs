@@ -0,0 +1,202 @@
+use alloc::boxed::Box;
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use crate::AllocatorGuard;
+
+/// Drop `value` with the allocator identified by the raw `allocator_tag`
+///
+/// Destructors may allocate, e.g. to log or to rehash, and those allocations go to the allocator
+/// that is current when the value is dropped. Dropping a value with the allocator it was
+/// allocated with keeps its allocations together.
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Arena => System,
+/// # }
+/// # fn main() {
+/// let mut cache = Vec::new();
+/// GlobalAllocator::with(AllocatorTag::Arena, || cache = vec![1, 2, 3]);
+/// okaoka::drop_in(AllocatorTag::Arena as u8, cache);
+/// # }
+/// ```
+#[inline(always)]
+#[track_caller]
+pub fn drop_in<T>(allocator_tag: impl Into<u16>, value: T) {
+    let _guard = AllocatorGuard::new(allocator_tag);
+    drop(value);
+}
+
+/// Wrapper that drops its value with the allocator identified by a raw tag
+///
+/// Like [`drop_in`], for values whose drop isn't done explicitly, e.g. fields of other types.
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Arena => System,
+/// # }
+/// # fn main() {
+/// use okaoka::TaggedDrop;
+///
+/// let mut names = TaggedDrop::new(AllocatorTag::Arena as u8, Vec::new());
+/// names.push("arena".to_string());
+/// assert_eq!(names.tag(), AllocatorTag::Arena as u16);
+/// // `names` is dropped with the arena
+/// # }
+/// ```
+pub struct TaggedDrop<T> {
+    value: ManuallyDrop<T>,
+    allocator_tag: u16,
+}
+
+impl<T> TaggedDrop<T> {
+    /// Wrap `value` to drop it with the allocator identified by the raw `allocator_tag`
+    pub fn new(allocator_tag: impl Into<u16>, value: T) -> Self {
+        Self {
+            value: ManuallyDrop::new(value),
+            allocator_tag: allocator_tag.into(),
+        }
+    }
+
+    /// Raw tag of the allocator the value is dropped with
+    pub fn tag(&self) -> u16 {
+        self.allocator_tag
+    }
+
+    /// Unwrap the value, which is then dropped with the current allocator
+    pub fn into_inner(self) -> T {
+        let mut this = ManuallyDrop::new(self);
+        unsafe { ManuallyDrop::take(&mut this.value) }
+    }
+}
+
+impl<T> Deref for TaggedDrop<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for TaggedDrop<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T> Drop for TaggedDrop<T> {
+    fn drop(&mut self) {
+        let _guard = AllocatorGuard::new(self.allocator_tag);
+        unsafe { ManuallyDrop::drop(&mut self.value) };
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for TaggedDrop<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaggedDrop")
+            .field("value", &*self.value)
+            .field("allocator_tag", &self.allocator_tag)
+            .finish()
+    }
+}
+
+/// Box that allocates, drops and deallocates its value with the allocator identified by a raw
+/// tag
+///
+/// The choice of allocator travels with the value, wherever it's dropped.
+///
+/// ```rust
+/// # use std::alloc::System;
+/// # okaoka::set_multi_global_allocator! {
+/// #     GlobalAllocator,
+/// #     AllocatorTag,
+/// #     System => System,
+/// #     Arena => System,
+/// # }
+/// # fn main() {
+/// use okaoka::Tagged;
+///
+/// let mut names = Tagged::new(AllocatorTag::Arena as u8, Vec::new());
+/// // Allocated with the current allocator, not with the arena
+/// names.push("arena".to_string());
+/// assert_eq!(names.tag(), AllocatorTag::Arena as u16);
+/// // `names` is dropped and deallocated with the arena
+/// # }
+/// ```
+pub struct Tagged<T> {
+    inner: TaggedDrop<Box<T>>,
+}
+
+impl<T> Tagged<T> {
+    /// Move `value` to a box allocated with the allocator identified by the raw `allocator_tag`
+    #[track_caller]
+    pub fn new(allocator_tag: impl Into<u16>, value: T) -> Self {
+        let allocator_tag = allocator_tag.into();
+        let value = {
+            let _guard = AllocatorGuard::new(allocator_tag);
+            Box::new(value)
+        };
+        Self {
+            inner: TaggedDrop::new(allocator_tag, value),
+        }
+    }
+
+    /// Raw tag of the allocator of the value
+    pub fn tag(&self) -> u16 {
+        self.inner.tag()
+    }
+
+    /// Move the value out of the box, which is deallocated with its allocator
+    pub fn into_inner(self) -> T {
+        let _guard = AllocatorGuard::new(self.tag());
+        // Locals are dropped in reverse order, so the box is deallocated before the guard
+        let boxed = self.inner.into_inner();
+        *boxed
+    }
+}
+
+impl<T> Deref for Tagged<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for Tagged<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> AsRef<T> for Tagged<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T> AsMut<T> for Tagged<T> {
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Tagged<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tagged")
+            .field("value", &**self)
+            .field("allocator_tag", &self.tag())
+            .finish()
+    }
+}
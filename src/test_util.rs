@@ -0,0 +1,49 @@
+//! Allocators shared by the tests
+//!
+//! The tests of a binary run in parallel, so each test module counts with its own ids:
+//!
+//! - `unknown_tag`: 0 and 1
+//! - `allocate_in`: 2 and 3
+//! - `thread_arenas`: 4 to 7
+//! - `clone_in`: 8 and 9, in `tests/clone_in.rs`, which includes this module with `#[path]`
+//! - `slab`: 10 to 14
+//! - `fork`: 15
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+const IDS: usize = 16;
+
+static ALLOCATED: [AtomicUsize; IDS] = [const { AtomicUsize::new(0) }; IDS];
+static FREED: [AtomicUsize; IDS] = [const { AtomicUsize::new(0) }; IDS];
+
+/// System allocator counting its allocations and deallocations under `ID`
+pub(crate) struct Counted<const ID: usize>;
+
+unsafe impl<const ID: usize> GlobalAlloc for Counted<ID> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED[ID].fetch_add(1, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        FREED[ID].fetch_add(1, Ordering::SeqCst);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+/// Number of blocks allocated by the `Counted` allocators of `id`
+pub(crate) fn allocated(id: usize) -> usize {
+    ALLOCATED[id].load(Ordering::SeqCst)
+}
+
+/// Number of blocks deallocated by the `Counted` allocators of `id`
+#[cfg_attr(
+    not(any(feature = "thread-arenas", feature = "slab")),
+    allow(dead_code)
+)]
+pub(crate) fn freed(id: usize) -> usize {
+    FREED[id].load(Ordering::SeqCst)
+}
@@ -0,0 +1,241 @@
+//! Leak checks for tests
+//!
+//! With the `testing` feature, [`check_balanced`] panics if a closure leaves more live bytes in
+//! an allocator than it found, which checks a test for leaks without Valgrind. Backends created
+//! with [`create_multi_allocator_backend`](crate::create_multi_allocator_backend) get
+//! `check_balanced(tag, closure)` as a shorthand:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let len = GlobalAllocator::check_balanced(AllocatorTag::Arena, || {
+//!         let mut names = Vec::new();
+//!         GlobalAllocator::with(AllocatorTag::Arena, || names.push("first".to_string()));
+//!         names.len()
+//!     });
+//!     assert_eq!(len, 1);
+//!
+//!     let result = std::panic::catch_unwind(|| {
+//!         GlobalAllocator::check_balanced(AllocatorTag::Arena, || {
+//!             GlobalAllocator::with(AllocatorTag::Arena, || std::mem::forget(vec![0u8; 64]));
+//!         })
+//!     });
+//!     assert!(result.is_err());
+//! }
+//! ```
+//!
+//! The live bytes are the ones of the [`stats`] of the allocator, so the
+//! allocations of other threads with the same allocator count too. With the `profiling` feature,
+//! the panic message lists the callsites whose live bytes grew.
+//!
+//! [`sweep_alloc_failures`] runs a closure again and again, failing its first allocation, then
+//! its second one, and so on until a run doesn't reach the allocation to fail, which goes
+//! through every error path of code handling allocation failures:
+//!
+//! ```rust
+//! use std::{alloc::System, collections::TryReserveError};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//! }
+//!
+//! fn load(names: &mut Vec<String>) -> Result<(), TryReserveError> {
+//!     names.try_reserve(4)?;
+//!     let mut name = String::new();
+//!     name.try_reserve(5)?;
+//!     name.push_str("first");
+//!     names.push(name);
+//!     Ok(())
+//! }
+//!
+//! fn main() {
+//!     let failures = okaoka::testing::sweep_alloc_failures(|| {
+//!         let mut names = Vec::new();
+//!         match load(&mut names) {
+//!             Ok(()) => assert_eq!(names, ["first"]),
+//!             Err(_) => assert!(names.is_empty()),
+//!         }
+//!     });
+//!     assert_eq!(failures, 2);
+//! }
+//! ```
+//!
+//! Only the allocations and reallocations of the current thread made through
+//! [`MultiAllocator`](crate::MultiAllocator) are counted, and the failing one returns null
+//! without trying the [`OomStrategy`](crate::oom::OomStrategy) of its allocator. The standard
+//! library aborts when an infallible allocation fails, so the closure can only allocate with
+//! fallible APIs like `try_reserve`. A batch allocation counts as one allocation. With the
+//! `single-allocator` feature, every allocation is made with the default allocator.
+
+use std::{cell::Cell, fmt};
+
+use crate::{stats, MultiAllocatorBackend};
+
+/// Callsites of the allocations of `raw_tag` and their live bytes
+#[cfg(feature = "profiling")]
+fn callsites(raw_tag: u16) -> Vec<(&'static std::panic::Location<'static>, usize)> {
+    crate::profiling::callsite_stats()
+        .into_iter()
+        .filter(|callsite| callsite.raw_tag == raw_tag)
+        .map(|callsite| (callsite.location, callsite.stats.live_bytes))
+        .collect()
+}
+
+/// Live bytes left by a closure in an allocator
+struct Leak {
+    tag_name: &'static str,
+    bytes: usize,
+    #[cfg(feature = "profiling")]
+    callsites: Vec<(&'static std::panic::Location<'static>, usize)>,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes leaked with {}", self.bytes, self.tag_name)?;
+        #[cfg(feature = "profiling")]
+        for (location, bytes) in &self.callsites {
+            write!(f, "\n  {bytes} bytes from {location}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Call the closure, panicking if the allocator identified by `tag` has more live bytes
+/// afterwards than before
+#[track_caller]
+pub fn check_balanced<Backend, R>(tag: Backend::Tag, closure: impl FnOnce() -> R) -> R
+where
+    Backend: MultiAllocatorBackend,
+{
+    #[cfg(feature = "profiling")]
+    let before = callsites(Backend::raw_tag(tag));
+    let live_bytes = stats::tag_stats::<Backend>(tag).live_bytes;
+    let result = closure();
+    let bytes = stats::tag_stats::<Backend>(tag)
+        .live_bytes
+        .saturating_sub(live_bytes);
+    if bytes > 0 {
+        let leak = Leak {
+            tag_name: Backend::tag_name(tag),
+            bytes,
+            #[cfg(feature = "profiling")]
+            callsites: callsites(Backend::raw_tag(tag))
+                .into_iter()
+                .filter_map(|(location, live_bytes)| {
+                    let before = before
+                        .iter()
+                        .find(|(other, _)| *other == location)
+                        .map_or(0, |&(_, live_bytes)| live_bytes);
+                    let bytes = live_bytes.checked_sub(before)?;
+                    (bytes > 0).then_some((location, bytes))
+                })
+                .collect(),
+        };
+        panic!("{leak}");
+    }
+    result
+}
+
+/// Allocation to fail on the current thread
+#[derive(Clone, Copy)]
+struct Failure {
+    /// Raw tag of the allocations counted, `None` for every allocation
+    raw_tag: Option<u16>,
+    /// Allocations left before the one to fail, which is 1
+    remaining: usize,
+    failed: bool,
+}
+
+thread_local! {
+    static FAILURE: Cell<Option<Failure>> = const { Cell::new(None) };
+}
+
+/// Call the closure, failing the `n`th allocation of the current thread inside it, starting at
+/// 1, and returning whether it was reached
+///
+/// No allocation fails when `n` is 0.
+pub fn fail_nth_alloc<R>(n: usize, closure: impl FnOnce() -> R) -> (R, bool) {
+    fail(None, n, closure)
+}
+
+/// Like [`fail_nth_alloc`], only counting the allocations made with the allocator identified
+/// by the raw `allocator_tag`
+pub fn fail_nth_alloc_in<R>(
+    allocator_tag: impl Into<u16>,
+    n: usize,
+    closure: impl FnOnce() -> R,
+) -> (R, bool) {
+    fail(Some(allocator_tag.into()), n, closure)
+}
+
+/// Call the closure with its first allocation failing, then its second one, and so on until
+/// a run makes fewer allocations, returning the number of runs with a failed allocation
+pub fn sweep_alloc_failures(closure: impl FnMut()) -> usize {
+    sweep(None, closure)
+}
+
+/// Like [`sweep_alloc_failures`], only counting the allocations made with the allocator
+/// identified by the raw `allocator_tag`
+pub fn sweep_alloc_failures_in(allocator_tag: impl Into<u16>, closure: impl FnMut()) -> usize {
+    sweep(Some(allocator_tag.into()), closure)
+}
+
+fn sweep(raw_tag: Option<u16>, mut closure: impl FnMut()) -> usize {
+    let mut failures = 0;
+    while fail(raw_tag, failures + 1, &mut closure).1 {
+        failures += 1;
+    }
+    failures
+}
+
+fn fail<R>(raw_tag: Option<u16>, n: usize, closure: impl FnOnce() -> R) -> (R, bool) {
+    // Restores the outer failure, even when unwinding
+    struct Restore(Option<Failure>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FAILURE.with(|failure| failure.set(self.0));
+        }
+    }
+
+    let failure = Failure {
+        raw_tag,
+        remaining: n,
+        failed: false,
+    };
+    let restore = Restore(FAILURE.with(|current| current.replace(Some(failure))));
+    let result = closure();
+    let failed = FAILURE
+        .with(Cell::get)
+        .is_some_and(|failure| failure.failed);
+    drop(restore);
+    (result, failed)
+}
+
+/// Whether the allocation with the allocator identified by `raw_tag` is the one to fail
+#[inline(always)]
+pub(crate) fn should_fail(raw_tag: u16) -> bool {
+    let Ok(Some(mut failure)) = FAILURE.try_with(Cell::get) else {
+        return false;
+    };
+    if failure.failed
+        || failure.remaining == 0
+        || failure.raw_tag.is_some_and(|other| other != raw_tag)
+    {
+        return false;
+    }
+    failure.remaining -= 1;
+    failure.failed = failure.remaining == 0;
+    FAILURE.with(|current| current.set(Some(failure)));
+    failure.failed
+}
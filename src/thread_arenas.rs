@@ -0,0 +1,482 @@
+//! One arena per thread under a single tag
+//!
+//! With the `thread-arenas` feature, a [`ThreadArenas`] gives each thread that allocates with it
+//! its own instance of an arena, so threads never contend on the same allocator. A block freed
+//! by the thread that allocated it goes straight back to its arena, while a block freed by
+//! another thread is pushed to a lock-free queue of remote frees, which the owning thread drains
+//! the next time it allocates or deallocates. The arena only has to be [`Send`]: it's only ever
+//! used by one thread at a time.
+//!
+//! ```rust
+//! use std::{alloc::System, thread};
+//!
+//! use okaoka::thread_arenas::ThreadArenas;
+//!
+//! fn arena() -> System {
+//!     System
+//! }
+//!
+//! static TRANSIENT: ThreadArenas<System, 16> = ThreadArenas::new(arena);
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Transient => TRANSIENT,
+//! }
+//!
+//! fn main() {
+//!     let handles: Vec<_> = (0..4)
+//!         .map(|_| {
+//!             thread::spawn(|| {
+//!                 let mut rows = Vec::new();
+//!                 GlobalAllocator::with(AllocatorTag::Transient, || {
+//!                     rows = (0..100).map(|x| vec![x; 16]).collect();
+//!                 });
+//!                 assert!(TRANSIENT.arenas_in_use() >= 1);
+//!                 rows
+//!             })
+//!         })
+//!         .collect();
+//!     for handle in handles {
+//!         drop(handle.join().unwrap()); // Freed remotely
+//!     }
+//!     // Released when their threads exited
+//!     assert_eq!(TRANSIENT.arenas_in_use(), 0);
+//! }
+//! ```
+//!
+//! Arenas are created on the first allocation of a thread and handed to another thread once their
+//! thread exits, along with their live blocks. When every one of the `N` arenas is in use, the
+//! allocations of other threads go to [`System`], like those a thread makes after releasing its
+//! arenas as it exits. In a child forked with the handlers of the `fork` module, the arenas of
+//! the threads that didn't survive the fork are handed to other threads in the same way, along
+//! with the blocks freed remotely to them.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::{Cell, UnsafeCell},
+    mem::size_of,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+/// Index written in the blocks allocated with `System`
+const NO_ARENA: usize = usize::MAX;
+
+/// Size of the prefix of a block, before its alignment
+///
+/// The prefix holds the index of the arena, and a freed block is reused as a node of the queue of
+/// remote frees, made of the next node and the layout of the block. It's a power of two, so the
+/// larger of it and the alignment is a multiple of the other.
+const PREFIX_SIZE: usize = 4 * size_of::<usize>();
+
+thread_local! {
+    /// Identifier of the current thread, 0 until it's assigned
+    static THREAD_ID: Cell<usize> = const { Cell::new(0) };
+
+    /// Arenas owned by the current thread, released when it exits
+    static OWNED: Owned = const { Owned(Cell::new(ptr::null())) };
+}
+
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(1);
+
+// Identifiers below it belong to threads lost in the last `fork`, except the one of the thread
+// that called it
+static FORK_BOUNDARY: AtomicUsize = AtomicUsize::new(0);
+static FORK_SURVIVOR: AtomicUsize = AtomicUsize::new(0);
+
+fn thread_id() -> usize {
+    THREAD_ID.with(|id| match id.get() {
+        0 => {
+            let new_id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+            id.set(new_id);
+            new_id
+        }
+        id => id,
+    })
+}
+
+/// Whether the thread identified by `id` exists and may own arenas
+fn is_alive(id: usize) -> bool {
+    id != 0
+        && (id >= FORK_BOUNDARY.load(Ordering::Relaxed)
+            || id == FORK_SURVIVOR.load(Ordering::Relaxed))
+}
+
+/// Release the arenas of the threads that don't exist in the child of a `fork`, to be called by
+/// the thread that called it
+///
+/// They're claimed again like those of exited threads, and their remote frees drained by their
+/// next owners, while the arenas of the calling thread stay its own.
+#[cfg(feature = "fork-safety")]
+pub(crate) fn forget_other_threads() {
+    FORK_SURVIVOR.store(thread_id(), Ordering::Relaxed);
+    FORK_BOUNDARY.store(NEXT_THREAD_ID.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// First of the owners of the arenas owned by the current thread, linked through `Owner::next`
+///
+/// The list is intrusive, so claiming an arena doesn't allocate.
+struct Owned(Cell<*const Owner>);
+
+impl Drop for Owned {
+    fn drop(&mut self) {
+        let mut owner = self.0.get();
+        while !owner.is_null() {
+            // SAFETY: arenas are statics, see `ThreadArenas::slot`. The link is read before the
+            // arena is released, since the next thread to own it links it in its own list.
+            unsafe {
+                let next = (*owner).next.load(Ordering::Relaxed);
+                (*owner).thread.store(0, Ordering::Release);
+                owner = next;
+            }
+        }
+    }
+}
+
+struct Owner {
+    // Identifier of the thread that owns the arena, 0 if it's free, or that of a thread lost in a
+    // `fork`
+    thread: AtomicUsize,
+    // Next arena owned by the same thread
+    next: AtomicPtr<Owner>,
+}
+
+struct Slot<A> {
+    owner: Owner,
+    arena: UnsafeCell<Option<A>>,
+    // Blocks freed by other threads
+    remote: AtomicPtr<u8>,
+}
+
+impl<A> Slot<A> {
+    const fn new() -> Self {
+        Self {
+            owner: Owner {
+                thread: AtomicUsize::new(0),
+                next: AtomicPtr::new(ptr::null_mut()),
+            },
+            arena: UnsafeCell::new(None),
+            remote: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+/// Allocator giving each thread its own arena, see the module documentation
+///
+/// It must be stored in a `static`, e.g. declared with `Name => static Type = init` in the
+/// backend, since threads keep pointers to the arenas they own until they exit.
+pub struct ThreadArenas<A, const N: usize> {
+    make: fn() -> A,
+    slots: [Slot<A>; N],
+}
+
+// SAFETY: an arena is only used by the thread that owns its slot
+unsafe impl<A: Send, const N: usize> Sync for ThreadArenas<A, N> {}
+
+impl<A, const N: usize> ThreadArenas<A, N> {
+    /// Allocator creating the arena of each thread with `make`
+    pub const fn new(make: fn() -> A) -> Self {
+        Self {
+            make,
+            slots: [const { Slot::new() }; N],
+        }
+    }
+
+    /// Number of arenas owned by threads
+    pub fn arenas_in_use(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| is_alive(slot.owner.thread.load(Ordering::Relaxed)))
+            .count()
+    }
+
+    /// Index of the arena of the current thread, claiming one if needed, `None` if there's none
+    /// left
+    fn slot(&self) -> Option<usize> {
+        let id = thread_id();
+        let indices = || (0..N).map(move |offset| (id + offset) % N);
+        let owned = |&index: &usize| self.slots[index].owner.thread.load(Ordering::Acquire) == id;
+        if let Some(index) = indices().find(owned) {
+            return Some(index);
+        }
+        let index = indices().find(|&i| {
+            let thread = &self.slots[i].owner.thread;
+            let current = thread.load(Ordering::Relaxed);
+            !is_alive(current)
+                && thread
+                    .compare_exchange(current, id, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+        })?;
+        let owner = &self.slots[index].owner;
+        let linked = OWNED.try_with(|owned| {
+            owner
+                .next
+                .store(owned.0.get().cast_mut(), Ordering::Relaxed);
+            owned.0.set(owner);
+        });
+        if linked.is_err() {
+            // The thread-locals of the thread are being destroyed, so the arena would never be
+            // released
+            owner.thread.store(0, Ordering::Release);
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Arena of the slot at `index`, creating it if needed
+    ///
+    /// # Safety
+    ///
+    /// The slot must be owned by the current thread.
+    unsafe fn arena(&self, index: usize) -> &A {
+        let arena = self.slots[index].arena.get();
+        unsafe {
+            if (*arena).is_none() {
+                *arena = Some((self.make)());
+            }
+            (*arena).as_ref().unwrap_unchecked()
+        }
+    }
+}
+
+impl<A: GlobalAlloc, const N: usize> ThreadArenas<A, N> {
+    /// Deallocate the blocks freed remotely to the arena at `index`
+    ///
+    /// # Safety
+    ///
+    /// The slot must be owned by the current thread.
+    unsafe fn drain(&self, index: usize) {
+        let slot = &self.slots[index];
+        if slot.remote.load(Ordering::Relaxed).is_null() {
+            return;
+        }
+        let mut node = slot.remote.swap(ptr::null_mut(), Ordering::Acquire);
+        let arena = unsafe { self.arena(index) };
+        while !node.is_null() {
+            unsafe {
+                let next = ptr::read_unaligned(node.cast::<*mut u8>());
+                let size = ptr::read_unaligned(node.cast::<usize>().add(1));
+                let align = ptr::read_unaligned(node.cast::<usize>().add(2));
+                arena.dealloc(node, Layout::from_size_align_unchecked(size, align));
+                node = next;
+            }
+        }
+    }
+
+    /// Push `block` of `layout` to the remote frees of the arena at `index`
+    fn push_remote(&self, index: usize, block: *mut u8, layout: Layout) {
+        let remote = &self.slots[index].remote;
+        let mut head = remote.load(Ordering::Relaxed);
+        loop {
+            unsafe {
+                ptr::write_unaligned(block.cast::<*mut u8>(), head);
+                ptr::write_unaligned(block.cast::<usize>().add(1), layout.size());
+                ptr::write_unaligned(block.cast::<usize>().add(2), layout.align());
+            }
+            match remote.compare_exchange_weak(head, block, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// Layout of the block holding an allocation of `layout` and its prefix, and the size of the
+/// prefix
+fn block_layout(layout: Layout) -> (Layout, usize) {
+    let prefix = layout.align().max(PREFIX_SIZE);
+    let size = layout.size() + prefix;
+    (
+        unsafe { Layout::from_size_align_unchecked(size, layout.align()) },
+        prefix,
+    )
+}
+
+unsafe impl<A: GlobalAlloc, const N: usize> GlobalAlloc for ThreadArenas<A, N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (block_layout, prefix) = block_layout(layout);
+        let (block, index) = match self.slot() {
+            Some(index) => unsafe {
+                self.drain(index);
+                (self.arena(index).alloc(block_layout), index)
+            },
+            None => (unsafe { System.alloc(block_layout) }, NO_ARENA),
+        };
+        if block.is_null() {
+            return block;
+        }
+        unsafe {
+            ptr::write_unaligned(block.cast::<usize>(), index);
+            block.add(prefix)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (block_layout, prefix) = block_layout(layout);
+        let block = unsafe { ptr.sub(prefix) };
+        let index = unsafe { ptr::read_unaligned(block.cast::<usize>()) };
+        if index == NO_ARENA {
+            return unsafe { System.dealloc(block, block_layout) };
+        }
+        if self.slots[index].owner.thread.load(Ordering::Acquire) == thread_id() {
+            unsafe {
+                self.drain(index);
+                self.arena(index).dealloc(block, block_layout);
+            }
+        } else {
+            self.push_remote(index, block, block_layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Barrier, thread};
+
+    use super::*;
+    use crate::test_util::{self, Counted};
+
+    /// Blocks allocated and freed by the arenas of `Counted<id>`
+    fn counts(id: usize) -> (usize, usize) {
+        (test_util::allocated(id), test_util::freed(id))
+    }
+
+    /// Index of the arena of the block at `ptr`
+    fn arena_of(ptr: *mut u8, layout: Layout) -> usize {
+        let (_, prefix) = block_layout(layout);
+        unsafe { ptr::read_unaligned(ptr.sub(prefix).cast::<usize>()) }
+    }
+
+    const LAYOUT: Layout = Layout::new::<[u64; 4]>();
+
+    #[test]
+    fn threads_own_their_arena_until_they_exit() {
+        static ARENAS: ThreadArenas<Counted<4>, 4> = ThreadArenas::new(|| Counted);
+        let barrier = Barrier::new(3);
+        let indices: Vec<usize> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    scope.spawn(|| unsafe {
+                        let first = ARENAS.alloc(LAYOUT);
+                        let second = ARENAS.alloc(LAYOUT);
+                        let index = arena_of(first, LAYOUT);
+                        assert_eq!(arena_of(second, LAYOUT), index);
+                        barrier.wait();
+                        assert_eq!(ARENAS.arenas_in_use(), 3);
+                        barrier.wait();
+                        ARENAS.dealloc(first, LAYOUT);
+                        ARENAS.dealloc(second, LAYOUT);
+                        index
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        let mut distinct = indices.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(distinct.len(), 3, "{indices:?}");
+        assert_eq!(ARENAS.arenas_in_use(), 0);
+        assert_eq!(counts(4), (6, 6));
+    }
+
+    #[test]
+    fn remote_frees_are_drained_by_the_owner() {
+        static ARENAS: ThreadArenas<Counted<5>, 4> = ThreadArenas::new(|| Counted);
+        // A block sent to the main thread, with its provenance
+        struct Block(*mut u8);
+        unsafe impl Send for Block {}
+
+        let (to_main, from_thread) = std::sync::mpsc::channel();
+        let (to_thread, from_main) = std::sync::mpsc::channel::<()>();
+        let owner = thread::spawn(move || {
+            let ptr = unsafe { ARENAS.alloc(LAYOUT) };
+            to_main.send(Block(ptr)).unwrap();
+            // Freed by the main thread in the meantime
+            from_main.recv().unwrap();
+            assert_eq!(counts(5), (1, 0));
+            let ptr = unsafe { ARENAS.alloc(LAYOUT) };
+            assert_eq!(counts(5), (2, 1));
+            unsafe { ARENAS.dealloc(ptr, LAYOUT) };
+            assert_eq!(counts(5), (2, 2));
+        });
+        let Block(ptr) = from_thread.recv().unwrap();
+        unsafe { ARENAS.dealloc(ptr, LAYOUT) };
+        assert_eq!(counts(5), (1, 0));
+        to_thread.send(()).unwrap();
+        owner.join().unwrap();
+    }
+
+    #[test]
+    fn threads_beyond_the_arenas_use_system() {
+        static ARENAS: ThreadArenas<Counted<6>, 2> = ThreadArenas::new(|| Counted);
+        let barrier = Barrier::new(3);
+        let indices: Vec<usize> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..3)
+                .map(|thread| {
+                    let barrier = &barrier;
+                    scope.spawn(move || unsafe {
+                        // One thread at a time, so that the last one finds no arena left
+                        for _ in 0..thread {
+                            barrier.wait();
+                        }
+                        let ptr = ARENAS.alloc(LAYOUT);
+                        for _ in thread..3 {
+                            barrier.wait();
+                        }
+                        let index = arena_of(ptr, LAYOUT);
+                        ARENAS.dealloc(ptr, LAYOUT);
+                        index
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        assert!(indices[..2].iter().all(|&index| index < 2), "{indices:?}");
+        assert_eq!(indices[2], NO_ARENA);
+        assert_eq!(counts(6), (2, 2));
+        assert_eq!(ARENAS.arenas_in_use(), 0);
+    }
+
+    #[test]
+    fn arenas_claimed_while_exiting_are_released() {
+        static ARENAS: ThreadArenas<Counted<7>, 2> = ThreadArenas::new(|| Counted);
+        static LATE_ARENA: AtomicUsize = AtomicUsize::new(0);
+
+        /// Allocates as the thread exits, after its arenas were released
+        struct Late;
+
+        impl Drop for Late {
+            fn drop(&mut self) {
+                unsafe {
+                    let ptr = ARENAS.alloc(LAYOUT);
+                    LATE_ARENA.store(arena_of(ptr, LAYOUT), Ordering::SeqCst);
+                    ARENAS.dealloc(ptr, LAYOUT);
+                }
+            }
+        }
+
+        thread_local! {
+            static LATE: Late = const { Late };
+        }
+
+        thread::spawn(|| {
+            // Destroyed after `OWNED`, which is registered after it
+            LATE.with(|_| ());
+            unsafe { ARENAS.dealloc(ARENAS.alloc(LAYOUT), LAYOUT) };
+        })
+        .join()
+        .unwrap();
+        assert_eq!(LATE_ARENA.load(Ordering::SeqCst), NO_ARENA);
+        assert_eq!(ARENAS.arenas_in_use(), 0);
+        assert_eq!(counts(7), (1, 1));
+    }
+}
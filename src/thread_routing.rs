@@ -0,0 +1,137 @@
+//! Routing of threads to allocators by name
+//!
+//! With the `thread-routing` feature, the threads that haven't selected an allocator use the one
+//! routed to their name with [`route_threads`] instead of the default allocator, so a subsystem
+//! running on its own named threads, like a runtime or a render thread, gets its own heap without
+//! calling [`with_allocator`](crate::with_allocator) throughout its code:
+//!
+//! ```rust
+//! use std::{alloc::System, thread};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Render => jemallocator::Jemalloc,
+//! }
+//!
+//! fn main() {
+//!     okaoka::thread_routing::route_threads("render-", AllocatorTag::Render as u8);
+//!
+//!     thread::Builder::new()
+//!         .name("render-0".into())
+//!         .spawn(|| {
+//!             assert_eq!(okaoka::thread_routing::thread_route(), Some(AllocatorTag::Render as u16));
+//!             let _frame = vec![0u8; 1024]; // Allocated with jemalloc
+//!         })
+//!         .unwrap()
+//!         .join()
+//!         .unwrap();
+//! }
+//! ```
+//!
+//! Threads match the first route whose prefix starts their name. Routes apply to threads that
+//! already run as well, and allocators selected with `with_allocator` or guards take precedence
+//! over them. With the `single-allocator` feature, routes are ignored.
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        RwLock, TryLockError,
+    },
+    thread,
+};
+
+// Prefix of the names of the threads of each route and their raw tag
+static ROUTES: RwLock<Vec<(&'static str, u16)>> = RwLock::new(Vec::new());
+
+// Incremented when the routes change, starts at 1 so that no thread has resolved it
+static GENERATION: AtomicU32 = AtomicU32::new(1);
+
+/// Route of a thread while it's being resolved, allocating for its name
+const RESOLVING: u32 = u32::MAX - 1;
+
+/// Route of a thread that doesn't match any route
+const NO_ROUTE: u32 = u32::MAX;
+
+thread_local! {
+    /// Generation of the routes the route of the current thread was resolved with, in the high
+    /// 32 bits, and the route, in the low ones
+    static ROUTE: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Route the threads whose name starts with `name_prefix` to the allocator identified by the raw
+/// `allocator_tag`
+pub fn route_threads(name_prefix: &'static str, allocator_tag: impl Into<u16>) {
+    let mut routes = ROUTES.write().unwrap_or_else(|e| e.into_inner());
+    routes.push((name_prefix, allocator_tag.into()));
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Routes, locked for writing while the process forks
+#[cfg(feature = "fork-safety")]
+pub(crate) fn lock_routes() -> std::sync::RwLockWriteGuard<'static, Vec<(&'static str, u16)>> {
+    ROUTES.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Remove every route
+pub fn clear_thread_routes() {
+    ROUTES.write().unwrap_or_else(|e| e.into_inner()).clear();
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Raw tag of the allocator the current thread is routed to, if any
+pub fn thread_route() -> Option<u16> {
+    let generation = GENERATION.load(Ordering::Acquire);
+    let route = ROUTE
+        .try_with(|route| {
+            let cached = route.get();
+            if (cached >> 32) as u32 == generation {
+                return cached as u32;
+            }
+            if cached as u32 == RESOLVING {
+                return NO_ROUTE;
+            }
+            // Getting the name allocates, those allocations see `RESOLVING`
+            route.set((u64::from(generation) << 32) | u64::from(RESOLVING));
+            match resolve() {
+                Some(resolved) => {
+                    route.set((u64::from(generation) << 32) | u64::from(resolved));
+                    resolved
+                }
+                None => {
+                    route.set(0);
+                    NO_ROUTE
+                }
+            }
+        })
+        .unwrap_or(NO_ROUTE);
+    match route {
+        NO_ROUTE | RESOLVING => None,
+        raw_tag => Some(raw_tag as u16),
+    }
+}
+
+/// Route of the current thread in the current routes, `None` while they're being changed
+fn resolve() -> Option<u32> {
+    // The routes are changed with the lock held, and changing them allocates
+    let routes = match ROUTES.try_read() {
+        Ok(routes) => routes,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => return None,
+    };
+    if routes.is_empty() {
+        return Some(NO_ROUTE);
+    }
+    let current = thread::current();
+    let Some(name) = current.name() else {
+        return Some(NO_ROUTE);
+    };
+    Some(
+        routes
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map_or(NO_ROUTE, |&(_, raw_tag)| u32::from(raw_tag)),
+    )
+}
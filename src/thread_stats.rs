@@ -0,0 +1,206 @@
+//! Statistics of threads
+//!
+//! With the `thread-stats` feature, the hidden tag put before every allocation also records the
+//! thread that made it, and statistics are kept for each pair of thread name and tag. Threads are
+//! identified by [`Thread::name`](std::thread::Thread::name) rather than by opaque IDs, so the
+//! breakdown reads like `tokio-runtime-worker (Arena): 440401920 live bytes`:
+//!
+//! ```rust
+//! use std::{alloc::System, thread};
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let buffer = thread::Builder::new()
+//!         .name("loader".into())
+//!         .spawn(|| {
+//!             let mut buffer = Vec::new();
+//!             GlobalAllocator::with(AllocatorTag::Arena, || buffer = vec![0u8; 4096]);
+//!             buffer
+//!         })
+//!         .unwrap()
+//!         .join()
+//!         .unwrap();
+//!
+//!     let stats = GlobalAllocator::all_thread_stats();
+//!     assert_eq!(stats[&(AllocatorTag::Arena, "loader")].live_bytes, buffer.len());
+//!     okaoka::thread_stats::dump::<GlobalAllocator>(std::io::stderr(), 10).unwrap();
+//! }
+//! ```
+//!
+//! A block deallocated by another thread is still counted for the thread that allocated it.
+//! Threads with the same name share their statistics, and unnamed threads are counted as
+//! [`UNNAMED`]. Up to [`CAPACITY`] names and pairs of name and tag are recorded, allocations of
+//! other ones are only counted in the statistics of their tag. With the `single-allocator`
+//! feature, there's no hidden tag, so nothing is recorded.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{
+    stats::{Counters, TagStats},
+    table::Table,
+    MultiAllocatorBackend, TagRepr,
+};
+
+/// Maximum number of thread names and of pairs of thread name and tag
+pub const CAPACITY: usize = 1024;
+
+/// Name of the threads without one
+pub const UNNAMED: &str = "<unnamed>";
+
+/// Size of the thread in the hidden tag
+#[cfg(not(feature = "single-allocator"))]
+pub(crate) const SIZE: usize = std::mem::size_of::<u16>();
+
+thread_local! {
+    /// Index of the name of the current thread, `None` until its first allocation
+    static CURRENT_INDEX: Cell<Option<u16>> = const { Cell::new(None) };
+}
+
+/// Statistics of a pair of thread name and tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ThreadStats {
+    pub thread_name: &'static str,
+    /// Raw tag of the allocator
+    pub raw_tag: u16,
+    pub stats: TagStats,
+}
+
+// Interned names, the index in the hidden tag is the index in this list plus one
+static NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+// Pairs of tag and thread name, packed with the tag in the high bits
+static COUNTERS: Table<u32, Counters, CAPACITY> = Table::new([const { Counters::new() }; CAPACITY]);
+
+pub(crate) fn names() -> MutexGuard<'static, Vec<&'static str>> {
+    NAMES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Copy of the interned names, taken without allocating with the lock held
+fn names_snapshot() -> Vec<&'static str> {
+    let mut snapshot = Vec::with_capacity(CAPACITY);
+    snapshot.extend_from_slice(&names());
+    snapshot
+}
+
+/// Intern the name of the current thread, 0 if there's no room for it
+///
+/// The allocations made while registering aren't attributed to any thread, which also keeps them
+/// from registering again.
+#[cfg(not(feature = "single-allocator"))]
+#[cold]
+fn register() -> u16 {
+    CURRENT_INDEX.with(|index| index.set(Some(0)));
+    let thread = std::thread::current();
+    let name = thread.name().unwrap_or(UNNAMED);
+    let mut names = names();
+    let index = match names.iter().position(|&known| known == name) {
+        Some(position) => position as u16 + 1,
+        None if names.len() < CAPACITY => {
+            names.push(Box::leak(name.into()));
+            names.len() as u16
+        }
+        None => 0,
+    };
+    drop(names);
+    CURRENT_INDEX.with(|current| current.set(Some(index)));
+    index
+}
+
+/// Index of the name of the current thread to write in the hidden tag, registering it on the
+/// first allocation of the thread
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) fn current_index() -> u16 {
+    CURRENT_INDEX.with(Cell::get).unwrap_or_else(register)
+}
+
+/// Counters of the thread written at `ptr` with the allocator identified by `raw_tag`
+///
+/// # Safety
+///
+/// `ptr` must be valid for reading `SIZE` bytes, which don't need to be aligned.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn counters(raw_tag: u16, ptr: *const u8) -> Option<&'static Counters> {
+    match unsafe { std::ptr::read_unaligned(ptr.cast::<u16>()) } {
+        0 => None,
+        index => COUNTERS.get_or_insert((u32::from(raw_tag) << 16) | u32::from(index)),
+    }
+}
+
+/// Write the index of the name of a thread to `ptr`, which doesn't need to be aligned
+///
+/// # Safety
+///
+/// `ptr` must be valid for writing `SIZE` bytes.
+#[cfg(not(feature = "single-allocator"))]
+#[inline(always)]
+pub(crate) unsafe fn write(ptr: *mut u8, index: u16) {
+    unsafe { std::ptr::write_unaligned(ptr.cast(), index) }
+}
+
+/// Statistics of every pair of thread name and tag that has allocated, by decreasing live bytes
+pub fn thread_stats() -> Vec<ThreadStats> {
+    let names = names_snapshot();
+    let mut stats: Vec<_> = COUNTERS
+        .iter()
+        .filter_map(|(key, counters)| {
+            let thread_name = names.get(usize::from(key as u16).checked_sub(1)?)?;
+            Some(ThreadStats {
+                thread_name,
+                raw_tag: (key >> 16) as u16,
+                stats: counters.snapshot(),
+            })
+        })
+        .collect();
+    stats.sort_by_key(|thread| std::cmp::Reverse(thread.stats.live_bytes));
+    stats
+}
+
+/// Statistics of every pair of tag of `Backend` and thread name that has allocated
+pub fn all_thread_stats<Backend>() -> HashMap<(Backend::Tag, &'static str), TagStats>
+where
+    Backend: MultiAllocatorBackend,
+    Backend::Tag: Eq + std::hash::Hash,
+{
+    thread_stats()
+        .into_iter()
+        .filter_map(|thread| {
+            let tag = Backend::Repr::from_raw(thread.raw_tag)
+                .and_then(|tag| Backend::Tag::try_from(tag).ok())?;
+            Some(((tag, thread.thread_name), thread.stats))
+        })
+        .collect()
+}
+
+/// Write the `top` pairs of thread name and tag with the most live bytes to `out`, naming tags
+/// after the tags of `Backend`
+pub fn dump<Backend: MultiAllocatorBackend>(mut out: impl io::Write, top: usize) -> io::Result<()> {
+    writeln!(out, "okaoka threads:")?;
+    for thread in thread_stats().iter().take(top) {
+        let tag_name = Backend::Repr::from_raw(thread.raw_tag)
+            .and_then(|tag| Backend::Tag::try_from(tag).ok())
+            .map_or("unknown", Backend::tag_name);
+        writeln!(
+            out,
+            "  {} ({tag_name}): {} live allocations, {} live bytes, {} allocations",
+            thread.thread_name,
+            thread.stats.live_allocations(),
+            thread.stats.live_bytes,
+            thread.stats.allocations
+        )?;
+    }
+    Ok(())
+}
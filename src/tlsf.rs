@@ -0,0 +1,571 @@
+//! Two-Level Segregated Fit allocator for real-time threads
+//!
+//! With the `tlsf` feature, [`Tlsf`] manages a fixed buffer with the TLSF algorithm: free blocks
+//! are kept in lists segregated by size, found through two levels of bitmaps, and merged with
+//! their neighbours when freed. Both allocating and deallocating take a bounded number of steps,
+//! whatever the state of the buffer, so a thread with deadlines, like an audio or control loop,
+//! can allocate under its tag with a predictable latency:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! use okaoka::tlsf::Tlsf;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     RealTime => static Tlsf<{ 1 << 20 }> = Tlsf::new(),
+//! }
+//!
+//! fn main() {
+//!     GlobalAllocator::with(AllocatorTag::RealTime, || {
+//!         let mut voices = Vec::new();
+//!         for voice in 0..64 {
+//!             voices.push(vec![voice as f32; 256]);
+//!         }
+//!         voices.retain(|voice| voice[0] as usize % 2 == 0);
+//!         voices.shrink_to_fit();
+//!     });
+//! }
+//! ```
+//!
+//! Allocations fail once the buffer has no free block large enough. Requests are rounded up to
+//! the smallest size of their list to find a block in constant time, so a request close to the
+//! size of the buffer can fail even when the buffer is empty. The buffer is protected by a
+//! spin lock, which is cheap when a single thread uses the tag but isn't fair between threads:
+//! the time is only bounded without contention, as a thread waits for as long as the others hold
+//! the lock. Give each real-time thread a `Tlsf` of its own, under a tag of its own, to keep the
+//! bound.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    hint,
+    mem::{size_of, MaybeUninit},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const WORD: usize = size_of::<usize>();
+
+/// Alignment of the blocks, larger alignments cost more memory
+pub const ALIGN: usize = 2 * WORD;
+
+/// Size of the header of a block, made of the previous block in the buffer and the size and
+/// flags of the block
+const HEADER: usize = 2 * WORD;
+
+/// Size of the smallest block, whose payload holds the links of the free lists once it's freed
+const MIN_BLOCK: usize = HEADER + 2 * WORD;
+
+/// Number of lists of the second level, per list of the first level
+const SL_LOG2: u32 = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+
+/// Number of lists of the first level, one per power of two
+const FL_COUNT: usize = usize::BITS as usize;
+
+/// Absent block
+const NONE: usize = usize::MAX;
+
+/// Flags stored in the low bits of the size of a block
+const FREE: usize = 1;
+const PREV_FREE: usize = 2;
+
+#[repr(C, align(16))]
+struct Buffer<const N: usize>(MaybeUninit<[u8; N]>);
+
+/// Free lists, with blocks identified by their offset in the buffer
+struct Heap {
+    initialized: bool,
+    fl_bitmap: usize,
+    sl_bitmaps: [usize; FL_COUNT],
+    heads: [[usize; SL_COUNT]; FL_COUNT],
+    used: usize,
+}
+
+/// TLSF allocator over a fixed buffer of `N` bytes, see the module documentation
+pub struct Tlsf<const N: usize> {
+    buffer: UnsafeCell<Buffer<N>>,
+    heap: UnsafeCell<Heap>,
+    locked: AtomicBool,
+}
+
+// SAFETY: the buffer and the free lists are only accessed with the lock held
+unsafe impl<const N: usize> Sync for Tlsf<N> {}
+
+impl<const N: usize> Tlsf<N> {
+    /// Allocator over an empty buffer of `N` bytes, held inline, so it's meant for a `static`
+    ///
+    /// The whole buffer becomes a single free block on the first allocation. Every thread
+    /// allocating with it takes the same spin lock, so its time is only bounded without
+    /// contention. Panics, at compile time in a `static`, if `N` can't hold two blocks of the
+    /// smallest size.
+    pub const fn new() -> Self {
+        assert!(
+            N >= 2 * MIN_BLOCK,
+            "the buffer of a TLSF allocator is too small"
+        );
+        Self {
+            buffer: UnsafeCell::new(Buffer(MaybeUninit::uninit())),
+            heap: UnsafeCell::new(Heap {
+                initialized: false,
+                fl_bitmap: 0,
+                sl_bitmaps: [0; FL_COUNT],
+                heads: [[NONE; SL_COUNT]; FL_COUNT],
+                used: 0,
+            }),
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    /// Size of the buffer
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Bytes of the blocks that haven't been deallocated yet, including their headers and
+    /// padding
+    pub fn used(&self) -> usize {
+        let _lock = self.lock();
+        unsafe { (*self.heap.get()).used }
+    }
+
+    fn lock(&self) -> Lock<'_> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+        Lock(&self.locked)
+    }
+
+    /// Free lists and blocks of the buffer
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held for the lifetime of the result.
+    unsafe fn blocks(&self) -> Blocks<'_> {
+        let mut blocks = Blocks {
+            base: self.buffer.get().cast(),
+            heap: unsafe { &mut *self.heap.get() },
+        };
+        if !blocks.heap.initialized {
+            blocks.heap.initialized = true;
+            // One free block spanning the buffer, followed by a used block of 0 bytes that is
+            // never merged
+            let end = (N - HEADER) & !(ALIGN - 1);
+            unsafe {
+                blocks.set_header(0, 0, end | FREE);
+                blocks.set_header(end, 0, PREV_FREE);
+                blocks.insert(0);
+            }
+        }
+        blocks
+    }
+}
+
+impl<const N: usize> Default for Tlsf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Lock<'a>(&'a AtomicBool);
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Indices of the list of the first and second levels holding the blocks of `size` bytes
+fn mapping(size: usize) -> (usize, usize) {
+    let units = size / ALIGN;
+    if units < SL_COUNT {
+        return (0, units);
+    }
+    let log2 = units.ilog2();
+    (
+        (log2 - SL_LOG2 + 1) as usize,
+        (units >> (log2 - SL_LOG2)) - SL_COUNT,
+    )
+}
+
+/// Indices of the first list whose blocks all have at least `size` bytes
+fn mapping_search(size: usize) -> (usize, usize) {
+    let units = size / ALIGN;
+    if units < SL_COUNT {
+        return (0, units);
+    }
+    let round = (1 << (units.ilog2() - SL_LOG2)) - 1;
+    mapping((units + round) * ALIGN)
+}
+
+struct Blocks<'a> {
+    base: *mut u8,
+    heap: &'a mut Heap,
+}
+
+// Each block starts with the offset of the previous block, only valid when that block is free,
+// and its size with the flags in the low bits. Free blocks then hold the next and previous blocks
+// of their list.
+impl Blocks<'_> {
+    unsafe fn word(&self, block: usize, index: usize) -> *mut usize {
+        unsafe { self.base.add(block).cast::<usize>().add(index) }
+    }
+
+    unsafe fn set_header(&self, block: usize, prev: usize, size_and_flags: usize) {
+        unsafe {
+            *self.word(block, 0) = prev;
+            *self.word(block, 1) = size_and_flags;
+        }
+    }
+
+    unsafe fn prev(&self, block: usize) -> usize {
+        unsafe { *self.word(block, 0) }
+    }
+
+    unsafe fn set_prev(&self, block: usize, prev: usize) {
+        unsafe { *self.word(block, 0) = prev }
+    }
+
+    unsafe fn size(&self, block: usize) -> usize {
+        unsafe { *self.word(block, 1) & !(ALIGN - 1) }
+    }
+
+    unsafe fn set_size(&self, block: usize, size: usize) {
+        unsafe { *self.word(block, 1) = size | (*self.word(block, 1) & (ALIGN - 1)) }
+    }
+
+    unsafe fn has(&self, block: usize, flag: usize) -> bool {
+        unsafe { *self.word(block, 1) & flag != 0 }
+    }
+
+    unsafe fn set(&self, block: usize, flag: usize, value: bool) {
+        unsafe {
+            if value {
+                *self.word(block, 1) |= flag;
+            } else {
+                *self.word(block, 1) &= !flag;
+            }
+        }
+    }
+
+    unsafe fn insert(&mut self, block: usize) {
+        let (fl, sl) = mapping(unsafe { self.size(block) });
+        let next = self.heap.heads[fl][sl];
+        unsafe {
+            *self.word(block, 2) = next;
+            *self.word(block, 3) = NONE;
+            if next != NONE {
+                *self.word(next, 3) = block;
+            }
+        }
+        self.heap.heads[fl][sl] = block;
+        self.heap.fl_bitmap |= 1 << fl;
+        self.heap.sl_bitmaps[fl] |= 1 << sl;
+    }
+
+    unsafe fn remove(&mut self, block: usize) {
+        let (fl, sl) = mapping(unsafe { self.size(block) });
+        let (next, prev) = unsafe { (*self.word(block, 2), *self.word(block, 3)) };
+        if prev == NONE {
+            self.heap.heads[fl][sl] = next;
+        } else {
+            unsafe { *self.word(prev, 2) = next };
+        }
+        if next != NONE {
+            unsafe { *self.word(next, 3) = prev };
+        }
+        if self.heap.heads[fl][sl] == NONE {
+            self.heap.sl_bitmaps[fl] &= !(1 << sl);
+            if self.heap.sl_bitmaps[fl] == 0 {
+                self.heap.fl_bitmap &= !(1 << fl);
+            }
+        }
+    }
+
+    /// First free block of the list at `(fl, sl)` or of a list of larger blocks
+    fn find(&self, fl: usize, sl: usize) -> Option<usize> {
+        let mut fl = fl;
+        let mut sl_bitmap = self.heap.sl_bitmaps[fl] & (!0 << sl);
+        if sl_bitmap == 0 {
+            let fl_bitmap = self.heap.fl_bitmap & (!0usize).checked_shl(fl as u32 + 1)?;
+            if fl_bitmap == 0 {
+                return None;
+            }
+            fl = fl_bitmap.trailing_zeros() as usize;
+            sl_bitmap = self.heap.sl_bitmaps[fl];
+        }
+        Some(self.heap.heads[fl][sl_bitmap.trailing_zeros() as usize])
+    }
+
+    /// Split the free block `block` at `offset`, returning the second part
+    unsafe fn split(&mut self, block: usize, offset: usize) -> usize {
+        let rest = block + offset;
+        unsafe {
+            let rest_size = self.size(block) - offset;
+            self.set_header(rest, block, rest_size | FREE);
+            self.set_size(block, offset);
+            let next = rest + rest_size;
+            self.set_prev(next, rest);
+            self.set(next, PREV_FREE, true);
+        }
+        rest
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for Tlsf<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(needed) = layout
+            .size()
+            .max(2 * WORD)
+            .checked_add(ALIGN - 1 + HEADER)
+            .map(|size| size & !(ALIGN - 1))
+        else {
+            return ptr::null_mut();
+        };
+        let search = match layout.align() {
+            align if align <= ALIGN => needed,
+            align => needed.saturating_add(align + MIN_BLOCK),
+        };
+        if search > N {
+            return ptr::null_mut();
+        }
+
+        let _lock = self.lock();
+        let mut blocks = unsafe { self.blocks() };
+        let (fl, sl) = mapping_search(search);
+        let Some(mut block) = blocks.find(fl, sl) else {
+            return ptr::null_mut();
+        };
+        unsafe {
+            blocks.remove(block);
+            if layout.align() > ALIGN {
+                // Free the blocks before the first aligned payload
+                let payload = blocks.base.add(block + HEADER);
+                let mut gap = payload.align_offset(layout.align());
+                if gap != 0 && gap < MIN_BLOCK {
+                    gap += layout.align();
+                }
+                if gap != 0 {
+                    let rest = blocks.split(block, gap);
+                    blocks.set(rest, PREV_FREE, true);
+                    blocks.insert(block);
+                    block = rest;
+                }
+            }
+            if blocks.size(block) - needed >= MIN_BLOCK {
+                let rest = blocks.split(block, needed);
+                blocks.insert(rest);
+            }
+            let next = block + blocks.size(block);
+            blocks.set(next, PREV_FREE, false);
+            blocks.set(block, FREE, false);
+            blocks.heap.used += blocks.size(block);
+            blocks.base.add(block + HEADER)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let _lock = self.lock();
+        let mut blocks = unsafe { self.blocks() };
+        let mut block = unsafe { ptr.offset_from(blocks.base) as usize } - HEADER;
+        unsafe {
+            blocks.heap.used -= blocks.size(block);
+            blocks.set(block, FREE, true);
+            if blocks.has(block, PREV_FREE) {
+                let prev = blocks.prev(block);
+                blocks.remove(prev);
+                blocks.set_size(prev, blocks.size(prev) + blocks.size(block));
+                block = prev;
+            }
+            let next = block + blocks.size(block);
+            if blocks.has(next, FREE) {
+                blocks.remove(next);
+                blocks.set_size(block, blocks.size(block) + blocks.size(next));
+            }
+            let next = block + blocks.size(block);
+            blocks.set_prev(next, block);
+            blocks.set(next, PREV_FREE, true);
+            blocks.insert(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 1 << 16;
+
+    type Block = (*mut u8, Layout);
+
+    /// Smallest block of the list at `(fl, sl)`
+    fn list_start(fl: usize, sl: usize) -> usize {
+        match fl {
+            0 => sl * ALIGN,
+            _ => ((SL_COUNT + sl) * ALIGN) << (fl - 1),
+        }
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    /// Largest allocation that succeeds in an empty allocator
+    fn largest<const N: usize>(tlsf: &Tlsf<N>) -> usize {
+        (1..=N)
+            .rev()
+            .step_by(ALIGN)
+            .find(|&size| unsafe {
+                let ptr = tlsf.alloc(layout(size, 1));
+                if !ptr.is_null() {
+                    tlsf.dealloc(ptr, layout(size, 1));
+                }
+                !ptr.is_null()
+            })
+            .unwrap()
+    }
+
+    /// Allocate blocks of varied sizes until the allocator is full
+    fn fill<const N: usize>(tlsf: &Tlsf<N>) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        for i in 0.. {
+            let layout = layout(1 + i * 37 % 500, 1);
+            let ptr = unsafe { tlsf.alloc(layout) };
+            if ptr.is_null() {
+                break;
+            }
+            unsafe { ptr.write_bytes(i as u8, layout.size()) };
+            blocks.push((ptr, layout));
+        }
+        blocks
+    }
+
+    #[test]
+    fn mapping_search_rounds_up_to_the_next_list() {
+        for size in (0..=SIZE).step_by(ALIGN) {
+            let (fl, sl) = mapping_search(size);
+            let start = list_start(fl, sl);
+            assert_eq!(mapping(start), (fl, sl), "{size}");
+            assert!(start >= size, "{size}");
+            let (fl, sl) = mapping(size);
+            assert!(list_start(fl, sl) <= size, "{size}");
+        }
+    }
+
+    #[test]
+    fn size_class_boundaries() {
+        let tlsf = Tlsf::<SIZE>::new();
+        for fl in 0..FL_COUNT {
+            for sl in 0..SL_COUNT {
+                let start = list_start(fl, sl);
+                if start > SIZE / 2 {
+                    continue;
+                }
+                for size in [
+                    start.saturating_sub(HEADER + 1),
+                    start - start.min(HEADER),
+                    start,
+                ]
+                .into_iter()
+                .chain([start + 1, start + ALIGN - 1])
+                {
+                    let layout = layout(size, 1);
+                    let ptr = unsafe { tlsf.alloc(layout) };
+                    assert!(!ptr.is_null(), "{size}");
+                    assert_eq!(ptr as usize % ALIGN, 0, "{size}");
+                    assert!(tlsf.used() >= size + HEADER, "{size}");
+                    unsafe {
+                        ptr.write_bytes(0xAA, size);
+                        tlsf.dealloc(ptr, layout);
+                    }
+                    assert_eq!(tlsf.used(), 0, "{size}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn freed_blocks_are_merged() {
+        let tlsf = Tlsf::<SIZE>::new();
+        let largest = largest(&tlsf);
+        assert!(largest > SIZE - SIZE / SL_COUNT, "{largest}");
+
+        let orders: [fn(&mut Vec<Block>); 3] = [
+            |_| {},
+            |blocks| blocks.reverse(),
+            |blocks| {
+                let even = blocks.iter().step_by(2);
+                let odd = blocks.iter().skip(1).step_by(2);
+                *blocks = even.chain(odd).copied().collect();
+            },
+        ];
+        for order in orders {
+            let mut blocks = fill(&tlsf);
+            assert!(blocks.len() > 100);
+            order(&mut blocks);
+            for (ptr, layout) in blocks {
+                unsafe { tlsf.dealloc(ptr, layout) };
+            }
+            assert_eq!(tlsf.used(), 0);
+
+            let ptr = unsafe { tlsf.alloc(layout(largest, 1)) };
+            assert!(!ptr.is_null());
+            unsafe { tlsf.dealloc(ptr, layout(largest, 1)) };
+        }
+    }
+
+    #[test]
+    fn over_aligned_layouts() {
+        static TLSF: Tlsf<{ 1 << 20 }> = Tlsf::new();
+        let mut blocks = Vec::new();
+        for align in (ALIGN.trailing_zeros() + 1..=12).map(|log2| 1 << log2) {
+            for size in [1, align - 1, align, align + 1, 3 * align] {
+                let layout = layout(size, align);
+                let ptr = unsafe { TLSF.alloc(layout) };
+                assert!(!ptr.is_null(), "{size} {align}");
+                assert_eq!(ptr as usize % align, 0, "{size} {align}");
+                unsafe { ptr.write_bytes(0xAA, size) };
+                blocks.push((ptr, layout));
+            }
+        }
+        for (ptr, layout) in blocks {
+            unsafe { TLSF.dealloc(ptr, layout) };
+        }
+        assert_eq!(TLSF.used(), 0);
+        assert!(largest(&TLSF) > TLSF.capacity() - TLSF.capacity() / SL_COUNT);
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let tlsf = Tlsf::<SIZE>::new();
+        let mut blocks = fill(&tlsf);
+        loop {
+            let ptr = unsafe { tlsf.alloc(layout(1, 1)) };
+            if ptr.is_null() {
+                break;
+            }
+            blocks.push((ptr, layout(1, 1)));
+        }
+        for size in [1, SIZE, isize::MAX as usize] {
+            assert!(unsafe { tlsf.alloc(layout(size, 1)) }.is_null(), "{size}");
+        }
+        assert!(unsafe { tlsf.alloc(layout(ALIGN, 64)) }.is_null());
+
+        let (ptr, layout) = blocks.swap_remove(0);
+        unsafe { tlsf.dealloc(ptr, layout) };
+        let ptr = unsafe { tlsf.alloc(layout) };
+        assert!(!ptr.is_null());
+        blocks.push((ptr, layout));
+        for (ptr, layout) in blocks {
+            unsafe { tlsf.dealloc(ptr, layout) };
+        }
+        assert_eq!(tlsf.used(), 0);
+    }
+}
@@ -0,0 +1,376 @@
+//! Allocation traces
+//!
+//! With the `trace` feature, [`start`] records every allocation, reallocation and deallocation
+//! of [`MultiAllocator`], with its size and tag, until [`stop`] returns
+//! them as a [`Trace`]. A trace can be saved with [`Trace::write`], and replayed against another
+//! backend or allocator to benchmark it on a real workload:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     okaoka::trace::start(1 << 16);
+//!     GlobalAllocator::with(AllocatorTag::Arena, || {
+//!         let mut values = Vec::new();
+//!         values.extend(0..1000);
+//!     });
+//!     let trace = okaoka::trace::stop();
+//!     assert_eq!(trace.dropped, 0);
+//!
+//!     let elapsed = trace.replay::<GlobalAllocator>();
+//!     println!("{} events replayed in {elapsed:?}", trace.events.len());
+//!     let elapsed = trace.replay_with(&System);
+//!     println!("{} events replayed with System in {elapsed:?}", trace.events.len());
+//! }
+//! ```
+//!
+//! The events are stored in a buffer allocated by [`start`], so recording doesn't allocate, and
+//! the events that don't fit are dropped. The buffer is protected by a lock, which serializes
+//! the allocations of every thread while recording, and a replay runs the events in a single
+//! thread, in the order they were recorded. When the block of a reallocation moves, another
+//! thread can reuse its old address before the reallocation is recorded, so the replay skips the
+//! events of addresses it doesn't know.
+
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{MultiAllocator, MultiAllocatorBackend};
+
+/// Allocation event, identifying blocks by their address when recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Block of `size` bytes aligned to `align` allocated at `ptr` by the allocator of `raw_tag`
+    Alloc {
+        ptr: usize,
+        size: usize,
+        align: usize,
+        raw_tag: u16,
+    },
+    /// Block at `ptr` moved to `new_ptr` and resized to `new_size` bytes by its allocator
+    Realloc {
+        ptr: usize,
+        new_ptr: usize,
+        new_size: usize,
+    },
+    /// Block at `ptr` deallocated
+    Dealloc { ptr: usize },
+}
+
+/// Recorded allocation events
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// Events in the order they were recorded
+    pub events: Vec<Event>,
+    /// Number of events that didn't fit in the buffer
+    pub dropped: usize,
+}
+
+pub(crate) struct Buffer {
+    events: Vec<Event>,
+    dropped: usize,
+}
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+static BUFFER: Mutex<Buffer> = Mutex::new(Buffer {
+    events: Vec::new(),
+    dropped: 0,
+});
+
+pub(crate) fn lock() -> std::sync::MutexGuard<'static, Buffer> {
+    BUFFER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Start recording the events of every thread, in a buffer of `capacity` events
+///
+/// The events recorded since the last [`stop`] are discarded.
+pub fn start(capacity: usize) {
+    let events = Vec::with_capacity(capacity);
+    RECORDING.store(false, Ordering::Release);
+    let previous = {
+        let mut buffer = lock();
+        buffer.dropped = 0;
+        std::mem::replace(&mut buffer.events, events)
+    };
+    // Freed without the lock held, as freeing records an event
+    drop(previous);
+    RECORDING.store(true, Ordering::Release);
+}
+
+/// Stop recording, returning the events recorded since [`start`]
+pub fn stop() -> Trace {
+    RECORDING.store(false, Ordering::Release);
+    let mut buffer = lock();
+    Trace {
+        events: std::mem::take(&mut buffer.events),
+        dropped: std::mem::take(&mut buffer.dropped),
+    }
+}
+
+/// Whether the events are being recorded
+pub fn is_recording() -> bool {
+    RECORDING.load(Ordering::Acquire)
+}
+
+/// Last `count` events recorded since [`start`], without stopping
+#[cfg(feature = "console")]
+pub(crate) fn recent(count: usize) -> Vec<Event> {
+    // Allocated before taking the lock again, as allocating records an event, and no larger than
+    // the buffer, as `count` comes from the console
+    let count = count.min(lock().events.len());
+    let mut events = Vec::with_capacity(count);
+    let buffer = lock();
+    events.extend_from_slice(&buffer.events[buffer.events.len().saturating_sub(count)..]);
+    events
+}
+
+#[inline(always)]
+fn record(event: Event) {
+    if RECORDING.load(Ordering::Relaxed) {
+        record_slow(event);
+    }
+}
+
+#[cold]
+fn record_slow(event: Event) {
+    let mut buffer = lock();
+    // Checked again with the lock held, as the buffer may have been taken by `stop`
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    // Pushing never grows the buffer, which would allocate
+    if buffer.events.len() < buffer.events.capacity() {
+        buffer.events.push(event);
+    } else {
+        buffer.dropped += 1;
+    }
+}
+
+/// Record the allocation of the block at `ptr`
+#[inline(always)]
+pub(crate) fn record_alloc(ptr: *mut u8, layout: Layout, raw_tag: u16) {
+    record(Event::Alloc {
+        ptr: ptr as usize,
+        size: layout.size(),
+        align: layout.align(),
+        raw_tag,
+    });
+}
+
+/// Record the reallocation of the block at `ptr`
+#[inline(always)]
+pub(crate) fn record_realloc(ptr: *mut u8, new_ptr: *mut u8, new_size: usize) {
+    record(Event::Realloc {
+        ptr: ptr as usize,
+        new_ptr: new_ptr as usize,
+        new_size,
+    });
+}
+
+/// Record the deallocation of the block at `ptr`, before it's deallocated
+#[inline(always)]
+pub(crate) fn record_dealloc(ptr: *mut u8) {
+    record(Event::Dealloc { ptr: ptr as usize });
+}
+
+impl Trace {
+    /// Replay the events with the allocators of `Backend`, returning how long it took
+    ///
+    /// Blocks are allocated with the allocator of their tag, or the default allocator for the
+    /// tags `Backend` doesn't have. Blocks still live at the end are deallocated afterwards.
+    pub fn replay<Backend: MultiAllocatorBackend>(&self) -> Duration {
+        let allocators: Vec<_> = Backend::TAGS
+            .iter()
+            .map(|&tag| {
+                (
+                    Backend::raw_tag(tag),
+                    MultiAllocator::<Backend>::for_tag(tag),
+                )
+            })
+            .collect();
+        let default = MultiAllocator::<Backend>::for_tag(Backend::default_tag());
+        self.run(&MultiAllocator::<Backend>::new(), |raw_tag| {
+            allocators
+                .iter()
+                .find(|(other, _)| *other == raw_tag)
+                .map_or(&default, |(_, allocator)| allocator)
+        })
+    }
+
+    /// Replay the events with `allocator`, ignoring their tags, returning how long it took
+    ///
+    /// Blocks still live at the end are deallocated afterwards.
+    pub fn replay_with<A: GlobalAlloc>(&self, allocator: &A) -> Duration {
+        self.run(allocator, |_| allocator)
+    }
+
+    fn run<'a, A: GlobalAlloc + 'a>(&self, dealloc: &A, alloc: impl Fn(u16) -> &'a A) -> Duration {
+        // Recorded addresses to the replayed blocks and their layouts
+        let mut blocks: HashMap<usize, (*mut u8, Layout)> = HashMap::new();
+        let start = Instant::now();
+        for event in &self.events {
+            match *event {
+                Event::Alloc {
+                    ptr,
+                    size,
+                    align,
+                    raw_tag,
+                } => {
+                    // Allocating zero bytes is undefined behavior
+                    let Some(layout) = Layout::from_size_align(size, align)
+                        .ok()
+                        .filter(|layout| layout.size() > 0)
+                    else {
+                        continue;
+                    };
+                    let block = unsafe { alloc(raw_tag).alloc(layout) };
+                    if block.is_null() {
+                        continue;
+                    }
+                    if let Some((old, old_layout)) = blocks.insert(ptr, (block, layout)) {
+                        unsafe { dealloc.dealloc(old, old_layout) };
+                    }
+                }
+                Event::Realloc {
+                    ptr,
+                    new_ptr,
+                    new_size,
+                } => {
+                    let Some((block, layout)) = blocks.remove(&ptr) else {
+                        continue;
+                    };
+                    let Some(new_layout) = Layout::from_size_align(new_size, layout.align())
+                        .ok()
+                        .filter(|layout| layout.size() > 0)
+                    else {
+                        blocks.insert(ptr, (block, layout));
+                        continue;
+                    };
+                    let new_block = unsafe { dealloc.realloc(block, layout, new_size) };
+                    if new_block.is_null() {
+                        blocks.insert(ptr, (block, layout));
+                        continue;
+                    }
+                    if let Some((old, old_layout)) = blocks.insert(new_ptr, (new_block, new_layout))
+                    {
+                        unsafe { dealloc.dealloc(old, old_layout) };
+                    }
+                }
+                Event::Dealloc { ptr } => {
+                    if let Some((block, layout)) = blocks.remove(&ptr) {
+                        unsafe { dealloc.dealloc(block, layout) };
+                    }
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        for (block, layout) in blocks.into_values() {
+            unsafe { dealloc.dealloc(block, layout) };
+        }
+        elapsed
+    }
+
+    /// Write the events as text, one per line
+    ///
+    /// The lines are `alloc <ptr> <size> <align> <raw_tag>`, `realloc <ptr> <new_ptr> <new_size>`
+    /// and `dealloc <ptr>`.
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        for event in &self.events {
+            writeln!(writer, "{event}")?;
+        }
+        Ok(())
+    }
+
+    /// Read the events written by [`Trace::write`]
+    pub fn read(reader: impl BufRead) -> io::Result<Self> {
+        let events = reader
+            .lines()
+            .map(|line| {
+                let line = line?;
+                line.parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, line))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { events, dropped: 0 })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Alloc {
+                ptr,
+                size,
+                align,
+                raw_tag,
+            } => write!(f, "alloc {ptr} {size} {align} {raw_tag}"),
+            Self::Realloc {
+                ptr,
+                new_ptr,
+                new_size,
+            } => write!(f, "realloc {ptr} {new_ptr} {new_size}"),
+            Self::Dealloc { ptr } => write!(f, "dealloc {ptr}"),
+        }
+    }
+}
+
+/// Error returned when parsing a line that isn't an event written by [`Trace::write`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseEventError;
+
+impl fmt::Display for ParseEventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected `alloc`, `realloc` or `dealloc` followed by integers")
+    }
+}
+
+impl std::error::Error for ParseEventError {}
+
+impl std::str::FromStr for Event {
+    type Err = ParseEventError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let kind = words.next().ok_or(ParseEventError)?;
+        let mut next = || words.next()?.parse::<usize>().ok();
+        let event = match kind {
+            "alloc" => Self::Alloc {
+                ptr: next().ok_or(ParseEventError)?,
+                size: next().ok_or(ParseEventError)?,
+                align: next().ok_or(ParseEventError)?,
+                raw_tag: next()
+                    .and_then(|raw_tag| raw_tag.try_into().ok())
+                    .ok_or(ParseEventError)?,
+            },
+            "realloc" => Self::Realloc {
+                ptr: next().ok_or(ParseEventError)?,
+                new_ptr: next().ok_or(ParseEventError)?,
+                new_size: next().ok_or(ParseEventError)?,
+            },
+            "dealloc" => Self::Dealloc {
+                ptr: next().ok_or(ParseEventError)?,
+            },
+            _ => return Err(ParseEventError),
+        };
+        match words.next() {
+            Some(_) => Err(ParseEventError),
+            None => Ok(event),
+        }
+    }
+}
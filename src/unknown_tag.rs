@@ -0,0 +1,168 @@
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::once::SetOnce;
+
+#[cfg(not(feature = "single-allocator"))]
+use crate::{InvalidTagError, MultiAllocatorBackend};
+
+/// What [`MultiAllocator`](crate::MultiAllocator) does when the current tag doesn't belong to
+/// any allocator
+///
+/// A tag can be unknown because of a bug, e.g. a raw tag of another backend passed to
+/// [`with_allocator`](crate::with_allocator), or because of memory corruption. Only allocations
+/// are affected: blocks remember the tag of the allocator that allocated them, so they're always
+/// deallocated by it.
+///
+/// ```rust
+/// use std::alloc::System;
+///
+/// use okaoka::UnknownTagMode;
+///
+/// okaoka::set_multi_global_allocator! {
+///     GlobalAllocator,
+///     AllocatorTag,
+///     System => System,
+/// }
+///
+/// fn main() {
+///     okaoka::set_unknown_tag_mode(UnknownTagMode::Fallback);
+///     okaoka::with_allocator(200u16, || {
+///         let _x = Box::new(10); // Allocated with `System`
+///     });
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum UnknownTagMode {
+    /// Panic, which usually aborts the program since it happens inside the allocator
+    #[default]
+    Panic,
+    /// Allocate with the default allocator, counting the allocation in [`unknown_tag_count`]
+    /// and calling the hook installed with [`set_unknown_tag_hook`]
+    Fallback,
+}
+
+static UNKNOWN_TAG_MODE: AtomicU8 = AtomicU8::new(UnknownTagMode::Panic as u8);
+
+static UNKNOWN_TAG_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+static UNKNOWN_TAG_HOOK: SetOnce<fn(u16)> = SetOnce::new();
+
+/// Set what happens when allocating with an unknown tag, for every thread
+pub fn set_unknown_tag_mode(mode: UnknownTagMode) {
+    UNKNOWN_TAG_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// What happens when allocating with an unknown tag
+pub fn unknown_tag_mode() -> UnknownTagMode {
+    match UNKNOWN_TAG_MODE.load(Ordering::Relaxed) {
+        0 => UnknownTagMode::Panic,
+        _ => UnknownTagMode::Fallback,
+    }
+}
+
+/// Number of allocations made with an unknown tag in [`UnknownTagMode::Fallback`]
+pub fn unknown_tag_count() -> usize {
+    UNKNOWN_TAG_COUNT.load(Ordering::Relaxed)
+}
+
+/// Install `hook` to be called with the raw tag of every allocation made with an unknown tag in
+/// [`UnknownTagMode::Fallback`], for the rest of the program
+///
+/// The hook is called inside the allocator, so it must not allocate, e.g. it can increment a
+/// metric or write to a pre-allocated buffer. Returns `hook` back if a hook is already installed.
+pub fn set_unknown_tag_hook(hook: fn(u16)) -> Result<(), fn(u16)> {
+    UNKNOWN_TAG_HOOK.set(hook)
+}
+
+/// Tag to allocate with instead of the unknown `raw_tag`, panicking in [`UnknownTagMode::Panic`]
+#[cfg(not(feature = "single-allocator"))]
+#[cold]
+pub(crate) fn fallback<Backend: MultiAllocatorBackend>(raw_tag: u16) -> Backend::Tag {
+    if unknown_tag_mode() == UnknownTagMode::Panic {
+        panic!("{}", InvalidTagError::new(raw_tag));
+    }
+    UNKNOWN_TAG_COUNT.fetch_add(1, Ordering::Relaxed);
+    if let Some(hook) = UNKNOWN_TAG_HOOK.get() {
+        hook(raw_tag);
+    }
+    Backend::default_tag()
+}
+
+#[cfg(all(test, feature = "std", not(feature = "single-allocator")))]
+// Clippy checks the expansion of the exported backend macro only inside this crate
+#[allow(clippy::macro_metavars_in_unsafe)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        panic::{self, AssertUnwindSafe},
+        ptr,
+        sync::atomic::AtomicU16,
+    };
+
+    use super::*;
+    use crate::{
+        test_util::{self, Counted},
+        MultiAllocator,
+    };
+
+    crate::create_multi_allocator_backend! {
+        Backend,
+        BackendTag,
+        dynamic = 1,
+        First => Counted::<0>,
+        Second => Counted::<1>,
+    }
+
+    static HOOKED: AtomicU16 = AtomicU16::new(0);
+
+    fn allocated() -> [usize; 2] {
+        [test_util::allocated(0), test_util::allocated(1)]
+    }
+
+    #[test]
+    fn unknown_tags() {
+        let allocator = MultiAllocator::<Backend>::new();
+        let layout = Layout::new::<u64>();
+        let alloc_with = |raw_tag: u16| {
+            let mut ptr = ptr::null_mut();
+            crate::with_allocator(raw_tag, || ptr = unsafe { allocator.alloc(layout) });
+            ptr
+        };
+        // A registry slot left empty
+        let dynamic = Backend::register(Box::new(System)).ok().unwrap();
+        drop(unsafe { Backend::unregister(dynamic) });
+        let unknown = [BackendTag::Second as u16 + 1, 200, dynamic.raw_tag()];
+
+        assert_eq!(unknown_tag_mode(), UnknownTagMode::Panic);
+        for raw_tag in unknown {
+            let before = allocated();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| alloc_with(raw_tag)));
+            assert!(result.is_err(), "{raw_tag}");
+            assert_eq!(allocated(), before, "{raw_tag}");
+        }
+        assert_eq!(unknown_tag_count(), 0);
+
+        set_unknown_tag_hook(|raw_tag| HOOKED.store(raw_tag, Ordering::SeqCst)).unwrap();
+        set_unknown_tag_mode(UnknownTagMode::Fallback);
+        assert_eq!(unknown_tag_mode(), UnknownTagMode::Fallback);
+        for (count, raw_tag) in unknown.into_iter().enumerate() {
+            let [first, second] = allocated();
+            let ptr = alloc_with(raw_tag);
+            assert!(!ptr.is_null(), "{raw_tag}");
+            // Allocated with the default allocator, and freed by it
+            assert_eq!(allocated(), [first + 1, second], "{raw_tag}");
+            assert_eq!(unknown_tag_count(), count + 1);
+            assert_eq!(HOOKED.load(Ordering::SeqCst), raw_tag);
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+
+        // Known tags aren't affected
+        let [first, second] = allocated();
+        let ptr = alloc_with(BackendTag::Second as u16);
+        assert_eq!(allocated(), [first, second + 1]);
+        unsafe { allocator.dealloc(ptr, layout) };
+        assert_eq!(unknown_tag_count(), unknown.len());
+        set_unknown_tag_mode(UnknownTagMode::Panic);
+    }
+}
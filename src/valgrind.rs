@@ -0,0 +1,78 @@
+//! Valgrind client requests
+//!
+//! With the `valgrind` feature, every block is declared to Memcheck as a block of its own, with the
+//! hidden tag as its redzone. Memcheck then reports the blocks as allocated by okaoka, and finds
+//! the pointers to them instead of pointers into the middle of the blocks of the backend, which it
+//! would report as possibly leaked. The requests are the instructions Valgrind recognizes, which do
+//! nothing outside of it, and are only emitted on x86_64 and aarch64. With the `single-allocator`
+//! feature, there's no hidden tag, so the blocks of the backend are the ones of the user and no
+//! request is emitted.
+
+const MALLOCLIKE_BLOCK: usize = 0x1301;
+const FREELIKE_BLOCK: usize = 0x1302;
+const MAKE_MEM_UNDEFINED: usize = 0x4d43_0001;
+const MAKE_MEM_DEFINED: usize = 0x4d43_0002;
+
+/// Send a client request, returning `default` when not running under Valgrind
+#[inline(always)]
+fn request(args: [usize; 6], default: usize) -> usize {
+    let result;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::asm!(
+            "rol rdi, 3",
+            "rol rdi, 13",
+            "rol rdi, 61",
+            "rol rdi, 51",
+            "xchg rbx, rbx",
+            in("rax") args.as_ptr(),
+            inout("rdx") default => result,
+            out("rdi") _,
+            options(nostack, preserves_flags),
+        )
+    };
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!(
+            "ror x12, x12, #3",
+            "ror x12, x12, #13",
+            "ror x12, x12, #51",
+            "ror x12, x12, #61",
+            "orr x10, x10, x10",
+            in("x4") args.as_ptr(),
+            inout("x3") default => result,
+            out("x12") _,
+            options(nostack, preserves_flags),
+        )
+    };
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = args;
+        result = default;
+    }
+    result
+}
+
+/// Declare the `size` bytes at `ptr` as a block, preceded by a redzone of `redzone` bytes
+///
+/// The contents of the block are undefined.
+#[inline(always)]
+pub(crate) fn malloclike(ptr: *const u8, size: usize, redzone: usize) {
+    request([MALLOCLIKE_BLOCK, ptr as usize, size, redzone, 0, 0], 0);
+}
+
+/// Release the block at `ptr` declared with [`malloclike`]
+///
+/// The block stays accessible, as it's given back to the backend, which forbids it if it's known
+/// to Valgrind. The redzone isn't touched, so the hidden tag can still be read.
+#[inline(always)]
+pub(crate) fn freelike(ptr: *const u8, size: usize) {
+    request([FREELIKE_BLOCK, ptr as usize, 0, 0, 0, 0], 0);
+    request([MAKE_MEM_UNDEFINED, ptr as usize, size, 0, 0, 0], 0);
+}
+
+/// Mark the `size` bytes at `ptr` as defined
+#[inline(always)]
+pub(crate) fn make_defined(ptr: *const u8, size: usize) {
+    request([MAKE_MEM_DEFINED, ptr as usize, size, 0, 0, 0], 0);
+}
@@ -0,0 +1,189 @@
+//! Detection of probable leaks
+//!
+//! A tag whose live bytes grow at every sample for a long time is probably leaking. A
+//! [`LeakWatchdog`] samples the statistics of every tag, with [`LeakWatchdog::sample`] or from a
+//! thread started with [`LeakWatchdog::spawn`], and calls its callback once for each run of growth
+//! that lasts at least the minimum duration with at least the minimum slope, long before the
+//! process runs out of memory:
+//!
+//! ```rust
+//! use std::{alloc::System, sync::mpsc, thread, time::Duration};
+//!
+//! use okaoka::watchdog::LeakWatchdog;
+//!
+//! okaoka::set_multi_global_allocator! {
+//!     GlobalAllocator,
+//!     AllocatorTag,
+//!     System => System,
+//!     Arena => System,
+//! }
+//!
+//! fn main() {
+//!     let (sender, suspicions) = mpsc::channel();
+//!     let mut watchdog = LeakWatchdog::<GlobalAllocator>::new(move |suspicion| {
+//!         eprintln!(
+//!             "{} grew by {:.0} bytes/s for {:?}",
+//!             suspicion.tag, suspicion.bytes_per_sec, suspicion.duration
+//!         );
+//!         sender.send(suspicion).unwrap();
+//!     })
+//!     .min_duration(Duration::from_millis(20))
+//!     .min_slope(1024.0);
+//!
+//!     let mut leaked = Vec::with_capacity(5);
+//!     for _ in 0..5 {
+//!         GlobalAllocator::with(AllocatorTag::Arena, || leaked.push(vec![0u8; 4096]));
+//!         watchdog.sample();
+//!         thread::sleep(Duration::from_millis(10));
+//!     }
+//!
+//!     // Reported once, when it had grown for 20 ms
+//!     let arena: Vec<_> = suspicions
+//!         .try_iter()
+//!         .filter(|suspicion| suspicion.tag == AllocatorTag::Arena)
+//!         .collect();
+//!     assert_eq!(arena.len(), 1);
+//!     assert!(arena[0].duration >= Duration::from_millis(20));
+//!     assert!(arena[0].live_bytes - arena[0].start_bytes >= 2 * 4096);
+//!     assert!(arena[0].bytes_per_sec >= 1024.0);
+//!
+//!     // A run of growth ends when the live bytes decrease
+//!     GlobalAllocator::with(AllocatorTag::Arena, || leaked.clear());
+//!     watchdog.sample();
+//!     assert!(suspicions.try_iter().all(|suspicion| suspicion.tag != AllocatorTag::Arena));
+//! }
+//! ```
+//!
+//! A run of growth ends when the live bytes of the tag decrease, so tags whose usage goes up and
+//! down, like caches, aren't reported.
+
+use std::{
+    io,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{stats::tag_stats, MultiAllocatorBackend};
+
+/// Tag whose live bytes grew beyond the thresholds of a [`LeakWatchdog`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LeakSuspicion<Tag> {
+    pub tag: Tag,
+    /// Live bytes when the growth started
+    pub start_bytes: usize,
+    /// Live bytes at the last sample
+    pub live_bytes: usize,
+    /// Time since the growth started
+    pub duration: Duration,
+    /// Average growth since the growth started
+    pub bytes_per_sec: f64,
+}
+
+/// Run of growth of a tag
+#[derive(Clone, Copy)]
+struct Growth {
+    start: Instant,
+    start_bytes: usize,
+    last_bytes: usize,
+    reported: bool,
+}
+
+/// Watchdog calling a callback when the live bytes of a tag grow for too long, see the module
+/// documentation
+pub struct LeakWatchdog<Backend: MultiAllocatorBackend> {
+    min_duration: Duration,
+    min_slope: f64,
+    on_suspicion: Box<dyn FnMut(LeakSuspicion<Backend::Tag>) + Send>,
+    // Growth of each tag, by index, empty until the first sample
+    growths: Vec<Growth>,
+}
+
+impl<Backend: MultiAllocatorBackend> LeakWatchdog<Backend> {
+    /// Watchdog reporting the tags that grow for a minute, at any slope
+    pub fn new(on_suspicion: impl FnMut(LeakSuspicion<Backend::Tag>) + Send + 'static) -> Self {
+        Self {
+            min_duration: Duration::from_secs(60),
+            min_slope: 0.0,
+            on_suspicion: Box::new(on_suspicion),
+            growths: Vec::new(),
+        }
+    }
+
+    /// Only report the tags that grow for at least `duration`
+    pub fn min_duration(mut self, duration: Duration) -> Self {
+        self.min_duration = duration;
+        self
+    }
+
+    /// Only report the tags that grow by at least `bytes_per_sec` on average
+    pub fn min_slope(mut self, bytes_per_sec: f64) -> Self {
+        self.min_slope = bytes_per_sec;
+        self
+    }
+
+    /// Take a snapshot of the live bytes of every tag, calling the callback for the tags that
+    /// went beyond the thresholds
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        if self.growths.is_empty() {
+            self.growths = Backend::TAGS
+                .iter()
+                .map(|&tag| {
+                    let live_bytes = tag_stats::<Backend>(tag).live_bytes;
+                    Growth {
+                        start: now,
+                        start_bytes: live_bytes,
+                        last_bytes: live_bytes,
+                        reported: false,
+                    }
+                })
+                .collect();
+            return;
+        }
+
+        for &tag in Backend::TAGS {
+            let live_bytes = tag_stats::<Backend>(tag).live_bytes;
+            let growth = &mut self.growths[Backend::tag_index(tag)];
+            if live_bytes < growth.last_bytes {
+                *growth = Growth {
+                    start: now,
+                    start_bytes: live_bytes,
+                    last_bytes: live_bytes,
+                    reported: false,
+                };
+                continue;
+            }
+            growth.last_bytes = live_bytes;
+
+            let duration = now.duration_since(growth.start);
+            if growth.reported || live_bytes == growth.start_bytes || duration < self.min_duration {
+                continue;
+            }
+            let bytes_per_sec = (live_bytes - growth.start_bytes) as f64 / duration.as_secs_f64();
+            if bytes_per_sec >= self.min_slope {
+                growth.reported = true;
+                (self.on_suspicion)(LeakSuspicion {
+                    tag,
+                    start_bytes: growth.start_bytes,
+                    live_bytes,
+                    duration,
+                    bytes_per_sec,
+                });
+            }
+        }
+    }
+
+    /// Sample every `interval` from a new thread, for the rest of the process
+    pub fn spawn(mut self, interval: Duration) -> io::Result<JoinHandle<()>>
+    where
+        Backend: 'static,
+    {
+        thread::Builder::new()
+            .name("okaoka-watchdog".into())
+            .spawn(move || loop {
+                self.sample();
+                thread::sleep(interval);
+            })
+    }
+}
@@ -0,0 +1,41 @@
+//! Clones made with `clone_into` are allocated with the requested allocator
+//!
+//! In a test binary of its own, as it installs the global allocator.
+
+// There's a single allocator with `single-allocator`
+#![cfg(not(feature = "single-allocator"))]
+
+#[path = "../src/test_util.rs"]
+mod test_util;
+
+use std::alloc::System;
+
+use test_util::{allocated, freed, Counted};
+
+okaoka::set_multi_global_allocator! {
+    GlobalAllocator,
+    AllocatorTag,
+    System => System,
+    Heap => Counted::<8>,
+    Arena => Counted::<9>,
+}
+
+#[test]
+fn containers_and_elements_are_cloned_with_the_allocator() {
+    let mut names = Vec::new();
+    GlobalAllocator::with(AllocatorTag::Arena, || {
+        names = vec!["request".to_string(), "response".to_string()];
+    });
+    // The vector and its strings
+    assert_eq!((allocated(8), allocated(9)), (0, 3));
+
+    let kept = okaoka::clone_into(AllocatorTag::Heap as u8, &names);
+    assert_eq!(kept, ["request", "response"]);
+    assert_eq!((allocated(8), allocated(9)), (3, 3));
+
+    // Blocks are freed by the allocator that made them, whatever the current one
+    okaoka::drop_in(AllocatorTag::Heap as u8, names);
+    assert_eq!((freed(8), freed(9)), (0, 3));
+    drop(kept);
+    assert_eq!((freed(8), freed(9)), (3, 3));
+}
@@ -0,0 +1,60 @@
+//! Model checks of the tag storage, the counters and the registry
+//!
+//! Run with `RUSTFLAGS="--cfg loom" cargo test --features stats --test loom --release`.
+
+#![cfg(loom)]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+
+use loom::thread;
+use okaoka::{MultiAllocator, MultiAllocatorBackend};
+
+okaoka::create_multi_allocator_backend! {
+    Backend,
+    BackendTag,
+    dynamic = 1,
+    System => System,
+    Arena => System,
+}
+
+/// Allocate and free a block with the current tag of the thread
+fn churn() {
+    let allocator = MultiAllocator::<Backend>::new();
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn threads_allocate_with_their_own_tag() {
+    loom::model(|| {
+        let arena = thread::spawn(|| Backend::with(BackendTag::Arena, churn));
+        churn();
+        arena.join().unwrap();
+
+        let system = Backend::system_stats();
+        let arena = Backend::arena_stats();
+        assert_eq!((system.allocations, system.live_bytes), (1, 0));
+        assert_eq!((arena.allocations, arena.live_bytes), (1, 0));
+    });
+}
+
+#[test]
+fn swap_while_allocating() {
+    loom::model(|| {
+        let tag = Backend::register(Box::new(System)).ok().unwrap();
+        let churning = thread::spawn(move || okaoka::with_allocator(tag, churn));
+        let swapped = Backend::swap(tag, Box::new(System)).ok().unwrap();
+        assert!(swapped.is_none());
+        churning.join().unwrap();
+
+        // Whichever generation the block was allocated from, it has been freed
+        let slot = Backend::dynamic_slot(tag.raw_tag()).unwrap();
+        assert_eq!(slot.live_blocks(0) + slot.live_blocks(1), 0);
+        assert!(Backend::reclaim(tag).is_some());
+        drop(unsafe { Backend::unregister(tag) });
+    });
+}
@@ -0,0 +1,122 @@
+//! Switching allocators and entering signal scopes don't allocate
+//!
+//! In a test binary of its own, as it replaces the global allocator.
+
+// Scopes are ignored with `single-allocator`
+#![cfg(all(feature = "signal-scope", not(feature = "single-allocator")))]
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicU16, AtomicUsize, Ordering},
+    thread,
+};
+
+use okaoka::{
+    signal_scope::{signal_route, signal_scope},
+    AllocatorGuard, MultiAllocator,
+};
+
+thread_local! {
+    /// Whether the allocations of the thread are counted
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocator counting the allocations of the threads that asked for it
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.try_with(Cell::get).unwrap_or(false) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.try_with(Cell::get).unwrap_or(false) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Id of the last `Marked` allocator that allocated
+static LAST_MARK: AtomicU16 = AtomicU16::new(u16::MAX);
+
+/// Allocator recording its id in `LAST_MARK`
+struct Marked(u16);
+
+unsafe impl GlobalAlloc for Marked {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LAST_MARK.store(self.0, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+okaoka::create_multi_allocator_backend! {
+    Backend,
+    BackendTag,
+    Zero => Marked(0),
+    One => Marked(1),
+    Two => Marked(2),
+    Three => Marked(3),
+    Four => Marked(4),
+    Five => Marked(5),
+}
+
+/// Raw tag of the allocator selected for the thread, found without counting allocations
+fn selected() -> u16 {
+    let counting = COUNTING.with(|counting| counting.replace(false));
+    let allocator = MultiAllocator::<Backend>::new();
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        assert!(!ptr.is_null());
+        allocator.dealloc(ptr, layout);
+    }
+    COUNTING.with(|flag| flag.set(counting));
+    LAST_MARK.load(Ordering::Relaxed)
+}
+
+#[test]
+fn switching_allocators_does_not_allocate() {
+    // On a new thread, so that the first accesses to its thread-locals are counted too
+    thread::spawn(|| {
+        COUNTING.with(|counting| counting.set(true));
+        let base = AllocatorGuard::new(1u16);
+        assert_eq!(selected(), 1);
+        {
+            let _outer = AllocatorGuard::new(2u16);
+            let _inner = AllocatorGuard::new(3u16);
+            assert_eq!(selected(), 3);
+            signal_scope(4u16, || {
+                assert_eq!(signal_route(), Some(4));
+                assert_eq!(selected(), 4);
+                signal_scope(5u16, || assert_eq!(signal_route(), Some(5)));
+                assert_eq!(signal_route(), Some(4));
+            });
+            assert_eq!(signal_route(), None);
+        }
+        assert_eq!(selected(), 1);
+        drop(base);
+        assert_eq!(selected(), 0);
+        COUNTING.with(|counting| counting.set(false));
+        assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+    })
+    .join()
+    .unwrap();
+}